@@ -0,0 +1,265 @@
+//! Aggregator carries the names of multiple local agents over a single
+//! gateway connection. The local agents connect to the aggregator like they
+//! connect to a gateway (over localhost), and the aggregating agent forwards
+//! the streams of each aggregated name to the local agent that registered it:
+//!
+//! ```no_run
+//! # async fn example() -> diglett::Result<()> {
+//! use diglett::agent::{Agent, Aggregator};
+//!
+//! let aggregator = Aggregator::new("127.0.0.1:20001".parse().unwrap())
+//!     .name("web", None)
+//!     .name("api", Some(22));
+//!
+//! let agent = Agent::builder()
+//!     .gateway("gateway.com:20000")
+//!     .token("secret")
+//!     .aggregate(aggregator)
+//!     .build()?;
+//!
+//! agent.run().await
+//! # }
+//! ```
+//!
+//! The local agents then run as usual, for example
+//! `diglett -g 127.0.0.1:20001 -n web localhost:3000`
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use tokio::{io::DuplexStream, net::TcpStream};
+
+use super::Backend;
+use crate::{
+    server::{
+        auth::{Authenticate, User},
+        register::{Handler, Registerer},
+        Server,
+    },
+    wire, Error, Result,
+};
+
+// local port of each name registered by a local agent
+type Ports = Arc<Mutex<HashMap<String, u16>>>;
+
+/// Aggregator accepts the local agents of the aggregated names, see the
+/// [module](self) documentation
+#[derive(Clone)]
+pub struct Aggregator {
+    listen: SocketAddr,
+    token: Option<String>,
+    names: Vec<(String, Option<u16>)>,
+    ports: Ports,
+}
+
+impl Aggregator {
+    /// accept the local agents on that address (usually on localhost)
+    pub fn new(listen: SocketAddr) -> Self {
+        Self {
+            listen,
+            token: None,
+            names: Vec::default(),
+            ports: Ports::default(),
+        }
+    }
+
+    /// only accept the local agents that login with that token. Default to
+    /// any token
+    pub fn with_token<T: Into<String>>(mut self, token: T) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// aggregate the name (or the service of the name on that port), it's
+    /// registered with the gateway and served by the local agent of the name
+    pub fn name<N: Into<String>>(mut self, name: N, port: Option<u16>) -> Self {
+        self.names.push((name.into().to_lowercase(), port));
+        self
+    }
+
+    /// the aggregated names and their ports
+    pub fn names(&self) -> &[(String, Option<u16>)] {
+        &self.names
+    }
+
+    /// backend of an aggregated name, the streams are connected to the
+    /// listener of the name on the local server. They are closed right
+    /// away while no local agent serves the name
+    pub(crate) fn backend(&self, name: &str, port: Option<u16>) -> Backend {
+        let name = match port {
+            Some(port) => format!("{}:{}", name, port),
+            None => name.into(),
+        };
+        let ports = Arc::clone(&self.ports);
+
+        Backend::local(move |stream: DuplexStream| {
+            let port = ports.lock().unwrap().get(&name).copied();
+            let name = name.clone();
+            tokio::spawn(async move {
+                let Some(port) = port else {
+                    log::debug!("no local agent serves '{}', closing stream", name);
+                    return;
+                };
+
+                if let Err(err) = relay(stream, port).await {
+                    log::debug!("failed to relay stream of '{}': {}", name, err);
+                }
+            });
+        })
+    }
+
+    /// accept the local agents until the shutdown future resolves
+    pub async fn serve_until<S: Future<Output = ()>>(self, shutdown: S) -> Result<()> {
+        let names = self.names.iter().map(|(name, _)| name.clone()).collect();
+        let auth = Local {
+            token: self.token,
+            names,
+        };
+        let server = Server::new(wire::keypair(), auth, Registrations(self.ports));
+
+        log::info!("accepting local agents on {}", self.listen);
+        // the server future is too large to be moved around on the stack
+        Box::pin(server.start_until(self.listen, shutdown)).await
+    }
+}
+
+async fn relay(mut stream: DuplexStream, port: u16) -> Result<()> {
+    let mut local = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut local).await?;
+    Ok(())
+}
+
+// authenticates the local agents, they can only register the aggregated names
+struct Local {
+    token: Option<String>,
+    names: HashSet<String>,
+}
+
+#[async_trait::async_trait]
+impl Authenticate for Local {
+    type U = ();
+
+    async fn authenticate(&self, token: &str) -> Result<User<()>> {
+        if matches!(&self.token, Some(expected) if expected != token) {
+            return Err(Error::AuthenticationError("invalid token".into()));
+        }
+
+        Ok(User::new(()))
+    }
+
+    async fn authorize(&self, _user: &Self::U, name: &str) -> Result<bool> {
+        Ok(self.names.contains(name))
+    }
+}
+
+// records the local port of the registered names
+struct Registrations(Ports);
+
+#[async_trait::async_trait]
+impl Registerer for Registrations {
+    type Handler = Registered;
+
+    async fn register(&self, name: &str, port: u16) -> Result<Registered> {
+        log::info!("local agent serves '{}'", name);
+        self.0.lock().unwrap().insert(name.into(), port);
+
+        Ok(Registered {
+            ports: Arc::clone(&self.0),
+            name: name.into(),
+            port,
+        })
+    }
+}
+
+struct Registered {
+    ports: Ports,
+    name: String,
+    port: u16,
+}
+
+impl Handler for Registered {}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        log::info!("local agent of '{}' is gone", self.name);
+        let mut ports = self.ports.lock().unwrap();
+        if ports.get(&self.name) == Some(&self.port) {
+            ports.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        agent::{login, register, serve_all, Options},
+        wire::{Client, Registration},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn aggregate() {
+        // a free port for the aggregator
+        let listen = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let aggregator = Aggregator::new(listen)
+            .with_token("local")
+            .name("web", None);
+        let web = aggregator.backend("web", None);
+        tokio::spawn(aggregator.serve_until(std::future::pending()));
+
+        // nothing serves the name yet
+        let (mut read, _write) = web.connect().await.unwrap();
+        assert_eq!(read.read(&mut [0; 16]).await.unwrap(), 0);
+
+        // the local agent echoes the data
+        let echo = Backend::local(|stream| {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        });
+        // the connections are boxed, they are too large for the test stack
+        let connect = || async {
+            let stream = TcpStream::connect(listen).await.unwrap();
+            Box::new(
+                Client::new(stream, wire::keypair())
+                    .negotiate()
+                    .await
+                    .unwrap(),
+            )
+        };
+
+        {
+            let mut local = connect().await;
+            assert!(login(&mut local, "other").await.is_err());
+        }
+        {
+            let mut local = connect().await;
+            login(&mut local, "local").await.unwrap();
+            assert!(register(&mut local, "api").await.is_err());
+        }
+
+        let mut local = connect().await;
+        login(&mut local, "local").await.unwrap();
+        register(&mut local, "web").await.unwrap();
+        let backends = HashMap::from([(Registration::from(0), echo)]);
+        tokio::spawn(serve_all(*local, backends, Options::default()));
+
+        let (mut read, mut write) = web.connect().await.unwrap();
+        write.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        read.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
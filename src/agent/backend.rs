@@ -0,0 +1,844 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    net::{TcpStream, UdpSocket, UnixStream},
+};
+
+use super::{docker, socks};
+#[cfg(feature = "tls")]
+use crate::tls::{self, ServerName, TlsConnector};
+use crate::{Error, Result};
+
+pub(crate) type BackendReader = Box<dyn AsyncRead + Unpin + Send>;
+pub(crate) type BackendWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// time the primary backend is not dialed after a failed connection, new
+/// streams go to the fallback meanwhile
+const PRIMARY_RETRY: Duration = Duration::from_secs(5);
+
+/// max time to wait for a busy named pipe
+#[cfg(windows)]
+const PIPE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// max size of a udp datagram
+const MAX_DATAGRAM: usize = u16::MAX as usize;
+
+/// size of the in memory pipe of an in-process stream
+const LOCAL_BUFFER: usize = 64 * 1024;
+
+/// Serve handles the streams of an in-process backend, like the http server
+/// of an application that embeds the agent. Each stream is one end of an in
+/// memory pipe, the agent holds the other end, so no socket is involved
+pub trait Serve: Send + Sync + 'static {
+    /// handle a new stream, it must not block (spawn a task instead)
+    fn serve(&self, stream: DuplexStream);
+}
+
+impl<F> Serve for F
+where
+    F: Fn(DuplexStream) + Send + Sync + 'static,
+{
+    fn serve(&self, stream: DuplexStream) {
+        self(stream)
+    }
+}
+
+/// Backend the streams of a registration are forwarded to. Addresses
+/// prefixed with `unix:` are unix sockets, `https://` are tls backends,
+/// `udp://` are udp services, `docker://` are ports of docker containers,
+/// `npipe:` are windows named pipes, `socks5://` is a socks5 server in the
+/// agent and anything else is a tcp address (host:port)
+#[derive(Clone)]
+pub enum Backend {
+    Tcp(String),
+    Unix(PathBuf),
+    /// each stream is relayed over its own udp socket. The datagrams are
+    /// framed on the stream with a 2 bytes length prefix (like dns over tcp)
+    Udp(String),
+    /// port of a docker container, the address of the container is resolved
+    /// for each stream
+    Docker {
+        container: String,
+        port: u16,
+    },
+    /// socks5 server in the agent, the clients of the stream connect to any
+    /// host of the agent network
+    Socks(Option<socks::Credentials>),
+    /// in-process backend, the streams are handed to the application
+    Local(Arc<dyn Serve>),
+    /// windows named pipe, like `\\.\pipe\app`
+    #[cfg(windows)]
+    Pipe(String),
+    #[cfg(feature = "tls")]
+    Tls {
+        address: String,
+        name: ServerName<'static>,
+        connector: TlsConnector,
+    },
+}
+
+/// Options of https backends
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// ca bundle to verify the backend against instead of the web pki roots
+    pub ca: Option<PathBuf>,
+    /// server name sent to the backend and verified instead of its host
+    pub sni: Option<String>,
+    /// don't verify the backend certificate
+    pub insecure: bool,
+}
+
+impl TlsOptions {
+    fn is_set(&self) -> bool {
+        self.ca.is_some() || self.sni.is_some() || self.insecure
+    }
+}
+
+impl Backend {
+    /// parse the backend address, the options apply to https backends only
+    pub fn parse(value: &str, options: &TlsOptions) -> Result<Self> {
+        if let Some(address) = value.strip_prefix("https://") {
+            return Self::tls(address.trim_end_matches('/'), options);
+        }
+
+        if options.is_set() {
+            return Err(Error::Config(format!(
+                "tls options are set for backend '{}' which is not https",
+                value
+            )));
+        }
+
+        if let Some(address) = value.strip_prefix("udp://") {
+            return Ok(Self::Udp(address.trim_end_matches('/').into()));
+        }
+
+        if let Some(address) = value.strip_prefix("docker://") {
+            return Self::docker(address.trim_end_matches('/'));
+        }
+
+        if let Some(path) = value.strip_prefix("npipe:") {
+            return Self::pipe(path);
+        }
+
+        if let Some(credentials) = value.strip_prefix("socks5://") {
+            return Ok(Self::Socks(socks::Credentials::parse(credentials)?));
+        }
+
+        Ok(match value.strip_prefix("unix:") {
+            Some(path) => Self::Unix(path.into()),
+            None => Self::Tcp(value.into()),
+        })
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls(address: &str, options: &TlsOptions) -> Result<Self> {
+        // the port is optional, the host can be an ipv6 address in brackets
+        let (host, address) = match address.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, address.to_string()),
+            _ => (address, format!("{}:443", address)),
+        };
+
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = tls::server_name(options.sni.as_deref().unwrap_or(host))?;
+        let ca = options.ca.as_ref().map(tls::certificates).transpose()?;
+        let connector = TlsConnector::from(tls::backend_config(ca, options.insecure)?);
+
+        Ok(Self::Tls {
+            address,
+            name,
+            connector,
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn tls(address: &str, _options: &TlsOptions) -> Result<Self> {
+        Err(Error::Config(format!(
+            "backend https://{} requires the tls feature",
+            address
+        )))
+    }
+
+    fn docker(address: &str) -> Result<Self> {
+        let port = address.rsplit_once(':').and_then(|(container, port)| {
+            Some((
+                container,
+                port.parse::<u16>().ok().filter(|port| *port > 0)?,
+            ))
+        });
+        let Some((container, port)) = port else {
+            return Err(Error::Config(format!(
+                "docker backend '{}' must be <container>:<port>",
+                address
+            )));
+        };
+
+        docker::container(container)?;
+        Ok(Self::Docker {
+            container: container.into(),
+            port,
+        })
+    }
+
+    #[cfg(windows)]
+    fn pipe(path: &str) -> Result<Self> {
+        Ok(Self::Pipe(pipe_path(path)?))
+    }
+
+    #[cfg(not(windows))]
+    fn pipe(path: &str) -> Result<Self> {
+        Err(Error::Config(format!(
+            "named pipe backend {} is only supported on windows",
+            pipe_path(path)?
+        )))
+    }
+
+    /// in-process backend, each stream is handed to serve
+    ///
+    /// ```no_run
+    /// use diglett::agent::Backend;
+    ///
+    /// let backend = Backend::local(|stream| {
+    ///     tokio::spawn(async move {
+    ///         let (mut reader, mut writer) = tokio::io::split(stream);
+    ///         let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    ///     });
+    /// });
+    /// ```
+    pub fn local<S: Serve>(serve: S) -> Self {
+        Self::Local(Arc::new(serve))
+    }
+
+    /// in-process http backend, each stream is an http/1 connection served
+    /// by the tower service (like an axum router)
+    #[cfg(feature = "tower")]
+    pub fn service<S, B>(service: S) -> Self
+    where
+        S: tower_service::Service<
+                hyper::Request<hyper::body::Incoming>,
+                Response = hyper::Response<B>,
+            > + Clone
+            + Send
+            + Sync
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        B: hyper::body::Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+
+        Self::local(move |stream: DuplexStream| {
+            let service = TowerToHyperService::new(service.clone());
+            tokio::spawn(async move {
+                let connection = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .with_upgrades();
+                if let Err(err) = connection.await {
+                    log::debug!("in-process http connection failed: {}", err);
+                }
+            });
+        })
+    }
+
+    /// open a new connection to the backend
+    pub(crate) async fn connect(&self) -> Result<(BackendReader, BackendWriter)> {
+        match self {
+            Self::Tcp(address) => {
+                let (read, write) = TcpStream::connect(address).await?.into_split();
+                Ok((Box::new(read), Box::new(write)))
+            }
+            Self::Unix(path) => {
+                let (read, write) = UnixStream::connect(path).await?.into_split();
+                Ok((Box::new(read), Box::new(write)))
+            }
+            Self::Udp(address) => {
+                let (read, write) = udp(address).await?;
+                Ok((Box::new(read), Box::new(write)))
+            }
+            Self::Docker { container, port } => {
+                let address = docker::address(container).await?;
+                let (read, write) = TcpStream::connect((address, *port)).await?.into_split();
+                Ok((Box::new(read), Box::new(write)))
+            }
+            Self::Socks(credentials) => {
+                let (read, write) = socks::connect(credentials.clone());
+                Ok((Box::new(read), Box::new(write)))
+            }
+            Self::Local(serve) => {
+                let (stream, local) = tokio::io::duplex(LOCAL_BUFFER);
+                serve.serve(local);
+                let (read, write) = tokio::io::split(stream);
+                Ok((Box::new(read), Box::new(write)))
+            }
+            #[cfg(windows)]
+            Self::Pipe(path) => {
+                let (read, write) = tokio::io::split(pipe(path).await?);
+                Ok((Box::new(read), Box::new(write)))
+            }
+            #[cfg(feature = "tls")]
+            Self::Tls {
+                address,
+                name,
+                connector,
+            } => {
+                let stream = TcpStream::connect(address).await?;
+                let stream = connector.connect(name.clone(), stream).await?;
+                let (read, write) = tokio::io::split(stream);
+                Ok((Box::new(read), Box::new(write)))
+            }
+        }
+    }
+}
+
+// path of a named pipe in the url form (`//./pipe/app`, like docker) or as is
+// (`\\.\pipe\app`)
+fn pipe_path(path: &str) -> Result<String> {
+    let path = path.replace('/', "\\");
+    let path = path.trim_start_matches('\\');
+    match path.split('\\').nth(1) {
+        Some(pipe) if pipe.eq_ignore_ascii_case("pipe") => Ok(format!("\\\\{}", path)),
+        _ => Err(Error::Config(format!("invalid named pipe '{}'", path))),
+    }
+}
+
+// open the named pipe, waiting while all its instances are busy serving
+// other clients
+#[cfg(windows)]
+async fn pipe(path: &str) -> Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+
+    const ERROR_PIPE_BUSY: i32 = 231;
+    let deadline = Instant::now() + PIPE_BUSY_TIMEOUT;
+    loop {
+        match ClientOptions::new().open(path) {
+            Ok(client) => return Ok(client),
+            Err(err)
+                if err.raw_os_error() == Some(ERROR_PIPE_BUSY) && Instant::now() < deadline =>
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+// open a udp socket connected to the address, the datagrams are relayed
+// from and to the returned stream until it's closed
+async fn udp(address: &str) -> Result<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>)> {
+    let target = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| Error::Config(format!("can't resolve udp backend '{}'", address)))?;
+    let local: SocketAddr = match target {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+
+    let (stream, relay) = tokio::io::duplex(2 * (MAX_DATAGRAM + 2));
+    tokio::spawn(async move {
+        let (reader, writer) = tokio::io::split(relay);
+        let result = tokio::select! {
+            result = send_datagrams(&socket, reader) => result,
+            result = recv_datagrams(&socket, writer) => result,
+        };
+
+        if let Err(err) = result {
+            log::debug!("udp backend {} relay ended: {}", target, err);
+        }
+    });
+
+    Ok(tokio::io::split(stream))
+}
+
+// send the framed datagrams of the stream until it's closed
+async fn send_datagrams(
+    socket: &UdpSocket,
+    mut reader: ReadHalf<DuplexStream>,
+) -> std::io::Result<()> {
+    let mut datagram = vec![0; MAX_DATAGRAM];
+    loop {
+        let len = match reader.read_u16().await {
+            Ok(len) => len as usize,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        reader.read_exact(&mut datagram[..len]).await?;
+        socket.send(&datagram[..len]).await?;
+    }
+}
+
+// frame the received datagrams on the stream
+async fn recv_datagrams(
+    socket: &UdpSocket,
+    mut writer: WriteHalf<DuplexStream>,
+) -> std::io::Result<()> {
+    let mut datagram = vec![0; MAX_DATAGRAM];
+    loop {
+        let len = socket.recv(&mut datagram).await?;
+        writer.write_u16(len as u16).await?;
+        writer.write_all(&datagram[..len]).await?;
+    }
+}
+
+/// Pool distributes the new streams round robin between its backends, the
+/// backends that refuse a connection are skipped. The streams dial their
+/// backends concurrently
+pub(crate) struct Pool {
+    backends: Vec<Backend>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    pub fn new(backends: Vec<Backend>) -> Self {
+        Self {
+            backends,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn backends(&self) -> &[Backend] {
+        &self.backends
+    }
+
+    /// connect to the next backend that accepts the connection. It returns
+    /// the backend of the connection
+    pub async fn connect(&self) -> Result<(BackendReader, BackendWriter, &Backend)> {
+        let mut error = None;
+        let mut connection = None;
+        for _ in 0..self.backends.len() {
+            let index = self.next.fetch_add(1, Ordering::Relaxed) % self.backends.len();
+
+            match self.backends[index].connect().await {
+                Ok((read, write)) => {
+                    connection = Some((read, write, index));
+                    break;
+                }
+                Err(err) => {
+                    if self.backends.len() > 1 {
+                        log::debug!(
+                            "backend {} refused connection: {}",
+                            self.backends[index],
+                            err
+                        );
+                    }
+                    error = Some(err);
+                }
+            }
+        }
+
+        match connection {
+            Some((read, write, index)) => Ok((read, write, &self.backends[index])),
+            None => Err(error.unwrap_or_else(|| Error::Config("no backends".into()))),
+        }
+    }
+}
+
+impl Display for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, backend) in self.backends.iter().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", backend)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Failover dials a fallback backend for new streams while the primary
+/// backends are down, and switches back once they recover
+pub(crate) struct Failover {
+    fallback: Backend,
+    // last failed connection to the primary
+    failed: std::sync::Mutex<Option<Instant>>,
+}
+
+impl Failover {
+    pub fn new(fallback: Backend) -> Self {
+        Self {
+            fallback,
+            failed: Default::default(),
+        }
+    }
+
+    /// connect to the primary backends, or to the fallback if they are down.
+    /// It returns the backend of the connection
+    pub async fn connect<'a>(
+        &'a self,
+        primary: &'a Pool,
+    ) -> Result<(BackendReader, BackendWriter, &'a Backend)> {
+        let retry = match *self.failed.lock().unwrap() {
+            Some(failed) => failed.elapsed() >= PRIMARY_RETRY,
+            None => true,
+        };
+
+        if retry {
+            match primary.connect().await {
+                Ok(connection) => {
+                    if self.failed.lock().unwrap().take().is_some() {
+                        log::info!("primary backend {} recovered", primary);
+                    }
+                    return Ok(connection);
+                }
+                Err(err) => {
+                    if self
+                        .failed
+                        .lock()
+                        .unwrap()
+                        .replace(Instant::now())
+                        .is_none()
+                    {
+                        log::warn!(
+                            "primary backend {} is down ({}), using fallback {}",
+                            primary,
+                            err,
+                            self.fallback
+                        );
+                    }
+                }
+            }
+        }
+
+        let (read, write) = self.fallback.connect().await?;
+        Ok((read, write, &self.fallback))
+    }
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Self::parse(value, &TlsOptions::default())
+    }
+}
+
+impl From<SocketAddr> for Backend {
+    fn from(value: SocketAddr) -> Self {
+        Self::Tcp(value.to_string())
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{}", address),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Udp(address) => write!(f, "udp://{}", address),
+            Self::Docker { container, port } => write!(f, "docker://{}:{}", container, port),
+            Self::Socks(None) => write!(f, "socks5://"),
+            Self::Socks(Some(credentials)) => write!(f, "socks5://{}", credentials),
+            Self::Local(_) => write!(f, "in-process"),
+            #[cfg(windows)]
+            Self::Pipe(path) => write!(f, "npipe://{}", path.replace('\\', "/")),
+            #[cfg(feature = "tls")]
+            Self::Tls { address, .. } => write!(f, "https://{}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let backend: Backend = "localhost:8080".parse().unwrap();
+        assert!(matches!(&backend, Backend::Tcp(address) if address == "localhost:8080"));
+
+        let backend: Backend = "unix:/run/app.sock".parse().unwrap();
+        assert!(matches!(&backend, Backend::Unix(path) if path.to_str() == Some("/run/app.sock")));
+        assert_eq!(backend.to_string(), "unix:/run/app.sock");
+
+        #[cfg(feature = "tls")]
+        {
+            let backend: Backend = "https://internal.service".parse().unwrap();
+            assert_eq!(backend.to_string(), "https://internal.service:443");
+        }
+
+        let backend: Backend = "udp://127.0.0.1:53".parse().unwrap();
+        assert!(matches!(&backend, Backend::Udp(address) if address == "127.0.0.1:53"));
+        assert_eq!(backend.to_string(), "udp://127.0.0.1:53");
+
+        let backend: Backend = "docker://web-1:8080".parse().unwrap();
+        assert!(
+            matches!(&backend, Backend::Docker { container, port } if container == "web-1" && *port == 8080)
+        );
+        assert_eq!(backend.to_string(), "docker://web-1:8080");
+        assert!("docker://web".parse::<Backend>().is_err());
+        assert!("docker://web:0".parse::<Backend>().is_err());
+
+        let backend: Backend = "socks5://".parse().unwrap();
+        assert!(matches!(&backend, Backend::Socks(None)));
+        let backend: Backend = "socks5://user:secret@".parse().unwrap();
+        assert_eq!(backend.to_string(), "socks5://user:***@");
+        assert!("socks5://localhost:1080".parse::<Backend>().is_err());
+
+        assert_eq!(pipe_path("////./pipe/app").unwrap(), r"\\.\pipe\app");
+        assert_eq!(pipe_path(r"\\.\pipe\app").unwrap(), r"\\.\pipe\app");
+        assert_eq!(pipe_path("//host/pipe/app").unwrap(), r"\\host\pipe\app");
+        assert!(pipe_path("/tmp/app").is_err());
+        #[cfg(not(windows))]
+        assert!("npipe:////./pipe/app".parse::<Backend>().is_err());
+
+        let options = TlsOptions {
+            insecure: true,
+            ..Default::default()
+        };
+        assert!(Backend::parse("localhost:8080", &options).is_err());
+    }
+
+    #[tokio::test]
+    async fn unix() {
+        let path =
+            std::env::temp_dir().join(format!("diglett-backend-{}.sock", std::process::id()));
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let handler = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let (mut read, _write) = Backend::Unix(path.clone()).connect().await.unwrap();
+        let mut buf = String::new();
+        read.read_to_string(&mut buf).await.unwrap();
+        handler.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(buf, "hello");
+    }
+
+    #[tokio::test]
+    async fn local() {
+        let backend = Backend::local(|stream| {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        });
+        assert_eq!(backend.to_string(), "in-process");
+
+        let (mut read, mut write) = backend.connect().await.unwrap();
+        write.write_all(b"hello").await.unwrap();
+        write.shutdown().await.unwrap();
+        let mut buf = String::new();
+        read.read_to_string(&mut buf).await.unwrap();
+
+        assert_eq!(buf, "hello");
+    }
+
+    #[cfg(feature = "tower")]
+    #[tokio::test]
+    async fn service() {
+        use std::{
+            future::{ready, Ready},
+            task::{Context, Poll},
+        };
+
+        #[derive(Clone)]
+        struct Hello;
+
+        impl<B> tower_service::Service<hyper::Request<B>> for Hello {
+            type Response = hyper::Response<String>;
+            type Error = std::convert::Infallible;
+            type Future = Ready<std::result::Result<Self::Response, Self::Error>>;
+
+            fn poll_ready(
+                &mut self,
+                _: &mut Context<'_>,
+            ) -> Poll<std::result::Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, request: hyper::Request<B>) -> Self::Future {
+                ready(Ok(hyper::Response::new(format!(
+                    "hello {}",
+                    request.uri().path()
+                ))))
+            }
+        }
+
+        let (mut read, mut write) = Backend::service(Hello).connect().await.unwrap();
+        write
+            .write_all(b"GET /world HTTP/1.1\r\nHost: web\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = String::new();
+        read.read_to_string(&mut buf).await.unwrap();
+
+        assert!(buf.starts_with("HTTP/1.1 200 OK"));
+        assert!(buf.ends_with("hello /world"));
+    }
+
+    #[tokio::test]
+    async fn udp() {
+        // echo service
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                socket.send_to(&buf[..len], peer).await.unwrap();
+            }
+        });
+
+        let backend = Backend::Udp(address.to_string());
+        let (mut read, mut write) = backend.connect().await.unwrap();
+        write.write_all(b"\x00\x05hello\x00\x03bye").await.unwrap();
+
+        let mut buf = [0; 12];
+        read.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x00\x05hello\x00\x03bye");
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        let fallback = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let failover = Failover::new(fallback.local_addr().unwrap().into());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = fallback.accept().await.unwrap();
+                let _ = stream.write_all(b"fallback").await;
+            }
+        });
+
+        // nothing is listening on the primary
+        let primary = Pool::new(vec![Backend::Tcp("127.0.0.1:1".into())]);
+        let (mut read, _write, backend) = failover.connect(&primary).await.unwrap();
+        let mut buf = String::new();
+        read.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "fallback");
+        assert_ne!(backend.to_string(), "127.0.0.1:1");
+        assert!(failover.failed.lock().unwrap().is_some());
+
+        // the primary is dialed again after a while
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let primary = Pool::new(vec![address.into()]);
+        let (_, _, backend) = failover.connect(&primary).await.unwrap();
+        assert_ne!(backend.to_string(), address.to_string());
+
+        *failover.failed.lock().unwrap() = Some(Instant::now() - PRIMARY_RETRY);
+        let (_, _, backend) = failover.connect(&primary).await.unwrap();
+        assert_eq!(backend.to_string(), address.to_string());
+        assert!(failover.failed.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn pool() {
+        let first = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (first, second) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+        let pool = Pool::new(vec![
+            first.into(),
+            Backend::Tcp("127.0.0.1:1".into()),
+            second.into(),
+        ]);
+
+        // round robin, the backend that refuses connections is skipped
+        let mut connected = vec![];
+        for _ in 0..4 {
+            let (_, _, backend) = pool.connect().await.unwrap();
+            connected.push(backend.to_string());
+        }
+
+        let (first, second) = (first.to_string(), second.to_string());
+        assert_eq!(
+            connected,
+            [first.clone(), second.clone(), first.clone(), second.clone()]
+        );
+        assert_eq!(
+            pool.to_string(),
+            format!("{},127.0.0.1:1,{}", first, second)
+        );
+
+        let pool = Pool::new(vec![Backend::Tcp("127.0.0.1:1".into())]);
+        assert!(pool.connect().await.is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn https() {
+        use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+        let certified =
+            rcgen::generate_simple_self_signed(vec!["internal.service".into()]).unwrap();
+        let ca = std::env::temp_dir().join(format!("diglett-backend-{}.pem", std::process::id()));
+        std::fs::write(&ca, certified.cert.pem()).unwrap();
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certified.cert.der().clone()],
+                tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer::from(
+                    certified.key_pair.serialize_der(),
+                )
+                .into(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(std::sync::Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("https://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let _ = stream.write_all(b"hello").await;
+                    let _ = stream.shutdown().await;
+                }
+            }
+        });
+
+        let fetch = |options: TlsOptions| {
+            let address = address.clone();
+            async move {
+                let (mut read, _write) = Backend::parse(&address, &options)?.connect().await?;
+                let mut buf = String::new();
+                read.read_to_string(&mut buf).await?;
+                Ok::<_, Error>(buf)
+            }
+        };
+
+        // verified against the ca with the sni override
+        let verified = fetch(TlsOptions {
+            ca: Some(ca.clone()),
+            sni: Some("internal.service".into()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(verified.unwrap(), "hello");
+
+        // the backend name doesn't match the certificate
+        let mismatch = fetch(TlsOptions {
+            ca: Some(ca.clone()),
+            ..Default::default()
+        })
+        .await;
+        assert!(mismatch.is_err());
+
+        let insecure = fetch(TlsOptions {
+            insecure: true,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(insecure.unwrap(), "hello");
+
+        std::fs::remove_file(&ca).unwrap();
+    }
+}
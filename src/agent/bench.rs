@@ -0,0 +1,337 @@
+//! Bench measures a real tunnel through a gateway. The agent registers an
+//! echo name served in-process, and a client dials the name over another
+//! gateway connection (see [`client`](crate::client)), so every byte goes
+//! through the gateway and both tunnels, and back:
+//!
+//! ```no_run
+//! # async fn example() -> diglett::Result<()> {
+//! use diglett::agent::bench::Bench;
+//!
+//! let report = Bench::new("gateway.com:20000", "secret")
+//!     .with_size(256 * 1024 * 1024)
+//!     .with_streams(4)
+//!     .run()
+//!     .await?;
+//! println!("{}", report);
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+use super::{Agent, Backend, Service};
+use crate::{
+    client,
+    wire::{self, Client},
+    Error, Result,
+};
+
+/// default bytes echoed by the throughput test
+pub const SIZE: u64 = 64 * 1024 * 1024;
+/// default streams of the throughput test
+pub const STREAMS: usize = 4;
+/// default round trips of the latency test
+pub const PINGS: usize = 100;
+
+/// size of the messages of the latency test
+const PING_SIZE: usize = 64;
+/// size of the writes of the throughput test
+const CHUNK: usize = 64 * 1024;
+/// max time to set up the tunnel
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bench of a gateway, see the [module](self) documentation
+#[derive(Debug, Clone)]
+pub struct Bench {
+    gateway: String,
+    token: String,
+    size: u64,
+    streams: usize,
+    pings: usize,
+}
+
+impl Bench {
+    /// bench the gateway, the agent and the client login with the token
+    pub fn new<G: Into<String>, T: Into<String>>(gateway: G, token: T) -> Self {
+        Self {
+            gateway: gateway.into(),
+            token: token.into(),
+            size: SIZE,
+            streams: STREAMS,
+            pings: PINGS,
+        }
+    }
+
+    /// bytes echoed by the throughput test, split between the streams.
+    /// Default to [`SIZE`]
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// concurrent streams of the throughput test. Default to [`STREAMS`]
+    pub fn with_streams(mut self, streams: usize) -> Self {
+        self.streams = streams.max(1);
+        self
+    }
+
+    /// round trips of the latency test. Default to [`PINGS`]
+    pub fn with_pings(mut self, pings: usize) -> Self {
+        self.pings = pings.max(1);
+        self
+    }
+
+    /// set up the tunnel, run the latency then the throughput test
+    pub async fn run(&self) -> Result<Report> {
+        let name = format!("bench-{:08x}", secp256k1::rand::random::<u32>());
+        let agent = Agent::builder()
+            .gateway(&self.gateway)
+            .token(&self.token)
+            .service(&name, Service::new(Backend::local(echo)))
+            .build()?;
+        let mut tunnel = agent.tunnel();
+        let (stop, stopped) = watch::channel(false);
+
+        // the agent is boxed, its future is too large for the stack
+        let agent = Box::pin(agent.run_until(wait(stopped)));
+        let measure = async {
+            let result = self.measure(&name, &mut tunnel, stop.subscribe()).await;
+            let _ = stop.send(true);
+            result
+        };
+
+        let (agent, result) = tokio::join!(agent, measure);
+        match agent {
+            Err(err) if result.is_ok() => Err(err),
+            _ => result,
+        }
+    }
+
+    async fn measure(
+        &self,
+        name: &str,
+        tunnel: &mut watch::Receiver<Option<super::Tunnel>>,
+        stopped: watch::Receiver<bool>,
+    ) -> Result<Report> {
+        tokio::time::timeout(SETUP_TIMEOUT, tunnel.wait_for(Option::is_some))
+            .await
+            .map_err(|_| Error::GatewayTimeout(SETUP_TIMEOUT))?
+            .map_err(|_| std::io::Error::other("agent stopped"))?;
+
+        // the client connection is served on a local listener
+        let stream = TcpStream::connect(&self.gateway).await?;
+        // the connection is too large to be moved around on the stack
+        let negotiate = Client::new(stream, wire::keypair()).negotiate();
+        let mut connection = Box::new(Box::pin(negotiate).await?);
+        client::dial(&mut connection, name, &self.token).await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let local = listener.local_addr()?;
+        tokio::spawn(async move {
+            let forward = client::forward_until(*connection, &listener, wait(stopped));
+            if let Err(err) = Box::pin(forward).await {
+                log::debug!("bench client stopped: {}", err);
+            }
+        });
+
+        let mut rtt = Vec::with_capacity(self.pings);
+        let mut stream = TcpStream::connect(local).await?;
+        stream.set_nodelay(true)?;
+        let mut ping = [7; PING_SIZE];
+        for _ in 0..self.pings {
+            let started = Instant::now();
+            stream.write_all(&ping).await?;
+            stream.read_exact(&mut ping).await?;
+            rtt.push(started.elapsed());
+        }
+        drop(stream);
+        rtt.sort();
+
+        let cpu = cpu_time();
+        let started = Instant::now();
+        let per_stream = self.size / self.streams as u64;
+        let mut transfers = Vec::with_capacity(self.streams);
+        for _ in 0..self.streams {
+            transfers.push(tokio::spawn(transfer(local, per_stream)));
+        }
+        for transfer in transfers {
+            transfer
+                .await
+                .map_err(|err| std::io::Error::other(err.to_string()))??;
+        }
+        let elapsed = started.elapsed();
+        let cpu = cpu_time()
+            .zip(cpu)
+            .map(|(after, before)| (after - before).as_secs_f64() / elapsed.as_secs_f64());
+
+        Ok(Report {
+            size: per_stream * self.streams as u64,
+            streams: self.streams,
+            elapsed,
+            rtt,
+            cpu,
+            cipher: wire::cipher_rate(Duration::from_millis(500)),
+        })
+    }
+}
+
+/// Report of a bench
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// bytes echoed by the throughput test
+    pub size: u64,
+    pub streams: usize,
+    /// time of the throughput test
+    pub elapsed: Duration,
+    /// round trip times of the latency test, sorted
+    pub rtt: Vec<Duration>,
+    /// cpu time of the process per second of the throughput test (1.0 is a
+    /// full core), the agent and the client both run in the process
+    pub cpu: Option<f64>,
+    /// bytes per second one core encrypts with the cipher of the tunnels
+    pub cipher: f64,
+}
+
+impl Report {
+    /// bytes per second echoed in each direction
+    pub fn throughput(&self) -> f64 {
+        self.size as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// round trip time of that percentile (0 to 100)
+    pub fn percentile(&self, percentile: usize) -> Duration {
+        let index = (self.rtt.len() * percentile.min(100) / 100).min(self.rtt.len() - 1);
+        self.rtt[index]
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MB: f64 = 1024.0 * 1024.0;
+        let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+
+        writeln!(
+            f,
+            "throughput: {:.1} MiB/s each way ({:.0} MiB over {} streams in {:.2}s)",
+            self.throughput() / MB,
+            self.size as f64 / MB,
+            self.streams,
+            self.elapsed.as_secs_f64()
+        )?;
+        writeln!(
+            f,
+            "round trip: min {:.2}ms, p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms ({} pings)",
+            ms(self.percentile(0)),
+            ms(self.percentile(50)),
+            ms(self.percentile(90)),
+            ms(self.percentile(99)),
+            ms(self.percentile(100)),
+            self.rtt.len()
+        )?;
+        if let Some(cpu) = self.cpu {
+            writeln!(f, "cpu: {:.0}% of a core during the transfer", cpu * 100.0)?;
+        }
+        write!(f, "cipher: {:.1} MiB/s per core", self.cipher / MB)
+    }
+}
+
+// the streams of the bench name are echoed
+fn echo(stream: DuplexStream) {
+    tokio::spawn(async move {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    });
+}
+
+// write the bytes on a stream and read them back
+async fn transfer(local: std::net::SocketAddr, size: u64) -> Result<()> {
+    let stream = TcpStream::connect(local).await?;
+    let (mut reader, mut writer) = stream.into_split();
+
+    let write = async move {
+        let chunk = vec![0x5a; CHUNK];
+        let mut left = size;
+        while left > 0 {
+            let count = left.min(CHUNK as u64) as usize;
+            writer.write_all(&chunk[..count]).await?;
+            left -= count as u64;
+        }
+        Ok::<_, Error>(writer)
+    };
+    let read = async move {
+        let mut buf = vec![0; CHUNK];
+        let mut left = size;
+        while left > 0 {
+            let count = reader.read(&mut buf).await?;
+            if count == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            left = left.saturating_sub(count as u64);
+        }
+        Ok::<_, Error>(())
+    };
+
+    // the write half is only dropped once all the data is echoed back
+    let (writer, read) = tokio::join!(write, read);
+    writer?;
+    read
+}
+
+async fn wait(mut stopped: watch::Receiver<bool>) {
+    let _ = stopped.wait_for(|stopped| *stopped).await;
+}
+
+// user and system time of the process
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+
+    let time = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+    use crate::server::{AuthorizeAll, PrintRegisterer, Server};
+
+    #[tokio::test]
+    async fn bench() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway = listener.local_addr().unwrap();
+        drop(listener);
+        let server = Server::new(wire::keypair(), AuthorizeAll, PrintRegisterer);
+        tokio::spawn(Box::pin(
+            server.start_until(gateway, std::future::pending()),
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let bench = Bench::new(gateway.to_string(), "token")
+            .with_size(1024 * 1024)
+            .with_streams(2)
+            .with_pings(10);
+        let report = Box::pin(bench.run()).await.unwrap();
+
+        assert_eq!(report.size, 1024 * 1024);
+        assert_eq!(report.rtt.len(), 10);
+        assert!(report.percentile(0) <= report.percentile(100));
+        assert!(report.throughput() > 0.0);
+    }
+}
@@ -0,0 +1,1029 @@
+//! High level agent for applications that embed a tunnel. The agent
+//! connects to the gateway, logs in, registers its names and serves their
+//! backends, reconnecting when the gateway restarts:
+//!
+//! ```no_run
+//! # async fn example() -> diglett::Result<()> {
+//! use diglett::agent::Agent;
+//!
+//! let agent = Agent::builder()
+//!     .gateway("gateway.com:20000")
+//!     .token("secret")
+//!     .forward("web", "127.0.0.1:3000".parse()?)
+//!     .build()?;
+//!
+//! agent.run_until(tokio::signal::ctrl_c()).await
+//! # }
+//! ```
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    path::PathBuf,
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use secp256k1::{Keypair, PublicKey};
+use tokio::{
+    net::TcpStream,
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::watch,
+};
+
+#[cfg(feature = "server")]
+use super::Aggregator;
+use super::{
+    config::{Reconnect, Tls, Token},
+    srv, Backend, Counters, HealthCheck, Inspector, KnownHosts, Notify, Options, Proxy, Refresh,
+    TokenFile,
+};
+use crate::{
+    wire::{fingerprint, keypair, Client, Metadata, Pipes, Reason, Registration, Split, VERSION},
+    Error, Result,
+};
+
+/// how long the agent waits for a gateway to accept the connection before
+/// it tries the next one
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// prefix of the gateways that are reached over the stdin and stdout of a
+/// command
+const EXEC: &str = "exec:";
+
+/// first wire version that accepts the ports of the names
+const PORT_VERSION: u8 = 8;
+
+/// Service is a name forwarded by the agent with its backends
+#[derive(Clone)]
+pub struct Service {
+    backends: Vec<Backend>,
+    fallback: Option<Backend>,
+    weight: Option<u32>,
+    health: Option<HealthCheck>,
+    max_connections: Option<usize>,
+    compression: Option<bool>,
+    port: Option<u16>,
+}
+
+impl Service {
+    pub fn new(backend: Backend) -> Self {
+        Self {
+            backends: vec![backend],
+            fallback: None,
+            weight: None,
+            health: None,
+            max_connections: None,
+            compression: None,
+            port: None,
+        }
+    }
+
+    /// other instances of the backend, the streams are distributed round
+    /// robin between the instances
+    pub fn with_replicas(mut self, replicas: Vec<Backend>) -> Self {
+        self.backends.extend(replicas);
+        self
+    }
+
+    /// backend dialed for new streams while the backend is down
+    pub fn with_fallback(mut self, fallback: Backend) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// weight of the agent when the gateway balances the name
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = Some(weight);
+        self
+    }
+
+    /// only serve the name while the backend is healthy
+    pub fn with_health_check(mut self, check: HealthCheck) -> Self {
+        self.health = Some(check);
+        self
+    }
+
+    /// max simultaneous connections to the backend, new streams are closed
+    /// right away once it's reached
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// whether the streams of the name should be compressed, the preference
+    /// is sent to the gateway with the registration. The wire protocol
+    /// doesn't compress streams yet, so only disabling it is supported
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// port of the service within its name, so a name exposes one service per
+    /// port. It's registered as `name:port` and needs a gateway of wire
+    /// version 8
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+}
+
+/// AgentBuilder builds an [`Agent`]
+#[derive(Default)]
+pub struct AgentBuilder {
+    gateways: Vec<String>,
+    identity: Option<Keypair>,
+    gateway_key: Option<PublicKey>,
+    known_hosts: Option<KnownHosts>,
+    replace_known_host: bool,
+    token: Token,
+    tls: Option<Tls>,
+    labels: Metadata,
+    reconnect: Reconnect,
+    inspector: Option<Inspector>,
+    counters: Option<Arc<Counters>>,
+    rate_limit: Option<u64>,
+    keepalive: Option<Duration>,
+    proxy: Option<Proxy>,
+    notify: Option<Notify>,
+    crypto_pipeline: bool,
+    wire_version: Option<u8>,
+    services: Vec<(String, Service)>,
+    #[cfg(feature = "server")]
+    aggregator: Option<Aggregator>,
+}
+
+impl AgentBuilder {
+    /// address (host:port) of the gateway, can be called multiple times for
+    /// a fleet of gateways. The agent connects (and reconnects) to the
+    /// first gateway that accepts the connection, in order. The gateways of
+    /// `srv:<name>` are discovered from the SRV records of the name, and
+    /// `exec:<command>` carries the tunnel over the stdin and stdout of the
+    /// command (like `ssh gateway diglett-server --stdio`)
+    pub fn gateway<G: Into<String>>(mut self, gateway: G) -> Self {
+        self.gateways.push(gateway.into());
+        self
+    }
+
+    /// key pair of the agent, a new one is generated for each connection
+    /// if not set
+    pub fn identity(mut self, keypair: Keypair) -> Self {
+        self.identity = Some(keypair);
+        self
+    }
+
+    /// only accept a gateway with that public key
+    pub fn gateway_key(mut self, key: PublicKey) -> Self {
+        self.gateway_key = Some(key);
+        self
+    }
+
+    /// trust the gateway key on first use
+    pub fn known_hosts(mut self, known_hosts: KnownHosts) -> Self {
+        self.known_hosts = Some(known_hosts);
+        self
+    }
+
+    /// accept a changed gateway key and replace it in the known hosts
+    pub fn replace_known_host(mut self, replace: bool) -> Self {
+        self.replace_known_host = replace;
+        self
+    }
+
+    /// authentication token as defined by the gateway
+    pub fn token<T: Into<String>>(mut self, token: T) -> Self {
+        self.token = Token::Value(token.into());
+        self
+    }
+
+    /// read the token from a file, read again every refresh to re-login
+    /// with a fresh token
+    pub fn token_file<P: Into<PathBuf>>(mut self, file: P, refresh: Duration) -> Self {
+        self.token = Token::File {
+            file: file.into(),
+            refresh: refresh.as_secs().max(1),
+        };
+        self
+    }
+
+    /// connect to the gateway over mutual tls
+    pub fn tls(mut self, tls: Tls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// label shown by the gateway admin api
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.labels = self.labels.set(key, value);
+        self
+    }
+
+    /// reach the gateway through the proxy, see [`Proxy::from_env`] for the
+    /// proxy of the environment
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// notify systemd once the names are registered, see
+    /// [`Notify::from_env`]
+    pub fn notify(mut self, notify: Notify) -> Self {
+        self.notify = Some(notify);
+        self
+    }
+
+    /// reconnect policy when the gateway shuts down
+    pub fn reconnect(mut self, reconnect: Reconnect) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// capture the http traffic of the streams with the inspector
+    pub fn inspector(mut self, inspector: Inspector) -> Self {
+        self.inspector = Some(inspector);
+        self
+    }
+
+    /// count the streams, traffic and round trip time of the tunnel
+    pub fn counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// limit the traffic to rate bytes per second in each direction
+    pub fn rate_limit(mut self, rate: u64) -> Self {
+        self.rate_limit = Some(rate);
+        self
+    }
+
+    /// interval of the keep alive pings, the gateway is considered dead
+    /// (and the agent reconnects) if it's silent for a few intervals
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// generate the encryption keystreams of the tunnel on worker threads, see
+    /// [`Connection::pipeline`](crate::wire::Connection::pipeline)
+    pub fn crypto_pipeline(mut self, pipeline: bool) -> Self {
+        self.crypto_pipeline = pipeline;
+        self
+    }
+
+    /// highest wire version offered to the gateway, default to [`VERSION`].
+    /// Gateways of version 1 refuse the agents that offer any other version
+    pub fn wire_version(mut self, version: u8) -> Self {
+        self.wire_version = Some(version);
+        self
+    }
+
+    /// forward the name to the backend
+    pub fn forward<N: Into<String>>(self, name: N, backend: Backend) -> Self {
+        self.service(name, Service::new(backend))
+    }
+
+    /// forward the name to the backends of the service
+    pub fn service<N: Into<String>>(mut self, name: N, service: Service) -> Self {
+        self.services.push((name.into(), service));
+        self
+    }
+
+    /// forward the names of the aggregator to the local agents that serve
+    /// them, the aggregator accepts the local agents while the agent runs
+    #[cfg(feature = "server")]
+    pub fn aggregate(mut self, aggregator: Aggregator) -> Self {
+        for (name, port) in aggregator.names() {
+            let mut service = Service::new(aggregator.backend(name, *port));
+            if let Some(port) = port {
+                service = service.with_port(*port);
+            }
+            self.services.push((name.clone(), service));
+        }
+
+        self.aggregator = Some(aggregator);
+        self
+    }
+
+    pub fn build(self) -> Result<Agent> {
+        if self.gateways.is_empty() {
+            return Err(Error::Config("gateway is not set".into()));
+        }
+
+        if self.services.is_empty() {
+            return Err(Error::Config("no forwards are configured".into()));
+        }
+
+        if matches!(self.wire_version, Some(version) if version == 0 || version > VERSION) {
+            return Err(Error::Config(format!(
+                "wire version must be between 1 and {}",
+                VERSION
+            )));
+        }
+
+        // the port of a service is part of its registered name
+        let services: Vec<_> = self
+            .services
+            .into_iter()
+            .map(|(name, service)| match service.port {
+                Some(port) => (format!("{}:{}", name, port), service),
+                None => (name, service),
+            })
+            .collect();
+
+        let mut names = HashSet::new();
+        for (name, service) in &services {
+            if service.port == Some(0) {
+                return Err(Error::Config(format!(
+                    "port of '{}' must be at least 1",
+                    name
+                )));
+            }
+            if !names.insert(name) {
+                return Err(Error::Config(format!("name '{}' is forwarded twice", name)));
+            }
+            if service.compression == Some(true) {
+                return Err(Error::Config(format!(
+                    "compression of '{}' is not supported by the wire protocol",
+                    name
+                )));
+            }
+        }
+
+        Ok(Agent {
+            gateways: self.gateways,
+            identity: self.identity,
+            gateway_key: self.gateway_key,
+            known_hosts: self.known_hosts,
+            replace_known_host: self.replace_known_host,
+            token: self.token,
+            tls: self.tls,
+            labels: self.labels,
+            reconnect: self.reconnect,
+            inspector: self.inspector,
+            counters: self.counters.unwrap_or_default(),
+            rate_limit: self.rate_limit,
+            keepalive: self.keepalive,
+            proxy: self.proxy,
+            notify: self.notify,
+            crypto_pipeline: self.crypto_pipeline,
+            wire_version: self.wire_version,
+            services,
+            #[cfg(feature = "server")]
+            aggregator: self.aggregator,
+            tunnel: watch::Sender::new(None),
+        })
+    }
+}
+
+/// Tunnel is the established connection of an agent, see [`Agent::tunnel`]
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Tunnel {
+    /// id of the agent connection on the gateway (as shown by its admin api),
+    /// gateways older than wire version 6 don't report it
+    pub id: Option<u64>,
+    /// address of the gateway
+    pub gateway: String,
+    /// fingerprint of the gateway public key
+    pub key: String,
+    pub forwards: Vec<Forwarded>,
+}
+
+/// Forwarded is a name served over the tunnel
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Forwarded {
+    pub name: String,
+    pub backends: Vec<String>,
+    /// where the name is reachable, the address of its public listener and
+    /// its url if the gateway routes http requests
+    pub endpoints: Vec<String>,
+}
+
+/// Agent serves its names over a connection to the gateway
+pub struct Agent {
+    gateways: Vec<String>,
+    identity: Option<Keypair>,
+    gateway_key: Option<PublicKey>,
+    known_hosts: Option<KnownHosts>,
+    replace_known_host: bool,
+    token: Token,
+    tls: Option<Tls>,
+    labels: Metadata,
+    reconnect: Reconnect,
+    inspector: Option<Inspector>,
+    counters: Arc<Counters>,
+    rate_limit: Option<u64>,
+    keepalive: Option<Duration>,
+    proxy: Option<Proxy>,
+    notify: Option<Notify>,
+    crypto_pipeline: bool,
+    wire_version: Option<u8>,
+    services: Vec<(String, Service)>,
+    #[cfg(feature = "server")]
+    aggregator: Option<Aggregator>,
+    tunnel: watch::Sender<Option<Tunnel>>,
+}
+
+impl Agent {
+    pub fn builder() -> AgentBuilder {
+        AgentBuilder::default()
+    }
+
+    /// counters of the traffic of the agent
+    pub fn counters(&self) -> Arc<Counters> {
+        Arc::clone(&self.counters)
+    }
+
+    /// the established tunnel, None while the agent is not connected
+    pub fn tunnel(&self) -> watch::Receiver<Option<Tunnel>> {
+        self.tunnel.subscribe()
+    }
+
+    /// run the agent until the shutdown future resolves. On shutdown the
+    /// open streams are closed and the gateway is told the agent terminates
+    pub async fn run_until<S>(&self, shutdown: S) -> Result<()>
+    where
+        S: Future,
+    {
+        let (stop, stopped) = watch::channel(false);
+        let aggregate = self.aggregate(stopped.clone());
+        let run = self.run_watch(stopped);
+        tokio::pin!(run);
+        tokio::pin!(aggregate);
+
+        tokio::select! {
+            result = &mut run => return result,
+            // the aggregator only returns early if it fails
+            result = &mut aggregate => return result,
+            _ = shutdown => {}
+        }
+
+        if let Some(notify) = &self.notify {
+            notify.stopping();
+        }
+        let _ = stop.send(true);
+        let (result, aggregated) = tokio::join!(run, aggregate);
+        if let Err(err) = aggregated {
+            log::error!("failed to stop the aggregator: {}", err);
+        }
+        result
+    }
+
+    // accept the local agents of the aggregated names until the agent stops
+    async fn aggregate(&self, stopped: watch::Receiver<bool>) -> Result<()> {
+        #[cfg(feature = "server")]
+        if let Some(aggregator) = &self.aggregator {
+            return aggregator.clone().serve_until(wait(stopped)).await;
+        }
+
+        wait(stopped).await;
+        Ok(())
+    }
+
+    /// run the agent, it reconnects when the gateway shuts down (for
+    /// example on a restart) or stops responding, and when the backends
+    /// recover after they were unhealthy
+    pub async fn run(&self) -> Result<()> {
+        self.run_until(std::future::pending::<()>()).await
+    }
+
+    async fn run_watch(&self, stopped: watch::Receiver<bool>) -> Result<()> {
+        let _notifier = self
+            .notify
+            .as_ref()
+            .map(|notify| notify.start(Arc::clone(&self.counters)));
+
+        let mut attempts = None;
+        loop {
+            let (connection, gateway) = tokio::select! {
+                connection = self.dial(&mut attempts) => connection?,
+                _ = wait(stopped.clone()) => return Ok(()),
+            };
+            if attempts.is_some() {
+                self.counters.reconnected();
+            }
+            if self.gateways.len() > 1 || self.gateways[0] != gateway {
+                log::info!("connected to gateway {}", gateway);
+            }
+
+            let result = match connection {
+                Transport::Tcp(stream) => {
+                    Box::pin(self.connect(stream, &gateway, wait(stopped.clone()))).await
+                }
+                // the command is killed once the tunnel is closed
+                Transport::Exec(_child, pipes) => {
+                    Box::pin(self.connect(pipes, &gateway, wait(stopped.clone()))).await
+                }
+            };
+            self.counters.set_connected(false);
+            self.tunnel.send_replace(None);
+            if let Some(notify) = self.notify.as_ref().filter(|_| !*stopped.borrow()) {
+                notify.disconnected();
+            }
+
+            match result {
+                Err(err @ Error::Unhealthy(_)) => {
+                    log::warn!("{}, disconnecting until it's healthy again", err);
+                    attempts = Some(0);
+                }
+                Err(Error::Terminated(termination))
+                    if termination.reason == Reason::Shutdown && self.reconnect.attempts > 0 =>
+                {
+                    log::info!("{}, reconnecting", termination.message);
+                    attempts = Some(0);
+                }
+                Err(err) if err.is_retryable() && self.reconnect.attempts > 0 => {
+                    log::warn!("{}, reconnecting", err);
+                    attempts = Some(0);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // connect to the first gateway that accepts the connection once the
+    // backends are healthy, attempts is set while reconnecting
+    async fn dial(&self, attempts: &mut Option<u32>) -> Result<(Transport, String)> {
+        loop {
+            self.healthy().await?;
+
+            let mut failure = None;
+            for gateway in self.targets().await {
+                let gateway = match gateway {
+                    Ok(gateway) => gateway,
+                    Err(err) => {
+                        failure = Some(err);
+                        continue;
+                    }
+                };
+
+                let connection = match (gateway.strip_prefix(EXEC), &self.proxy) {
+                    (Some(command), _) => exec(command),
+                    (None, Some(proxy)) => proxy.connect(&gateway).await.map(Transport::Tcp),
+                    (None, None) => {
+                        tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&gateway))
+                            .await
+                            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+                            .map(Transport::Tcp)
+                            .map_err(Error::from)
+                    }
+                };
+
+                match connection {
+                    Ok(connection) => return Ok((connection, gateway)),
+                    Err(err) => {
+                        log::debug!("failed to connect to gateway {}: {}", gateway, err);
+                        failure = Some(err);
+                    }
+                }
+            }
+
+            match failure {
+                None => return Err(Error::Config("gateway is not set".into())),
+                Some(err) => match *attempts {
+                    Some(attempt) if attempt < self.reconnect.attempts && err.is_retryable() => {
+                        log::debug!("failed to reconnect to gateway: {}", err);
+                        *attempts = Some(attempt + 1);
+                        tokio::time::sleep(self.reconnect.delay()).await;
+                    }
+                    _ => return Err(err),
+                },
+            }
+        }
+    }
+
+    // the gateways to connect to in order, the SRV names are resolved again
+    // on each connection
+    async fn targets(&self) -> Vec<Result<String>> {
+        let mut targets = Vec::with_capacity(self.gateways.len());
+        for gateway in &self.gateways {
+            let Some(name) = gateway.strip_prefix(srv::PREFIX) else {
+                targets.push(Ok(gateway.clone()));
+                continue;
+            };
+
+            match srv::resolve(name).await {
+                Ok(resolved) => {
+                    log::debug!("gateways of '{}': {}", name, resolved.join(","));
+                    targets.extend(resolved.into_iter().map(Ok));
+                }
+                Err(err) => {
+                    log::debug!("failed to resolve gateway '{}': {}", name, err);
+                    targets.push(Err(err));
+                }
+            }
+        }
+        targets
+    }
+
+    // wait until the backends with a health check are healthy
+    async fn healthy(&self) -> Result<()> {
+        for (name, service) in &self.services {
+            let Some(check) = &service.health else {
+                continue;
+            };
+
+            let mut logged = false;
+            while let Err(err) = check.check_any(&service.backends).await {
+                if !logged {
+                    log::warn!("{}, waiting to serve '{}'", err, name);
+                    logged = true;
+                }
+                tokio::time::sleep(check.interval()).await;
+            }
+
+            if logged {
+                log::info!("backend of '{}' is healthy", name);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    async fn connect<S: Split, D: Future>(
+        &self,
+        connection: S,
+        gateway: &str,
+        shutdown: D,
+    ) -> Result<()> {
+        use crate::tls;
+
+        let Some(config) = &self.tls else {
+            return self.serve(connection, gateway, shutdown).await;
+        };
+
+        let client = tls::client_config(
+            tls::certificates(&config.ca)?,
+            tls::certificates(&config.cert)?,
+            tls::private_key(&config.key)?,
+        )?;
+
+        let host = gateway
+            .rsplit_once(':')
+            .map(|(host, _)| host)
+            .unwrap_or(gateway);
+
+        let connection = tls::TlsConnector::from(client)
+            .connect(tls::server_name(host)?, connection)
+            .await?;
+
+        self.serve(connection, gateway, shutdown).await
+    }
+
+    #[cfg(not(feature = "tls"))]
+    async fn connect<S: Split, D: Future>(
+        &self,
+        connection: S,
+        gateway: &str,
+        shutdown: D,
+    ) -> Result<()> {
+        if self.tls.is_some() {
+            return Err(Error::Config("tls requires the tls feature".into()));
+        }
+
+        self.serve(connection, gateway, shutdown).await
+    }
+
+    async fn serve<S: Split, D: Future>(
+        &self,
+        connection: S,
+        gateway: &str,
+        shutdown: D,
+    ) -> Result<()> {
+        let mut client = Client::new(connection, self.identity.unwrap_or_else(keypair));
+        if let Some(key) = self.gateway_key {
+            client = client.with_pin(key);
+        }
+        if let Some(version) = self.wire_version {
+            client = client.with_version(version);
+        }
+
+        // the connection is large, the futures that own it are boxed so they
+        // don't overflow the stack of the task
+        let mut client = Box::pin(client.negotiate()).await?;
+        if self.crypto_pipeline {
+            client.pipeline()?;
+        }
+        if let Some(known_hosts) = &self.known_hosts {
+            known_hosts
+                .verify(gateway, &client.remote_key(), self.replace_known_host)
+                .await?;
+        }
+
+        let (token, refresh) = match &self.token {
+            Token::Value(token) => (token.clone(), None),
+            Token::File { file, refresh } => {
+                let refresh = TokenFile::new(file, Duration::from_secs(*refresh));
+                (refresh.token().await?, Some(refresh))
+            }
+        };
+
+        super::login_with(&mut client, token, self.labels.clone()).await?;
+
+        if client.version() < PORT_VERSION
+            && self
+                .services
+                .iter()
+                .any(|(_, service)| service.port.is_some())
+        {
+            log::error!("gateway does not support the ports of the names, it needs to be upgraded");
+            return Err(Error::InvalidVersion(client.version()));
+        }
+
+        let names = self
+            .services
+            .iter()
+            .map(|(name, service)| {
+                let mut metadata = Metadata::default();
+                if let Some(weight) = service.weight {
+                    metadata = metadata.set("weight", weight.to_string());
+                }
+                if let Some(compression) = service.compression {
+                    metadata = metadata.set("compression", compression.to_string());
+                }
+                (name.clone(), metadata)
+            })
+            .collect();
+
+        let registered = super::register_all(&mut client, names).await?;
+        let mut forwards = Vec::with_capacity(self.services.len());
+        for ((name, service), endpoints) in self.services.iter().zip(registered.endpoints) {
+            let backends: Vec<_> = service.backends.iter().map(ToString::to_string).collect();
+            if endpoints.is_empty() {
+                log::info!("forwarding '{}' -> {}", name, backends.join(","));
+            }
+            for endpoint in &endpoints {
+                log::info!("forwarding {} -> {}", endpoint, backends.join(","));
+            }
+
+            forwards.push(Forwarded {
+                name: name.clone(),
+                backends,
+                endpoints,
+            });
+        }
+        self.tunnel.send_replace(Some(Tunnel {
+            id: registered.session,
+            gateway: gateway.into(),
+            key: fingerprint(&client.remote_key()),
+            forwards,
+        }));
+        self.counters.set_connected(true);
+        if let Some(notify) = &self.notify {
+            notify.ready(self.services.len(), &self.counters);
+        }
+
+        let mut backends = HashMap::default();
+        let mut options = Options::default().with_counters(Arc::clone(&self.counters));
+        if let Some(refresh) = refresh {
+            options = options.with_refresh(Box::new(refresh));
+        }
+        if let Some(inspector) = &self.inspector {
+            options = options.with_inspector(inspector.clone());
+        }
+        if let Some(rate) = self.rate_limit {
+            options = options.with_rate_limit(rate);
+        }
+        if let Some(interval) = self.keepalive {
+            options = options.with_keepalive(interval);
+        }
+
+        for (index, (_, service)) in self.services.iter().enumerate() {
+            let id = Registration::from(index as u16);
+            let mut instances = service.backends.clone();
+            backends.insert(id, instances.remove(0));
+            if !instances.is_empty() {
+                options = options.with_replicas(id, instances);
+            }
+            if let Some(check) = &service.health {
+                options = options.with_health_check(id, check.clone());
+            }
+            if let Some(fallback) = &service.fallback {
+                options = options.with_fallback(id, fallback.clone());
+            }
+            if let Some(max) = service.max_connections {
+                options = options.with_max_connections(id, max);
+            }
+        }
+
+        Box::pin(super::serve_all_until(client, backends, options, shutdown)).await
+    }
+}
+
+// connection of the agent to a gateway
+enum Transport {
+    Tcp(TcpStream),
+    // the stdin and stdout of a command, it's killed when dropped
+    Exec(Child, Pipes<ChildStdout, ChildStdin>),
+}
+
+// run the command (with `sh -c`) that carries the tunnel over its stdin and
+// stdout, like `ssh gateway diglett-server --stdio`
+fn exec(command: &str) -> Result<Transport> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        return Err(Error::Config(format!("failed to run '{}'", command)));
+    };
+
+    Ok(Transport::Exec(child, Pipes::new(stdout, stdin)))
+}
+
+// resolves once the agent is stopped
+async fn wait(mut stopped: watch::Receiver<bool>) {
+    let _ = stopped.wait_for(|stopped| *stopped).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::{Control, Message};
+
+    #[test]
+    fn build() {
+        let backend: Backend = "127.0.0.1:3000".parse().unwrap();
+        assert!(Agent::builder().build().is_err());
+        assert!(Agent::builder()
+            .forward("web", backend.clone())
+            .build()
+            .is_err());
+        assert!(Agent::builder()
+            .gateway("gateway.com:20000")
+            .build()
+            .is_err());
+
+        let duplicate = Agent::builder()
+            .gateway("gateway.com:20000")
+            .forward("web", backend.clone())
+            .forward("web", backend.clone())
+            .build();
+        assert!(duplicate.is_err());
+
+        let agent = Agent::builder()
+            .gateway("gateway.com:20000")
+            .token("secret")
+            .service(
+                "web",
+                Service::new(backend.clone())
+                    .with_replicas(vec!["127.0.0.1:3001".parse().unwrap()])
+                    .with_weight(2),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(agent.services[0].1.backends.len(), 2);
+        assert!(matches!(&agent.token, Token::Value(token) if token == "secret"));
+
+        // a name is forwarded once per port
+        let agent = Agent::builder()
+            .gateway("gateway.com:20000")
+            .forward("web", backend.clone())
+            .service("web", Service::new(backend.clone()).with_port(22))
+            .build()
+            .unwrap();
+        assert_eq!(agent.services[1].0, "web:22");
+        assert!(Agent::builder()
+            .gateway("gateway.com:20000")
+            .service("web", Service::new(backend.clone()).with_port(0))
+            .build()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        // a gateway that is down, its listener is closed right away
+        let down = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let up = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = up.local_addr().unwrap().to_string();
+
+        let agent = Agent::builder()
+            .gateway(&down)
+            .gateway(&up)
+            .forward("web", "127.0.0.1:3000".parse().unwrap())
+            .build()
+            .unwrap();
+
+        let (_, gateway) = agent.dial(&mut None).await.unwrap();
+        assert_eq!(gateway, up);
+
+        let agent = Agent::builder()
+            .gateway(&down)
+            .forward("web", "127.0.0.1:3000".parse().unwrap())
+            .build()
+            .unwrap();
+        assert!(agent.dial(&mut None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnect_dns() {
+        // the name never resolves, like while the network is down
+        let agent = Agent::builder()
+            .gateway("gateway.invalid:20000")
+            .forward("web", "127.0.0.1:3000".parse().unwrap())
+            .reconnect(Reconnect {
+                attempts: 2,
+                delay: 0,
+            })
+            .build()
+            .unwrap();
+
+        let mut attempts = Some(0);
+        let Err(err) = agent.dial(&mut attempts).await else {
+            panic!("the gateway name resolved");
+        };
+        assert!(err.is_retryable(), "{}", err);
+        assert_eq!(attempts, Some(2));
+    }
+
+    // a gateway of wire version 1, it doesn't negotiate and drops the agents
+    // that don't offer version 1. It returns the messages of the registration
+    async fn gateway_v1(listener: tokio::net::TcpListener) -> Option<Vec<Message>> {
+        let (stream, _) = listener.accept().await.unwrap();
+        // the version follows the magic of the handshake
+        let mut handshake = [0; 5];
+        while stream.peek(&mut handshake).await.unwrap() < handshake.len() {}
+        if handshake[4] != 1 {
+            return None;
+        }
+
+        let mut connection = crate::wire::Server::new(stream, keypair())
+            .accept()
+            .await
+            .unwrap();
+        assert_eq!(connection.version(), 1);
+
+        // login, register and finish register
+        let mut messages = Vec::new();
+        for _ in 0..3 {
+            messages.push(connection.read().await.unwrap());
+            connection.ok().await.unwrap();
+        }
+
+        // the idle agent doesn't send frames the gateway doesn't understand
+        let idle = tokio::time::timeout(Duration::from_secs(3), connection.read()).await;
+        assert!(idle.is_err(), "unexpected message: {:?}", idle);
+        Some(messages)
+    }
+
+    #[tokio::test]
+    async fn wire_version() {
+        let builder = || {
+            Agent::builder()
+                .token("token")
+                .forward("web", "127.0.0.1:3000".parse().unwrap())
+                .keepalive(Duration::from_secs(1))
+                .reconnect(Reconnect {
+                    attempts: 0,
+                    delay: 0,
+                })
+        };
+        assert!(builder()
+            .gateway("gateway.com:20000")
+            .wire_version(VERSION + 1)
+            .build()
+            .is_err());
+
+        // the gateway drops the agents that offer the latest version
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = builder()
+            .gateway(listener.local_addr().unwrap().to_string())
+            .build()
+            .unwrap();
+        let gateway = tokio::spawn(gateway_v1(listener));
+        assert!(agent.run().await.is_err());
+        assert!(gateway.await.unwrap().is_none());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent = builder()
+            .gateway(listener.local_addr().unwrap().to_string())
+            .wire_version(1)
+            .build()
+            .unwrap();
+        let gateway = tokio::spawn(gateway_v1(listener));
+        // the agent stops once the gateway is done
+        let messages = tokio::select! {
+            biased;
+            messages = gateway => messages.unwrap().unwrap(),
+            result = agent.run() => panic!("agent stopped: {:?}", result),
+        };
+        assert!(
+            matches!(&messages[0], Message::Control(Control::Login { token, .. }) if token == "token")
+        );
+        assert!(
+            matches!(&messages[1], Message::Control(Control::Register { name, .. }) if name == "web")
+        );
+        assert!(matches!(
+            messages[2],
+            Message::Control(Control::FinishRegister)
+        ));
+    }
+}
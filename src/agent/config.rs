@@ -0,0 +1,864 @@
+//! Agent configuration file, it describes the gateway and the forwarded names
+//! of an agent so it can be deployed declaratively:
+//!
+//! ```toml
+//! gateway = "gateway.com:20000"
+//! # or a fleet of gateways, tried in order on connect and on reconnect
+//! # gateway = ["gw1.gateway.com:20000", "gw2.gateway.com:20000"]
+//! # or discovered from the SRV records of a name
+//! # gateway = "srv:_diglett._tcp.gateway.com"
+//! # only accept a gateway with that public key
+//! gateway-key = "02a1..."
+//! # or trust the gateway key on first use
+//! known-hosts = true
+//! # web ui of the http traffic
+//! inspect = "127.0.0.1:4040"
+//! # prometheus metrics
+//! metrics = "127.0.0.1:9100"
+//! log-http = true
+//! # log the traffic of the agent every 10 seconds
+//! stats-interval = 10
+//! # max traffic of the agent in each direction
+//! rate-limit = "5mbps"
+//! # reconnect if the gateway is silent for 3 keep alive intervals
+//! keepalive = 10
+//! # reach the gateway through a socks5 proxy
+//! proxy = "socks5://127.0.0.1:1080"
+//! # generate the encryption keystreams on worker threads (busy tunnels)
+//! crypto-pipeline = true
+//! # offer an older wire version, to reach gateways of version 1
+//! wire-version = 1
+//!
+//! [token]
+//! file = "/run/diglett/token"
+//! refresh = 300
+//!
+//! [reconnect]
+//! attempts = 30
+//! delay = 1
+//!
+//! [[forward]]
+//! name = "web"
+//! backend = "localhost:3000"
+//! # used while the backend is down
+//! fallback = "backup:3001"
+//!
+//! # only serve the name while the backend is healthy
+//! [forward.health]
+//! path = "/healthz"
+//! status = 200
+//! interval = 10
+//!
+//! # streams are distributed round robin between the instances
+//! [[forward]]
+//! name = "app"
+//! backend = "localhost:8000,localhost:8001"
+//! # protect a fragile backend from bursts of public traffic
+//! max-connections = 20
+//!
+//! [[forward]]
+//! name = "api"
+//! backend = "https://internal.service:8443"
+//! ca = "/etc/diglett/internal-ca.pem"
+//! weight = 2
+//! # ask the gateway not to compress the streams (already encrypted)
+//! compression = false
+//!
+//! # another service of the same name, registered as `api:22`
+//! [[forward]]
+//! name = "api"
+//! port = 22
+//! backend = "localhost:2222"
+//!
+//! # names served by local agents that connect to this agent instead of the
+//! # gateway, they all share its gateway connection
+//! [aggregate]
+//! listen = "127.0.0.1:20001"
+//! names = ["docs", "db:5432"]
+//! ```
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use secp256k1::PublicKey;
+use serde::Deserialize;
+
+#[cfg(feature = "server")]
+use super::Aggregator;
+use super::{Agent, AgentBuilder, Backend, HealthCheck, KnownHosts, Proxy, Service, TlsOptions};
+#[cfg(feature = "server")]
+use crate::server::names;
+use crate::{Error, Result};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// addresses (host:port) of the gateways, as a list or a comma separated
+    /// string. The agent connects to the first one that accepts the
+    /// connection, in order
+    #[serde(deserialize_with = "gateways")]
+    pub gateway: Vec<String>,
+
+    /// public key of the gateway (hex), the agent refuses to connect to a
+    /// gateway with another key
+    #[serde(default, deserialize_with = "public_key")]
+    pub gateway_key: Option<PublicKey>,
+
+    /// trust the gateway key on first use, the agent refuses to connect if
+    /// the key changes later on
+    #[serde(default)]
+    pub known_hosts: bool,
+
+    /// known hosts file, default to `~/.config/diglett/known_hosts`
+    pub known_hosts_file: Option<PathBuf>,
+
+    #[serde(default)]
+    pub token: Token,
+
+    /// mutual tls with the gateway
+    pub tls: Option<Tls>,
+
+    /// labels shown by the gateway admin api
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub reconnect: Reconnect,
+
+    /// address of the web ui that lists the http traffic of the agent
+    pub inspect: Option<SocketAddr>,
+
+    /// address of the prometheus metrics endpoint (`GET /metrics`)
+    pub metrics: Option<SocketAddr>,
+
+    /// log a line for each http request through the tunnel
+    #[serde(default)]
+    pub log_http: bool,
+
+    /// log a status line every that many seconds with the open streams, the
+    /// traffic and the round trip time of the tunnel
+    pub stats_interval: Option<u64>,
+
+    /// max rate in bytes per second of the traffic of the agent (each
+    /// direction), set as a bit rate like `5mbps`
+    #[serde(default, deserialize_with = "rate")]
+    pub rate_limit: Option<u64>,
+
+    /// interval in seconds of the keep alive pings to the gateway, the agent
+    /// reconnects if the gateway doesn't respond for 3 intervals
+    pub keepalive: Option<u64>,
+
+    /// reach the gateway through a proxy, like `socks5://127.0.0.1:1080` or
+    /// `http://proxy:3128`
+    #[serde(default, deserialize_with = "proxy")]
+    pub proxy: Option<Proxy>,
+
+    /// generate the encryption keystreams of the tunnel on worker threads
+    #[serde(default)]
+    pub crypto_pipeline: bool,
+
+    /// highest wire version offered to the gateway, gateways of version 1
+    /// refuse the agents that offer any other version
+    pub wire_version: Option<u8>,
+
+    #[serde(default, rename = "forward")]
+    pub forwards: Vec<Forward>,
+
+    /// names served by local agents over the connection of this agent
+    pub aggregate: Option<Aggregate>,
+}
+
+/// Source of the authentication token
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Token {
+    /// the token itself
+    Value(String),
+    /// file of the token, read again every `refresh` seconds to re-login
+    /// with a fresh token
+    File {
+        file: PathBuf,
+        #[serde(default = "default_refresh")]
+        refresh: u64,
+    },
+}
+
+// the token itself is redacted, so it doesn't leak in the logs
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Value(_) => f
+                .debug_tuple("Value")
+                .field(&format_args!("<redacted>"))
+                .finish(),
+            Token::File { file, refresh } => f
+                .debug_struct("File")
+                .field("file", file)
+                .field("refresh", refresh)
+                .finish(),
+        }
+    }
+}
+
+impl Default for Token {
+    fn default() -> Self {
+        Self::Value(String::default())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    /// ca certificate of the gateway
+    pub ca: PathBuf,
+    /// agent certificate
+    pub cert: PathBuf,
+    /// private key of the agent certificate
+    pub key: PathBuf,
+}
+
+/// Reconnect policy when the gateway shuts down (for example on a restart)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reconnect {
+    /// max attempts to connect again, 0 to exit instead
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// seconds between attempts
+    #[serde(default = "default_delay")]
+    pub delay: u64,
+}
+
+impl Reconnect {
+    pub fn delay(&self) -> Duration {
+        Duration::from_secs(self.delay)
+    }
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            attempts: default_attempts(),
+            delay: default_delay(),
+        }
+    }
+}
+
+/// A name forwarded to a local backend
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Forward {
+    pub name: String,
+    /// port of the service within its name, a name can be forwarded once
+    /// per port
+    pub port: Option<u16>,
+    /// address of the backend, `unix:<path>` for a unix socket. Addresses of
+    /// multiple instances are separated by commas
+    pub backend: String,
+    /// backend dialed for new streams while the backend is down
+    pub fallback: Option<String>,
+    /// weight of the agent when the gateway balances the name
+    pub weight: Option<u32>,
+    /// protocol of the backend
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// compress the streams of the name. The preference is sent to the
+    /// gateway with the registration, but the streams are not compressed by
+    /// the wire protocol yet so only `false` is accepted
+    #[serde(alias = "compress")]
+    pub compression: Option<bool>,
+    /// ca bundle of an https backend
+    pub ca: Option<PathBuf>,
+    /// server name of an https backend, default to its host
+    pub sni: Option<String>,
+    /// don't verify the certificate of an https backend
+    #[serde(default)]
+    pub insecure: bool,
+    /// health check of the backend
+    pub health: Option<Health>,
+    /// max simultaneous connections to the backend, new streams are closed
+    /// once it's reached
+    pub max_connections: Option<usize>,
+}
+
+/// Local agents that share the gateway connection of the agent, see
+/// `Aggregator` (it requires the server feature)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Aggregate {
+    /// address the local agents connect to instead of the gateway
+    pub listen: SocketAddr,
+    /// aggregated names, as `name:port` for the service of a name on a port
+    pub names: Vec<String>,
+}
+
+#[cfg(feature = "server")]
+impl Aggregate {
+    /// the aggregator of the names
+    pub fn aggregator(&self) -> Result<Aggregator> {
+        self.names
+            .iter()
+            .try_fold(Aggregator::new(self.listen), |aggregator, name| {
+                let (name, port) = names::port(name)?;
+                Ok(aggregator.name(name, port))
+            })
+    }
+}
+
+/// Health check of a backend, an http `GET` of the path if set or a
+/// connection to the backend otherwise
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Health {
+    pub path: Option<String>,
+    /// expected status of the http check
+    #[serde(default = "default_status")]
+    pub status: u16,
+    /// seconds between checks
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+impl Health {
+    pub fn check(&self) -> HealthCheck {
+        let check = match &self.path {
+            Some(path) => HealthCheck::http(path).with_status(self.status),
+            None => HealthCheck::tcp(),
+        };
+
+        check.with_interval(Duration::from_secs(self.interval))
+    }
+}
+
+impl Forward {
+    /// the backend instances of the forward
+    pub fn backends(&self) -> Result<Vec<Backend>> {
+        let options = self.tls_options();
+        self.backend
+            .split(',')
+            .map(|backend| Backend::parse(backend.trim(), &options))
+            .collect()
+    }
+
+    /// the fallback backend of the forward, the tls options apply to it if
+    /// it's an https backend as well
+    pub fn fallback(&self) -> Result<Option<Backend>> {
+        let Some(fallback) = &self.fallback else {
+            return Ok(None);
+        };
+
+        let options = match fallback.starts_with("https://") {
+            true => self.tls_options(),
+            false => TlsOptions::default(),
+        };
+
+        Backend::parse(fallback, &options).map(Some)
+    }
+
+    fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            ca: self.ca.clone(),
+            sni: self.sni.clone(),
+            insecure: self.insecure,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+}
+
+impl Config {
+    /// load and validate the configuration file at path
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)?;
+        let config: Self = toml::from_str(&data)
+            .map_err(|err| Error::Config(format!("{}: {}", path.display(), err)))?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// builder of an agent with this configuration
+    pub fn agent(&self) -> Result<AgentBuilder> {
+        self.validate()?;
+
+        let mut builder = self
+            .gateway
+            .iter()
+            .fold(Agent::builder(), |builder, gateway| {
+                builder.gateway(gateway)
+            })
+            .reconnect(self.reconnect.clone());
+
+        builder = match &self.token {
+            Token::Value(token) => builder.token(token),
+            Token::File { file, refresh } => {
+                builder.token_file(file, Duration::from_secs(*refresh))
+            }
+        };
+        if let Some(key) = self.gateway_key {
+            builder = builder.gateway_key(key);
+        }
+        if let Some(known_hosts) = self.known_hosts()? {
+            builder = builder.known_hosts(known_hosts);
+        }
+        if let Some(tls) = &self.tls {
+            builder = builder.tls(tls.clone());
+        }
+        if let Some(rate) = self.rate_limit {
+            builder = builder.rate_limit(rate);
+        }
+        if let Some(interval) = self.keepalive {
+            builder = builder.keepalive(Duration::from_secs(interval));
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        if self.crypto_pipeline {
+            builder = builder.crypto_pipeline(true);
+        }
+        if let Some(version) = self.wire_version {
+            builder = builder.wire_version(version);
+        }
+        for (key, value) in &self.labels {
+            builder = builder.label(key, value);
+        }
+
+        for forward in &self.forwards {
+            let mut backends = forward.backends()?;
+            let mut service = Service::new(backends.remove(0)).with_replicas(backends);
+            if let Some(fallback) = forward.fallback()? {
+                service = service.with_fallback(fallback);
+            }
+            if let Some(weight) = forward.weight {
+                service = service.with_weight(weight);
+            }
+            if let Some(health) = &forward.health {
+                service = service.with_health_check(health.check());
+            }
+            if let Some(max) = forward.max_connections {
+                service = service.with_max_connections(max);
+            }
+            if let Some(compression) = forward.compression {
+                service = service.with_compression(compression);
+            }
+            if let Some(port) = forward.port {
+                service = service.with_port(port);
+            }
+
+            builder = builder.service(&forward.name, service);
+        }
+
+        #[cfg(feature = "server")]
+        if let Some(aggregate) = &self.aggregate {
+            builder = builder.aggregate(aggregate.aggregator()?);
+        }
+
+        Ok(builder)
+    }
+
+    /// the known hosts of the agent if enabled
+    pub fn known_hosts(&self) -> Result<Option<KnownHosts>> {
+        if let Some(path) = &self.known_hosts_file {
+            return Ok(Some(KnownHosts::new(path)));
+        }
+
+        if !self.known_hosts {
+            return Ok(None);
+        }
+
+        KnownHosts::default_path()
+            .map(|path| Some(KnownHosts::new(path)))
+            .ok_or_else(|| Error::Config("can't find the home directory for known hosts".into()))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.gateway.is_empty() {
+            return Err(Error::Config("gateway is not set".into()));
+        }
+
+        if self.stats_interval == Some(0) {
+            return Err(Error::Config("stats interval must be at least 1".into()));
+        }
+
+        if self.keepalive == Some(0) {
+            return Err(Error::Config("keepalive must be at least 1".into()));
+        }
+
+        if self.forwards.is_empty() && self.aggregate.is_none() {
+            return Err(Error::Config("no forwards are configured".into()));
+        }
+
+        let mut names = HashSet::new();
+        for forward in &self.forwards {
+            if !names.insert((&forward.name, forward.port)) {
+                return Err(Error::Config(format!(
+                    "name '{}' is forwarded twice",
+                    forward.name
+                )));
+            }
+
+            if forward.port == Some(0) {
+                return Err(Error::Config(format!(
+                    "port of '{}' must be at least 1",
+                    forward.name
+                )));
+            }
+
+            if forward.max_connections == Some(0) {
+                return Err(Error::Config(format!(
+                    "max connections of '{}' must be at least 1",
+                    forward.name
+                )));
+            }
+
+            if forward.weight == Some(0) {
+                return Err(Error::Config(format!(
+                    "weight of '{}' must be at least 1",
+                    forward.name
+                )));
+            }
+
+            forward.backends()?;
+            forward.fallback()?;
+
+            if let Some(health) = &forward.health {
+                if health.interval == 0 {
+                    return Err(Error::Config(format!(
+                        "health check interval of '{}' must be at least 1",
+                        forward.name
+                    )));
+                }
+
+                if matches!(&health.path, Some(path) if !path.starts_with('/')) {
+                    return Err(Error::Config(format!(
+                        "health check path of '{}' must start with /",
+                        forward.name
+                    )));
+                }
+            }
+
+            if forward.compression == Some(true) {
+                return Err(Error::Config(format!(
+                    "compression of '{}' is not supported by the wire protocol",
+                    forward.name
+                )));
+            }
+        }
+
+        if let Some(aggregate) = &self.aggregate {
+            if aggregate.names.is_empty() {
+                return Err(Error::Config("no aggregated names are configured".into()));
+            }
+
+            // the aggregator is a local server
+            #[cfg(not(feature = "server"))]
+            return Err(Error::Config(
+                "aggregation requires the server feature".into(),
+            ));
+
+            #[cfg(feature = "server")]
+            for (name, port) in aggregate.aggregator()?.names() {
+                if !names.insert((name, *port)) {
+                    return Err(Error::Config(format!("name '{}' is forwarded twice", name)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn default_refresh() -> u64 {
+    300
+}
+
+fn default_attempts() -> u32 {
+    30
+}
+
+fn default_delay() -> u64 {
+    1
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+/// parse a bit rate (`bps`, `kbps`, `mbps` or `gbps`, for example `5mbps`)
+/// into bytes per second
+pub fn parse_rate(value: &str) -> Result<u64> {
+    let value = value.trim().to_lowercase();
+    let (number, unit) = value
+        .find(|c: char| c.is_ascii_alphabetic())
+        .map(|index| value.split_at(index))
+        .ok_or_else(|| Error::Config(format!("rate '{}' has no unit", value)))?;
+
+    let multiplier = match unit {
+        "bps" => 1.0,
+        "kbps" => 1e3,
+        "mbps" => 1e6,
+        "gbps" => 1e9,
+        _ => return Err(Error::Config(format!("invalid rate unit '{}'", unit))),
+    };
+
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| Error::Config(format!("invalid rate '{}'", value)))?;
+
+    let rate = (number * multiplier / 8.0) as u64;
+    if rate == 0 {
+        return Err(Error::Config(format!("rate '{}' is too low", value)));
+    }
+
+    Ok(rate)
+}
+
+fn rate<'de, D>(deserializer: D) -> std::result::Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let rate = String::deserialize(deserializer)?;
+    parse_rate(&rate)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn proxy<'de, D>(deserializer: D) -> std::result::Result<Option<Proxy>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let proxy = String::deserialize(deserializer)?;
+    Proxy::from_str(&proxy)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn gateways<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Gateways {
+        One(String),
+        List(Vec<String>),
+    }
+
+    let gateways = match Gateways::deserialize(deserializer)? {
+        Gateways::One(gateways) => gateways.split(',').map(str::to_owned).collect(),
+        Gateways::List(gateways) => gateways,
+    };
+
+    Ok(gateways
+        .iter()
+        .map(|gateway| gateway.trim())
+        .filter(|gateway| !gateway.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+fn public_key<'de, D>(deserializer: D) -> std::result::Result<Option<PublicKey>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let key = String::deserialize(deserializer)?;
+    PublicKey::from_str(&key)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config() {
+        let key = crate::wire::keypair().public_key();
+        let config: Config = toml::from_str(&format!(
+            r#"
+            gateway = "gateway.com:20000"
+            gateway-key = "{}"
+            labels = {{ env = "prod" }}
+
+            [token]
+            file = "/run/diglett/token"
+
+            [[forward]]
+            name = "web"
+            backend = "localhost:3000"
+
+            [[forward]]
+            name = "api"
+            backend = "localhost:8080, unix:/run/api.sock"
+            weight = 2
+            protocol = "tcp"
+            compress = false
+
+            [forward.health]
+            path = "/healthz"
+            "#,
+            key
+        ))
+        .unwrap();
+
+        config.validate().unwrap();
+        assert_eq!(config.gateway_key, Some(key));
+        assert!(matches!(config.token, Token::File { refresh: 300, .. }));
+        assert_eq!(config.reconnect.attempts, 30);
+        assert_eq!(config.labels["env"], "prod");
+        assert_eq!(config.forwards.len(), 2);
+        assert_eq!(config.forwards[1].weight, Some(2));
+        let backends = config.forwards[1].backends().unwrap();
+        assert_eq!(backends[1].to_string(), "unix:/run/api.sock");
+        assert_eq!(config.forwards[1].protocol, Protocol::Tcp);
+        assert_eq!(config.forwards[1].compression, Some(false));
+        assert!(config.forwards[0].health.is_none());
+        let health = config.forwards[1].health.as_ref().unwrap();
+        assert_eq!((health.status, health.interval), (200, 10));
+
+        let config: Config = toml::from_str(
+            r#"
+            gateway = "gateway.com:20000"
+            token = "secret"
+
+            [[forward]]
+            name = "web"
+            backend = "localhost:3000"
+
+            [[forward]]
+            name = "web"
+            backend = "localhost:8080"
+            "#,
+        )
+        .unwrap();
+
+        assert!(matches!(&config.token, Token::Value(token) if token == "secret"));
+        assert!(config.validate().is_err());
+
+        // a name is forwarded once per port
+        let config: Config = toml::from_str(
+            r#"
+            gateway = "gateway.com:20000"
+            token = "secret"
+
+            [[forward]]
+            name = "web"
+            backend = "localhost:3000"
+
+            [[forward]]
+            name = "web"
+            port = 22
+            backend = "localhost:2222"
+            "#,
+        )
+        .unwrap();
+
+        config.validate().unwrap();
+        assert_eq!(config.forwards[1].port, Some(22));
+    }
+
+    #[test]
+    fn gateways() {
+        let forward = r#"
+            [[forward]]
+            name = "web"
+            backend = "localhost:3000"
+            "#;
+
+        let config: Config = toml::from_str(&format!(
+            r#"gateway = ["gw1.gateway.com:20000", "gw2.gateway.com:20000"]{}"#,
+            forward
+        ))
+        .unwrap();
+        assert_eq!(
+            config.gateway,
+            ["gw1.gateway.com:20000", "gw2.gateway.com:20000"]
+        );
+
+        let config: Config = toml::from_str(&format!(
+            r#"gateway = "gw1.gateway.com:20000, gw2.gateway.com:20000"{}"#,
+            forward
+        ))
+        .unwrap();
+        assert_eq!(config.gateway.len(), 2);
+        config.validate().unwrap();
+
+        let config: Config = toml::from_str(&format!(r#"gateway = []{}"#, forward)).unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn aggregate() {
+        let config: Config = toml::from_str(
+            r#"
+            gateway = "gateway.com:20000"
+
+            [aggregate]
+            listen = "127.0.0.1:20001"
+            names = ["web", "db:5432"]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let aggregator = config.aggregate.as_ref().unwrap().aggregator().unwrap();
+        assert_eq!(
+            aggregator.names(),
+            [("web".to_string(), None), ("db".to_string(), Some(5432))]
+        );
+        config.agent().unwrap().build().unwrap();
+
+        let config: Config = toml::from_str(
+            r#"
+            gateway = "gateway.com:20000"
+
+            [[forward]]
+            name = "web"
+            backend = "localhost:3000"
+
+            [aggregate]
+            listen = "127.0.0.1:20001"
+            names = ["web"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str(
+            r#"
+            gateway = "gateway.com:20000"
+
+            [aggregate]
+            listen = "127.0.0.1:20001"
+            names = []
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rate() {
+        assert_eq!(parse_rate("5mbps").unwrap(), 625_000);
+        assert_eq!(parse_rate("1.5 Gbps").unwrap(), 187_500_000);
+        assert_eq!(parse_rate("800kbps").unwrap(), 100_000);
+        assert!(parse_rate("5").is_err());
+        assert!(parse_rate("5mb").is_err());
+        assert!(parse_rate("0mbps").is_err());
+    }
+}
@@ -0,0 +1,34 @@
+//! Credentials stores the agent tokens in the os keyring (the keychain on
+//! macos, the credential manager on windows and the kernel keyring on linux),
+//! so the agent runs without a token in its flags or configuration file. The
+//! tokens are stored per gateway address
+use keyring::Entry;
+
+use crate::Result;
+
+/// service of the keyring entries
+const SERVICE: &str = "diglett";
+
+/// store the token of the gateway, replacing the stored one
+pub fn store(gateway: &str, token: &str) -> Result<()> {
+    Entry::new(SERVICE, gateway)?.set_password(token)?;
+    Ok(())
+}
+
+/// the stored token of the gateway, None if there is none
+pub fn load(gateway: &str) -> Result<Option<String>> {
+    match Entry::new(SERVICE, gateway)?.get_password() {
+        Ok(token) => Ok(Some(token)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// delete the stored token of the gateway, returns false if there is none
+pub fn delete(gateway: &str) -> Result<bool> {
+    match Entry::new(SERVICE, gateway)?.delete_credential() {
+        Ok(()) => Ok(true),
+        Err(keyring::Error::NoEntry) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,184 @@
+//! docker backends, `docker://<container>:<port>` forwards the streams to the
+//! port of a container. The address of the container is resolved over the
+//! docker socket for every stream, so the agent follows containers that are
+//! restarted (or recreated by compose) with another address
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{http, Error, Result};
+
+/// docker socket if `DOCKER_HOST` is not set
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Container {
+    state: State,
+    network_settings: NetworkSettings,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct State {
+    running: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkSettings {
+    // address on the default bridge network
+    #[serde(rename = "IPAddress", default)]
+    ip_address: String,
+    #[serde(default)]
+    networks: BTreeMap<String, Network>,
+}
+
+#[derive(Deserialize)]
+struct Network {
+    #[serde(rename = "IPAddress", default)]
+    ip_address: String,
+}
+
+/// validate the name (or id) of a container
+pub(crate) fn container(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+
+    match valid {
+        true => Ok(()),
+        false => Err(Error::Config(format!("invalid container name '{}'", name))),
+    }
+}
+
+/// current address of the container
+pub(crate) async fn address(container: &str) -> Result<IpAddr> {
+    inspect(&socket()?, container).await
+}
+
+// the docker socket of the environment
+fn socket() -> Result<PathBuf> {
+    let Ok(host) = std::env::var("DOCKER_HOST") else {
+        return Ok(DOCKER_SOCKET.into());
+    };
+
+    match host.strip_prefix("unix://") {
+        Some(path) => Ok(path.into()),
+        None => Err(Error::Docker(format!(
+            "DOCKER_HOST '{}' is not a unix socket",
+            host
+        ))),
+    }
+}
+
+async fn inspect(socket: &Path, name: &str) -> Result<IpAddr> {
+    let response = http::get_unix(socket, &format!("/containers/{}/json", name))
+        .await
+        .map_err(|err| Error::Docker(format!("failed to inspect container '{}': {}", name, err)))?;
+
+    match response.status {
+        200 => {}
+        404 => return Err(Error::Docker(format!("no such container '{}'", name))),
+        status => {
+            return Err(Error::Docker(format!(
+                "failed to inspect container '{}': {} {}",
+                name,
+                status,
+                String::from_utf8_lossy(&response.body).trim()
+            )))
+        }
+    }
+
+    let container: Container = serde_json::from_slice(&response.body)
+        .map_err(|err| Error::Docker(format!("invalid inspection of '{}': {}", name, err)))?;
+
+    if !container.state.running {
+        return Err(Error::Docker(format!(
+            "container '{}' is not running",
+            name
+        )));
+    }
+
+    // the first network the container is attached to, the default bridge
+    // otherwise
+    let settings = container.network_settings;
+    settings
+        .networks
+        .values()
+        .map(|network| network.ip_address.as_str())
+        .chain(std::iter::once(settings.ip_address.as_str()))
+        .find(|address| !address.is_empty())
+        .and_then(|address| address.parse().ok())
+        .ok_or_else(|| Error::Docker(format!("container '{}' has no address", name)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixListener,
+    };
+
+    // docker api that serves a single inspection
+    fn docker(path: &Path, status: &'static str, body: &'static str) {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let count = stream.read(&mut buf).await.unwrap();
+            assert!(buf[..count].starts_with(b"GET /containers/web/json HTTP/1.0\r\n"));
+
+            let response = format!(
+                "HTTP/1.0 {}\r\nContent-Type: application/json\r\n\r\n{}",
+                status, body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+    }
+
+    #[tokio::test]
+    async fn inspect() {
+        let path = std::env::temp_dir().join(format!("diglett-docker-{}.sock", std::process::id()));
+
+        docker(
+            &path,
+            "200 OK",
+            r#"{"State":{"Running":true},"NetworkSettings":{"IPAddress":"","Networks":{"app_default":{"IPAddress":"172.18.0.3"}}}}"#,
+        );
+        let address = super::inspect(&path, "web").await.unwrap();
+        assert_eq!(address, IpAddr::from([172, 18, 0, 3]));
+
+        docker(
+            &path,
+            "200 OK",
+            r#"{"State":{"Running":false},"NetworkSettings":{"IPAddress":"172.17.0.2"}}"#,
+        );
+        assert!(super::inspect(&path, "web").await.is_err());
+
+        docker(
+            &path,
+            "404 Not Found",
+            r#"{"message":"No such container: web"}"#,
+        );
+        let err = super::inspect(&path, "web").await.unwrap_err();
+        assert_eq!(err.to_string(), "docker error: no such container 'web'");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn name() {
+        assert!(container("web-1").is_ok());
+        assert!(container("app_web.1").is_ok());
+        assert!(container("").is_err());
+        assert!(container("../web").is_err());
+    }
+}
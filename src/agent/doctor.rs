@@ -0,0 +1,362 @@
+//! Doctor diagnoses the configuration of an agent without serving it. It
+//! goes through the steps of a tunnel one by one (reaching the gateway, the
+//! handshake, the server key, the login and the backends) and reports each
+//! step, so a tunnel that doesn't work points at its broken step:
+//!
+//! ```no_run
+//! # async fn example() -> diglett::Result<()> {
+//! use diglett::agent::{doctor, Config};
+//!
+//! let config = Config::load("agent.toml")?;
+//! for check in doctor::diagnose(&config).await {
+//!     println!("{}", check);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use tokio::net::TcpStream;
+
+use super::{
+    config::{Config, Token},
+    login,
+};
+use crate::{
+    wire::{fingerprint, keypair, Client},
+    Error, Result,
+};
+
+/// max time of each step
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// status of a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// works but might not be what is expected
+    Warning,
+    Failed,
+    /// can't be checked by the doctor
+    Skipped,
+}
+
+/// Check is the outcome of one step
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new<N: Into<String>, D: Into<String>>(name: N, status: Status, detail: D) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warning => "warn",
+            Status::Failed => "FAIL",
+            Status::Skipped => "skip",
+        };
+
+        write!(f, "[{:<4}] {}: {}", status, self.name, self.detail)
+    }
+}
+
+/// run the checks of the configuration. The gateway checks stop if the
+/// gateway can't be reached or the handshake fails, the backends are
+/// checked anyway
+pub async fn diagnose(config: &Config) -> Vec<Check> {
+    let mut checks = vec![];
+
+    if let Err(err) = config.validate() {
+        checks.push(Check::new("configuration", Status::Failed, err.to_string()));
+        return checks;
+    }
+    checks.push(Check::new("configuration", Status::Ok, "valid"));
+
+    gateway(config, &mut checks).await;
+
+    for forward in &config.forwards {
+        let name = match forward.port {
+            Some(port) => format!("backend of '{}:{}'", forward.name, port),
+            None => format!("backend of '{}'", forward.name),
+        };
+        let backends = match forward.backends() {
+            Ok(backends) => backends,
+            Err(err) => {
+                checks.push(Check::new(name, Status::Failed, err.to_string()));
+                continue;
+            }
+        };
+
+        for backend in backends {
+            let started = Instant::now();
+            let check = match timed(backend.connect()).await {
+                Ok(_) => Check::new(
+                    &name,
+                    Status::Ok,
+                    format!("{} accepted in {}", backend, elapsed(started)),
+                ),
+                Err(err) => Check::new(&name, Status::Failed, format!("{}: {}", backend, err)),
+            };
+            checks.push(check);
+        }
+    }
+
+    checks
+}
+
+async fn gateway(config: &Config, checks: &mut Vec<Check>) {
+    // the first gateway is the one the agent uses unless it's down
+    let Some(gateway) = config.gateway.first() else {
+        return;
+    };
+    let name = format!("gateway {}", gateway);
+
+    if gateway.starts_with("exec:") || gateway.starts_with("srv:") {
+        checks.push(Check::new(
+            name,
+            Status::Skipped,
+            "only host:port gateways are diagnosed",
+        ));
+        return;
+    }
+    if config.proxy.is_some() || config.tls.is_some() {
+        checks.push(Check::new(
+            name,
+            Status::Skipped,
+            "gateways behind a proxy or with tls are not diagnosed",
+        ));
+        return;
+    }
+
+    let addresses = match timed(tokio::net::lookup_host(gateway.as_str())).await {
+        Ok(addresses) => addresses.collect::<Vec<_>>(),
+        Err(err) => {
+            checks.push(Check::new("resolve", Status::Failed, err.to_string()));
+            return;
+        }
+    };
+    let list: Vec<String> = addresses.iter().map(|a| a.ip().to_string()).collect();
+    checks.push(Check::new("resolve", Status::Ok, list.join(", ")));
+
+    let started = Instant::now();
+    let stream = match timed(TcpStream::connect(&addresses[..])).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            checks.push(Check::new("reachable", Status::Failed, err.to_string()));
+            return;
+        }
+    };
+    let latency = elapsed(started);
+    checks.push(Check::new(
+        "reachable",
+        Status::Ok,
+        format!(
+            "connected to {} in {}",
+            stream
+                .peer_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_default(),
+            latency
+        ),
+    ));
+    checks.push(match mtu(&stream) {
+        Some(mtu) => Check::new("path mtu", Status::Ok, format!("{} bytes", mtu)),
+        None => Check::new("path mtu", Status::Skipped, "not available on this system"),
+    });
+
+    let started = Instant::now();
+    // the connection is large, it's kept off the stack
+    let mut connection = match timed(Client::new(stream, keypair()).negotiate()).await {
+        Ok(connection) => Box::new(connection),
+        Err(err) => {
+            checks.push(Check::new("handshake", Status::Failed, err.to_string()));
+            return;
+        }
+    };
+    let key = connection.remote_key();
+    let observed = fingerprint(&key);
+    checks.push(Check::new(
+        "handshake",
+        Status::Ok,
+        format!(
+            "wire version {} in {}, server key {}",
+            connection.version(),
+            elapsed(started),
+            observed
+        ),
+    ));
+
+    checks.push(server_key(config, gateway, &key, &observed).await);
+
+    let token = match &config.token {
+        Token::Value(token) => Ok(token.clone()),
+        Token::File { file, .. } => std::fs::read_to_string(file)
+            .map(|token| token.trim().to_string())
+            .map_err(Error::from),
+    };
+    let check = match token {
+        Ok(token) => match timed(login(&mut *connection, token)).await {
+            Ok(()) => Check::new("login", Status::Ok, "token accepted"),
+            Err(err) => Check::new("login", Status::Failed, err.to_string()),
+        },
+        Err(err) => Check::new(
+            "login",
+            Status::Failed,
+            format!("can't read the token: {}", err),
+        ),
+    };
+    checks.push(check);
+}
+
+// the key is compared to the pinned key, or to the known hosts
+async fn server_key(
+    config: &Config,
+    gateway: &str,
+    key: &secp256k1::PublicKey,
+    observed: &str,
+) -> Check {
+    const NAME: &str = "server key";
+
+    if let Some(pinned) = &config.gateway_key {
+        return match pinned == key {
+            true => Check::new(NAME, Status::Ok, "matches the pinned key"),
+            false => Check::new(
+                NAME,
+                Status::Failed,
+                format!("expected {}", fingerprint(pinned)),
+            ),
+        };
+    }
+
+    let known_hosts = match config.known_hosts() {
+        Ok(Some(known_hosts)) => known_hosts,
+        Ok(None) => return Check::new(NAME, Status::Warning, "not pinned, any key is accepted"),
+        Err(err) => return Check::new(NAME, Status::Failed, err.to_string()),
+    };
+
+    match known_hosts.get(gateway).await {
+        Ok(Some(known)) if known == observed => {
+            Check::new(NAME, Status::Ok, "matches the known hosts")
+        }
+        Ok(Some(known)) => Check::new(
+            NAME,
+            Status::Failed,
+            format!("known hosts expect {}", known),
+        ),
+        Ok(None) => Check::new(
+            NAME,
+            Status::Warning,
+            "not in the known hosts yet, it's trusted on first use",
+        ),
+        Err(err) => Check::new(NAME, Status::Failed, err.to_string()),
+    }
+}
+
+async fn timed<T, E, F>(future: F) -> Result<T>
+where
+    F: std::future::Future<Output = std::result::Result<T, E>>,
+    E: Into<Error>,
+{
+    tokio::time::timeout(TIMEOUT, future)
+        .await
+        .map_err(|_| Error::IO(std::io::ErrorKind::TimedOut.into()))?
+        .map_err(Into::into)
+}
+
+fn elapsed(started: Instant) -> String {
+    format!("{:.1}ms", started.elapsed().as_secs_f64() * 1000.0)
+}
+
+// mtu of the path to the peer as known by the kernel
+#[cfg(target_os = "linux")]
+fn mtu(stream: &TcpStream) -> Option<u32> {
+    use std::os::fd::AsRawFd;
+
+    let (level, option) = match stream.peer_addr().ok()? {
+        std::net::SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+        std::net::SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut size = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            option,
+            &mut mtu as *mut libc::c_int as *mut libc::c_void,
+            &mut size,
+        )
+    };
+
+    (result == 0 && mtu > 0).then_some(mtu as u32)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mtu(_stream: &TcpStream) -> Option<u32> {
+    None
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+    use crate::server::{AuthorizeAll, PrintRegisterer, Server};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn diagnose() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway = listener.local_addr().unwrap();
+        drop(listener);
+        let server = Server::new(keypair(), AuthorizeAll, PrintRegisterer);
+        tokio::spawn(Box::pin(
+            server.start_until(gateway, std::future::pending()),
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config: Config = toml::from_str(&format!(
+            r#"
+            gateway = "{}"
+            token = "fail"
+
+            [[forward]]
+            name = "web"
+            backend = "{}"
+            "#,
+            gateway,
+            backend.local_addr().unwrap()
+        ))
+        .unwrap();
+
+        let checks = super::diagnose(&config).await;
+        let status = |name: &str| {
+            checks
+                .iter()
+                .find(|check| check.name == name)
+                .map(|check| check.status)
+        };
+
+        assert_eq!(status("configuration"), Some(Status::Ok));
+        assert_eq!(status("reachable"), Some(Status::Ok));
+        assert_eq!(status("handshake"), Some(Status::Ok));
+        assert_eq!(status("server key"), Some(Status::Warning));
+        // the token is refused by the server
+        assert_eq!(status("login"), Some(Status::Failed));
+        assert_eq!(status("backend of 'web'"), Some(Status::Ok));
+    }
+}
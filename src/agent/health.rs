@@ -0,0 +1,178 @@
+//! Health checks of the backends. An agent only registers names with
+//! healthy backends, and disconnects (so the gateway unregisters its names
+//! and balances the traffic to other agents) once a backend is down instead
+//! of serving connection errors
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::Backend;
+use crate::{Error, Result};
+
+/// max time of a single check
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// max size of the status line of a health check response
+const MAX_STATUS_LINE: usize = 1024;
+
+/// HealthCheck connects to the backend, or sends it an http `GET` request
+/// and expects a status
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    path: Option<String>,
+    status: u16,
+    interval: Duration,
+}
+
+impl HealthCheck {
+    /// the backend is healthy if it accepts connections
+    pub fn tcp() -> Self {
+        Self {
+            path: None,
+            status: 200,
+            interval: Duration::from_secs(10),
+        }
+    }
+
+    /// the backend is healthy if it answers `GET path` with status 200
+    pub fn http<P: Into<String>>(path: P) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..Self::tcp()
+        }
+    }
+
+    /// expected status of the http check
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// how often the backend is checked while it's served
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// check the backend once
+    pub async fn check(&self, backend: &Backend) -> Result<()> {
+        tokio::time::timeout(CHECK_TIMEOUT, self.probe(backend))
+            .await
+            .map_err(|_| Error::Unhealthy(format!("{}: timed out", backend)))?
+            .map_err(|err| match err {
+                Error::Unhealthy(_) => err,
+                err => Error::Unhealthy(format!("{}: {}", backend, err)),
+            })
+    }
+
+    /// check instances of the same backend, they are healthy as long as one
+    /// of them is healthy
+    pub async fn check_any(&self, backends: &[Backend]) -> Result<()> {
+        let mut error = None;
+        for backend in backends {
+            match self.check(backend).await {
+                Ok(_) => return Ok(()),
+                Err(err) => error = Some(err),
+            }
+        }
+
+        Err(error.unwrap_or_else(|| Error::Unhealthy("no backends".into())))
+    }
+
+    async fn probe(&self, backend: &Backend) -> Result<()> {
+        let (mut read, mut write) = backend.connect().await?;
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let host = match backend {
+            Backend::Unix(_) | Backend::Local(_) => "localhost".into(),
+            Backend::Docker { container, port } => format!("{}:{}", container, port),
+            backend => backend.to_string(),
+        };
+        let host = host.trim_start_matches("https://");
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: diglett\r\nConnection: close\r\n\r\n",
+            path, host
+        );
+        write.write_all(request.as_bytes()).await?;
+
+        let mut line = Vec::new();
+        let mut buf = [0; 256];
+        while !line.windows(2).any(|window| window == b"\r\n") {
+            let count = read.read(&mut buf).await?;
+            if count == 0 || line.len() > MAX_STATUS_LINE {
+                return Err(Error::Unhealthy(format!(
+                    "{}: invalid http response",
+                    backend
+                )));
+            }
+            line.extend_from_slice(&buf[..count]);
+        }
+
+        // HTTP/1.1 200 OK
+        let status = std::str::from_utf8(&line)
+            .ok()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| Error::Unhealthy(format!("{}: invalid http response", backend)))?;
+
+        if status != self.status {
+            return Err(Error::Unhealthy(format!(
+                "{}: GET {} returned {}",
+                backend, path, status
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn check() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = Backend::from(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let count = stream.read(&mut buf).await.unwrap_or_default();
+                let response: &[u8] = if buf[..count].starts_with(b"GET /healthz ") {
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+                };
+                let _ = stream.write_all(response).await;
+            }
+        });
+
+        HealthCheck::tcp().check(&backend).await.unwrap();
+        HealthCheck::http("/healthz").check(&backend).await.unwrap();
+
+        let result = HealthCheck::http("/").check(&backend).await;
+        assert!(matches!(result, Err(Error::Unhealthy(_))));
+        HealthCheck::http("/")
+            .with_status(404)
+            .check(&backend)
+            .await
+            .unwrap();
+
+        // nothing is listening
+        let down = Backend::Tcp("127.0.0.1:1".into());
+        let result = HealthCheck::tcp().check(&down).await;
+        assert!(matches!(result, Err(Error::Unhealthy(_))));
+
+        let check = HealthCheck::tcp();
+        check.check_any(&[down.clone(), backend]).await.unwrap();
+        assert!(check.check_any(&[down]).await.is_err());
+    }
+}
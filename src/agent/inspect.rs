@@ -0,0 +1,963 @@
+//! Inspection of the http traffic of the agent. The streams are parsed as
+//! http/1 on their way between the tunnel and the backends, and the recent
+//! requests with their responses are kept by the [`Inspector`] and listed by
+//! a local web ui (or logged). Streams that are not http are forwarded as is
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use url::Url;
+
+use super::Backend;
+use crate::{
+    http::{post, read_request, respond},
+    Error, Result,
+};
+
+/// number of exchanges kept by default
+pub const CAPACITY: usize = 100;
+/// max size of a captured body, the rest is forwarded but not kept
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+/// max size of a message head, streams with larger heads are not inspected
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// max size of a chunk size or trailer line
+const MAX_LINE_SIZE: usize = 1024;
+/// max time of a replayed request
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const UI: &str = include_str!("inspect.html");
+
+/// A request and its response
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub id: u64,
+    /// backend that served the request
+    pub backend: String,
+    pub method: String,
+    pub path: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Body,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: Body,
+    pub started: SystemTime,
+    /// time from the request head to the end of the response
+    pub duration: Duration,
+    /// id of the exchange this one replayed
+    pub replay_of: Option<u64>,
+}
+
+/// Captured body of a message
+#[derive(Debug, Clone, Default)]
+pub struct Body {
+    /// the body up to [`MAX_BODY_SIZE`], chunked bodies are decoded
+    pub data: Vec<u8>,
+    /// full size of the body
+    pub size: u64,
+}
+
+impl Body {
+    fn extend(&mut self, data: &[u8]) {
+        let room = MAX_BODY_SIZE.saturating_sub(self.data.len());
+        self.data.extend_from_slice(&data[..data.len().min(room)]);
+        self.size += data.len() as u64;
+    }
+
+    /// the body was larger than what is kept
+    pub fn truncated(&self) -> bool {
+        self.size > self.data.len() as u64
+    }
+}
+
+/// Inspector keeps the recent http exchanges of all the streams
+#[derive(Clone)]
+pub struct Inspector {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    exchanges: VecDeque<Exchange>,
+    capacity: usize,
+    next: u64,
+    log: bool,
+    // the backends of the exchanges, to replay them
+    backends: HashMap<String, Backend>,
+}
+
+impl Default for Inspector {
+    fn default() -> Self {
+        Self::new(CAPACITY)
+    }
+}
+
+impl Inspector {
+    /// inspector that keeps the last `capacity` exchanges
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                exchanges: VecDeque::with_capacity(capacity),
+                capacity,
+                next: 1,
+                log: false,
+                backends: HashMap::default(),
+            })),
+        }
+    }
+
+    /// log a line for each exchange, an inspector with no capacity only logs
+    pub fn with_log(self) -> Self {
+        self.inner.lock().unwrap().log = true;
+        self
+    }
+
+    /// the kept exchanges, newest first
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        let inner = self.inner.lock().unwrap();
+        inner.exchanges.iter().rev().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Exchange> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .exchanges
+            .iter()
+            .find(|exchange| exchange.id == id)
+            .cloned()
+    }
+
+    /// capture of a new stream to the backend
+    pub(crate) fn capture(&self, backend: &Backend) -> Capture {
+        let name = backend.to_string();
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.backends.contains_key(&name) {
+            inner.backends.insert(name.clone(), backend.clone());
+        }
+
+        Capture {
+            inspector: self.clone(),
+            backend: name,
+            requests: Parser::default(),
+            responses: Parser::default(),
+            pending: VecDeque::default(),
+            interim: false,
+            replay_of: None,
+            last: None,
+        }
+    }
+
+    /// send the request of the exchange again to its backend. The new
+    /// exchange is kept (and returned) like any other
+    pub async fn replay(&self, id: u64) -> Result<Exchange> {
+        let exchange = self
+            .get(id)
+            .ok_or_else(|| Error::Http(format!("request {} is not found", id)))?;
+
+        if exchange.request_body.truncated() {
+            return Err(Error::Http(format!(
+                "body of request {} is too large to replay",
+                id
+            )));
+        }
+
+        let backend = self
+            .inner
+            .lock()
+            .unwrap()
+            .backends
+            .get(&exchange.backend)
+            .cloned();
+        let backend = backend
+            .ok_or_else(|| Error::Http(format!("backend {} is not found", exchange.backend)))?;
+
+        let mut capture = self.capture(&backend);
+        capture.replay_of = Some(id);
+
+        let request = replay_request(&exchange);
+        tokio::time::timeout(REPLAY_TIMEOUT, async {
+            let (mut read, mut write) = backend.connect().await?;
+            capture.request(&request);
+            write.write_all(&request).await?;
+
+            // the request asks the backend to close the connection after
+            // the response
+            let mut buf = vec![0; 16 * 1024];
+            loop {
+                let count = read.read(&mut buf).await?;
+                if count == 0 {
+                    capture.finish();
+                    return Ok::<_, Error>(());
+                }
+
+                capture.response(&buf[..count]);
+            }
+        })
+        .await
+        .map_err(|_| Error::Http(format!("replay of request {} timed out", id)))??;
+
+        capture
+            .last
+            .and_then(|id| self.get(id))
+            .ok_or_else(|| Error::Http(format!("no response to the replay of request {}", id)))
+    }
+
+    fn push(&self, mut exchange: Exchange) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        exchange.id = inner.next;
+        inner.next += 1;
+        let id = exchange.id;
+
+        if inner.log {
+            // the fields of the line are also key values for the json logs
+            log::info!(
+                target: "access",
+                method = exchange.method.as_str(),
+                path = exchange.path.as_str(),
+                status = exchange.status,
+                duration_ms = exchange.duration.as_secs_f64() * 1000.0,
+                request_bytes = exchange.request_body.size,
+                response_bytes = exchange.response_body.size,
+                backend = exchange.backend.as_str();
+                "http method={} path={} status={} duration={:.1}ms request={} response={} backend={}",
+                exchange.method,
+                exchange.path,
+                exchange.status,
+                exchange.duration.as_secs_f64() * 1000.0,
+                exchange.request_body.size,
+                exchange.response_body.size,
+                exchange.backend,
+            );
+        }
+
+        if inner.capacity == 0 {
+            return id;
+        }
+
+        if inner.exchanges.len() == inner.capacity {
+            inner.exchanges.pop_front();
+        }
+        inner.exchanges.push_back(exchange);
+        id
+    }
+
+    /// serve the inspection web ui and its json api on the listener
+    ///  - `GET /` the web ui
+    ///  - `GET /api/requests` the recent exchanges, newest first
+    ///  - `GET /api/requests/<id>` a single exchange
+    ///  - `POST /api/requests/<id>/replay` replay an exchange, it returns the
+    ///    new exchange
+    pub async fn serve(self, listener: TcpListener) {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::error!("failed to accept inspection connection: {}", err);
+                    continue;
+                }
+            };
+
+            let inspector = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = inspector.handle(stream).await {
+                    log::debug!("failed to handle inspection request: {}", err);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let request = read_request(&mut stream).await?;
+        let path = request.path.as_str();
+
+        match (request.method.as_str(), path) {
+            ("GET", "/") => {
+                respond(
+                    &mut stream,
+                    "200 OK",
+                    &[("Content-Type", "text/html; charset=utf-8")],
+                    UI.as_bytes(),
+                )
+                .await
+            }
+            ("GET", "/api/requests") => {
+                let exchanges: Vec<_> = self.exchanges().iter().map(to_json).collect();
+                let body = serde_json::to_vec(&exchanges).unwrap_or_default();
+                respond(
+                    &mut stream,
+                    "200 OK",
+                    &[("Content-Type", "application/json")],
+                    &body,
+                )
+                .await
+            }
+            ("GET", _) if path.starts_with("/api/requests/") => {
+                let exchange = path["/api/requests/".len()..]
+                    .parse()
+                    .ok()
+                    .and_then(|id| self.get(id));
+
+                match exchange {
+                    Some(exchange) => {
+                        let body = serde_json::to_vec(&to_json(&exchange)).unwrap_or_default();
+                        respond(
+                            &mut stream,
+                            "200 OK",
+                            &[("Content-Type", "application/json")],
+                            &body,
+                        )
+                        .await
+                    }
+                    None => respond(&mut stream, "404 Not Found", &[], b"not found").await,
+                }
+            }
+            ("POST", _) if path.starts_with("/api/requests/") && path.ends_with("/replay") => {
+                let id = path["/api/requests/".len()..path.len() - "/replay".len()].parse();
+                let Ok(id) = id else {
+                    return respond(&mut stream, "404 Not Found", &[], b"not found").await;
+                };
+
+                match self.replay(id).await {
+                    Ok(exchange) => {
+                        let body = serde_json::to_vec(&to_json(&exchange)).unwrap_or_default();
+                        respond(
+                            &mut stream,
+                            "200 OK",
+                            &[("Content-Type", "application/json")],
+                            &body,
+                        )
+                        .await
+                    }
+                    Err(err) => {
+                        let body = err.to_string();
+                        respond(&mut stream, "502 Bad Gateway", &[], body.as_bytes()).await
+                    }
+                }
+            }
+            _ => respond(&mut stream, "404 Not Found", &[], b"not found").await,
+        }
+    }
+}
+
+/// ask the inspector of a running agent (listening on address) to replay the
+/// request with that id. It returns the id and the status of the new exchange
+pub async fn replay(address: SocketAddr, id: u64) -> Result<(u64, u16)> {
+    let url = Url::parse(&format!("http://{}/api/requests/{}/replay", address, id))
+        .map_err(|err| Error::Http(err.to_string()))?;
+
+    let response = post(&url, "application/json", "").await?;
+    if response.status != 200 {
+        return Err(Error::Http(format!(
+            "replay failed ({}): {}",
+            response.status,
+            String::from_utf8_lossy(&response.body)
+        )));
+    }
+
+    let exchange: serde_json::Value = serde_json::from_slice(&response.body)
+        .map_err(|err| Error::Http(format!("invalid replay response: {}", err)))?;
+
+    Ok((
+        exchange["id"].as_u64().unwrap_or_default(),
+        exchange["status"].as_u64().unwrap_or_default() as u16,
+    ))
+}
+
+// the request of the exchange as sent again, with its decoded body
+fn replay_request(exchange: &Exchange) -> Vec<u8> {
+    let mut request = format!("{} {} HTTP/1.1\r\n", exchange.method, exchange.path);
+    for (key, value) in &exchange.request_headers {
+        let skip = ["content-length", "transfer-encoding", "connection"]
+            .iter()
+            .any(|header| key.eq_ignore_ascii_case(header));
+        if !skip {
+            request.push_str(&format!("{}: {}\r\n", key, value));
+        }
+    }
+
+    if !exchange.request_body.data.is_empty() {
+        request.push_str(&format!(
+            "Content-Length: {}\r\n",
+            exchange.request_body.data.len()
+        ));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+
+    let mut request = request.into_bytes();
+    request.extend_from_slice(&exchange.request_body.data);
+    request
+}
+
+fn to_json(exchange: &Exchange) -> serde_json::Value {
+    let headers = |headers: &[(String, String)]| -> Vec<_> {
+        headers
+            .iter()
+            .map(|(key, value)| json!([key, value]))
+            .collect()
+    };
+
+    let body = |body: &Body| {
+        json!({
+            "data": String::from_utf8_lossy(&body.data),
+            "size": body.size,
+            "truncated": body.truncated(),
+        })
+    };
+
+    let started = exchange
+        .started
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    json!({
+        "id": exchange.id,
+        "backend": exchange.backend,
+        "method": exchange.method,
+        "path": exchange.path,
+        "status": exchange.status,
+        "started": started.as_secs_f64(),
+        "duration": exchange.duration.as_secs_f64(),
+        "replay_of": exchange.replay_of,
+        "request": {
+            "headers": headers(&exchange.request_headers),
+            "body": body(&exchange.request_body),
+        },
+        "response": {
+            "headers": headers(&exchange.response_headers),
+            "body": body(&exchange.response_body),
+        },
+    })
+}
+
+/// Capture parses both directions of a stream, the requests from the tunnel
+/// and the responses of the backend
+pub(crate) struct Capture {
+    inspector: Inspector,
+    backend: String,
+    requests: Parser,
+    responses: Parser,
+    // requests waiting for their response, oldest first
+    pending: VecDeque<(Exchange, Instant)>,
+    // the response being read is an interim (1xx) response
+    interim: bool,
+    // the exchanges of a replay are marked with the replayed exchange
+    replay_of: Option<u64>,
+    // id of the last complete exchange
+    last: Option<u64>,
+}
+
+impl Capture {
+    /// data sent to the backend
+    pub fn request(&mut self, data: &[u8]) {
+        let mut events = vec![];
+        self.requests.feed(data, &mut request_length, &mut events);
+
+        for event in events {
+            match event {
+                Event::Head(head) => {
+                    let (line, headers) = parse_head(&head);
+                    let mut line = line.split_whitespace();
+                    let exchange = Exchange {
+                        id: 0,
+                        backend: self.backend.clone(),
+                        method: line.next().unwrap_or_default().into(),
+                        path: line.next().unwrap_or_default().into(),
+                        request_headers: headers,
+                        request_body: Body::default(),
+                        status: 0,
+                        response_headers: vec![],
+                        response_body: Body::default(),
+                        started: SystemTime::now(),
+                        duration: Duration::ZERO,
+                        replay_of: self.replay_of,
+                    };
+
+                    self.pending.push_back((exchange, Instant::now()));
+                }
+                Event::Body(data) => {
+                    if let Some((exchange, _)) = self.pending.back_mut() {
+                        exchange.request_body.extend(&data);
+                    }
+                }
+                Event::End => {}
+            }
+        }
+
+        if self.requests.is_opaque() {
+            self.responses.state = State::Opaque;
+        }
+    }
+
+    /// data received from the backend
+    pub fn response(&mut self, data: &[u8]) {
+        let mut events = vec![];
+        let pending = &self.pending;
+        let mut length = |head: &[u8]| response_length(head, pending.front().map(|p| &p.0));
+        self.responses.feed(data, &mut length, &mut events);
+
+        for event in events {
+            self.response_event(event);
+        }
+
+        // upgraded connections (websockets) are not http anymore
+        if self.responses.is_opaque() {
+            self.requests.state = State::Opaque;
+        }
+    }
+
+    /// the backend closed its side, which ends responses without a length
+    pub fn finish(&mut self) {
+        let mut events = vec![];
+        self.responses.finish(&mut events);
+        for event in events {
+            self.response_event(event);
+        }
+    }
+
+    fn response_event(&mut self, event: Event) {
+        match event {
+            Event::Head(head) => {
+                let (line, headers) = parse_head(&head);
+                let status = status(&line);
+                self.interim = (100..200).contains(&status) && status != 101;
+                if self.interim {
+                    return;
+                }
+
+                if let Some((exchange, _)) = self.pending.front_mut() {
+                    exchange.status = status;
+                    exchange.response_headers = headers;
+                }
+            }
+            Event::Body(data) => {
+                if let Some((exchange, _)) = self.pending.front_mut() {
+                    exchange.response_body.extend(&data);
+                }
+            }
+            Event::End => {
+                if std::mem::take(&mut self.interim) {
+                    return;
+                }
+
+                if let Some((mut exchange, started)) = self.pending.pop_front() {
+                    exchange.duration = started.elapsed();
+                    self.last = Some(self.inspector.push(exchange));
+                }
+            }
+        }
+    }
+}
+
+/// length of the body of a message, decided once its head is parsed
+#[derive(Debug, PartialEq, Eq)]
+enum Length {
+    None,
+    Fixed(u64),
+    Chunked,
+    /// until the connection is closed
+    Close,
+    /// the stream is not (or not anymore) http
+    Opaque,
+}
+
+fn request_length(head: &[u8]) -> Length {
+    let (line, headers) = parse_head(head);
+    if !line.ends_with("HTTP/1.1") && !line.ends_with("HTTP/1.0") {
+        return Length::Opaque;
+    }
+
+    body_length(&headers).unwrap_or(Length::None)
+}
+
+fn response_length(head: &[u8], request: Option<&Exchange>) -> Length {
+    let (line, headers) = parse_head(head);
+    if !line.starts_with("HTTP/1.") {
+        return Length::Opaque;
+    }
+
+    let status = status(&line);
+    if status == 101 || request.is_some_and(|r| r.method == "CONNECT" && status / 100 == 2) {
+        return Length::Opaque;
+    }
+
+    if status / 100 == 1 || status == 204 || status == 304 {
+        return Length::None;
+    }
+
+    if request.is_some_and(|r| r.method == "HEAD") {
+        return Length::None;
+    }
+
+    body_length(&headers).unwrap_or(Length::Close)
+}
+
+fn body_length(headers: &[(String, String)]) -> Option<Length> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+
+    if header("transfer-encoding").is_some_and(|value| value.to_lowercase().contains("chunked")) {
+        return Some(Length::Chunked);
+    }
+
+    header("content-length").map(|value| match value.trim().parse() {
+        Ok(0) => Length::None,
+        Ok(length) => Length::Fixed(length),
+        Err(_) => Length::Opaque,
+    })
+}
+
+fn status(line: &str) -> u16 {
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or_default()
+}
+
+// first line and headers of a message head
+fn parse_head(head: &[u8]) -> (String, Vec<(String, String)>) {
+    let text = String::from_utf8_lossy(head);
+    let mut lines = text.split("\r\n");
+    let line = lines.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    (line, headers)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    Head(Vec<u8>),
+    Body(Vec<u8>),
+    End,
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Head,
+    Body(u64),
+    ChunkSize,
+    ChunkData(u64),
+    // the crlf after the chunk data
+    ChunkEnd(usize),
+    Trailer,
+    Close,
+    Opaque,
+}
+
+/// Parser is an incremental parser of the http/1 messages of one direction
+/// of a stream
+#[derive(Debug, Default)]
+struct Parser {
+    state: State,
+    // the head or line being read
+    buffer: Vec<u8>,
+}
+
+impl Parser {
+    fn is_opaque(&self) -> bool {
+        matches!(self.state, State::Opaque)
+    }
+
+    fn feed(
+        &mut self,
+        mut data: &[u8],
+        length: &mut dyn FnMut(&[u8]) -> Length,
+        events: &mut Vec<Event>,
+    ) {
+        while !data.is_empty() {
+            match self.state {
+                State::Opaque => return,
+                State::Head => {
+                    let read = self.buffer.len();
+                    self.buffer.extend_from_slice(data);
+
+                    // the end of the head can span the previous data
+                    let from = read.saturating_sub(3);
+                    let Some(end) = find(&self.buffer[from..], b"\r\n\r\n") else {
+                        if self.buffer.len() > MAX_HEAD_SIZE {
+                            self.opaque();
+                        }
+                        return;
+                    };
+
+                    let end = from + end + 4;
+                    self.buffer.truncate(end);
+                    data = &data[end - read..];
+
+                    let head = std::mem::take(&mut self.buffer);
+                    let length = length(&head);
+                    if length == Length::Opaque {
+                        self.opaque();
+                        return;
+                    }
+
+                    events.push(Event::Head(head));
+                    self.state = match length {
+                        Length::None => {
+                            events.push(Event::End);
+                            State::Head
+                        }
+                        Length::Fixed(length) => State::Body(length),
+                        Length::Chunked => State::ChunkSize,
+                        Length::Close => State::Close,
+                        Length::Opaque => unreachable!(),
+                    };
+                }
+                State::Body(remaining) => {
+                    let count = remaining.min(data.len() as u64) as usize;
+                    events.push(Event::Body(data[..count].to_vec()));
+                    data = &data[count..];
+
+                    let remaining = remaining - count as u64;
+                    self.state = if remaining == 0 {
+                        events.push(Event::End);
+                        State::Head
+                    } else {
+                        State::Body(remaining)
+                    };
+                }
+                State::ChunkData(remaining) => {
+                    let count = remaining.min(data.len() as u64) as usize;
+                    events.push(Event::Body(data[..count].to_vec()));
+                    data = &data[count..];
+
+                    let remaining = remaining - count as u64;
+                    self.state = if remaining == 0 {
+                        State::ChunkEnd(2)
+                    } else {
+                        State::ChunkData(remaining)
+                    };
+                }
+                State::ChunkEnd(remaining) => {
+                    let count = data.len().min(remaining);
+                    data = &data[count..];
+                    self.state = if remaining == count {
+                        State::ChunkSize
+                    } else {
+                        State::ChunkEnd(remaining - count)
+                    };
+                }
+                State::ChunkSize | State::Trailer => {
+                    let Some(line) = self.line(&mut data) else {
+                        return;
+                    };
+
+                    self.state = match self.state {
+                        State::ChunkSize => {
+                            let size = line.split(|b| *b == b';').next().unwrap_or_default();
+                            let size = std::str::from_utf8(size)
+                                .ok()
+                                .and_then(|size| u64::from_str_radix(size.trim(), 16).ok());
+
+                            match size {
+                                Some(0) => State::Trailer,
+                                Some(size) => State::ChunkData(size),
+                                None => {
+                                    self.opaque();
+                                    return;
+                                }
+                            }
+                        }
+                        // an empty line ends the trailers
+                        _ if line.is_empty() => {
+                            events.push(Event::End);
+                            State::Head
+                        }
+                        _ => State::Trailer,
+                    };
+                }
+                State::Close => {
+                    events.push(Event::Body(data.to_vec()));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// the connection is closed
+    fn finish(&mut self, events: &mut Vec<Event>) {
+        if matches!(self.state, State::Close) {
+            events.push(Event::End);
+            self.state = State::Head;
+        }
+    }
+
+    // read a line (without its crlf) into the buffer, returns it once complete
+    fn line(&mut self, data: &mut &[u8]) -> Option<Vec<u8>> {
+        match data.iter().position(|b| *b == b'\n') {
+            Some(end) => {
+                self.buffer.extend_from_slice(&data[..end]);
+                *data = &data[end + 1..];
+
+                let mut line = std::mem::take(&mut self.buffer);
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                Some(line)
+            }
+            None => {
+                self.buffer.extend_from_slice(data);
+                *data = &[];
+                if self.buffer.len() > MAX_LINE_SIZE {
+                    self.opaque();
+                }
+                None
+            }
+        }
+    }
+
+    fn opaque(&mut self) {
+        self.state = State::Opaque;
+        self.buffer = vec![];
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parser() {
+        let mut parser = Parser::default();
+        let mut events = vec![];
+        let mut length = |head: &[u8]| response_length(head, None);
+
+        // a fixed body then a chunked body, fed byte by byte
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello\
+            HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nwor\r\n2;x=1\r\nld\r\n0\r\nA: b\r\n\r\n";
+        for byte in data.chunks(1) {
+            parser.feed(byte, &mut length, &mut events);
+        }
+
+        let bodies: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Body(data) => Some(data.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(bodies, b"helloworld");
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| matches!(event, Event::Head(_)))
+                .count(),
+            2
+        );
+        assert_eq!(
+            events.iter().filter(|event| **event == Event::End).count(),
+            2
+        );
+
+        // not http
+        let mut parser = Parser::default();
+        parser.feed(b"SSH-2.0-OpenSSH\r\n\r\n", &mut request_length, &mut vec![]);
+        assert!(parser.is_opaque());
+    }
+
+    #[test]
+    fn capture() {
+        let inspector = Inspector::new(2);
+        let mut capture = inspector.capture(&Backend::Tcp("localhost:8080".into()));
+
+        // pipelined requests, the second one with a body
+        capture.request(b"GET /a HTTP/1.1\r\nHost: web\r\n\r\n");
+        capture.request(b"POST /b HTTP/1.1\r\nContent-Length: 4\r\n\r\nping");
+        capture.response(b"HTTP/1.1 100 Continue\r\n\r\n");
+        capture.response(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+        capture.response(b"HTTP/1.1 200 OK\r\n\r\npo");
+        capture.response(b"ng");
+        capture.finish();
+
+        let exchanges = inspector.exchanges();
+        assert_eq!(exchanges.len(), 2);
+
+        let (b, a) = (&exchanges[0], &exchanges[1]);
+        assert_eq!(
+            (a.method.as_str(), a.path.as_str(), a.status),
+            ("GET", "/a", 404)
+        );
+        assert_eq!(a.request_headers, vec![("Host".into(), "web".into())]);
+        assert_eq!(
+            (b.method.as_str(), b.path.as_str(), b.status),
+            ("POST", "/b", 200)
+        );
+        assert_eq!(b.request_body.data, b"ping");
+        assert_eq!(b.response_body.data, b"pong");
+        assert_eq!(inspector.get(b.id).unwrap().path, "/b");
+
+        // the oldest exchange is dropped
+        capture.request(b"GET /c HTTP/1.1\r\n\r\n");
+        let mut capture = inspector.capture(&Backend::Tcp("localhost:8080".into()));
+        capture.request(b"GET /d HTTP/1.1\r\n\r\n");
+        capture.response(b"HTTP/1.1 204 No Content\r\n\r\n");
+        let paths: Vec<_> = inspector.exchanges().into_iter().map(|e| e.path).collect();
+        assert_eq!(paths, vec!["/d", "/b"]);
+
+        // only logged
+        let inspector = Inspector::new(0).with_log();
+        let mut capture = inspector.capture(&Backend::Tcp("localhost:8080".into()));
+        capture.request(b"GET /a HTTP/1.1\r\n\r\n");
+        capture.response(b"HTTP/1.1 204 No Content\r\n\r\n");
+        assert!(inspector.exchanges().is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = Backend::from(listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            // the request is complete when the body is received
+            while !request.ends_with(b"ping") {
+                let count = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..count]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\npong")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let inspector = Inspector::new(10);
+        let mut capture = inspector.capture(&backend);
+        capture.request(b"POST /b HTTP/1.1\r\nHost: web\r\nTransfer-Encoding: chunked\r\n\r\n");
+        capture.request(b"4\r\nping\r\n0\r\n\r\n");
+        capture.response(b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n");
+        let id = inspector.exchanges()[0].id;
+
+        let replayed = inspector.replay(id).await.unwrap();
+        assert_eq!(replayed.status, 200);
+        assert_eq!(replayed.replay_of, Some(id));
+        assert_eq!(replayed.response_body.data, b"pong");
+        assert_eq!(inspector.exchanges().len(), 2);
+        assert_eq!(
+            server.await.unwrap(),
+            "POST /b HTTP/1.1\r\nHost: web\r\nContent-Length: 4\r\nConnection: close\r\n\r\nping"
+        );
+
+        assert!(inspector.replay(100).await.is_err());
+    }
+}
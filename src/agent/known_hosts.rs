@@ -0,0 +1,139 @@
+//! Trust on first use of the gateways keys, like the ssh `known_hosts`. The
+//! fingerprint of a gateway key is stored the first time the agent connects
+//! to it, and the agent refuses to connect if the gateway presents another
+//! key later on
+use std::path::{Path, PathBuf};
+
+use secp256k1::PublicKey;
+
+use crate::{wire::fingerprint, Error, Result};
+
+/// KnownHosts is a file of `gateway fingerprint` lines
+#[derive(Debug, Clone)]
+pub struct KnownHosts {
+    path: PathBuf,
+}
+
+impl KnownHosts {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().into(),
+        }
+    }
+
+    /// the default known hosts file `~/.config/diglett/known_hosts` (or
+    /// under `$XDG_CONFIG_HOME`)
+    pub fn default_path() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+        Some(config.join("diglett").join("known_hosts"))
+    }
+
+    /// verify the key of the gateway. The key is stored if the gateway is not
+    /// known yet, or if `replace` is set and the key changed
+    pub async fn verify(&self, gateway: &str, key: &PublicKey, replace: bool) -> Result<()> {
+        let fingerprint = fingerprint(key);
+        let mut hosts = self.load().await?;
+
+        match hosts.iter_mut().find(|(host, _)| host == gateway) {
+            Some((_, known)) if *known == fingerprint => return Ok(()),
+            Some((_, known)) if replace => {
+                log::warn!(
+                    "replacing the key of gateway '{}' in {}",
+                    gateway,
+                    self.path.display()
+                );
+                *known = fingerprint;
+            }
+            Some(_) => return Err(Error::KeyChanged(gateway.into(), fingerprint)),
+            None => {
+                log::info!(
+                    "adding gateway '{}' ({}) to {}",
+                    gateway,
+                    fingerprint,
+                    self.path.display()
+                );
+                hosts.push((gateway.into(), fingerprint));
+            }
+        }
+
+        self.store(&hosts).await
+    }
+
+    /// fingerprint of the gateway key stored in the file, if the gateway is
+    /// known
+    pub async fn get(&self, gateway: &str) -> Result<Option<String>> {
+        Ok(self
+            .load()
+            .await?
+            .into_iter()
+            .find(|(host, _)| host == gateway)
+            .map(|(_, fingerprint)| fingerprint))
+    }
+
+    async fn load(&self) -> Result<Vec<(String, String)>> {
+        let data = match tokio::fs::read_to_string(&self.path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(data
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(host, fingerprint)| (host.into(), fingerprint.trim().into()))
+            .collect())
+    }
+
+    // the file is replaced at once so a crash doesn't leave it half written
+    async fn store(&self, hosts: &[(String, String)]) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        let data: String = hosts
+            .iter()
+            .map(|(host, fingerprint)| format!("{} {}\n", host, fingerprint))
+            .collect();
+
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::keypair;
+
+    #[tokio::test]
+    async fn known_hosts() {
+        let dir = std::env::temp_dir().join(format!("diglett-known-{}", std::process::id()));
+        let hosts = KnownHosts::new(dir.join("known_hosts"));
+        let (key, other) = (keypair().public_key(), keypair().public_key());
+
+        // trusted on first use
+        assert_eq!(hosts.get("gateway:20000").await.unwrap(), None);
+        hosts.verify("gateway:20000", &key, false).await.unwrap();
+        assert_eq!(
+            hosts.get("gateway:20000").await.unwrap(),
+            Some(fingerprint(&key))
+        );
+        hosts.verify("gateway:20000", &key, false).await.unwrap();
+        hosts.verify("other:20000", &other, false).await.unwrap();
+
+        let result = hosts.verify("gateway:20000", &other, false).await;
+        assert!(matches!(result, Err(Error::KeyChanged(..))));
+
+        hosts.verify("gateway:20000", &other, true).await.unwrap();
+        hosts.verify("gateway:20000", &other, false).await.unwrap();
+        hosts.verify("other:20000", &other, false).await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
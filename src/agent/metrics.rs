@@ -0,0 +1,150 @@
+//! Metrics of the agent in the prometheus text format, so agents deployed
+//! on servers are monitored like any other service
+//!  - `GET /metrics`
+use std::{fmt::Write, sync::Arc};
+
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{Counters, Stats};
+use crate::{
+    http::{read_request, respond},
+    Result,
+};
+
+/// serve the metrics of the counters on the listener
+pub async fn serve(counters: Arc<Counters>, listener: TcpListener) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::error!("failed to accept metrics connection: {}", err);
+                continue;
+            }
+        };
+
+        let counters = Arc::clone(&counters);
+        tokio::spawn(async move {
+            if let Err(err) = handle(&counters, stream).await {
+                log::debug!("failed to handle metrics request: {}", err);
+            }
+        });
+    }
+}
+
+async fn handle(counters: &Counters, mut stream: TcpStream) -> Result<()> {
+    let request = read_request(&mut stream).await?;
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let metrics = render(&counters.stats());
+            respond(
+                &mut stream,
+                "200 OK",
+                &[("Content-Type", "text/plain; version=0.0.4")],
+                metrics.as_bytes(),
+            )
+            .await
+        }
+        _ => respond(&mut stream, "404 Not Found", &[], b"not found").await,
+    }
+}
+
+/// render the stats in prometheus text format
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+    metric(
+        &mut out,
+        "diglett_agent_connected",
+        "gauge",
+        "1 while the agent is connected to the gateway and serves its names",
+        stats.connected as u64,
+    );
+    metric(
+        &mut out,
+        "diglett_agent_reconnects_total",
+        "counter",
+        "reconnections to the gateway",
+        stats.reconnects,
+    );
+    metric(
+        &mut out,
+        "diglett_agent_streams",
+        "gauge",
+        "currently open streams",
+        stats.streams as u64,
+    );
+    metric(
+        &mut out,
+        "diglett_agent_up_bytes_total",
+        "counter",
+        "bytes forwarded from the backends to the gateway",
+        stats.up,
+    );
+    metric(
+        &mut out,
+        "diglett_agent_down_bytes_total",
+        "counter",
+        "bytes forwarded from the gateway to the backends",
+        stats.down,
+    );
+    metric(
+        &mut out,
+        "diglett_agent_backend_errors_total",
+        "counter",
+        "streams rejected because the backend refused the connection",
+        stats.backend_errors,
+    );
+
+    if let Some(rtt) = stats.rtt {
+        let _ = writeln!(
+            out,
+            "# HELP diglett_agent_rtt_seconds round trip time of the tunnel"
+        );
+        let _ = writeln!(out, "# TYPE diglett_agent_rtt_seconds gauge");
+        let _ = writeln!(out, "diglett_agent_rtt_seconds {}", rtt.as_secs_f64());
+    }
+
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn metrics() {
+        let stats = Stats {
+            connected: true,
+            reconnects: 2,
+            streams: 3,
+            up: 100,
+            down: 200,
+            rtt: None,
+            backend_errors: 1,
+        };
+
+        let rendered = render(&stats);
+        assert!(rendered.contains("diglett_agent_connected 1\n"));
+        assert!(rendered.contains("diglett_agent_reconnects_total 2\n"));
+        assert!(rendered.contains("diglett_agent_streams 3\n"));
+        assert!(rendered.contains("diglett_agent_up_bytes_total 100\n"));
+        assert!(rendered.contains("diglett_agent_down_bytes_total 200\n"));
+        assert!(rendered.contains("diglett_agent_backend_errors_total 1\n"));
+        assert!(!rendered.contains("diglett_agent_rtt_seconds"));
+
+        let stats = Stats {
+            rtt: Some(Duration::from_millis(250)),
+            ..Default::default()
+        };
+        let rendered = render(&stats);
+        assert!(rendered.contains("diglett_agent_connected 0\n"));
+        assert!(rendered.contains("diglett_agent_rtt_seconds 0.25\n"));
+    }
+}
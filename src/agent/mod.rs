@@ -1,44 +1,167 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, future::Future, path::PathBuf, sync::Arc, time::Duration};
 
 use crate::{
+    shaping::{Bandwidth, Limit, Shaper},
+    window::Window,
     wire::{
-        self, Connection, Control, FrameReader, FrameStream, FrameWriter, Message, Registration,
-        Stream,
+        self, Connection, Control, FrameReader, FrameStream, FrameWriter, Message, Metadata,
+        Reason, Registration, Split, Stream, StreamMap, StreamState, Termination,
     },
-    Result,
+    Error, Result,
 };
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream, ToSocketAddrs,
-    },
-    sync::Mutex,
+    sync::{mpsc, watch, Mutex, OwnedSemaphorePermit, Semaphore},
     task::JoinHandle,
 };
 
+#[cfg(feature = "server")]
+mod aggregator;
+mod backend;
+pub mod bench;
+mod builder;
+pub mod config;
+#[cfg(feature = "keyring")]
+pub mod credentials;
+mod docker;
+pub mod doctor;
+mod health;
+pub mod inspect;
+mod known_hosts;
+pub mod metrics;
+mod notify;
+mod proxy;
+mod socks;
+mod srv;
+pub mod stats;
+#[cfg(feature = "server")]
+pub use aggregator::Aggregator;
+pub use backend::{Backend, Serve, TlsOptions};
+use backend::{BackendReader, BackendWriter, Failover, Pool};
+pub use builder::{Agent, AgentBuilder, Forwarded, Service, Tunnel};
+pub use config::Config;
+pub use health::HealthCheck;
+use inspect::Capture;
+pub use inspect::Inspector;
+pub use known_hosts::KnownHosts;
+pub use notify::Notify;
+pub use proxy::Proxy;
+pub use stats::{Counters, Stats};
+
 pub async fn login<T: Into<String>, S, F>(client: &mut Connection<S, F>, token: T) -> Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
     F: FrameReader + FrameWriter,
 {
-    // we only expose the possibility to register one name, but this can easily changed
-    // in the future to enable more. but right now we can forward one port per agent
+    login_with(client, token, Metadata::default()).await
+}
 
-    client.control(Control::Login(token.into())).await?;
+/// login with labels that describe the agent (hostname, version, environment, ...)
+/// so operators can tell which machine serves a name. Labels are dropped if the
+/// server doesn't support them
+pub async fn login_with<T: Into<String>, S, F>(
+    client: &mut Connection<S, F>,
+    token: T,
+    labels: Metadata,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    F: FrameReader + FrameWriter,
+{
+    client
+        .control(Control::Login {
+            token: token.into(),
+            labels,
+        })
+        .await?;
     client.read().await?.ok_or_err()
 }
 
-pub async fn register<N: Into<String>, S, F>(client: &mut Connection<S, F>, name: N) -> Result<()>
+/// register a name with the server. It returns the public endpoints of the registration,
+/// the address of its public listener and its url if the server routes http requests.
+pub async fn register<N: Into<String>, S, F>(
+    client: &mut Connection<S, F>,
+    name: N,
+) -> Result<Vec<String>>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
     F: FrameReader + FrameWriter,
 {
-    // we only expose the possibility to register one name, but this can easily changed
-    // in the future to enable more. but right now we can forward one port per agent
+    register_with(client, name, Metadata::default()).await
+}
 
-    register_one(client, Registration::from(0), name).await?;
-    client.control(Control::FinishRegister).await
+/// register a name with the server with metadata (for example the weight of
+/// the agent). The metadata is ignored by servers that don't support it
+pub async fn register_with<N: Into<String>, S, F>(
+    client: &mut Connection<S, F>,
+    name: N,
+    metadata: Metadata,
+) -> Result<Vec<String>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    F: FrameReader + FrameWriter,
+{
+    let mut registered = register_all(client, vec![(name.into(), metadata)]).await?;
+    Ok(registered.endpoints.pop().unwrap_or_default())
+}
+
+/// Registered is the result of the registration of names
+#[derive(Debug, Clone, Default)]
+pub struct Registered {
+    /// id of the agent connection on the server (as shown by its admin api),
+    /// servers older than wire version 6 don't report it
+    pub session: Option<u64>,
+    /// public endpoints of each registration (see [`register`])
+    pub endpoints: Vec<Vec<String>>,
+}
+
+/// register multiple names (with their metadata) over the same connection,
+/// the registration of each name is its index.
+pub async fn register_all<S, F>(
+    client: &mut Connection<S, F>,
+    names: Vec<(String, Metadata)>,
+) -> Result<Registered>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    F: FrameReader + FrameWriter,
+{
+    let count = names.len();
+    for (index, (name, metadata)) in names.into_iter().enumerate() {
+        let id = Registration::from(index as u16);
+        register_one(client, id, name).await?;
+
+        if !metadata.is_empty() {
+            if client.version() < 2 {
+                log::warn!("gateway does not support registration metadata, ignoring it");
+            } else {
+                client.control(Control::Metadata { id, metadata }).await?;
+                client.read().await?.ok_or_err()?;
+            }
+        }
+    }
+
+    client.control(Control::FinishRegister).await?;
+
+    // the server report the session and the endpoints of public registrations
+    // followed by an okay
+    let mut registered = Registered {
+        session: None,
+        endpoints: vec![Vec::default(); count],
+    };
+    loop {
+        match client.read().await? {
+            Message::Control(Control::Session(id)) => registered.session = Some(id),
+            Message::Control(Control::Endpoint { id, address }) => {
+                if let Some(endpoints) = registered.endpoints.get_mut(u32::from(&id) as usize) {
+                    endpoints.push(address);
+                }
+            }
+            message => {
+                message.ok_or_err()?;
+                return Ok(registered);
+            }
+        }
+    }
 }
 
 async fn register_one<N: Into<String>, S, F>(
@@ -61,76 +184,443 @@ where
     client.read().await?.ok_or_err()
 }
 
-type Connections = Arc<Mutex<HashMap<Stream, BackendClient>>>;
+type Connections = Arc<Mutex<StreamMap<BackendClient>>>;
+
+/// interval of the keep alive pings sent to the server to renew the
+/// registrations lease
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// the server is considered dead if nothing is received from it for that
+/// many keep alive intervals
+pub(crate) const MISSED_KEEPALIVES: u32 = 3;
+
+/// interval of the probes sent by the server (since wire version 3), the
+/// server is always heard of at least that often
+const SERVER_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// max time to close the streams and terminate the connection on shutdown
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Refresh provides fresh login tokens to long lived agents, so they can
+/// re-login before their (short lived) token expires
+#[async_trait::async_trait]
+pub trait Refresh: Send + Sync + 'static {
+    /// how often a fresh token is sent to the server
+    fn interval(&self) -> Duration;
+
+    /// get a fresh token
+    async fn token(&self) -> Result<String>;
+}
+
+/// TokenFile reads a fresh token from a file that is rotated by
+/// an external process
+pub struct TokenFile {
+    path: PathBuf,
+    interval: Duration,
+}
+
+impl TokenFile {
+    pub fn new<P: Into<PathBuf>>(path: P, interval: Duration) -> Self {
+        Self {
+            path: path.into(),
+            interval,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Refresh for TokenFile {
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    async fn token(&self) -> Result<String> {
+        let token = tokio::fs::read_to_string(&self.path).await?;
+        Ok(token.trim().into())
+    }
+}
+
+pub async fn serve<S: Split, B: Into<Backend>>(
+    server: Connection<S, FrameStream>,
+    backend: B,
+) -> Result<()> {
+    serve_with(server, backend, None).await
+}
 
-pub async fn serve<A: ToSocketAddrs>(
-    server: Connection<TcpStream, FrameStream>,
-    backend: A,
+/// serve the backend, the agent re-login with fresh tokens from refresh (if set)
+/// without disturbing the active streams
+pub async fn serve_with<S: Split, B: Into<Backend>>(
+    server: Connection<S, FrameStream>,
+    backend: B,
+    refresh: Option<Box<dyn Refresh>>,
 ) -> Result<()> {
-    let backend_connections: Connections = Arc::new(Mutex::new(HashMap::default()));
+    let backends = HashMap::from([(Registration::from(0), backend.into())]);
+    let mut options = Options::default();
+    if let Some(refresh) = refresh {
+        options = options.with_refresh(refresh);
+    }
+
+    serve_all(server, backends, options).await
+}
+
+/// Options of [`serve_all`]
+#[derive(Default)]
+pub struct Options {
+    refresh: Option<Box<dyn Refresh>>,
+    inspector: Option<Inspector>,
+    counters: Option<Arc<Counters>>,
+    rate_limit: Option<u64>,
+    health_checks: Vec<(Registration, HealthCheck)>,
+    fallbacks: HashMap<Registration, Backend>,
+    replicas: HashMap<Registration, Vec<Backend>>,
+    keepalive: Option<Duration>,
+    max_connections: HashMap<Registration, usize>,
+}
+
+impl Options {
+    /// re-login with fresh tokens from refresh without disturbing the
+    /// active streams
+    pub fn with_refresh(mut self, refresh: Box<dyn Refresh>) -> Self {
+        self.refresh = Some(refresh);
+        self
+    }
+
+    /// capture the http traffic of the streams with the inspector
+    pub fn with_inspector(mut self, inspector: Inspector) -> Self {
+        self.inspector = Some(inspector);
+        self
+    }
+
+    /// count the streams, traffic and round trip time of the tunnel in
+    /// counters, they can be shared by multiple (successive) connections
+    pub fn with_counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// limit the traffic of all the streams to rate bytes per second in
+    /// each direction
+    pub fn with_rate_limit(mut self, rate: u64) -> Self {
+        self.rate_limit = Some(rate);
+        self
+    }
+
+    /// check the backend of the registration periodically, serving stops
+    /// with [`Error::Unhealthy`] once the backend is down
+    pub fn with_health_check(mut self, id: Registration, check: HealthCheck) -> Self {
+        self.health_checks.push((id, check));
+        self
+    }
+
+    /// dial the fallback backend for new streams of the registration while
+    /// its backend is down
+    pub fn with_fallback(mut self, id: Registration, fallback: Backend) -> Self {
+        self.fallbacks.insert(id, fallback);
+        self
+    }
+
+    /// other instances of the backend of the registration, the new streams
+    /// are distributed round robin between the backend and its replicas
+    pub fn with_replicas(mut self, id: Registration, replicas: Vec<Backend>) -> Self {
+        self.replicas.insert(id, replicas);
+        self
+    }
+
+    /// interval of the keep alive pings (default to [`KEEPALIVE_INTERVAL`]).
+    /// Serving stops with [`Error::GatewayTimeout`] if nothing is received
+    /// from the server for a few intervals
+    pub fn with_keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// max simultaneous backend connections of the registration, new
+    /// streams are closed right away once it's reached
+    pub fn with_max_connections(mut self, id: Registration, max: usize) -> Self {
+        self.max_connections.insert(id, max);
+        self
+    }
+}
+
+/// serve the backend of each registration, for agents that registered
+/// multiple names with [`register_all`]
+pub async fn serve_all<S: Split>(
+    server: Connection<S, FrameStream>,
+    backends: HashMap<Registration, Backend>,
+    options: Options,
+) -> Result<()> {
+    serve_all_until(server, backends, options, std::future::pending::<()>()).await
+}
+
+/// serve like [`serve_all`] until the shutdown future resolves. On shutdown
+/// the open streams are closed and the server is told the agent terminates
+/// (within [`SHUTDOWN_TIMEOUT`]) instead of just dropping the connection
+pub async fn serve_all_until<S: Split, D: Future>(
+    server: Connection<S, FrameStream>,
+    backends: HashMap<Registration, Backend>,
+    options: Options,
+    shutdown: D,
+) -> Result<()> {
+    tokio::pin!(shutdown);
+    let backend_connections: Connections = Arc::new(Mutex::new(StreamMap::new(server.version())));
+    let counters = options.counters.unwrap_or_default();
+    let mut replicas = options.replicas;
+    let pools: HashMap<_, _> = backends
+        .into_iter()
+        .map(|(id, backend)| {
+            let mut backends = vec![backend];
+            backends.extend(replicas.remove(&id).unwrap_or_default());
+            (id, Arc::new(Pool::new(backends)))
+        })
+        .collect();
+    let slots: HashMap<_, _> = options
+        .max_connections
+        .iter()
+        .map(|(id, max)| (*id, Arc::new(Semaphore::new(*max))))
+        .collect();
+    let version = server.version();
+    let limit = options.rate_limit.map(|rate| Arc::new(Limit::new(rate)));
+    let shaper = Shaper::new(&Bandwidth::default(), limit.as_ref());
 
     let (mut server_reader, server_writer) = server.split();
 
     let server_writer = Arc::new(Mutex::new(server_writer));
+    let keepalive = options.keepalive.unwrap_or(KEEPALIVE_INTERVAL);
+    let _keepalive = KeepAlive::start(
+        Arc::clone(&server_writer),
+        keepalive,
+        version,
+        Arc::clone(&counters),
+    );
+    // gateways of version 1 don't understand relogins, the token is
+    // refreshed on the next connection
+    if options.refresh.is_some() && version < 2 {
+        log::warn!("gateway does not support relogin, the token is not refreshed");
+    }
+    let _relogin = options
+        .refresh
+        .filter(|_| version >= 2)
+        .map(|refresh| Relogin::start(Arc::clone(&server_writer), refresh));
+
+    let (unhealthy_tx, mut unhealthy) = mpsc::channel(1);
+    let _health = options
+        .health_checks
+        .into_iter()
+        .filter_map(|(id, check)| {
+            let backends = pools.get(&id)?.backends().to_vec();
+            Some(HealthMonitor::start(backends, check, unhealthy_tx.clone()))
+        })
+        .collect::<Vec<_>>();
+    drop(unhealthy_tx);
+
+    let failovers: HashMap<_, _> = options
+        .fallbacks
+        .into_iter()
+        .map(|(id, fallback)| (id, Arc::new(Failover::new(fallback))))
+        .collect();
+
+    // a server that answered our probes is dead once it misses a few of
+    // them, others are at least heard of when they probe us. Servers older
+    // than version 3 don't probe and are never considered dead
+    let mut received = tokio::time::Instant::now();
+    let mut answered = false;
+    // queues of the open streams, so payloads are queued without locking
+    // the connections. An entry is stale once its stream is closed
+    let mut queues: HashMap<Stream, (mpsc::WeakUnboundedSender<Vec<u8>>, Arc<Window>)> =
+        HashMap::new();
+    loop {
+        let silence = match answered {
+            true => keepalive,
+            false => keepalive.max(SERVER_PROBE_INTERVAL),
+        } * MISSED_KEEPALIVES;
+
+        let message = tokio::select! {
+            message = server_reader.read() => message,
+            _ = tokio::time::sleep_until(received + silence), if version >= 3 => {
+                return Err(Error::GatewayTimeout(silence));
+            }
+            Some(err) = unhealthy.recv() => return Err(err),
+            _ = &mut shutdown => {
+                let terminate = terminate(&backend_connections, &server_writer);
+                return match tokio::time::timeout(SHUTDOWN_TIMEOUT, terminate).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        log::warn!("timed out terminating the connection to the server");
+                        Ok(())
+                    }
+                };
+            }
+        };
+
+        let Ok(message) = message else {
+            break;
+        };
+        received = tokio::time::Instant::now();
 
-    while let Ok(message) = server_reader.read().await {
         match message {
             Message::Payload { id, data } => {
+                if let Some((sender, window)) = queues
+                    .get(&id)
+                    .and_then(|(sender, window)| Some((sender.upgrade()?, window)))
+                {
+                    queue(
+                        id,
+                        data,
+                        &sender,
+                        window,
+                        &server_writer,
+                        &backend_connections,
+                    )
+                    .await?;
+                    continue;
+                }
+
                 let mut connections = backend_connections.lock().await;
-                let entry = connections.get_mut(&id);
 
-                let client = match entry {
-                    Some(client) => client,
+                let (sender, window) = match connections.state(&id) {
+                    Some(StreamState::Open) => {
+                        let client = connections.get(&id).unwrap();
+                        (client.sender.clone(), Arc::clone(&client.window))
+                    }
+                    // we closed the stream, this data was sent before the
+                    // server received the close
+                    Some(StreamState::HalfClosed) => {
+                        log::trace!("dropping data of half closed stream [{}]", id);
+                        continue;
+                    }
                     None => {
-                        // open connection and insert it!
-                        let stream = match TcpStream::connect(&backend).await {
-                            Ok(stream) => stream,
-                            Err(err) => {
-                                log::error!("failed to establish connection to backend: {}", err);
-                                // tell server that connection has been rejected
-                                server_writer
-                                    .lock()
-                                    .await
-                                    .control(Control::Close { id })
-                                    .await?;
-
-                                continue;
-                            }
+                        let Some(pool) = pools.get(&id.registration()) else {
+                            log::error!("stream [{}] of an unknown registration", id);
+                            connections.reject(id);
+                            server_writer
+                                .lock()
+                                .await
+                                .control(Control::Close { id })
+                                .await?;
+
+                            continue;
                         };
 
-                        let (up, down) = stream.into_split();
+                        let slot = match slots.get(&id.registration()) {
+                            Some(slots) => match Arc::clone(slots).try_acquire_owned() {
+                                Ok(slot) => Some(slot),
+                                Err(_) => {
+                                    log::warn!(
+                                        "backend {} has too many connections, rejecting stream [{}]",
+                                        pool,
+                                        id
+                                    );
+                                    connections.reject(id);
+                                    server_writer
+                                        .lock()
+                                        .await
+                                        .control(Control::Close { id })
+                                        .await?;
+
+                                    continue;
+                                }
+                            },
+                            None => None,
+                        };
 
-                        let handler = make_upstream(
+                        // the backend is dialed by the stream task, so other
+                        // streams are not held up. The payloads are queued meanwhile
+                        let dial = dial(
+                            Arc::clone(pool),
+                            failovers.get(&id.registration()).cloned(),
+                            options.inspector.clone(),
+                        );
+                        let (paused, gate) = watch::channel(false);
+                        let (sender, receiver) = mpsc::unbounded_channel();
+                        let window = Arc::new(Window::new(version >= 6));
+                        let handler = make_stream(
                             id,
-                            up,
+                            dial,
+                            receiver,
+                            sender.downgrade(),
+                            gate,
+                            Arc::clone(&window),
+                            Arc::clone(&counters),
+                            shaper.clone(),
                             Arc::clone(&server_writer),
                             Arc::clone(&backend_connections),
                         );
 
+                        counters.opened();
                         let client = BackendClient {
-                            writer: down,
+                            sender: sender.clone(),
+                            window: Arc::clone(&window),
+                            paused,
+                            counters: Arc::clone(&counters),
                             handler,
+                            _slot: slot,
                         };
 
-                        connections.insert(id, client);
-                        connections.get_mut(&id).unwrap()
+                        let _ = connections.open(id, client);
+                        (sender, window)
                     }
                 };
+                drop(connections);
 
-                if let Err(err) = client.writer.write_all(&data).await {
-                    // drop the connection.
-                    log::error!("failed to write data to backend: {}", err);
+                queues.retain(|_, (sender, _)| sender.upgrade().is_some());
+                queues.insert(id, (sender.downgrade(), Arc::clone(&window)));
+                queue(
+                    id,
+                    data,
+                    &sender,
+                    &window,
+                    &server_writer,
+                    &backend_connections,
+                )
+                .await?;
+            }
+            Message::Control(Control::Close { id }) => {
+                let mut connections = backend_connections.lock().await;
+                connections.remote_closed(id);
+                if connections.acknowledged() {
                     server_writer
                         .lock()
                         .await
-                        .control(Control::Close { id })
+                        .control(Control::CloseAck { id })
                         .await?;
-
-                    connections.remove(&id);
                 }
             }
-            Message::Control(Control::Close { id }) => {
-                backend_connections.lock().await.remove(&id);
+            Message::Control(Control::CloseAck { id }) => {
+                backend_connections.lock().await.acked(id);
+            }
+            Message::Control(Control::Pause { id }) => {
+                if let Some(client) = backend_connections.lock().await.get(&id) {
+                    log::trace!("stream [{}] paused by server", id);
+                    client.paused.send_replace(true);
+                }
+            }
+            Message::Control(Control::Resume { id }) => {
+                if let Some(client) = backend_connections.lock().await.get(&id) {
+                    log::trace!("stream [{}] resumed by server", id);
+                    client.paused.send_replace(false);
+                }
+            }
+            Message::Control(Control::Probe(seq)) => {
+                server_writer
+                    .lock()
+                    .await
+                    .control(Control::ProbeReply(seq))
+                    .await?;
+            }
+            Message::Control(Control::ProbeReply(seq)) => {
+                answered = true;
+                counters.replied(seq);
+            }
+            Message::Terminate(termination) => {
+                return Err(Error::Terminated(termination));
+            }
+            // the only control messages that are answered after registration
+            // are relogin requests
+            Message::Control(Control::Ok) => {
+                log::debug!("relogin accepted");
+            }
+            Message::Control(Control::Error(_, err)) => {
+                log::error!("relogin rejected: {}", err);
             }
             unexpected => {
                 log::debug!("received an unexpected message: {:?}", unexpected);
@@ -141,36 +631,239 @@ pub async fn serve<A: ToSocketAddrs>(
     Ok(())
 }
 
-fn make_upstream<W, F>(
+// queue a payload for the backend, it's written by the stream task. The
+// server stops sending before the queue is long, the streams of servers that
+// can't be paused are closed once their backend can't keep up
+async fn queue<W, F>(
+    id: Stream,
+    data: Vec<u8>,
+    sender: &mpsc::UnboundedSender<Vec<u8>>,
+    window: &Window,
+    server_writer: &Mutex<Connection<W, F>>,
+    connections: &Connections,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+    F: FrameWriter,
+{
+    if window.overflowed() {
+        log::warn!("backend of stream [{}] is too slow, closing the stream", id);
+        let mut connections = connections.lock().await;
+        if connections.close(id).is_some() {
+            server_writer
+                .lock()
+                .await
+                .control(Control::Close { id })
+                .await?;
+        }
+        return Ok(());
+    }
+
+    if window.queued() {
+        window.pause(id, server_writer).await?;
+    }
+
+    if sender.send(data).is_err() {
+        log::trace!("dropping data of closed stream [{}]", id);
+    }
+
+    Ok(())
+}
+
+// close the open streams and tell the server the agent terminates
+async fn terminate<W, F>(
+    connections: &Connections,
+    server_writer: &Arc<Mutex<Connection<W, F>>>,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+    F: FrameWriter,
+{
+    let mut connections = connections.lock().await;
+    let mut writer = server_writer.lock().await;
+    let ids = connections.open_ids();
+    log::debug!("closing {} open streams", ids.len());
+    for id in ids {
+        // dropping the client stops forwarding its backend data
+        let _client = connections.close(id);
+        writer.control(Control::Close { id }).await?;
+    }
+
+    writer
+        .terminate(Termination::new(Reason::Shutdown, "agent is shutting down"))
+        .await
+}
+
+// dial a backend of the pool, or the fallback while the pool is down
+async fn dial(
+    pool: Arc<Pool>,
+    failover: Option<Arc<Failover>>,
+    inspector: Option<Inspector>,
+) -> Result<(BackendReader, BackendWriter, Option<SharedCapture>)> {
+    let connection = match &failover {
+        Some(failover) => failover.connect(&pool).await,
+        None => pool.connect().await,
+    };
+
+    let (up, down, backend) = match connection {
+        Ok(connection) => connection,
+        Err(err) => {
+            log::error!(
+                "failed to establish connection to backend {}: {}",
+                pool,
+                err
+            );
+            return Err(err);
+        }
+    };
+
+    let capture = inspector.map(|inspector| {
+        let capture = inspector.capture(backend);
+        Arc::new(std::sync::Mutex::new(capture))
+    });
+
+    Ok((up, down, capture))
+}
+
+// dial the backend of a new stream and forward the stream both ways once
+// connected, the payloads of the stream are queued until then
+#[allow(clippy::too_many_arguments)]
+fn make_stream<W, F, D>(
     id: Stream,
-    up: OwnedReadHalf,
+    dial: D,
+    receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    stream: mpsc::WeakUnboundedSender<Vec<u8>>,
+    paused: watch::Receiver<bool>,
+    window: Arc<Window>,
+    counters: Arc<Counters>,
+    shaper: Shaper,
     server_writer: Arc<Mutex<Connection<W, F>>>,
     connections: Connections,
 ) -> JoinHandle<()>
 where
     W: AsyncWrite + Unpin + Send + 'static,
     F: FrameWriter + Send + 'static,
+    D: Future<Output = Result<(BackendReader, BackendWriter, Option<SharedCapture>)>>
+        + Send
+        + 'static,
 {
     tokio::spawn(async move {
-        // this starts copy upstream (so from backend connection to server)
-        if let Err(err) = upstream(id, up, Arc::clone(&server_writer)).await {
-            log::error!("failed to forward data upstream: {}", err);
-        }
+        match dial.await {
+            Ok((up, down, capture)) => {
+                make_downstream(
+                    id,
+                    down,
+                    receiver,
+                    stream,
+                    window,
+                    capture.clone(),
+                    Arc::clone(&counters),
+                    shaper.clone(),
+                    Arc::clone(&server_writer),
+                    Arc::clone(&connections),
+                );
 
-        let _ = server_writer
-            .lock()
-            .await
-            .control(Control::Close { id })
-            .await;
+                // this starts copy upstream (so from backend connection to server)
+                if let Err(err) = upstream(
+                    id,
+                    up,
+                    paused,
+                    capture,
+                    counters,
+                    shaper,
+                    Arc::clone(&server_writer),
+                )
+                .await
+                {
+                    log::error!("failed to forward data upstream: {}", err);
+                }
+            }
+            // the stream is closed like any other, so the server is told
+            // the connection has been rejected
+            Err(_) => counters.backend_error(),
+        }
 
-        // send a close up stream
-        connections.lock().await.remove(&id);
+        // close the stream on our side. The server is told while the connections
+        // are locked so its ack can't be processed before the stream is half closed
+        let mut connections = connections.lock().await;
+        // dropping the client aborts this task, so it's kept until the end
+        let client = connections.close(id);
+        if client.is_some() {
+            let _ = server_writer
+                .lock()
+                .await
+                .control(Control::Close { id })
+                .await;
+        }
     })
 }
 
+// write the payloads of the stream to the backend. The payloads queued when
+// the stream is closed are still written, and the backend write half is
+// closed once the stream is gone
+#[allow(clippy::too_many_arguments)]
+fn make_downstream<W, F>(
+    id: Stream,
+    mut writer: BackendWriter,
+    mut receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    stream: mpsc::WeakUnboundedSender<Vec<u8>>,
+    window: Arc<Window>,
+    capture: Option<SharedCapture>,
+    counters: Arc<Counters>,
+    shaper: Shaper,
+    server_writer: Arc<Mutex<Connection<W, F>>>,
+    connections: Connections,
+) where
+    W: AsyncWrite + Unpin + Send + 'static,
+    F: FrameWriter + Send + 'static,
+{
+    tokio::spawn(async move {
+        while let Some(data) = receiver.recv().await {
+            if let Some(capture) = &capture {
+                capture.lock().unwrap().request(&data);
+            }
+
+            // the gateway stops sending once it can't write, so delaying
+            // here throttles all the streams
+            shaper.down(data.len()).await;
+
+            if let Err(err) = writer.write_all(&data).await {
+                log::error!("failed to write data to backend: {}", err);
+                // drop the connection (see make_stream) unless the stream
+                // is already gone, its id could be reused meanwhile
+                let mut connections = connections.lock().await;
+                let current = match (connections.get(&id), stream.upgrade()) {
+                    (Some(client), Some(sender)) => client.sender.same_channel(&sender),
+                    _ => false,
+                };
+                if current && connections.close(id).is_some() {
+                    let _ = server_writer
+                        .lock()
+                        .await
+                        .control(Control::Close { id })
+                        .await;
+                }
+                return;
+            }
+
+            counters.down(data.len());
+
+            if window.written() {
+                if let Err(err) = window.resume(id, &server_writer).await {
+                    log::debug!("failed to resume stream [{}]: {}", id, err);
+                }
+            }
+        }
+    });
+}
+
 async fn upstream<W, F>(
     id: Stream,
-    mut reader: OwnedReadHalf,
+    mut reader: BackendReader,
+    mut paused: watch::Receiver<bool>,
+    capture: Option<SharedCapture>,
+    counters: Arc<Counters>,
+    shaper: Shaper,
     server_writer: Arc<Mutex<Connection<W, F>>>,
 ) -> Result<()>
 where
@@ -179,26 +872,526 @@ where
 {
     let mut buf: [u8; wire::MAX_PAYLOAD_SIZE] = [0; wire::MAX_PAYLOAD_SIZE];
     loop {
+        // not reading the backend while the stream is paused pushes back on
+        // the backend through its tcp window
+        if paused.wait_for(|paused| !paused).await.is_err() {
+            // the stream is gone
+            return Ok(());
+        }
+
         let count = reader.read(&mut buf).await?;
+        if let Some(capture) = &capture {
+            let mut capture = capture.lock().unwrap();
+            match count {
+                0 => capture.finish(),
+                _ => capture.response(&buf[..count]),
+            }
+        }
+
         if count == 0 {
             return Ok(());
         }
 
+        shaper.up(count).await;
         server_writer
             .lock()
             .await
             .write(id, &mut buf[..count])
             .await?;
+        counters.up(count);
     }
 }
 
+type SharedCapture = Arc<std::sync::Mutex<Capture>>;
+
 struct BackendClient {
-    writer: OwnedWriteHalf,
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+    window: Arc<Window>,
+    // the backend is not read while the server paused the stream
+    paused: watch::Sender<bool>,
+    counters: Arc<Counters>,
     handler: JoinHandle<()>,
+    // released once the stream is closed
+    _slot: Option<OwnedSemaphorePermit>,
 }
 
 impl Drop for BackendClient {
     fn drop(&mut self) {
+        self.counters.closed();
         self.handler.abort()
     }
 }
+
+struct KeepAlive {
+    handler: JoinHandle<()>,
+}
+
+impl KeepAlive {
+    fn start<W, F>(
+        server_writer: Arc<Mutex<Connection<W, F>>>,
+        interval: Duration,
+        version: u8,
+        counters: Arc<Counters>,
+    ) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+        F: FrameWriter + Send + 'static,
+    {
+        let handler = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            let mut seq: u32 = 0;
+            loop {
+                interval.tick().await;
+                let mut writer = server_writer.lock().await;
+                // gateways of version 1 don't understand pings
+                if version >= 2 {
+                    if let Err(err) = writer.control(Control::Ping).await {
+                        log::debug!("failed to send keep alive: {}", err);
+                        return;
+                    }
+                }
+
+                // the round trip time is measured with a probe, older
+                // gateways don't understand probes
+                if version >= 3 {
+                    seq = seq.wrapping_add(1);
+                    counters.probed(seq);
+                    if let Err(err) = writer.control(Control::Probe(seq)).await {
+                        log::debug!("failed to send probe: {}", err);
+                        return;
+                    }
+                }
+            }
+        });
+
+        Self { handler }
+    }
+}
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.handler.abort()
+    }
+}
+
+struct HealthMonitor {
+    handler: JoinHandle<()>,
+}
+
+impl HealthMonitor {
+    // the backends are unhealthy once all of them are down
+    fn start(backends: Vec<Backend>, check: HealthCheck, unhealthy: mpsc::Sender<Error>) -> Self {
+        let handler = tokio::spawn(async move {
+            let interval = check.interval();
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                interval.tick().await;
+                if let Err(err) = check.check_any(&backends).await {
+                    let _ = unhealthy.send(err).await;
+                    return;
+                }
+            }
+        });
+
+        Self { handler }
+    }
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        self.handler.abort()
+    }
+}
+
+struct Relogin {
+    handler: JoinHandle<()>,
+}
+
+impl Relogin {
+    fn start<W, F>(server_writer: Arc<Mutex<Connection<W, F>>>, refresh: Box<dyn Refresh>) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+        F: FrameWriter + Send + 'static,
+    {
+        let handler = tokio::spawn(async move {
+            let interval = refresh.interval();
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                interval.tick().await;
+                let token = match refresh.token().await {
+                    Ok(token) => token,
+                    Err(err) => {
+                        log::error!("failed to refresh login token: {}", err);
+                        continue;
+                    }
+                };
+
+                let relogin = Control::Relogin(token);
+                if let Err(err) = server_writer.lock().await.control(relogin).await {
+                    log::debug!("failed to send relogin: {}", err);
+                    return;
+                }
+            }
+        });
+
+        Self { handler }
+    }
+}
+
+impl Drop for Relogin {
+    fn drop(&mut self) {
+        self.handler.abort()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        window::STREAM_QUEUE,
+        wire::{record::pair, VERSION},
+    };
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn registered() {
+        let (mut agent, mut server) = pair(VERSION);
+        let handler = tokio::spawn(async move {
+            let names = vec![
+                ("web".into(), Metadata::default()),
+                ("api".into(), Metadata::default()),
+            ];
+            register_all(&mut agent, names).await
+        });
+
+        for _ in 0..2 {
+            let message = server.read().await.unwrap();
+            assert!(matches!(
+                message,
+                Message::Control(Control::Register { .. })
+            ));
+            server.ok().await.unwrap();
+        }
+        let message = server.read().await.unwrap();
+        assert!(matches!(message, Message::Control(Control::FinishRegister)));
+
+        let api = Registration::from(1);
+        server.control(Control::Session(7)).await.unwrap();
+        for address in ["gateway.com:2222", "http://api.gateway.com"] {
+            let address = address.into();
+            server
+                .control(Control::Endpoint { id: api, address })
+                .await
+                .unwrap();
+        }
+        server.ok().await.unwrap();
+
+        let registered = handler.await.unwrap().unwrap();
+        assert_eq!(registered.session, Some(7));
+        assert_eq!(
+            registered.endpoints,
+            vec![
+                vec![],
+                vec![
+                    "gateway.com:2222".to_string(),
+                    "http://api.gateway.com".into()
+                ]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = Backend::from(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"welcome").await.unwrap();
+            let mut buf = [0; 16];
+            while stream.read(&mut buf).await.unwrap_or_default() > 0 {}
+        });
+
+        let (agent, mut server) = pair(VERSION);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let backends = HashMap::from([(Registration::from(0), backend)]);
+        let handler = tokio::spawn(serve_all_until(
+            agent,
+            backends,
+            Options::default(),
+            stopped,
+        ));
+
+        let id = Stream::new(Registration::from(0), 1);
+        server.write(id, &mut b"hello".to_vec()).await.unwrap();
+        // the stream is open once the backend data is received
+        loop {
+            if let Message::Payload { .. } = server.read().await.unwrap() {
+                break;
+            }
+        }
+
+        stop.send(()).unwrap();
+        handler.await.unwrap().unwrap();
+
+        let mut messages = vec![];
+        while let Ok(message) = server.read().await {
+            messages.push(message);
+        }
+
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                ..,
+                Message::Control(Control::Close { id: closed }),
+                Message::Terminate(Termination {
+                    reason: Reason::Shutdown,
+                    ..
+                })
+            ] if *closed == id
+        ));
+    }
+
+    #[tokio::test]
+    async fn max_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = Backend::from(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 16];
+                    while let Ok(count) = stream.read(&mut buf).await {
+                        if count == 0 || stream.write_all(&buf[..count]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let (agent, mut server) = pair(VERSION);
+        let id = Registration::from(0);
+        let backends = HashMap::from([(id, backend)]);
+        let options = Options::default().with_max_connections(id, 1);
+        tokio::spawn(serve_all(agent, backends, options));
+
+        // the second stream is rejected while the first one is open
+        let first = Stream::new(id, 1);
+        let second = Stream::new(id, 2);
+        server.write(first, &mut b"first".to_vec()).await.unwrap();
+        server.write(second, &mut b"second".to_vec()).await.unwrap();
+
+        let mut echoed = false;
+        let mut rejected = false;
+        while !(echoed && rejected) {
+            match server.read().await.unwrap() {
+                Message::Payload { id, .. } => {
+                    assert_eq!(id, first);
+                    echoed = true;
+                }
+                Message::Control(Control::Close { id }) => {
+                    assert_eq!(id, second);
+                    rejected = true;
+                }
+                _ => {}
+            }
+        }
+
+        // the slot is released once the first stream is closed
+        server.control(Control::Close { id: first }).await.unwrap();
+        loop {
+            if let Message::Control(Control::CloseAck { id }) = server.read().await.unwrap() {
+                assert_eq!(id, first);
+                break;
+            }
+        }
+        server
+            .control(Control::CloseAck { id: second })
+            .await
+            .unwrap();
+
+        let third = Stream::new(id, 3);
+        server.write(third, &mut b"third".to_vec()).await.unwrap();
+        loop {
+            match server.read().await.unwrap() {
+                Message::Payload { id, .. } => {
+                    assert_eq!(id, third);
+                    break;
+                }
+                Message::Control(Control::Close { id }) => panic!("stream [{}] rejected", id),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn backpressure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = Backend::from(listener.local_addr().unwrap());
+        let (drain, drained) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // the backend is stuck until the stream is paused
+            let _ = drained.await;
+            let mut buf = vec![0; wire::MAX_PAYLOAD_SIZE];
+            while let Ok(count) = stream.read(&mut buf).await {
+                if count == 0 {
+                    break;
+                }
+            }
+        });
+
+        let (agent, server) = pair(VERSION);
+        let id = Registration::from(0);
+        let backends = HashMap::from([(id, backend)]);
+        tokio::spawn(serve_all(agent, backends, Options::default()));
+
+        let (mut reader, mut writer) = server.split();
+        let stream = Stream::new(id, 1);
+        // more than the socket buffers can hold
+        tokio::spawn(async move {
+            let mut data = vec![0; wire::MAX_PAYLOAD_SIZE];
+            for _ in 0..STREAM_QUEUE * 4 {
+                writer.write(stream, &mut data).await.unwrap();
+            }
+        });
+
+        loop {
+            match reader.read().await.unwrap() {
+                Message::Control(Control::Pause { id }) => {
+                    assert_eq!(id, stream);
+                    break;
+                }
+                Message::Control(Control::Close { id }) => panic!("stream [{}] closed", id),
+                _ => {}
+            }
+        }
+
+        drain.send(()).unwrap();
+        loop {
+            match reader.read().await.unwrap() {
+                Message::Control(Control::Resume { id }) => {
+                    assert_eq!(id, stream);
+                    break;
+                }
+                Message::Control(Control::Pause { .. }) => panic!("stream paused twice"),
+                _ => {}
+            }
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn slow_backend() {
+        // the tls handshake of the backend never completes
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let slow = Registration::from(0);
+        let echo = Registration::from(1);
+        let backends = HashMap::from([
+            (slow, format!("https://{}", address).parse().unwrap()),
+            (
+                echo,
+                Backend::local(|stream| {
+                    tokio::spawn(async move {
+                        let (mut reader, mut writer) = tokio::io::split(stream);
+                        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                    });
+                }),
+            ),
+        ]);
+        let (agent, mut server) = pair(VERSION);
+        tokio::spawn(serve_all(agent, backends, Options::default()));
+
+        // the stream of the slow backend doesn't hold up the others
+        let stream = Stream::new(slow, 1);
+        server.write(stream, &mut b"stuck".to_vec()).await.unwrap();
+        let stream = Stream::new(echo, 1);
+        server.write(stream, &mut b"hello".to_vec()).await.unwrap();
+
+        let echoed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Message::Payload { id, data } = server.read().await.unwrap() {
+                    break (id, data);
+                }
+            }
+        })
+        .await
+        .expect("stream was held up by a slow backend");
+        assert_eq!(echoed, (stream, b"hello".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn overflow() {
+        // the backend never reads its stream
+        let backend = Backend::local(|stream| {
+            tokio::spawn(async move {
+                let _stream = stream;
+                std::future::pending::<()>().await;
+            });
+        });
+
+        // servers older than version 6 can't be paused
+        let (agent, server) = pair(5);
+        let id = Registration::from(0);
+        let backends = HashMap::from([(id, backend)]);
+        tokio::spawn(serve_all(agent, backends, Options::default()));
+
+        let (mut reader, mut writer) = server.split();
+        let stream = Stream::new(id, 1);
+        tokio::spawn(async move {
+            let mut data = vec![0; wire::MAX_PAYLOAD_SIZE];
+            for _ in 0..STREAM_QUEUE * 4 {
+                writer.write(stream, &mut data).await.unwrap();
+            }
+        });
+
+        // the stream is closed instead of holding up the others
+        loop {
+            match reader.read().await.unwrap() {
+                Message::Control(Control::Close { id }) => {
+                    assert_eq!(id, stream);
+                    break;
+                }
+                Message::Control(Control::Pause { .. }) => panic!("server can't be paused"),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dead_server() {
+        let (agent, mut server) = pair(VERSION);
+        let options = Options::default().with_keepalive(Duration::from_secs(1));
+        let mut handler = tokio::spawn(serve_all(agent, HashMap::default(), options));
+
+        // the server answers the first probe only
+        let mut answered = false;
+        let result = loop {
+            tokio::select! {
+                message = server.read() => {
+                    if let Ok(Message::Control(Control::Probe(seq))) = message {
+                        if !answered {
+                            server.control(Control::ProbeReply(seq)).await.unwrap();
+                            answered = true;
+                        }
+                    }
+                }
+                result = async { (&mut handler).await } => break result,
+            }
+        };
+
+        assert!(answered);
+        assert!(matches!(
+            result.unwrap(),
+            Err(Error::GatewayTimeout(timeout)) if timeout == Duration::from_secs(3)
+        ));
+    }
+}
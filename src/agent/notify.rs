@@ -0,0 +1,187 @@
+//! systemd notifications (`Type=notify` services). The agent tells systemd
+//! once its names are registered, keeps the status line of the unit up to
+//! date and pings the watchdog
+use std::{
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::task::JoinHandle;
+
+use super::Counters;
+use crate::Result;
+
+/// interval of the status updates if the watchdog is not enabled
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Notify sends the state of the agent to the systemd notification socket
+#[derive(Debug, Clone)]
+pub struct Notify {
+    socket: String,
+    watchdog: Option<Duration>,
+    // number of served names, 0 while the agent is not connected
+    names: Arc<AtomicUsize>,
+}
+
+impl Notify {
+    /// notify over the socket, a name starting with `@` is an abstract socket
+    pub fn new<S: Into<String>>(socket: S) -> Self {
+        Self {
+            socket: socket.into(),
+            watchdog: None,
+            names: Arc::default(),
+        }
+    }
+
+    /// ping the watchdog that often
+    pub fn with_watchdog(mut self, interval: Duration) -> Self {
+        self.watchdog = Some(interval);
+        self
+    }
+
+    /// notify systemd if the agent runs as a `Type=notify` service
+    /// (`NOTIFY_SOCKET`). The watchdog is pinged at half the `WATCHDOG_USEC`
+    /// timeout
+    pub fn from_env() -> Option<Self> {
+        let socket = std::env::var("NOTIFY_SOCKET").ok()?;
+        let mut notify = Self::new(socket);
+
+        let pid = std::env::var("WATCHDOG_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let timeout = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok());
+
+        if let Some(timeout) = timeout {
+            if pid.is_none() || pid == Some(std::process::id()) {
+                notify = notify.with_watchdog(Duration::from_micros(timeout) / 2);
+            }
+        }
+
+        Some(notify)
+    }
+
+    /// the names are registered with the gateway
+    pub(crate) fn ready(&self, names: usize, counters: &Counters) {
+        self.names.store(names, Ordering::Relaxed);
+        self.notify(&format!("READY=1\nSTATUS={}", self.status(counters)));
+    }
+
+    /// the agent lost its connection to the gateway
+    pub(crate) fn disconnected(&self) {
+        self.names.store(0, Ordering::Relaxed);
+        self.notify("STATUS=connecting to gateway");
+    }
+
+    pub(crate) fn stopping(&self) {
+        self.notify("STOPPING=1\nSTATUS=shutting down");
+    }
+
+    /// start updating the status (and pinging the watchdog) periodically
+    pub(crate) fn start(&self, counters: Arc<Counters>) -> Notifier {
+        let notify = self.clone();
+        let handler = tokio::spawn(async move {
+            let interval = notify.watchdog.unwrap_or(STATUS_INTERVAL);
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let mut state = format!("STATUS={}", notify.status(&counters));
+                if notify.watchdog.is_some() {
+                    state.push_str("\nWATCHDOG=1");
+                }
+                notify.notify(&state);
+            }
+        });
+
+        Notifier { handler }
+    }
+
+    fn status(&self, counters: &Counters) -> String {
+        match self.names.load(Ordering::Relaxed) {
+            0 => "connecting to gateway".into(),
+            names => format!(
+                "serving {} names, {} open streams",
+                names,
+                counters.stats().streams
+            ),
+        }
+    }
+
+    // notifications are best effort
+    fn notify(&self, state: &str) {
+        if let Err(err) = self.send(state) {
+            log::debug!("failed to notify systemd: {}", err);
+        }
+    }
+
+    fn send(&self, state: &str) -> Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        match self.socket.strip_prefix('@') {
+            Some(name) => {
+                let address = SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &address)?;
+            }
+            None => {
+                socket.send_to(state.as_bytes(), &self.socket)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Notifier updates the status of the unit until it's dropped
+pub(crate) struct Notifier {
+    handler: JoinHandle<()>,
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.handler.abort()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn notify() {
+        let path = std::env::temp_dir().join(format!("diglett-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = tokio::net::UnixDatagram::bind(&path).unwrap();
+
+        let counters = Arc::new(Counters::default());
+        let notify = Notify::new(path.to_str().unwrap()).with_watchdog(Duration::from_secs(10));
+
+        let mut buf = [0; 256];
+        notify.ready(2, &counters);
+        let len = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(
+            &buf[..len],
+            b"READY=1\nSTATUS=serving 2 names, 0 open streams"
+        );
+
+        counters.opened();
+        let _notifier = notify.start(Arc::clone(&counters));
+        let len = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(
+            &buf[..len],
+            b"STATUS=serving 2 names, 1 open streams\nWATCHDOG=1"
+        );
+
+        notify.disconnected();
+        let len = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"STATUS=connecting to gateway");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
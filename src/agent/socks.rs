@@ -0,0 +1,378 @@
+//! socks5 backends, `socks5://` serves every stream with a socks5 server
+//! (rfc 1928) running in the agent, so the clients of the name reach any host
+//! of the agent network. Only the `CONNECT` command is supported. With
+//! `socks5://user:password@` the clients must authenticate with the
+//! username/password method (rfc 1929)
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    net::TcpStream,
+};
+
+use crate::{Error, Result};
+
+const VERSION: u8 = 5;
+// version of the username/password sub negotiation
+const AUTH_VERSION: u8 = 1;
+
+const NO_AUTHENTICATION: u8 = 0;
+const PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const CONNECT: u8 = 1;
+
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+// reply codes
+const SUCCEEDED: u8 = 0;
+const FAILURE: u8 = 1;
+const NETWORK_UNREACHABLE: u8 = 3;
+const HOST_UNREACHABLE: u8 = 4;
+const CONNECTION_REFUSED: u8 = 5;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// size of the buffer between the stream and the socks5 server
+const BUFFER: usize = 64 * 1024;
+
+/// credentials the clients of a socks5 backend must authenticate with
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// parse the credentials of `socks5://[user:password@]`
+    pub(crate) fn parse(value: &str) -> Result<Option<Self>> {
+        let value = value.trim_end_matches('/');
+        if value.is_empty() {
+            return Ok(None);
+        }
+
+        let credentials = value
+            .strip_suffix('@')
+            .and_then(|credentials| credentials.split_once(':'))
+            .filter(|(user, password)| {
+                !user.is_empty()
+                    && !password.is_empty()
+                    && user.len() <= 255
+                    && password.len() <= 255
+            });
+
+        match credentials {
+            Some((user, password)) => Ok(Some(Self {
+                user: user.into(),
+                password: password.into(),
+            })),
+            None => Err(Error::Config(format!(
+                "socks5 backend must be socks5:// or socks5://<user>:<password>@, got 'socks5://{}'",
+                value
+            ))),
+        }
+    }
+}
+
+// the password is never shown
+impl Display for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:***@", self.user)
+    }
+}
+
+/// start a socks5 server for a new stream, it serves the returned stream
+/// until it's closed
+pub(crate) fn connect(
+    credentials: Option<Credentials>,
+) -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>) {
+    let (stream, relay) = tokio::io::duplex(BUFFER);
+    tokio::spawn(async move {
+        if let Err(err) = serve(relay, credentials.as_ref()).await {
+            log::debug!("socks5 stream ended: {}", err);
+        }
+    });
+
+    tokio::io::split(stream)
+}
+
+async fn serve(mut stream: DuplexStream, credentials: Option<&Credentials>) -> Result<()> {
+    authenticate(&mut stream, credentials).await?;
+
+    let target = match request(&mut stream).await? {
+        Ok(target) => target,
+        Err(code) => {
+            reply(&mut stream, code, None).await?;
+            return Ok(());
+        }
+    };
+
+    let mut upstream = match TcpStream::connect(&target).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            reply(&mut stream, failure(&err), None).await?;
+            return Err(err.into());
+        }
+    };
+
+    reply(&mut stream, SUCCEEDED, upstream.local_addr().ok()).await?;
+    log::debug!("socks5 stream connected to {}", target);
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+
+    Ok(())
+}
+
+// negotiate the authentication method, and check the credentials of the
+// client if the backend has some
+async fn authenticate(stream: &mut DuplexStream, credentials: Option<&Credentials>) -> Result<()> {
+    if stream.read_u8().await? != VERSION {
+        return Err(Error::Config("not a socks5 client".into()));
+    }
+
+    let mut methods = vec![0; stream.read_u8().await? as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = match credentials {
+        Some(_) => PASSWORD,
+        None => NO_AUTHENTICATION,
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(Error::Config(
+            "socks5 client has no acceptable method".into(),
+        ));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    let Some(credentials) = credentials else {
+        return Ok(());
+    };
+
+    if stream.read_u8().await? != AUTH_VERSION {
+        return Err(Error::Config("invalid socks5 authentication".into()));
+    }
+    let user = read_string(stream).await?;
+    let password = read_string(stream).await?;
+
+    if user != credentials.user || password != credentials.password {
+        stream.write_all(&[AUTH_VERSION, FAILURE]).await?;
+        return Err(Error::Config(format!(
+            "socks5 authentication of '{}' failed",
+            user
+        )));
+    }
+
+    stream.write_all(&[AUTH_VERSION, SUCCEEDED]).await?;
+    Ok(())
+}
+
+// read the request of the client, it's the address to connect to or the
+// code to refuse it with
+async fn request(stream: &mut DuplexStream) -> Result<std::result::Result<String, u8>> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, command, _, kind] = header;
+    if version != VERSION {
+        return Err(Error::Config("invalid socks5 request".into()));
+    }
+
+    let host = match kind {
+        IPV4 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        IPV6 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        DOMAIN => read_string(stream).await?,
+        _ => return Ok(Err(ADDRESS_NOT_SUPPORTED)),
+    };
+    let port = stream.read_u16().await?;
+
+    if command != CONNECT {
+        return Ok(Err(COMMAND_NOT_SUPPORTED));
+    }
+
+    Ok(Ok(format!("{}:{}", host, port)))
+}
+
+// reply code of a failed connection
+fn failure(err: &std::io::Error) -> u8 {
+    use std::io::ErrorKind;
+
+    match err.kind() {
+        ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+        ErrorKind::NetworkUnreachable => NETWORK_UNREACHABLE,
+        ErrorKind::HostUnreachable | ErrorKind::TimedOut | ErrorKind::NotFound => HOST_UNREACHABLE,
+        _ => FAILURE,
+    }
+}
+
+async fn reply(stream: &mut DuplexStream, code: u8, bound: Option<SocketAddr>) -> Result<()> {
+    let mut reply = vec![VERSION, code, 0];
+    match bound.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))) {
+        SocketAddr::V4(address) => {
+            reply.push(IPV4);
+            reply.extend_from_slice(&address.ip().octets());
+            reply.extend_from_slice(&address.port().to_be_bytes());
+        }
+        SocketAddr::V6(address) => {
+            reply.push(IPV6);
+            reply.extend_from_slice(&address.ip().octets());
+            reply.extend_from_slice(&address.port().to_be_bytes());
+        }
+    }
+
+    stream.write_all(&reply).await?;
+    Ok(())
+}
+
+// a string prefixed with its 1 byte length
+async fn read_string(stream: &mut DuplexStream) -> Result<String> {
+    let mut value = vec![0; stream.read_u8().await? as usize];
+    stream.read_exact(&mut value).await?;
+    String::from_utf8(value).map_err(|_| Error::Config("invalid socks5 string".into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::TcpListener,
+    };
+
+    async fn echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.into_split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+
+        address
+    }
+
+    // send a connect request of the loopback port, and return the reply code
+    async fn connect_to<R, W>(read: &mut R, write: &mut W, port: u16) -> u8
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut request = vec![VERSION, CONNECT, 0, IPV4, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        write.write_all(&request).await.unwrap();
+
+        let mut reply = [0; 10];
+        read.read_exact(&mut reply).await.unwrap();
+        reply[1]
+    }
+
+    #[test]
+    fn parse() {
+        assert!(Credentials::parse("").unwrap().is_none());
+        assert!(Credentials::parse("/").unwrap().is_none());
+
+        let credentials = Credentials::parse("user:secret@").unwrap().unwrap();
+        assert_eq!(credentials.user, "user");
+        assert_eq!(credentials.password, "secret");
+        assert_eq!(credentials.to_string(), "user:***@");
+
+        assert!(Credentials::parse("user@").is_err());
+        assert!(Credentials::parse(":secret@").is_err());
+        assert!(Credentials::parse("localhost:1080").is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy() {
+        let address = echo().await;
+        let (mut read, mut write) = connect(None);
+
+        write
+            .write_all(&[VERSION, 1, NO_AUTHENTICATION])
+            .await
+            .unwrap();
+        let mut method = [0; 2];
+        read.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [VERSION, NO_AUTHENTICATION]);
+
+        assert_eq!(
+            connect_to(&mut read, &mut write, address.port()).await,
+            SUCCEEDED
+        );
+
+        write.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        read.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // a closed port is refused
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let (mut read, mut write) = connect(None);
+        write
+            .write_all(&[VERSION, 1, NO_AUTHENTICATION])
+            .await
+            .unwrap();
+        read.read_exact(&mut method).await.unwrap();
+        assert_eq!(
+            connect_to(&mut read, &mut write, port).await,
+            CONNECTION_REFUSED
+        );
+    }
+
+    #[tokio::test]
+    async fn password() {
+        let address = echo().await;
+        let credentials = Credentials::parse("user:secret@").unwrap();
+
+        // clients without the password method are refused
+        let (mut read, mut write) = connect(credentials.clone());
+        write
+            .write_all(&[VERSION, 1, NO_AUTHENTICATION])
+            .await
+            .unwrap();
+        let mut method = [0; 2];
+        read.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [VERSION, NO_ACCEPTABLE_METHODS]);
+
+        // and so are wrong passwords
+        let (mut read, mut write) = connect(credentials.clone());
+        write
+            .write_all(&[VERSION, 2, NO_AUTHENTICATION, PASSWORD])
+            .await
+            .unwrap();
+        read.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [VERSION, PASSWORD]);
+        write.write_all(b"\x01\x04user\x05wrong").await.unwrap();
+        let mut status = [0; 2];
+        read.read_exact(&mut status).await.unwrap();
+        assert_eq!(status, [AUTH_VERSION, FAILURE]);
+
+        let (mut read, mut write) = connect(credentials);
+        write.write_all(&[VERSION, 1, PASSWORD]).await.unwrap();
+        read.read_exact(&mut method).await.unwrap();
+        write.write_all(b"\x01\x04user\x06secret").await.unwrap();
+        read.read_exact(&mut status).await.unwrap();
+        assert_eq!(status, [AUTH_VERSION, SUCCEEDED]);
+
+        assert_eq!(
+            connect_to(&mut read, &mut write, address.port()).await,
+            SUCCEEDED
+        );
+    }
+}
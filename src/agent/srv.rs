@@ -0,0 +1,329 @@
+//! dns discovery of the gateways, `srv:_diglett._tcp.example.com` is resolved
+//! to the targets of its SRV records each time the agent connects, so the
+//! operators steer the agents by editing the dns records only
+use std::{net::SocketAddr, time::Duration};
+
+use secp256k1::rand::{self, Rng};
+use tokio::net::UdpSocket;
+
+use crate::{dns::encode, Error, Result};
+
+/// prefix of the gateways that are discovered over dns
+pub(crate) const PREFIX: &str = "srv:";
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const HEADER_LEN: usize = 12;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// how long the agent waits for the answer of a nameserver
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+/// the gateways (host:port) of the SRV records of the name, in the order
+/// the agent tries them: by priority, and randomly by weight within the
+/// same priority
+pub(crate) async fn resolve(name: &str) -> Result<Vec<String>> {
+    let conf = tokio::fs::read_to_string(RESOLV_CONF)
+        .await
+        .unwrap_or_default();
+
+    let mut failure = None;
+    for server in nameservers(&conf) {
+        match query(server, name).await {
+            Ok(records) => return Ok(order(records, &mut rand::thread_rng())),
+            Err(err) => {
+                log::debug!("failed to resolve '{}' with {}: {}", name, server, err);
+                failure = Some(err);
+            }
+        }
+    }
+
+    Err(failure.unwrap_or_else(|| Error::Dns("no nameserver is configured".into())))
+}
+
+// the nameservers of resolv.conf, the local one if there are none
+fn nameservers(conf: &str) -> Vec<SocketAddr> {
+    let servers: Vec<_> = conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect();
+
+    match servers.is_empty() {
+        true => vec![SocketAddr::from(([127, 0, 0, 1], 53))],
+        false => servers,
+    }
+}
+
+async fn query(server: SocketAddr, name: &str) -> Result<Vec<Record>> {
+    let local = match server {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+
+    let id: u16 = rand::thread_rng().gen();
+    // a standard query with recursion desired
+    let mut packet = id.to_be_bytes().to_vec();
+    packet.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    packet.extend(encode(name));
+    packet.extend(TYPE_SRV.to_be_bytes());
+    packet.extend(CLASS_IN.to_be_bytes());
+    socket.send(&packet).await?;
+
+    let mut buf = [0; 4096];
+    let response = tokio::time::timeout(QUERY_TIMEOUT, async {
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            // ignore the responses of other queries
+            if buf[..n].starts_with(&id.to_be_bytes()) {
+                return Ok::<_, Error>(&buf[..n]);
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::Dns(format!("{} did not respond", server)))??;
+
+    let records = parse(response)
+        .ok_or_else(|| Error::Dns(format!("invalid response from {}", server)))??;
+    if records.is_empty() {
+        return Err(Error::Dns(format!("'{}' has no SRV records", name)));
+    }
+
+    Ok(records)
+}
+
+// parse the SRV records of a response, None if the response is malformed
+fn parse(packet: &[u8]) -> Option<Result<Vec<Record>>> {
+    let header = packet.get(..HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    if flags & 0x8000 == 0 {
+        return None;
+    }
+    if flags & 0x0200 != 0 {
+        return Some(Err(Error::Dns("response is truncated".into())));
+    }
+    match flags & 0xf {
+        0 => {}
+        3 => return Some(Ok(Vec::new())),
+        rcode => {
+            return Some(Err(Error::Dns(format!(
+                "query failed with rcode {}",
+                rcode
+            ))))
+        }
+    }
+
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        let (_, next) = read_name(packet, offset)?;
+        offset = next + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let (_, next) = read_name(packet, offset)?;
+        let fields = packet.get(next..next + 10)?;
+        let kind = u16::from_be_bytes([fields[0], fields[1]]);
+        let len = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+        let data = next + 10;
+        offset = data + len;
+
+        // answers can include the CNAME chain of the name
+        if kind != TYPE_SRV {
+            continue;
+        }
+
+        let fields = packet.get(data..data + 6)?;
+        let (target, _) = read_name(packet, data + 6)?;
+        // a target of "." means the service is not available at this name
+        if target.is_empty() {
+            continue;
+        }
+
+        records.push(Record {
+            priority: u16::from_be_bytes([fields[0], fields[1]]),
+            weight: u16::from_be_bytes([fields[2], fields[3]]),
+            port: u16::from_be_bytes([fields[4], fields[5]]),
+            target,
+        });
+    }
+
+    Some(Ok(records))
+}
+
+// read the (possibly compressed) name at offset, returns the name and the
+// offset that follows it
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // bound the pointers to reject loops
+    for _ in 0..128 {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => return Some((labels.join("."), end.unwrap_or(offset + 1))),
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = u16::from_be_bytes([packet[offset], *packet.get(offset + 1)?]);
+                end.get_or_insert(offset + 2);
+                offset = (pointer & 0x3fff) as usize;
+            }
+            len if len <= 63 => {
+                let label = packet.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).to_lowercase());
+                offset += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+// order the records as in rfc 2782: by priority, then by a random
+// selection weighted by the weights of the records of the same priority.
+// Records with a zero weight come last, in a random order
+fn order<R: Rng>(mut records: Vec<Record>, rng: &mut R) -> Vec<String> {
+    records.sort_by_key(|record| record.priority);
+
+    let mut ordered = Vec::with_capacity(records.len());
+    for group in records.chunk_by(|a, b| a.priority == b.priority) {
+        let mut group = group.to_vec();
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|record| record.weight as u32).sum();
+            let index = match total {
+                0 => rng.gen_range(0..group.len()),
+                total => {
+                    let pick = rng.gen_range(1..=total);
+                    let mut sum = 0;
+                    group
+                        .iter()
+                        .position(|record| {
+                            sum += record.weight as u32;
+                            sum >= pick
+                        })
+                        .unwrap_or(0)
+                }
+            };
+
+            let record = group.remove(index);
+            ordered.push(format!("{}:{}", record.target, record.port));
+        }
+    }
+
+    ordered
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // a response with the SRV records of the question in the query
+    fn response(query: &[u8], records: &[(u16, u16, u16, &str)]) -> Vec<u8> {
+        let mut packet = query.to_vec();
+        packet[2] = 0x81;
+        packet[3] = 0x80;
+        packet[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        for (priority, weight, port, target) in records {
+            let target = encode(target);
+            // compression pointer to the question name
+            packet.extend([0xc0, HEADER_LEN as u8]);
+            packet.extend(TYPE_SRV.to_be_bytes());
+            packet.extend(CLASS_IN.to_be_bytes());
+            packet.extend(60u32.to_be_bytes());
+            packet.extend(((target.len() + 6) as u16).to_be_bytes());
+            for value in [priority, weight, port] {
+                packet.extend(value.to_be_bytes());
+            }
+            packet.extend(target);
+        }
+        packet
+    }
+
+    #[tokio::test]
+    async fn resolve() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (n, peer) = server.recv_from(&mut buf).await.unwrap();
+            assert!(buf[..n].ends_with(&[0, 33, 0, 1]));
+
+            let response = response(
+                &buf[..n],
+                &[
+                    (20, 0, 20000, "backup.example.com"),
+                    (10, 5, 20000, "gw1.example.com"),
+                    (10, 0, 20001, "."),
+                ],
+            );
+            server.send_to(&response, peer).await.unwrap();
+        });
+
+        let records = query(address, "_diglett._tcp.example.com").await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[1],
+            Record {
+                priority: 10,
+                weight: 5,
+                port: 20000,
+                target: "gw1.example.com".into()
+            }
+        );
+        assert_eq!(
+            order(records, &mut rand::thread_rng()),
+            ["gw1.example.com:20000", "backup.example.com:20000"]
+        );
+    }
+
+    #[test]
+    fn weights() {
+        let record = |weight, target: &str| Record {
+            priority: 10,
+            weight,
+            port: 20000,
+            target: target.into(),
+        };
+
+        let mut first = 0;
+        for _ in 0..1000 {
+            let ordered = order(
+                vec![record(1, "light"), record(9, "heavy")],
+                &mut rand::thread_rng(),
+            );
+            assert_eq!(ordered.len(), 2);
+            if ordered[0] == "heavy:20000" {
+                first += 1;
+            }
+        }
+
+        // heavy is first about 90% of the time
+        assert!((850..=950).contains(&first), "{}", first);
+    }
+
+    #[test]
+    fn resolv_conf() {
+        let conf = "# comment\nnameserver 10.0.0.2\nsearch example.com\nnameserver ::1\n";
+        assert_eq!(
+            nameservers(conf),
+            [
+                "10.0.0.2:53".parse::<SocketAddr>().unwrap(),
+                "[::1]:53".parse().unwrap()
+            ]
+        );
+        assert_eq!(nameservers("").len(), 1);
+    }
+}
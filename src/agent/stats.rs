@@ -0,0 +1,143 @@
+//! Counters of the traffic through the agent, so users can see whether
+//! traffic is flowing and how healthy the tunnel is
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Statistics of the agent. Bytes counters are accumulated since the
+/// counters were created (across reconnects if the counters are reused)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// the agent is connected to the gateway and serves its names
+    pub connected: bool,
+    /// number of reconnections to the gateway
+    pub reconnects: u64,
+    /// number of currently open streams
+    pub streams: usize,
+    /// bytes forwarded up, from the backends to the gateway
+    pub up: u64,
+    /// bytes forwarded down, from the gateway to the backends
+    pub down: u64,
+    /// last measured round trip time of the tunnel, unknown if the gateway
+    /// doesn't answer probes
+    pub rtt: Option<Duration>,
+    /// number of streams rejected because the backend refused the connection
+    pub backend_errors: u64,
+}
+
+/// Counters are updated by [`super::serve_all`]
+#[derive(Debug, Default)]
+pub struct Counters {
+    connected: AtomicBool,
+    reconnects: AtomicU64,
+    streams: AtomicUsize,
+    up: AtomicU64,
+    down: AtomicU64,
+    // rtt in micro seconds, 0 if unknown
+    rtt: AtomicU64,
+    probe: Mutex<Option<(u32, Instant)>>,
+    backend_errors: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn opened(&self) {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn closed(&self) {
+        self.streams.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn up(&self, n: usize) {
+        self.up.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn down(&self, n: usize) {
+        self.down.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn backend_error(&self) {
+        self.backend_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a probe with that sequence number is sent to the gateway
+    pub(crate) fn probed(&self, seq: u32) {
+        *self.probe.lock().unwrap() = Some((seq, Instant::now()));
+    }
+
+    /// the gateway answered the probe with that sequence number
+    pub(crate) fn replied(&self, seq: u32) {
+        let mut probe = self.probe.lock().unwrap();
+        if let Some((sent, at)) = *probe {
+            if sent == seq {
+                let rtt = at.elapsed().as_micros().max(1) as u64;
+                self.rtt.store(rtt, Ordering::Relaxed);
+                *probe = None;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            connected: self.connected.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            streams: self.streams.load(Ordering::Relaxed),
+            up: self.up.load(Ordering::Relaxed),
+            down: self.down.load(Ordering::Relaxed),
+            rtt: match self.rtt.load(Ordering::Relaxed) {
+                0 => None,
+                rtt => Some(Duration::from_micros(rtt)),
+            },
+            backend_errors: self.backend_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let counters = Counters::default();
+        counters.opened();
+        counters.opened();
+        counters.closed();
+        counters.up(10);
+        counters.down(5);
+        counters.down(5);
+        counters.set_connected(true);
+        counters.backend_error();
+
+        // a reply to another probe is ignored
+        counters.probed(2);
+        counters.replied(1);
+        assert_eq!(
+            counters.stats(),
+            Stats {
+                connected: true,
+                reconnects: 0,
+                streams: 1,
+                up: 10,
+                down: 10,
+                rtt: None,
+                backend_errors: 1,
+            }
+        );
+
+        counters.replied(2);
+        assert!(counters.stats().rtt.is_some());
+    }
+}
@@ -1,48 +1,480 @@
-use clap::{ArgAction, Parser};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+#[cfg(feature = "keyring")]
+use diglett::agent::credentials;
+#[cfg(unix)]
+use diglett::daemon::{daemonize, Pidfile};
 use diglett::{
-    agent,
-    wire::{keypair, Client},
-    Result,
+    agent::{
+        bench::{self, Bench},
+        config::{self, Aggregate, Forward, Health, Reconnect, Tls, Token},
+        doctor, inspect, metrics, Agent, Backend, Config, Counters, HealthCheck, Inspector,
+        KnownHosts, Notify, Proxy, Tunnel, SHUTDOWN_TIMEOUT,
+    },
+    logs::{self, Format, LogFile, Rotation},
+    wire::{fingerprint, keypair, keypair_to_file, Client, VERSION},
+    Error, Result,
 };
-use tokio::net::TcpStream;
+use log::LevelFilter;
+use secp256k1::PublicKey;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    process::Child,
+    sync::watch,
+};
+
+#[cfg(windows)]
+mod service;
+
+/// environment variable of the authentication token
+const TOKEN_ENV: &str = "DIGLETT_TOKEN";
 
 /// diglett gateway agent
-#[derive(Parser, Debug)]
+// not Debug, so the token doesn't leak in the logs
+#[derive(Parser)]
 #[command(author, version = env!("GIT_VERSION"), about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    gateway: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// run the command (with `sh -c`) as the backend, for example a dev
+    /// server. The names are registered once the backends accept
+    /// connections, and the agent and the command terminate together
+    #[arg(long)]
+    exec: Option<String>,
+
+    /// read the agent configuration from that file instead of the command
+    /// line. The gateway options (or their environment variables) override
+    /// the gateway of the file
+    #[arg(
+        short,
+        long,
+        env = "DIGLETT_CONFIG",
+        conflicts_with_all = ["known_hosts", "known_hosts_file", "name", "forwards", "aggregate", "aggregate_names", "token", "token_file", "token_stdin", "tls_ca", "weight", "labels", "backend_ca", "backend_sni", "backend_insecure", "inspect", "metrics", "log_http", "stats_interval", "rate_limit", "keepalive", "proxy", "crypto_pipeline", "wire_version", "max_connections", "health_check", "health_status", "health_interval", "backend_fallback"]
+    )]
+    config: Option<PathBuf>,
+
+    /// address (host:port) of the gateway, can be repeated for a fleet of
+    /// gateways. The agent connects (and reconnects) to the first gateway
+    /// that accepts the connection, in order. `srv:<name>` discovers the
+    /// gateways from the SRV records of the name on each connection, and
+    /// `exec:<command>` carries the tunnel over the stdin and stdout of the
+    /// command (like `exec:ssh gateway diglett-server --stdio`). Multiple
+    /// gateways can also be separated by commas
+    #[arg(
+        short,
+        long,
+        env = "DIGLETT_GATEWAY",
+        value_delimiter = ',',
+        required_unless_present = "config"
+    )]
+    gateway: Vec<String>,
+
+    /// only accept a gateway with that public key (hex)
+    #[arg(long = "gateway-key", env = "DIGLETT_GATEWAY_KEY")]
+    gateway_key: Option<PublicKey>,
+
+    /// trust the gateway key on first use (stored in
+    /// ~/.config/diglett/known_hosts), and refuse to connect if it changes
+    #[arg(long = "known-hosts")]
+    known_hosts: bool,
+
+    /// known hosts file to use instead of the default one
+    #[arg(long = "known-hosts-file")]
+    known_hosts_file: Option<PathBuf>,
+
+    /// accept a changed gateway key and replace it in the known hosts
+    #[arg(long = "replace-known-host")]
+    replace_known_host: bool,
+
+    /// name to register with the gateway, as `name:port` to expose the
+    /// backend on a port of the name
+    #[arg(
+        short,
+        long,
+        env = "DIGLETT_NAME",
+        requires = "backend",
+        required_unless_present_any = ["forwards", "aggregate", "config"],
+        value_parser = parse_name
+    )]
+    name: Option<(String, Option<u16>)>,
+
+    /// forward a name to a backend as `name=address` (or `name:port=address`
+    /// so a name exposes one backend per port), can be repeated to expose
+    /// multiple local services over the same connection
+    #[arg(long = "forward", value_parser = parse_forward)]
+    forwards: Vec<((String, Option<u16>), String)>,
+
+    /// accept local agents on that address (instead of the gateway), the
+    /// names of --aggregate-name are then served by them over the gateway
+    /// connection of this agent
+    #[arg(long, requires = "aggregate_names")]
+    aggregate: Option<SocketAddr>,
 
-    /// name to register with the gateway
+    /// name served by the local agents, as `name:port` for the service of a
+    /// name on a port. Can be repeated
+    #[arg(long = "aggregate-name", requires = "aggregate", value_parser = parse_name)]
+    aggregate_names: Vec<(String, Option<u16>)>,
+
+    /// authentication token as defined by the server. It's visible in the
+    /// process list, prefer --token-file, --token-stdin or the DIGLETT_TOKEN
+    /// environment variable (used if no token option is set)
     #[arg(short, long)]
-    name: String,
+    token: Option<String>,
+
+    /// read the authentication token from that file instead. The file is read
+    /// again periodically to re-login with a fresh token
+    #[arg(long = "token-file", conflicts_with = "token")]
+    token_file: Option<PathBuf>,
+
+    /// read the authentication token from the first line of stdin
+    #[arg(long = "token-stdin", conflicts_with_all = ["token", "token_file"])]
+    token_stdin: bool,
+
+    /// how often in seconds to re-login with a fresh token from the token file
+    #[arg(long = "token-refresh", default_value_t = 300, requires = "token_file")]
+    token_refresh: u64,
+
+    /// connect over mutual tls, verifying the gateway against that ca certificate
+    #[arg(long = "tls-ca", requires_all = ["tls_cert", "tls_key"])]
+    tls_ca: Option<PathBuf>,
+
+    /// agent certificate used to authenticate over mutual tls
+    #[arg(long = "tls-cert", requires = "tls_ca")]
+    tls_cert: Option<PathBuf>,
+
+    /// private key of the agent certificate
+    #[arg(long = "tls-key", requires = "tls_ca")]
+    tls_key: Option<PathBuf>,
+
+    /// weight of the agent when the gateway balances the name between
+    /// multiple agents. An agent with weight 2 gets twice the connections of
+    /// an agent with weight 1
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    weight: Option<u32>,
+
+    /// label shown by the gateway admin api as `key=value`, can be repeated.
+    /// The hostname and version of the agent are always sent
+    #[arg(long = "label", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+
+    /// serve a web ui that lists the recent http requests and responses
+    /// through the tunnel, on 127.0.0.1:4040 unless set
+    #[arg(long, num_args = 0..=1, default_missing_value = "127.0.0.1:4040")]
+    inspect: Option<SocketAddr>,
+
+    /// serve prometheus metrics of the tunnel on that address (`GET /metrics`)
+    #[arg(long)]
+    metrics: Option<SocketAddr>,
+
+    /// log a line for each http request through the tunnel (method, path,
+    /// status, duration and body sizes)
+    #[arg(long = "log-http")]
+    log_http: bool,
+
+    /// log a status line every that many seconds with the open streams, the
+    /// traffic per second and the round trip time of the tunnel
+    #[arg(long = "stats-interval", value_parser = clap::value_parser!(u64).range(1..))]
+    stats_interval: Option<u64>,
+
+    /// max traffic of the agent in each direction as a bit rate, like `5mbps`
+    /// (`bps`, `kbps`, `mbps` or `gbps`)
+    #[arg(long = "rate-limit", value_parser = parse_rate)]
+    rate_limit: Option<u64>,
+
+    /// interval in seconds of the keep alive pings to the gateway, the agent
+    /// reconnects if the gateway doesn't respond for 3 intervals [default: 10]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    keepalive: Option<u64>,
 
-    /// authentication token as defined by the server
-    #[arg(short, long, default_value = "")]
-    token: String,
+    /// reach the gateway through a proxy, like `socks5://[user:password@]host:port`
+    /// or `http://[user:password@]host:port`. Default to the `HTTPS_PROXY`
+    /// environment variable
+    #[arg(long)]
+    proxy: Option<Proxy>,
 
-    /// enable debugging logs
+    /// generate the encryption keystreams of the tunnel on worker threads, it
+    /// improves the throughput of busy tunnels on multi core hosts
+    #[arg(long = "crypto-pipeline")]
+    crypto_pipeline: bool,
+
+    /// highest wire version offered to the gateway [default: the latest].
+    /// Gateways of version 1 refuse agents that offer any other version, use
+    /// `--wire-version 1` to reach them
+    #[arg(long = "wire-version", value_parser = clap::value_parser!(u8).range(1..=VERSION as i64))]
+    wire_version: Option<u8>,
+
+    /// max simultaneous connections to each backend, new streams are closed
+    /// right away once it's reached
+    #[arg(long = "max-connections", value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections: Option<u64>,
+
+    /// only serve the backends while they are healthy. The backends are
+    /// checked with an http `GET` of the path if set (`--health-check=/healthz`)
+    /// or by connecting to them otherwise
+    #[arg(long = "health-check", require_equals = true)]
+    health_check: Option<Option<String>>,
+
+    /// expected status of the http health check
+    #[arg(
+        long = "health-status",
+        default_value_t = 200,
+        requires = "health_check"
+    )]
+    health_status: u16,
+
+    /// seconds between health checks
+    #[arg(
+        long = "health-interval",
+        default_value_t = 10,
+        requires = "health_check",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    health_interval: u64,
+
+    /// run in the background, detached from the terminal. The logs are
+    /// discarded unless they go to a log file
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "token_stdin")]
+    daemon: bool,
+
+    /// write the pid of the agent to that file, it is removed when the
+    /// agent exits
+    #[cfg(unix)]
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+
+    /// write the logs to that file instead of stderr, the file is rotated
+    /// and the rotated files are kept as `<file>.1`, `<file>.2`, ...
+    #[arg(long = "log-file", env = "DIGLETT_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// rotate the log file once it reaches that size (like 10M)
+    #[arg(long = "log-max-size", default_value = "10M", value_parser = parse_size, requires = "log_file")]
+    log_max_size: u64,
+
+    /// rotate the log file once it is that old (like 1d), even if it's
+    /// smaller
+    #[arg(long = "log-max-age", value_parser = parse_duration, requires = "log_file")]
+    log_max_age: Option<Duration>,
+
+    /// number of rotated log files to keep
+    #[arg(long = "log-keep", default_value_t = logs::KEEP, requires = "log_file")]
+    log_keep: usize,
+
+    /// format of the logs, `json` logs a json object per line with stable
+    /// field names (including the fields of the access logs)
+    #[arg(
+        long = "log-format",
+        env = "DIGLETT_LOG_FORMAT",
+        default_value = "text"
+    )]
+    log_format: Format,
+
+    /// level of the logs: error, warn, info, debug or trace
+    #[arg(long = "log-level", env = "DIGLETT_LOG", default_value = "info")]
+    log_level: LevelFilter,
+
+    /// enable debugging logs, it overrides --log-level
     #[arg(short, long, action=ArgAction::Count)]
     debug: u8,
 
-    backend: String,
+    /// ca bundle to verify https backends against instead of the web pki roots
+    #[arg(long = "backend-ca")]
+    backend_ca: Option<PathBuf>,
+
+    /// server name sent to https backends and verified instead of their host
+    #[arg(long = "backend-sni")]
+    backend_sni: Option<String>,
+
+    /// don't verify the certificates of https backends
+    #[arg(long = "backend-insecure")]
+    backend_insecure: bool,
+
+    /// backend address of the name, `unix:<path>` for a unix socket,
+    /// `https://host:port` for a tls backend, `udp://host:port` for a udp
+    /// service, `docker://container:port` for a docker container or
+    /// `npipe:////./pipe/<name>` for a windows named pipe. Streams are
+    /// distributed round robin between multiple instances separated by commas
+    #[arg(env = "DIGLETT_BACKEND", requires = "name")]
+    backend: Option<String>,
+
+    /// backend of the name dialed for new streams while the backend is down,
+    /// the agent switches back once the backend recovers
+    #[arg(long = "backend-fallback", requires = "name")]
+    backend_fallback: Option<String>,
+
+    /// with `json` the tunnel (the public endpoints of the names, the tunnel
+    /// id and the gateway key fingerprint) is printed as a json line on
+    /// stdout each time the agent connects. The logs are always on stderr
+    #[arg(long, value_enum, default_value_t = Output::Text)]
+    output: Output,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// send a request captured by the inspector of a running agent again to
+    /// its backend
+    Replay {
+        /// id of the captured request
+        id: u64,
+
+        /// address of the inspector of the agent
+        #[arg(long, default_value = "127.0.0.1:4040")]
+        inspect: SocketAddr,
+    },
+
+    /// generate a new identity (secp256k1 keypair) and write its secret key
+    /// to the file, only readable by its owner. The public key and its
+    /// fingerprint are printed
+    Keygen {
+        /// file of the secret key
+        path: PathBuf,
+
+        /// replace the file if it exists
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// connect to a gateway and print the fingerprint of its key, it is then
+    /// compared to the expected key and to the known hosts. It fails if
+    /// any of them doesn't match
+    Fingerprint {
+        /// address of the gateway, as given to --gateway
+        gateway: String,
+
+        /// the expected key of the gateway, either its public key (hex) or
+        /// its fingerprint
+        #[arg(long)]
+        expect: Option<String>,
+
+        /// known hosts file to use instead of the default one
+        #[arg(long = "known-hosts-file")]
+        known_hosts_file: Option<PathBuf>,
+    },
+
+    /// diagnose a tunnel without serving it: reach the gateway, do the
+    /// handshake, check the server key and the token, and connect to the
+    /// backends. For example `diglett doctor -g gateway.com:20000 --token
+    /// secret -n web localhost:3000` or `diglett doctor -c agent.toml`
+    #[command(disable_help_flag = true)]
+    Doctor {
+        /// the options of the agent to diagnose
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+
+    /// measure the throughput and the latency of a tunnel through the
+    /// gateway. The agent registers an echo name and dials it over a second
+    /// gateway connection, so the traffic goes through the gateway both ways
+    Bench {
+        /// address of the gateway
+        #[arg(short, long)]
+        gateway: String,
+
+        /// authentication token of the agent and the client
+        #[arg(long, default_value = "")]
+        token: String,
+
+        /// bytes echoed by the throughput test (like 256M)
+        #[arg(long, default_value = "64M", value_parser = parse_size)]
+        size: u64,
+
+        /// concurrent streams of the throughput test
+        #[arg(long, default_value_t = bench::STREAMS)]
+        streams: usize,
+
+        /// round trips of the latency test
+        #[arg(long, default_value_t = bench::PINGS)]
+        pings: usize,
+    },
+
+    /// store the token of a gateway in the os keyring, the agent then uses it
+    /// when no token is set. The token is read from the terminal (or stdin)
+    #[cfg(feature = "keyring")]
+    Login {
+        /// address of the gateway, as given to --gateway
+        gateway: String,
+    },
+
+    /// delete the token of a gateway from the os keyring
+    #[cfg(feature = "keyring")]
+    Logout {
+        /// address of the gateway, as given to --gateway
+        gateway: String,
+    },
+
+    /// manage the windows service of the agent
+    #[cfg(windows)]
+    Service {
+        #[command(subcommand)]
+        action: Service,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+#[cfg(windows)]
+#[derive(Subcommand, Debug)]
+enum Service {
+    /// register the agent to start at boot with the configuration file
+    Install {
+        /// agent configuration file
+        #[arg(short, long)]
+        config: PathBuf,
+    },
+
+    /// stop the service and remove it
+    Uninstall,
+
+    /// run the agent as the service, this is how windows starts it
+    Run {
+        /// agent configuration file
+        #[arg(short, long)]
+        config: PathBuf,
+    },
+}
+
+fn main() -> Result<()> {
     let args = Args::parse();
 
-    simple_logger::SimpleLogger::default()
-        .with_level(match args.debug {
-            0 => log::LevelFilter::Info,
-            1 => log::LevelFilter::Debug,
-            _ => log::LevelFilter::Trace,
-        })
-        .with_utc_timestamps()
-        .init()
-        .unwrap();
+    // before the runtime starts its threads
+    #[cfg(unix)]
+    let pidfile = match pidfile(&args) {
+        Ok(pidfile) => pidfile,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = logger(&args) {
+        eprintln!("failed to open the log file: {}", err);
+        std::process::exit(1);
+    }
 
-    if let Err(err) = app(args).await {
+    let result = tokio::runtime::Runtime::new()?.block_on(start(args));
+    // the pid file is removed before the process exits
+    #[cfg(unix)]
+    drop(pidfile);
+
+    if let Err(err) = result {
         eprintln!("{}", err);
         std::process::exit(1);
     }
@@ -50,15 +482,759 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn app(args: Args) -> Result<()> {
-    let connection = TcpStream::connect(args.gateway).await?;
-    let client = Client::new(connection, keypair());
+async fn start(args: Args) -> Result<()> {
+    if let Some(command) = args.command {
+        return run(command).await;
+    }
+
+    let overrides = Overrides::from(&args);
+    let config = match &args.config {
+        Some(path) => load(path, &overrides)?,
+        None => config(&args)?,
+    };
+
+    app(
+        config,
+        args.config.as_deref(),
+        &overrides,
+        args.replace_known_host,
+        args.exec.as_deref(),
+        args.output,
+    )
+    .await
+}
+
+// detach the agent and write its pid file, as requested
+#[cfg(unix)]
+fn pidfile(args: &Args) -> Result<Option<Pidfile>> {
+    if args.daemon {
+        return daemonize(args.pidfile.as_deref());
+    }
 
-    let mut client = client.negotiate().await?;
+    args.pidfile.as_ref().map(Pidfile::create).transpose()
+}
 
-    agent::login(&mut client, args.token).await?;
-    agent::register(&mut client, args.name).await?;
-    agent::serve(client, args.backend).await?;
+// log to stderr or to the log file
+fn logger(args: &Args) -> Result<()> {
+    let level = match args.debug {
+        0 => args.log_level,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let rotation = Rotation {
+        size: Some(args.log_max_size),
+        age: args.log_max_age,
+        keep: args.log_keep,
+    };
+    let file = args
+        .log_file
+        .as_ref()
+        .map(|path| LogFile::open(path, rotation))
+        .transpose()?;
 
+    logs::init(level, args.log_format, file)
+}
+
+// write a new keypair to the file and print its public key
+fn keygen(path: &Path, force: bool) -> Result<()> {
+    if force {
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+
+    let kp = keypair();
+    keypair_to_file(&kp, path)?;
+    println!("public key: {}", kp.public_key());
+    println!("fingerprint: {}", fingerprint(&kp.public_key()));
     Ok(())
 }
+
+// print the key of the gateway and compare it to the expected and known keys
+async fn gateway_fingerprint(
+    gateway: &str,
+    expect: Option<String>,
+    known_hosts_file: Option<PathBuf>,
+) -> Result<()> {
+    let stream = tokio::time::timeout(Duration::from_secs(10), TcpStream::connect(gateway))
+        .await
+        .map_err(|_| Error::GatewayTimeout(Duration::from_secs(10)))??;
+    let key = Client::new(stream, keypair())
+        .negotiate()
+        .await?
+        .remote_key();
+    let observed = fingerprint(&key);
+    println!("public key: {}", key);
+    println!("fingerprint: {}", observed);
+
+    if let Some(expect) = expect {
+        if expect != observed && expect != key.to_string() {
+            return Err(Error::UnexpectedKey(observed));
+        }
+        println!("matches the expected key");
+    }
+
+    let Some(path) = known_hosts_file.or_else(KnownHosts::default_path) else {
+        return Ok(());
+    };
+    match KnownHosts::new(&path).get(gateway).await? {
+        Some(known) if known == observed => println!("matches {}", path.display()),
+        Some(_) => return Err(Error::KeyChanged(gateway.into(), observed)),
+        None => println!("not in {}", path.display()),
+    }
+
+    Ok(())
+}
+
+// diagnose the agent of the arguments, it fails if any check failed
+async fn doctor(args: Vec<String>) -> Result<()> {
+    let args = Args::parse_from(std::iter::once("diglett".into()).chain(args));
+    let config = match &args.config {
+        Some(path) => load(path, &Overrides::from(&args))?,
+        None => config(&args)?,
+    };
+
+    let checks = doctor::diagnose(&config).await;
+    for check in &checks {
+        println!("{}", check);
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == doctor::Status::Failed)
+        .count();
+    if failed > 0 {
+        return Err(Error::IO(std::io::Error::other(format!(
+            "{} of {} checks failed",
+            failed,
+            checks.len()
+        ))));
+    }
+
+    Ok(())
+}
+
+async fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Replay { id, inspect } => {
+            let (replayed, status) = inspect::replay(inspect, id).await?;
+            println!("request {} replayed as {}: {}", id, replayed, status);
+        }
+        Command::Keygen { path, force } => keygen(&path, force)?,
+        Command::Doctor { args } => doctor(args).await?,
+        Command::Bench {
+            gateway,
+            token,
+            size,
+            streams,
+            pings,
+        } => {
+            let report = Bench::new(gateway, token)
+                .with_size(size)
+                .with_streams(streams)
+                .with_pings(pings)
+                .run()
+                .await?;
+            println!("{}", report);
+        }
+        Command::Fingerprint {
+            gateway,
+            expect,
+            known_hosts_file,
+        } => gateway_fingerprint(&gateway, expect, known_hosts_file).await?,
+        #[cfg(feature = "keyring")]
+        Command::Login { gateway } => {
+            credentials::store(&gateway, &read_token()?)?;
+            println!("token of {} stored in the keyring", gateway);
+        }
+        #[cfg(feature = "keyring")]
+        Command::Logout { gateway } => match credentials::delete(&gateway)? {
+            true => println!("token of {} deleted from the keyring", gateway),
+            false => println!("no token of {} in the keyring", gateway),
+        },
+        #[cfg(windows)]
+        Command::Service { action } => match action {
+            Service::Install { config } => {
+                service::install(&config)?;
+                println!("service installed, start it with `sc start diglett`");
+            }
+            Service::Uninstall => {
+                service::uninstall()?;
+                println!("service removed");
+            }
+            Service::Run { config } => service::run(config).await?,
+        },
+    }
+
+    Ok(())
+}
+
+// the options of the command line (or of the environment) that override the
+// configuration file
+#[derive(Debug, Clone, Default)]
+struct Overrides {
+    gateway: Vec<String>,
+    gateway_key: Option<PublicKey>,
+}
+
+impl From<&Args> for Overrides {
+    fn from(args: &Args) -> Self {
+        Self {
+            gateway: args.gateway.clone(),
+            gateway_key: args.gateway_key,
+        }
+    }
+}
+
+// load the configuration file with its overrides, the token of the
+// environment (or the keyring) is used if the file has none
+fn load(path: &Path, overrides: &Overrides) -> Result<Config> {
+    let mut config = Config::load(path)?;
+    if !overrides.gateway.is_empty() {
+        config.gateway = overrides.gateway.clone();
+    }
+    if let Some(key) = overrides.gateway_key {
+        config.gateway_key = Some(key);
+    }
+    if matches!(&config.token, Token::Value(token) if token.is_empty()) {
+        if let Some(token) = stored_token(config.gateway.first())? {
+            config.token = Token::Value(token);
+        }
+    }
+
+    Ok(config)
+}
+
+// the token of the environment, or the token of the gateway in the keyring
+fn stored_token(gateway: Option<&String>) -> Result<Option<String>> {
+    if let Some(token) = std::env::var(TOKEN_ENV)
+        .ok()
+        .filter(|token| !token.is_empty())
+    {
+        return Ok(Some(token));
+    }
+
+    #[cfg(feature = "keyring")]
+    if let Some(gateway) = gateway {
+        return credentials::load(gateway);
+    }
+    #[cfg(not(feature = "keyring"))]
+    let _ = gateway;
+
+    Ok(None)
+}
+
+// the configuration of the command line arguments. The token is taken from
+// --token, --token-file, --token-stdin, the environment and then the keyring
+fn config(args: &Args) -> Result<Config> {
+    let token = match (&args.token, &args.token_file) {
+        (Some(token), _) => Token::Value(token.clone()),
+        (None, Some(file)) => Token::File {
+            file: file.clone(),
+            refresh: args.token_refresh,
+        },
+        (None, None) if args.token_stdin => Token::Value(token_stdin()?),
+        (None, None) => Token::Value(stored_token(args.gateway.first())?.unwrap_or_default()),
+    };
+
+    let tls = match (&args.tls_ca, &args.tls_cert, &args.tls_key) {
+        (Some(ca), Some(cert), Some(key)) => Some(Tls {
+            ca: ca.clone(),
+            cert: cert.clone(),
+            key: key.clone(),
+        }),
+        _ => None,
+    };
+
+    let mut forwards = args
+        .name
+        .iter()
+        .zip(&args.backend)
+        .chain(args.forwards.iter().map(|(name, backend)| (name, backend)))
+        .map(|((name, port), backend)| {
+            // the backend tls options apply to the https backends only
+            let https = backend.starts_with("https://");
+            Forward {
+                name: name.clone(),
+                port: *port,
+                backend: backend.clone(),
+                fallback: None,
+                weight: args.weight,
+                protocol: Default::default(),
+                compression: None,
+                ca: args.backend_ca.clone().filter(|_| https),
+                sni: args.backend_sni.clone().filter(|_| https),
+                insecure: args.backend_insecure && https,
+                health: args.health_check.as_ref().map(|path| Health {
+                    path: path.clone(),
+                    status: args.health_status,
+                    interval: args.health_interval,
+                }),
+                max_connections: args.max_connections.map(|max| max as usize),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // the fallback is the one of the named backend
+    if let (Some(fallback), Some(forward)) = (&args.backend_fallback, forwards.first_mut()) {
+        forward.fallback = Some(fallback.clone());
+    }
+
+    let config = Config {
+        gateway: args.gateway.clone(),
+        gateway_key: args.gateway_key,
+        known_hosts: args.known_hosts,
+        known_hosts_file: args.known_hosts_file.clone(),
+        inspect: args.inspect,
+        metrics: args.metrics,
+        log_http: args.log_http,
+        stats_interval: args.stats_interval,
+        rate_limit: args.rate_limit,
+        keepalive: args.keepalive,
+        proxy: args.proxy.clone(),
+        crypto_pipeline: args.crypto_pipeline,
+        wire_version: args.wire_version,
+        token,
+        tls,
+        labels: args.labels.iter().cloned().collect(),
+        reconnect: Reconnect::default(),
+        forwards,
+        aggregate: args.aggregate.map(|listen| Aggregate {
+            listen,
+            names: args
+                .aggregate_names
+                .iter()
+                .map(|(name, port)| match port {
+                    Some(port) => format!("{}:{}", name, port),
+                    None => name.clone(),
+                })
+                .collect(),
+        }),
+    };
+
+    config.validate()?;
+    Ok(config)
+}
+
+// serve the gateway, reconnecting when the gateway shuts down (for example
+// on a restart). With a configuration file the agent is started again with
+// the new configuration on SIGHUP
+async fn app(
+    mut config: Config,
+    path: Option<&Path>,
+    overrides: &Overrides,
+    replace_known_host: bool,
+    exec: Option<&str>,
+    output: Output,
+) -> Result<()> {
+    // the inspector, metrics and counters outlive the reloads
+    let counters = Arc::new(Counters::default());
+    let mut inspector = match config.inspect {
+        Some(address) => {
+            let listener = TcpListener::bind(address).await?;
+            log::info!("inspect the http traffic on: http://{}", address);
+
+            let inspector = Inspector::default();
+            tokio::spawn(inspector.clone().serve(listener));
+            Some(inspector)
+        }
+        None => None,
+    };
+
+    if config.log_http {
+        inspector = Some(inspector.unwrap_or_else(|| Inspector::new(0)).with_log());
+    }
+
+    if let Some(address) = config.metrics {
+        let listener = TcpListener::bind(address).await?;
+        log::info!("metrics on: http://{}/metrics", address);
+        tokio::spawn(metrics::serve(Arc::clone(&counters), listener));
+    }
+    if let Some(interval) = config.stats_interval {
+        tokio::spawn(stats(Arc::clone(&counters), Duration::from_secs(interval)));
+    }
+
+    let mut child = match exec {
+        Some(command) => {
+            let mut backends = Vec::new();
+            for forward in &config.forwards {
+                backends.extend(forward.backends()?);
+            }
+
+            let mut child = spawn(command)?;
+            tokio::select! {
+                _ = reachable(&backends) => {}
+                status = child.wait() => return Err(exited(status?)),
+                _ = shutdown() => {
+                    terminate(child).await;
+                    return Ok(());
+                }
+            }
+            Some(child)
+        }
+        None => None,
+    };
+
+    let mut status = None;
+    let result = loop {
+        let agent = agent(&config, replace_known_host, &counters, inspector.clone())?;
+        let tunnel = agent.tunnel();
+
+        let mut reloaded = None;
+        let result = agent
+            .run_until(async {
+                tokio::select! {
+                    _ = shutdown() => {}
+                    exit = exited_child(&mut child) => status = Some(exit),
+                    config = reload(path, overrides) => reloaded = Some(config),
+                    _ = print(tunnel), if output == Output::Json => {}
+                }
+            })
+            .await;
+
+        match reloaded {
+            Some(reloaded) => {
+                if let Err(err) = result {
+                    log::error!("{}", err);
+                }
+                log::info!("restarting with the reloaded configuration");
+                config = reloaded;
+            }
+            None => break result,
+        }
+    };
+
+    let Some(child) = child else {
+        return result;
+    };
+
+    match status {
+        Some(status) => {
+            let status = status?;
+            result?;
+            match status.success() {
+                true => Ok(()),
+                false => Err(exited(status)),
+            }
+        }
+        None => {
+            terminate(child).await;
+            result
+        }
+    }
+}
+
+// print the tunnel as json each time the agent connects, never returns
+async fn print(mut tunnel: watch::Receiver<Option<Tunnel>>) {
+    while tunnel.changed().await.is_ok() {
+        let Some(tunnel) = tunnel.borrow_and_update().clone() else {
+            continue;
+        };
+
+        match serde_json::to_string(&tunnel) {
+            Ok(json) => println!("{}", json),
+            Err(err) => log::error!("failed to print tunnel: {}", err),
+        }
+    }
+
+    std::future::pending().await
+}
+
+fn agent(
+    config: &Config,
+    replace_known_host: bool,
+    counters: &Arc<Counters>,
+    inspector: Option<Inspector>,
+) -> Result<Agent> {
+    let mut builder = config
+        .agent()?
+        .replace_known_host(replace_known_host)
+        .counters(Arc::clone(counters))
+        .label("version", env!("GIT_VERSION"));
+
+    if let Some(hostname) = hostname() {
+        builder = builder.label("hostname", hostname);
+    }
+    // labels of the configuration override the defaults
+    for (key, value) in &config.labels {
+        builder = builder.label(key, value);
+    }
+
+    if let Some(inspector) = inspector {
+        builder = builder.inspector(inspector);
+    }
+
+    if let Some(notify) = Notify::from_env() {
+        builder = builder.notify(notify);
+    }
+
+    // the gateways of a fleet are reached through the proxy of the first one,
+    // the domain of an SRV name is matched against NO_PROXY and commands
+    // don't use a proxy
+    let target = config
+        .gateway
+        .first()
+        .filter(|_| config.proxy.is_none())
+        .and_then(|gateway| match gateway.split_once(':') {
+            Some(("srv", name)) => Some(format!("{}:0", name)),
+            Some(("exec", _)) => None,
+            _ => Some(gateway.clone()),
+        });
+    if let Some(target) = target {
+        if let Some(proxy) = Proxy::from_env(&target)? {
+            log::debug!("reaching the gateway through {}", proxy);
+            builder = builder.proxy(proxy);
+        }
+    }
+
+    builder.build()
+}
+
+// wait for SIGHUP and load the configuration file again. An invalid
+// configuration is logged and the agent keeps running with the current one
+#[cfg(unix)]
+async fn reload(path: Option<&Path>, overrides: &Overrides) -> Config {
+    let Some(path) = path else {
+        return std::future::pending().await;
+    };
+
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    loop {
+        hangup.recv().await;
+        log::info!("reloading configuration {}", path.display());
+        match load(path, overrides) {
+            Ok(config) => return config,
+            Err(err) => log::error!("failed to reload configuration: {}", err),
+        }
+    }
+}
+
+// there is no SIGHUP, the service is restarted instead
+#[cfg(windows)]
+async fn reload(_path: Option<&Path>, _overrides: &Overrides) -> Config {
+    std::future::pending().await
+}
+
+async fn exited_child(child: &mut Option<Child>) -> std::io::Result<ExitStatus> {
+    match child {
+        Some(child) => child.wait().await,
+        None => std::future::pending().await,
+    }
+}
+
+// the token on the first line of stdin
+fn token_stdin() -> Result<String> {
+    let mut token = String::new();
+    std::io::stdin().read_line(&mut token)?;
+
+    match token.trim() {
+        "" => Err(Error::Config("no token on stdin".into())),
+        token => Ok(token.into()),
+    }
+}
+
+// the token typed on the terminal (without echo), or the first line of stdin
+#[cfg(all(feature = "keyring", unix))]
+fn read_token() -> Result<String> {
+    let stdin = libc::STDIN_FILENO;
+    let mut terminal: libc::termios = unsafe { std::mem::zeroed() };
+    // stdin is not a terminal
+    if unsafe { libc::tcgetattr(stdin, &mut terminal) } != 0 {
+        return token_stdin();
+    }
+
+    eprint!("token: ");
+    let mut silent = terminal;
+    silent.c_lflag &= !libc::ECHO;
+    unsafe { libc::tcsetattr(stdin, libc::TCSANOW, &silent) };
+    let token = token_stdin();
+    unsafe { libc::tcsetattr(stdin, libc::TCSANOW, &terminal) };
+    eprintln!();
+
+    token
+}
+
+// the first line of stdin, the token is echoed on the windows console
+#[cfg(all(feature = "keyring", windows))]
+fn read_token() -> Result<String> {
+    eprint!("token: ");
+    token_stdin()
+}
+
+// spawn the command in its own process group, so the processes it starts
+// (npm, node, ...) are terminated with it. The command doesn't inherit the
+// token of the environment
+fn spawn(command: &str) -> Result<Child> {
+    let mut sh = std::process::Command::new("sh");
+    sh.arg("-c").arg(command).env_remove(TOKEN_ENV);
+    #[cfg(unix)]
+    sh.process_group(0);
+    let child = tokio::process::Command::from(sh)
+        .kill_on_drop(true)
+        .spawn()?;
+
+    log::info!("started '{}'", command);
+    Ok(child)
+}
+
+// terminate the process group of the command, it's killed if it doesn't
+// exit in time
+#[cfg(unix)]
+async fn terminate(mut child: Child) {
+    let Some(pid) = child.id() else {
+        return;
+    };
+
+    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, child.wait())
+        .await
+        .is_err()
+    {
+        log::warn!("command did not exit in time, killing it");
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+        let _ = child.wait().await;
+    }
+}
+
+#[cfg(windows)]
+async fn terminate(mut child: Child) {
+    let _ = child.kill().await;
+}
+
+fn exited(status: ExitStatus) -> Error {
+    std::io::Error::other(format!("command exited with {}", status)).into()
+}
+
+// wait until the backends accept connections
+async fn reachable(backends: &[Backend]) {
+    let check = HealthCheck::tcp();
+    for backend in backends {
+        let mut waiting = false;
+        while check.check(backend).await.is_err() {
+            if !waiting {
+                log::info!("waiting for backend {}", backend);
+                waiting = true;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+
+    log::info!("shutting down");
+}
+
+// ctrl-c, or a stop of the windows service
+#[cfg(windows)]
+async fn shutdown() {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = service::stopped() => {},
+    }
+
+    log::info!("shutting down");
+}
+
+// log the stats of the agent every interval
+async fn stats(counters: Arc<Counters>, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    let mut last = counters.stats();
+    loop {
+        ticker.tick().await;
+        let stats = counters.stats();
+        let rate = |bytes: u64| bytes as f64 / interval.as_secs_f64();
+        log::info!(
+            "stats streams={} up={}/s down={}/s rtt={}",
+            stats.streams,
+            human(rate(stats.up - last.up)),
+            human(rate(stats.down - last.down)),
+            match stats.rtt {
+                Some(rtt) => format!("{:.1}ms", rtt.as_secs_f64() * 1000.0),
+                None => "-".into(),
+            }
+        );
+        last = stats;
+    }
+}
+
+// human readable size in bytes
+fn human(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{:.0}{}", size, UNITS[unit]),
+        _ => format!("{:.1}{}", size, UNITS[unit]),
+    }
+}
+
+fn hostname() -> Option<String> {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .ok()?;
+
+    let hostname = hostname.trim();
+    (!hostname.is_empty()).then(|| hostname.into())
+}
+
+fn parse_size(value: &str) -> std::result::Result<u64, String> {
+    logs::parse_size(value).map_err(|err| err.to_string())
+}
+
+fn parse_duration(value: &str) -> std::result::Result<Duration, String> {
+    logs::parse_duration(value).map_err(|err| err.to_string())
+}
+
+fn parse_rate(value: &str) -> std::result::Result<u64, String> {
+    config::parse_rate(value).map_err(|err| err.to_string())
+}
+
+fn parse_forward(value: &str) -> std::result::Result<((String, Option<u16>), String), String> {
+    let (name, backend) = value
+        .split_once('=')
+        .ok_or_else(|| "expected format name[:port]=address".to_string())?;
+
+    if name.is_empty() || backend.is_empty() {
+        return Err("expected format name[:port]=address".into());
+    }
+
+    Ok((parse_name(name)?, backend.into()))
+}
+
+// name[:port], the port is a port of the name
+fn parse_name(value: &str) -> std::result::Result<(String, Option<u16>), String> {
+    let Some((name, port)) = value.rsplit_once(':') else {
+        return Ok((value.into(), None));
+    };
+
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 && !name.is_empty() => Ok((name.into(), Some(port))),
+        _ => Err("expected format name[:port]".into()),
+    }
+}
+
+fn parse_label(value: &str) -> std::result::Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| "expected format key=value".to_string())?;
+
+    if key.is_empty() || value.contains('\n') {
+        return Err("invalid label".into());
+    }
+
+    Ok((key.into(), value.into()))
+}
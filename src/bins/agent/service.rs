@@ -0,0 +1,186 @@
+//! windows service of the agent. `service install --config <file>` registers
+//! the agent to start at boot with the configuration file, and windows runs
+//! it with `service run`. Stopping the service (or windows) shuts the agent
+//! down like ctrl-c does
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{LazyLock, OnceLock},
+    time::Duration,
+};
+
+use diglett::{Error, Result};
+use tokio::{runtime::Handle, sync::watch};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use super::Output;
+
+const SERVICE_NAME: &str = "diglett";
+/// how often the uninstall checks if the service stopped
+const STOP_POLL: Duration = Duration::from_millis(500);
+
+// set once windows asks the service to stop
+static STOP: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+// the configuration file of the service, and the runtime of main that runs
+// the agent
+static SERVICE: OnceLock<(PathBuf, Handle)> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// register the service, started at boot as LocalSystem with the
+/// configuration file
+pub(crate) fn install(config: &Path) -> Result<()> {
+    // the service doesn't run in the current directory
+    let config = std::path::absolute(config)?;
+    // fail now rather than when windows starts the service
+    super::load(&config, &Default::default())?;
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(error)?;
+
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: "diglett agent".into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: vec![
+            OsString::from("service"),
+            OsString::from("run"),
+            OsString::from("--config"),
+            config.into_os_string(),
+        ],
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(error)?;
+    service
+        .set_description("exposes the local services through a diglett gateway")
+        .map_err(error)?;
+
+    Ok(())
+}
+
+/// stop the service if it's running, and remove it
+pub(crate) fn uninstall() -> Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(error)?;
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(error)?;
+
+    // the service is removed once it stops
+    service.delete().map_err(error)?;
+    if service.query_status().map_err(error)?.current_state != ServiceState::Stopped {
+        service.stop().map_err(error)?;
+    }
+
+    let deadline = std::time::Instant::now() + super::SHUTDOWN_TIMEOUT + STOP_POLL;
+    while service.query_status().map_err(error)?.current_state != ServiceState::Stopped {
+        if std::time::Instant::now() > deadline {
+            log::warn!("service did not stop in time, it's removed once it does");
+            break;
+        }
+        std::thread::sleep(STOP_POLL);
+    }
+
+    Ok(())
+}
+
+/// run the agent as the service, this blocks until the service stops
+pub(crate) async fn run(config: PathBuf) -> Result<()> {
+    SERVICE
+        .set((config, Handle::current()))
+        .expect("service runs once");
+
+    tokio::task::spawn_blocking(|| service_dispatcher::start(SERVICE_NAME, ffi_service_main))
+        .await
+        .map_err(std::io::Error::other)?
+        .map_err(error)
+}
+
+/// resolves once windows asks the service to stop
+pub(crate) async fn stopped() {
+    let mut stop = STOP.subscribe();
+    let _ = stop.wait_for(|stop| *stop).await;
+}
+
+// called by windows on a thread of its own
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(err) = serve() {
+        log::error!("{}", err);
+    }
+}
+
+fn serve() -> Result<()> {
+    let (config, handle) = SERVICE.get().expect("service configuration is set");
+
+    let status = service_control_handler::register(SERVICE_NAME, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            STOP.send_replace(true);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })
+    .map_err(error)?;
+
+    status
+        .set_service_status(state(ServiceState::Running, ServiceExitCode::NO_ERROR))
+        .map_err(error)?;
+
+    let result = handle.block_on(async {
+        let config = super::load(config, &Default::default())?;
+        super::app(config, None, &Default::default(), false, None, Output::Text).await
+    });
+
+    let exit = match &result {
+        Ok(_) => ServiceExitCode::NO_ERROR,
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    status
+        .set_service_status(state(ServiceState::Stopped, exit))
+        .map_err(error)?;
+
+    result
+}
+
+fn state(current_state: ServiceState, exit_code: ServiceExitCode) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state,
+        controls_accepted: match current_state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
+
+fn error(err: windows_service::Error) -> Error {
+    std::io::Error::other(err).into()
+}
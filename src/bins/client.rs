@@ -0,0 +1,264 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{ArgAction, Parser};
+use diglett::{
+    agent::KnownHosts,
+    client,
+    wire::{keypair, Client, Connection, FrameStream},
+    Error, Result,
+};
+use secp256k1::PublicKey;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    task::JoinSet,
+};
+
+/// environment variable of the authentication token
+const TOKEN_ENV: &str = "DIGLETT_TOKEN";
+
+/// delay before connecting again to a gateway that dropped the connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// diglett client, reaches the names served by the agents of the user
+/// privately through the gateway (like `ssh -L`)
+#[derive(Parser)]
+#[command(author, version = env!("GIT_VERSION"), about, long_about = None)]
+struct Args {
+    /// address (host:port) of the gateway
+    #[arg(short, long)]
+    gateway: String,
+
+    /// only accept a gateway with that public key (hex)
+    #[arg(long = "gateway-key")]
+    gateway_key: Option<PublicKey>,
+
+    /// trust the gateway key on first use (stored in
+    /// ~/.config/diglett/known_hosts), and refuse to connect if it changes
+    #[arg(long = "known-hosts")]
+    known_hosts: bool,
+
+    /// known hosts file to use instead of the default one
+    #[arg(long = "known-hosts-file")]
+    known_hosts_file: Option<PathBuf>,
+
+    /// token of the user of the agents, defaults to the DIGLETT_TOKEN
+    /// environment variable
+    #[arg(long, conflicts_with = "token_file")]
+    token: Option<String>,
+
+    /// read the token from that file
+    #[arg(long = "token-file")]
+    token_file: Option<PathBuf>,
+
+    /// forward a local port to the agent of a name, in the format
+    /// [bind_address:]port:name[:port] (with the port of the name if it
+    /// exposes one service per port). Can be repeated
+    #[arg(short = 'L', long = "forward", required = true, value_parser = parse_forward)]
+    forwards: Vec<(SocketAddr, String)>,
+
+    /// enable debugging logs
+    #[arg(short, long, action=ArgAction::Count)]
+    debug: u8,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    simple_logger::SimpleLogger::default()
+        .with_level(match args.debug {
+            0 => log::LevelFilter::Info,
+            1 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        })
+        .with_utc_timestamps()
+        .init()
+        .unwrap();
+
+    if let Err(err) = app(args).await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+async fn app(args: Args) -> Result<()> {
+    let gateway = Arc::new(Gateway {
+        token: token(&args)?,
+        known_hosts: known_hosts(&args)?,
+        address: args.gateway,
+        key: args.gateway_key,
+    });
+
+    let (stop, stopped) = watch::channel(false);
+    let mut forwards = JoinSet::new();
+    for (address, name) in args.forwards {
+        let listener = TcpListener::bind(address).await?;
+        log::info!("forwarding {} -> '{}'", listener.local_addr()?, name);
+        forwards.spawn(forward(
+            Arc::clone(&gateway),
+            listener,
+            name,
+            stopped.clone(),
+        ));
+    }
+
+    // the first forward that fails stops the others
+    let mut result = tokio::select! {
+        _ = shutdown() => Ok(()),
+        Some(joined) = forwards.join_next() => joined.map_err(std::io::Error::other)?,
+    };
+
+    stop.send_replace(true);
+    while let Some(joined) = forwards.join_next().await {
+        result = result.and(joined.map_err(std::io::Error::other)?);
+    }
+
+    result
+}
+
+// the gateway of the client
+struct Gateway {
+    address: String,
+    key: Option<PublicKey>,
+    known_hosts: Option<KnownHosts>,
+    token: String,
+}
+
+impl Gateway {
+    // connect and dial the name
+    async fn dial(&self, name: &str) -> Result<Connection<TcpStream, FrameStream>> {
+        let stream = TcpStream::connect(&self.address).await?;
+        let mut client = Client::new(stream, keypair());
+        if let Some(key) = self.key {
+            client = client.with_pin(key);
+        }
+
+        let mut connection = client.negotiate().await?;
+        if let Some(known_hosts) = &self.known_hosts {
+            known_hosts
+                .verify(&self.address, &connection.remote_key(), false)
+                .await?;
+        }
+
+        client::dial(&mut connection, name, &self.token).await?;
+        Ok(connection)
+    }
+}
+
+// forward the listener to the name, connecting again when the gateway drops
+// the connection. A refused dial (or login) is not retried
+async fn forward(
+    gateway: Arc<Gateway>,
+    listener: TcpListener,
+    name: String,
+    mut stopped: watch::Receiver<bool>,
+) -> Result<()> {
+    loop {
+        let result = match gateway.dial(&name).await {
+            Ok(connection) => {
+                log::info!("connected to '{}' through {}", name, gateway.address);
+                client::forward_until(connection, &listener, stop(&mut stopped)).await
+            }
+            Err(err @ (Error::Remote(_) | Error::Refused(..) | Error::InvalidVersion(_))) => {
+                return Err(err)
+            }
+            Err(err) => Err(err),
+        };
+
+        if *stopped.borrow() {
+            return result;
+        }
+
+        match result {
+            Ok(()) => log::info!("gateway closed the connection of '{}'", name),
+            Err(err) => log::error!("connection of '{}' failed: {}", name, err),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = stop(&mut stopped) => return Ok(()),
+        }
+    }
+}
+
+// the token of the command line, its file or the environment
+fn token(args: &Args) -> Result<String> {
+    let token = match (&args.token, &args.token_file) {
+        (Some(token), _) => token.clone(),
+        (None, Some(file)) => std::fs::read_to_string(file)?.trim().into(),
+        (None, None) => std::env::var(TOKEN_ENV).unwrap_or_default(),
+    };
+
+    match token.is_empty() {
+        true => Err(Error::Config(format!(
+            "no token, use --token, --token-file or {}",
+            TOKEN_ENV
+        ))),
+        false => Ok(token),
+    }
+}
+
+fn known_hosts(args: &Args) -> Result<Option<KnownHosts>> {
+    if let Some(path) = &args.known_hosts_file {
+        return Ok(Some(KnownHosts::new(path)));
+    }
+
+    if !args.known_hosts {
+        return Ok(None);
+    }
+
+    KnownHosts::default_path()
+        .map(|path| Some(KnownHosts::new(path)))
+        .ok_or_else(|| Error::Config("can't find the home directory for known hosts".into()))
+}
+
+// wait until the client stops
+async fn stop(stopped: &mut watch::Receiver<bool>) {
+    let _ = stopped.wait_for(|stop| *stop).await;
+}
+
+async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+
+    log::info!("shutting down");
+}
+
+// [bind_address:]port:name[:port], the port is bound on the loopback by
+// default. Names are not numbers, so a trailing number is the port of the name
+fn parse_forward(value: &str) -> std::result::Result<(SocketAddr, String), String> {
+    const FORMAT: &str = "expected format [bind_address:]port:name[:port]";
+    let (mut address, mut name) = value.rsplit_once(':').ok_or(FORMAT)?;
+    let mut port = None;
+    if name.parse::<u16>().is_ok() {
+        port = Some(name);
+        (address, name) = address.rsplit_once(':').ok_or(FORMAT)?;
+    }
+    if name.is_empty() {
+        return Err(FORMAT.into());
+    }
+    let name = match port {
+        Some(port) => format!("{}:{}", name, port),
+        None => name.into(),
+    };
+
+    let address = match address.parse::<u16>() {
+        Ok(port) => SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+        Err(_) => address.parse().map_err(|_| FORMAT.to_string())?,
+    };
+
+    Ok((address, name))
+}
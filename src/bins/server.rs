@@ -1,37 +1,598 @@
-use clap::{ArgAction, Parser};
+use std::{
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::{error::ErrorKind, ArgAction, ArgGroup, CommandFactory, Parser, Subcommand};
+#[cfg(unix)]
+use diglett::server::Listeners;
+#[cfg(unix)]
+use diglett::server::{maintenance::Mode, Maintenance};
+#[cfg(target_os = "linux")]
+use diglett::server::{Privileges, Sandbox};
 use diglett::{
-    server::{AuthorizeAll, PrintRegisterer, Server},
-    wire::keypair,
-    Result,
+    daemon::{daemonize, Pidfile},
+    logs::{self, Format, LogFile, Rotation},
+    server::{
+        auth::Authenticate,
+        balance::Strategy,
+        geoip::{MaxMind, Policy},
+        token::Claims,
+        Bandwidth, Bind, CertAuth, ClientLimits, Denylist, Dns, DuplicateLogin, GeoFilter, HookSet,
+        HttpRouter, Limits, Nats, OAuth, Pcap, PrintRegisterer, Public, RateLimit, Relay, Server,
+        ServerBuilder, ServerConfig, Signed, UserNamespace, Validation, Webhooks,
+    },
+    tls,
+    wire::{fingerprint, keypair, keypair_from_file, keypair_to_file, Pipes, VERSION},
+    Error, Result,
 };
+use log::LevelFilter;
+use regex::Regex;
+use secp256k1::Keypair;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use url::Url;
 
 /// diglett gateway agent
 #[derive(Parser, Debug)]
 #[command(author, version = env!("GIT_VERSION"), about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(group(ArgGroup::new("authenticated").args(["tls_cert", "token_secret"])))]
 struct Args {
-    #[arg(short, long, default_value = "0.0.0.0:20000")]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, env = "DIGLETT_LISTEN", default_value = "0.0.0.0:20000")]
     listen: String,
 
-    /// enable debugging logs
+    /// serve a single agent over stdin and stdout instead of listening for
+    /// agents, so the tunnel can be carried over ssh. The server exits once
+    /// the agent disconnects
+    #[arg(long, conflicts_with = "handoff")]
+    stdio: bool,
+
+    /// run as a relay: forward the agents accepted on --listen to that
+    /// gateway (host:port). The tunnel stays encrypted end to end, the
+    /// agents negotiate their keys with the gateway
+    #[arg(long, conflicts_with_all = ["stdio", "handoff"])]
+    relay: Option<String>,
+
+    /// file of the server secret key, created if it doesn't exist. Without it
+    /// the server uses a new key on each start, so agents can't pin its key
+    #[arg(long, env = "DIGLETT_KEY")]
+    key: Option<PathBuf>,
+
+    /// expose registrations directly on that public ip instead of localhost
+    #[arg(long, env = "DIGLETT_PUBLIC")]
+    public: Option<IpAddr>,
+
+    /// host name advertised to agents for public registrations. default to
+    /// the public ip
+    #[arg(long, env = "DIGLETT_ADVERTISE", requires = "public")]
+    advertise: Option<String>,
+
+    /// map a registration name to a fixed public port (name=port)
+    #[arg(long = "port-map", requires = "public", value_parser = parse_port_map)]
+    port_map: Vec<(String, u16)>,
+
+    /// range (from-to) of stable public ports for names without a mapping
+    #[arg(long = "port-range", requires = "public", value_parser = parse_port_range)]
+    port_range: Option<RangeInclusive<u16>>,
+
+    /// lease ttl in seconds of agents registrations. An agent that does not
+    /// renew its lease is disconnected. At least 30 seconds (3 keep alive
+    /// intervals), 0 to disable leases
+    #[arg(long = "lease-ttl", default_value_t = 30)]
+    lease_ttl: u64,
+
+    /// seconds to hold the registration of a disconnected agent, new client
+    /// connections are parked until the agent reconnects. 0 to disable
+    #[arg(long, default_value_t = 0)]
+    hold: u64,
+
+    /// serve all names by multiple agents at the same time, distributing the
+    /// client connections with that strategy (round-robin or ip-hash)
+    #[arg(long, value_parser = parse_strategy)]
+    balance: Option<Strategy>,
+
+    /// balancing strategy of a single name, in the format name=strategy.
+    /// Can be repeated
+    #[arg(long = "balance-name", value_parser = parse_balance_name)]
+    balance_name: Vec<(String, Strategy)>,
+
+    /// max number of concurrent agents per user
+    #[arg(long = "max-agents")]
+    max_agents: Option<usize>,
+
+    /// max number of registered names per user
+    #[arg(long = "max-names")]
+    max_names: Option<usize>,
+
+    /// max concurrent connections of a single client ip to one registration
+    #[arg(long = "max-ip-connections")]
+    max_ip_connections: Option<usize>,
+
+    /// max concurrent connections of a single client ip to all registrations
+    #[arg(long = "max-ip-connections-global")]
+    max_ip_connections_global: Option<usize>,
+
+    /// max bandwidth in Mbit/s (each direction) of all the streams of
+    /// a registration
+    #[arg(long = "registration-bandwidth")]
+    registration_bandwidth: Option<f64>,
+
+    /// max bandwidth in Mbit/s (each direction) of a single stream
+    #[arg(long = "stream-bandwidth")]
+    stream_bandwidth: Option<f64>,
+
+    /// max handshake attempts per second from a single source ip. 0 to disable
+    #[arg(long = "handshake-rate", default_value_t = 5.0)]
+    handshake_rate: f64,
+
+    /// max burst of handshake attempts from a single source ip
+    #[arg(long = "handshake-burst", default_value_t = 20)]
+    handshake_burst: u32,
+
+    /// ban duration in seconds of sources that exceed the handshake rate
+    #[arg(long = "handshake-ban", default_value_t = 300)]
+    handshake_ban: u64,
+
+    /// path to a MaxMind (GeoLite2) country database used to filter client connections
+    #[arg(long = "geoip-db")]
+    geoip_db: Option<PathBuf>,
+
+    /// only allow clients from that country (ISO code), can be repeated
+    #[arg(
+        long = "allow-country",
+        requires = "geoip_db",
+        conflicts_with = "deny_country",
+        value_parser = parse_country
+    )]
+    allow_country: Vec<String>,
+
+    /// deny clients from that country (ISO code), can be repeated
+    #[arg(long = "deny-country", requires = "geoip_db", value_parser = parse_country)]
+    deny_country: Vec<String>,
+
+    /// post registrations events (registered, released and kicked agents)
+    /// as json to that url, can be repeated
+    #[arg(long)]
+    webhook: Vec<Url>,
+
+    /// publish the server events (agents, registrations and streams stats)
+    /// to that nats server (nats://[user:password@]host[:port])
+    #[arg(long)]
+    nats: Option<Url>,
+
+    /// subjects prefix of the published nats events
+    #[arg(long = "nats-prefix", default_value = diglett::server::nats::PREFIX, requires = "nats")]
+    nats_prefix: String,
+
+    /// debug mode: write the streams of the --pcap-name registrations to
+    /// pcapng files in that directory
+    #[arg(long = "pcap-dir", requires = "pcap_name")]
+    pcap_dir: Option<PathBuf>,
+
+    /// registration name to capture, can be repeated
+    #[arg(long = "pcap-name", requires = "pcap_dir")]
+    pcap_name: Vec<String>,
+
+    /// server port of the captured synthetic tcp connections, so Wireshark
+    /// picks the right dissector
+    #[arg(long = "pcap-port", default_value_t = 80, requires = "pcap_dir")]
+    pcap_port: u16,
+
+    /// debug mode: record the frames of all agents sessions to files in
+    /// that directory
+    #[arg(long = "record-dir")]
+    record_dir: Option<PathBuf>,
+
+    /// unix socket used to restart the server without dropping connections.
+    /// A new server started with the same socket takes over the listeners of
+    /// the running one, which then shuts down (unix only)
+    #[arg(long)]
+    handoff: Option<PathBuf>,
+
+    /// drop the root privileges to that user (name or uid) once the server
+    /// listeners are bound (linux only)
+    #[arg(long)]
+    user: Option<String>,
+
+    /// group (name or gid) of the dropped privileges. default to the primary
+    /// group of the user
+    #[arg(long, requires = "user")]
+    group: Option<String>,
+
+    /// restrict the process with landlock and seccomp, only the system
+    /// libraries and configuration and the files given to the server are
+    /// accessible (linux only)
+    #[arg(long)]
+    sandbox: bool,
+
+    /// serve the admin api (metrics and open streams) on that address. A non
+    /// loopback address requires --admin-token
+    #[arg(long = "admin-listen", env = "DIGLETT_ADMIN_LISTEN")]
+    admin_listen: Option<SocketAddr>,
+
+    /// require the token of that file as a bearer token on the admin api
+    /// requests
+    #[arg(
+        long = "admin-token",
+        env = "DIGLETT_ADMIN_TOKEN",
+        requires = "admin_listen"
+    )]
+    admin_token: Option<PathBuf>,
+
+    /// accept agents and clients over websockets on that address (like the
+    /// ones running in browsers)
+    #[arg(long = "websocket-listen")]
+    websocket_listen: Option<SocketAddr>,
+
+    /// serve all registrations over a single http listener on that address,
+    /// requests are routed by their host header
+    #[arg(
+        long = "http-listen",
+        env = "DIGLETT_HTTP_LISTEN",
+        requires = "http_domain"
+    )]
+    http_listen: Option<SocketAddr>,
+
+    /// domain of the http router, `<name>.<domain>` is routed to the agent
+    /// that registered `name`
+    #[arg(
+        long = "http-domain",
+        env = "DIGLETT_HTTP_DOMAIN",
+        requires = "http_listen"
+    )]
+    http_domain: Option<String>,
+
+    /// scheme of the urls of the routed names reported to the agents, https
+    /// if tls is terminated in front of the router
+    #[arg(long = "http-scheme", default_value = "http")]
+    http_scheme: String,
+
+    /// html page returned by the http router for domains that are offline
+    #[arg(long = "offline-page", requires = "http_listen")]
+    offline_page: Option<PathBuf>,
+
+    /// json body returned by the http router for domains that are offline
+    /// to clients that accept json
+    #[arg(long = "offline-json", requires = "http_listen")]
+    offline_json: Option<PathBuf>,
+
+    /// require basic-auth credentials for a routed name, in the format
+    /// name=user:password. Can be repeated
+    #[arg(long = "http-basic-auth", requires = "http_listen", value_parser = parse_basic_auth)]
+    http_basic_auth: Vec<(String, String, String)>,
+
+    /// require an oauth login for a routed name, can be repeated. The
+    /// provider is configured with the other --oauth-* flags
+    #[arg(long = "oauth-name", requires_all = ["http_listen", "oauth_authorize_url", "oauth_token_url", "oauth_client_id", "oauth_client_secret"])]
+    oauth_name: Vec<String>,
+
+    /// authorization endpoint of the oauth provider
+    #[arg(long = "oauth-authorize-url")]
+    oauth_authorize_url: Option<Url>,
+
+    /// token endpoint of the oauth provider
+    #[arg(long = "oauth-token-url")]
+    oauth_token_url: Option<Url>,
+
+    /// oauth client id of the gateway
+    #[arg(long = "oauth-client-id")]
+    oauth_client_id: Option<String>,
+
+    /// oauth client secret of the gateway
+    #[arg(
+        long = "oauth-client-secret",
+        env = "DIGLETT_OAUTH_CLIENT_SECRET",
+        hide_env_values = true
+    )]
+    oauth_client_secret: Option<String>,
+
+    /// only allow users with an email of that domain, can be repeated
+    #[arg(long = "oauth-allow-domain")]
+    oauth_allow_domain: Vec<String>,
+
+    /// scheme of the public urls of the routed names (used in the oauth
+    /// redirect uri)
+    #[arg(long = "oauth-scheme", default_value = "https")]
+    oauth_scheme: String,
+
+    /// answer the dns queries (udp) of the registered names on that address,
+    /// so the zone can be delegated to the gateway
+    #[arg(long = "dns-listen", requires = "dns_zone")]
+    dns_listen: Option<SocketAddr>,
+
+    /// dns zone of the gateway, `<name>.<zone>` is answered while `name`
+    /// is registered
+    #[arg(long = "dns-zone", requires = "dns_listen")]
+    dns_zone: Option<String>,
+
+    /// address of the gateway returned in the dns answers, can be repeated.
+    /// Default to the public ip
+    #[arg(long = "dns-address", requires = "dns_listen")]
+    dns_address: Vec<IpAddr>,
+
+    /// answer the registered names with a CNAME to that host instead
+    /// of addresses
+    #[arg(
+        long = "dns-alias",
+        requires = "dns_listen",
+        conflicts_with = "dns_address"
+    )]
+    dns_alias: Option<String>,
+
+    /// ttl in seconds of the dns answers
+    #[arg(long = "dns-ttl", default_value_t = diglett::server::dns::TTL)]
+    dns_ttl: u32,
+
+    /// allow registering names with multiple labels (for example `api.example`)
+    #[arg(long = "allow-dotted-names")]
+    allow_dotted_names: bool,
+
+    /// name that can't be registered, can be repeated
+    #[arg(long = "deny-name")]
+    deny_name: Vec<String>,
+
+    /// deny names that ends with that suffix (for example the gateway own
+    /// host name), can be repeated
+    #[arg(long = "deny-suffix")]
+    deny_suffix: Vec<String>,
+
+    /// deny names that matches that regular expression, can be repeated
+    #[arg(long = "deny-pattern")]
+    deny_pattern: Vec<Regex>,
+
+    /// require agents to connect over mutual tls with that server certificate.
+    /// Agents are then authenticated by the subject of their certificate
+    #[arg(long = "tls-cert", requires_all = ["tls_key", "tls_client_ca"])]
+    tls_cert: Option<PathBuf>,
+
+    /// private key of the server certificate
+    #[arg(long = "tls-key", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// ca certificate that signs the agents certificates
+    #[arg(long = "tls-client-ca", requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// scope registered names under the agent certificate subject (or the
+    /// user of its signed token) as `<name>.<subject>`
+    #[arg(long, requires = "authenticated")]
+    namespace: bool,
+
+    /// only accept agents with tokens signed by the secret of that file (see
+    /// the token command), instead of accepting any token
+    #[arg(
+        long = "token-secret",
+        env = "DIGLETT_TOKEN_SECRET",
+        conflicts_with = "tls_cert"
+    )]
+    token_secret: Option<PathBuf>,
+
+    /// refuse agents that don't support at least that wire version
+    #[arg(long = "min-version", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=VERSION as i64))]
+    min_version: u8,
+
+    /// what happens to a connected agent when another agent logs in with
+    /// the same token (or certificate): allow, warn or disconnect
+    #[arg(long = "duplicate-login", default_value = "warn", value_parser = parse_duplicate_login)]
+    duplicate_login: DuplicateLogin,
+
+    /// run in the background, detached from the terminal. The logs are
+    /// discarded unless they go to a log file
+    #[arg(long, conflicts_with = "stdio")]
+    daemon: bool,
+
+    /// write the pid of the server to that file, it is removed when the
+    /// server exits
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+
+    /// write the logs to that file instead of stderr, the file is rotated
+    /// and the rotated files are kept as `<file>.1`, `<file>.2`, ...
+    #[arg(long = "log-file", env = "DIGLETT_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// rotate the log file once it reaches that size (like 10M)
+    #[arg(long = "log-max-size", default_value = "10M", value_parser = parse_size, requires = "log_file")]
+    log_max_size: u64,
+
+    /// rotate the log file once it is that old (like 1d), even if it's
+    /// smaller
+    #[arg(long = "log-max-age", value_parser = parse_duration, requires = "log_file")]
+    log_max_age: Option<Duration>,
+
+    /// number of rotated log files to keep
+    #[arg(long = "log-keep", default_value_t = logs::KEEP, requires = "log_file")]
+    log_keep: usize,
+
+    /// format of the logs, `json` logs a json object per line with stable
+    /// field names (including the fields of the access logs)
+    #[arg(
+        long = "log-format",
+        env = "DIGLETT_LOG_FORMAT",
+        default_value = "text"
+    )]
+    log_format: Format,
+
+    /// level of the logs: error, warn, info, debug or trace
+    #[arg(long = "log-level", env = "DIGLETT_LOG", default_value = "info")]
+    log_level: LevelFilter,
+
+    /// enable debugging logs, it overrides --log-level
     #[arg(short, long, action=ArgAction::Count)]
     debug: u8,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// generate a new server key and write it to the file (as given to
+    /// --key), only readable by its owner. The public key and its
+    /// fingerprint are printed, so they can be pinned by the agents
+    Keygen {
+        /// file of the secret key
+        path: PathBuf,
+
+        /// replace the file if it exists
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// print the public key and the fingerprint of the server key file (as
+    /// given to --key), so they can be handed to the agents
+    Fingerprint {
+        /// file of the secret key
+        path: PathBuf,
+    },
+
+    /// mint a token of a user, accepted by a server started with the same
+    /// --token-secret
+    Token {
+        /// file of the secret that signs the tokens
+        #[arg(long)]
+        secret: PathBuf,
+
+        /// the user of the token
+        #[arg(long)]
+        user: String,
+
+        /// domains the user can register, a domain starting with `*.`
+        /// matches its subdomains. Can be repeated or comma separated
+        #[arg(long, required = true, value_delimiter = ',')]
+        domains: Vec<String>,
+
+        /// expire the token after that time, like 12h or 30d. Without it the
+        /// token never expires
+        #[arg(long, value_parser = parse_duration)]
+        expires: Option<Duration>,
+    },
+}
+
+fn main() -> Result<()> {
     let args = Args::parse();
 
-    simple_logger::SimpleLogger::default()
-        .with_level(match args.debug {
-            0 => log::LevelFilter::Info,
-            1 => log::LevelFilter::Debug,
-            _ => log::LevelFilter::Trace,
-        })
-        .with_utc_timestamps()
-        .init()
-        .unwrap();
+    if let Err(err) = logger(&args) {
+        eprintln!("failed to open the log file: {}", err);
+        std::process::exit(1);
+    }
+
+    match &args.command {
+        Some(Command::Keygen { path, force }) => {
+            if let Err(err) = keygen(path, *force) {
+                eprintln!("failed to generate the server key: {}", err);
+                std::process::exit(1);
+            }
+
+            return Ok(());
+        }
+        Some(Command::Fingerprint { path }) => {
+            // the key file is only read, never created
+            let kp = std::fs::metadata(path)
+                .map_err(Error::from)
+                .and_then(|_| keypair_from_file(path));
+            match kp {
+                Ok(kp) => {
+                    println!("public key: {}", kp.public_key());
+                    println!("fingerprint: {}", fingerprint(&kp.public_key()));
+                }
+                Err(err) => {
+                    eprintln!("failed to load the server key: {}", err);
+                    std::process::exit(1);
+                }
+            }
+
+            return Ok(());
+        }
+        Some(Command::Token {
+            secret,
+            user,
+            domains,
+            expires,
+        }) => {
+            let signed = match std::fs::read(secret) {
+                Ok(secret) => Signed::new(secret.trim_ascii())?,
+                Err(err) => {
+                    eprintln!("failed to read the token secret: {}", err);
+                    std::process::exit(1);
+                }
+            };
+
+            let mut claims = domains
+                .iter()
+                .fold(Claims::new(user), |claims, domain| claims.domain(domain));
+            if let Some(expires) = expires {
+                claims = claims.expires_in(*expires);
+            }
+
+            println!("{}", signed.mint(&claims));
+            return Ok(());
+        }
+        None => {}
+    }
 
-    if let Err(err) = app(args).await {
+    // a relay doesn't terminate the tunnels, so it has no key
+    if let Some(upstream) = &args.relay {
+        let pidfile = detach(&args);
+        let runtime = tokio::runtime::Runtime::new()?;
+        let relay = Relay::new(upstream).start_until(&args.listen, shutdown());
+        let result = runtime.block_on(relay);
+        drop(pidfile);
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    let kp = match &args.key {
+        Some(path) => keypair_from_file(path),
+        None => Ok(keypair()),
+    };
+    let kp = match kp {
+        Ok(kp) => kp,
+        Err(err) => {
+            eprintln!("failed to load the server key: {}", err);
+            std::process::exit(1);
+        }
+    };
+    log::info!(
+        "server public key: {} ({})",
+        kp.public_key(),
+        fingerprint(&kp.public_key())
+    );
+
+    // before the runtime starts its threads
+    let pidfile = detach(&args);
+    #[cfg(not(target_os = "linux"))]
+    if args.sandbox {
+        eprintln!("the sandbox is only supported on linux");
+        std::process::exit(1);
+    }
+
+    #[cfg(target_os = "linux")]
+    if args.sandbox {
+        match sandbox(&args).apply() {
+            Ok(true) => log::info!("process is sandboxed"),
+            Ok(false) => {}
+            Err(err) => {
+                eprintln!("failed to sandbox the process: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(app(args, kp));
+    // the pid file is removed before the process exits
+    drop(pidfile);
+    if let Err(err) = result {
         eprintln!("{}", err);
         std::process::exit(1);
     }
@@ -39,9 +600,556 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn app(args: Args) -> Result<()> {
+// detach the server and write its pid file, as requested. The server exits
+// if it fails
+fn detach(args: &Args) -> Option<Pidfile> {
+    let pidfile = match args.daemon {
+        true => daemonize(args.pidfile.as_deref()),
+        false => args.pidfile.as_ref().map(Pidfile::create).transpose(),
+    };
+
+    match pidfile {
+        Ok(pidfile) => pidfile,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// log to stderr or to the log file
+fn logger(args: &Args) -> Result<()> {
+    let level = match args.debug {
+        0 => args.log_level,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let rotation = Rotation {
+        size: Some(args.log_max_size),
+        age: args.log_max_age,
+        keep: args.log_keep,
+    };
+    let file = args
+        .log_file
+        .as_ref()
+        .map(|path| LogFile::open(path, rotation))
+        .transpose()?;
+
+    logs::init(level, args.log_format, file)
+}
+
+// write a new keypair to the file and print its public key
+fn keygen(path: &Path, force: bool) -> Result<()> {
+    if force {
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+
     let kp = keypair();
-    let server = Server::new(kp, AuthorizeAll, PrintRegisterer);
+    keypair_to_file(&kp, path)?;
+    println!("public key: {}", kp.public_key());
+    println!("fingerprint: {}", fingerprint(&kp.public_key()));
+    Ok(())
+}
+
+async fn app(args: Args, kp: Keypair) -> Result<()> {
+    let mut builder = Server::builder().keypair(kp).config(server_config(&args)?);
+    if let Some(hooks) = hooks(&args)? {
+        builder = builder.hooks(hooks);
+    }
+
+    if let (Some(cert), Some(key), Some(ca)) = (&args.tls_cert, &args.tls_key, &args.tls_client_ca)
+    {
+        let config = tls::server_config(
+            tls::certificates(cert)?,
+            tls::private_key(key)?,
+            tls::certificates(ca)?,
+        )?;
+
+        let mut builder = builder.auth(CertAuth::new()).tls(config);
+        if args.namespace {
+            builder = builder.namespace(UserNamespace);
+        }
+
+        return run(builder, args).await;
+    }
+
+    if let Some(secret) = &args.token_secret {
+        let mut signed = Signed::new(std::fs::read(secret)?.trim_ascii())?;
+        if let Some(domain) = &args.http_domain {
+            signed = signed.with_domain(domain);
+        }
+        if args.namespace {
+            signed = signed.scoped();
+        }
+
+        let mut builder = builder.auth(signed);
+        if args.namespace {
+            builder = builder.namespace(UserNamespace);
+        }
+
+        return run(builder, args).await;
+    }
+
+    run(builder, args).await
+}
+
+// the options of the server that are plain values
+fn server_config(args: &Args) -> Result<ServerConfig> {
+    let mut config = ServerConfig::default();
+    config.limits = Limits {
+        agents: args.max_agents,
+        names: args.max_names,
+    };
+    config.client_limits = ClientLimits {
+        registration: args.max_ip_connections,
+        global: args.max_ip_connections_global,
+    };
+
+    // Mbit/s to bytes per second
+    let rate = |mbit: f64| (mbit * 1_000_000.0 / 8.0) as u64;
+    config.bandwidth = Bandwidth {
+        registration: args.registration_bandwidth.map(rate),
+        stream: args.stream_bandwidth.map(rate),
+    };
+
+    if args.handshake_rate > 0.0 {
+        config.handshake_limit = Some(RateLimit {
+            rate: args.handshake_rate,
+            burst: args.handshake_burst,
+            ban: Duration::from_secs(args.handshake_ban),
+        });
+    }
+
+    config.admin = args.admin_listen;
+    if let Some(token) = &args.admin_token {
+        let token = std::fs::read_to_string(token)?;
+        config.admin_token = Some(token.trim().into());
+    }
+    config.websocket = args.websocket_listen;
+    config.min_version = args.min_version;
+    config.duplicate_login = args.duplicate_login;
+
+    if let Some(strategy) = args.balance {
+        config.balancing = config.balancing.global(strategy);
+    }
+    for (name, strategy) in &args.balance_name {
+        config.balancing = config.balancing.name(name, *strategy);
+    }
+
+    if args.hold > 0 {
+        config.hold = Some(Duration::from_secs(args.hold));
+    }
+
+    if args.lease_ttl > 0 {
+        config.lease = Some(Duration::from_secs(args.lease_ttl));
+    }
+
+    if let Some(ip) = args.public {
+        let host = args.advertise.clone().unwrap_or_else(|| ip.to_string());
+        let mut public = Public::new(ip, host);
+        for (name, port) in &args.port_map {
+            public = public.map(name, *port);
+        }
+
+        if let Some(range) = &args.port_range {
+            public = public.range(range.clone());
+        }
+
+        config.bind = Bind::Public(public);
+    }
+
+    if args.allow_dotted_names {
+        config.validation = Validation::default().allow_dots();
+    }
+
+    config.recordings = args.record_dir.clone();
+    #[cfg(unix)]
+    {
+        config.handoff = args.handoff.clone();
+    }
+
+    Ok(config)
+}
+
+fn hooks(args: &Args) -> Result<Option<HookSet>> {
+    let mut hooks = HookSet::default();
+    if !args.webhook.is_empty() {
+        hooks = hooks.with(Webhooks::new(args.webhook.clone()));
+    }
+
+    if let Some(url) = &args.nats {
+        hooks = hooks.with(Nats::new(url.clone(), &args.nats_prefix)?);
+    }
+
+    Ok(if hooks.is_empty() { None } else { Some(hooks) })
+}
+
+async fn run<A>(mut builder: ServerBuilder<A, PrintRegisterer>, args: Args) -> Result<()>
+where
+    A: Authenticate,
+    A::U: Clone + Eq + Hash + Sync,
+{
+    if let Some(dir) = &args.pcap_dir {
+        let mut pcap = Pcap::new(dir).port(args.pcap_port);
+        for name in &args.pcap_name {
+            pcap = pcap.name(name);
+        }
+
+        builder = builder.tap(pcap.start());
+    }
+
+    if let (Some(listen), Some(zone)) = (args.dns_listen, &args.dns_zone) {
+        let mut dns = Dns::new(listen, zone).ttl(args.dns_ttl);
+        if let Some(alias) = &args.dns_alias {
+            dns = dns.alias(alias);
+        } else if !args.dns_address.is_empty() {
+            for ip in &args.dns_address {
+                dns = dns.address(*ip);
+            }
+        } else if let Some(ip) = args.public {
+            dns = dns.address(ip);
+        } else {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--dns-listen requires --dns-address, --dns-alias or --public",
+                )
+                .exit();
+        }
+
+        builder = builder.dns(dns);
+    }
+
+    if let (Some(listen), Some(domain)) = (args.http_listen, &args.http_domain) {
+        let mut router = HttpRouter::new(listen, domain).scheme(&args.http_scheme);
+        if let Some(page) = &args.offline_page {
+            router = router.offline_html(tokio::fs::read_to_string(page).await?);
+        }
+
+        if let Some(json) = &args.offline_json {
+            router = router.offline_json(tokio::fs::read_to_string(json).await?);
+        }
+
+        for (name, user, password) in &args.http_basic_auth {
+            router = router.basic_auth(name, user, password);
+        }
+
+        if let (Some(authorize), Some(token), Some(id), Some(secret)) = (
+            &args.oauth_authorize_url,
+            &args.oauth_token_url,
+            &args.oauth_client_id,
+            &args.oauth_client_secret,
+        ) {
+            let mut oauth = OAuth::new(authorize.clone(), token.clone(), id, secret)?
+                .scheme(&args.oauth_scheme);
+            for domain in &args.oauth_allow_domain {
+                oauth = oauth.allow_domain(domain);
+            }
+
+            let oauth = Arc::new(oauth);
+            for name in &args.oauth_name {
+                router = router.oauth(name, Arc::clone(&oauth));
+            }
+        }
+
+        builder = builder.http_router(router);
+    }
+
+    if !args.deny_name.is_empty() || !args.deny_suffix.is_empty() || !args.deny_pattern.is_empty() {
+        let mut denylist = Denylist::new();
+        for name in args.deny_name {
+            denylist = denylist.name(name);
+        }
+        for suffix in args.deny_suffix {
+            denylist = denylist.suffix(suffix);
+        }
+        for pattern in args.deny_pattern {
+            denylist = denylist.pattern(pattern);
+        }
+
+        builder = builder.denylist(denylist);
+    }
+
+    if let Some(db) = args.geoip_db {
+        let policy = if !args.allow_country.is_empty() {
+            Policy::Allow(args.allow_country.into_iter().collect())
+        } else {
+            Policy::Deny(args.deny_country.into_iter().collect())
+        };
+
+        builder = builder.geoip(GeoFilter::new(MaxMind::open(db)?).global(policy));
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(user) = &args.user {
+        builder = builder.privileges(Privileges::lookup(user, args.group.as_deref())?);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if args.user.is_some() || args.group.is_some() {
+        return Err(Error::Config(
+            "dropping privileges is only supported on linux".into(),
+        ));
+    }
+
+    // systemd socket activation
+    #[cfg(unix)]
+    if let Some(listeners) = Listeners::activated()? {
+        builder = builder.listeners(listeners);
+    }
+
+    #[cfg(not(unix))]
+    if args.handoff.is_some() {
+        return Err(Error::Config("handoff is only supported on unix".into()));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &args.handoff {
+        match Listeners::take_over(path).await {
+            Ok(listeners) => builder = builder.listeners(listeners),
+            // no server is running
+            Err(Error::IO(err))
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+                ) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    let server = builder.build()?;
+    #[cfg(unix)]
+    {
+        let toggle = signal(SignalKind::user_defined1())?;
+        let evict = signal(SignalKind::user_defined2())?;
+        tokio::spawn(maintenance(server.maintenance(), toggle, evict));
+    }
+
+    if args.stdio {
+        return server
+            .serve_until(Pipes::stdio(), ssh_client(), shutdown())
+            .await;
+    }
 
-    server.start(args.listen).await
+    server.start_until(args.listen, shutdown()).await
+}
+
+// address of the agent of a stdio server, from the environment of the ssh
+// session (`SSH_CLIENT=<ip> <port> <server port>`)
+fn ssh_client() -> SocketAddr {
+    std::env::var("SSH_CLIENT")
+        .ok()
+        .and_then(|client| {
+            let mut fields = client.split_whitespace();
+            let ip = fields.next()?.parse().ok()?;
+            let port = fields.next()?.parse().ok()?;
+            Some(SocketAddr::new(ip, port))
+        })
+        .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 0)))
+}
+
+// the sandbox allows reading the system libraries and configuration (for name
+// resolution) and the configured files, and writing to the output directories
+#[cfg(target_os = "linux")]
+fn sandbox(args: &Args) -> Sandbox {
+    let mut sandbox = Sandbox::default()
+        .read("/etc")
+        .read("/usr")
+        .read("/lib")
+        .read("/lib64");
+
+    for file in files(args) {
+        sandbox = sandbox.read(file);
+    }
+
+    for dir in [&args.pcap_dir, &args.record_dir].into_iter().flatten() {
+        sandbox = sandbox.write(dir);
+    }
+
+    // the pid file is removed from its directory
+    if let Some(dir) = args.pidfile.as_deref().and_then(Path::parent) {
+        if !dir.as_os_str().is_empty() {
+            sandbox = sandbox.write(dir);
+        }
+    }
+
+    // the log file is rotated (and the old files removed) in its directory
+    if let Some(file) = &args.log_file {
+        match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => sandbox = sandbox.write(dir),
+            _ => sandbox = sandbox.write("."),
+        }
+    }
+
+    // the handoff socket is created (and removed) in its directory
+    if let Some(handoff) = &args.handoff {
+        match handoff.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => sandbox = sandbox.write(dir),
+            _ => sandbox = sandbox.write("."),
+        }
+    }
+
+    sandbox
+}
+
+// the files that are read once the process is sandboxed
+#[cfg(target_os = "linux")]
+fn files(args: &Args) -> impl Iterator<Item = &PathBuf> {
+    [
+        &args.geoip_db,
+        &args.offline_page,
+        &args.offline_json,
+        &args.tls_cert,
+        &args.tls_key,
+        &args.tls_client_ca,
+        &args.token_secret,
+        &args.admin_token,
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// SIGUSR1 toggles maintenance mode, SIGUSR2 evicts all agents
+#[cfg(unix)]
+async fn maintenance(maintenance: Maintenance, mut toggle: Signal, mut evict: Signal) {
+    loop {
+        tokio::select! {
+            _ = toggle.recv() => {
+                match maintenance.mode() {
+                    Mode::Off => maintenance.set(Mode::On),
+                    _ => maintenance.set(Mode::Off),
+                }
+            },
+            _ = evict.recv() => maintenance.set(Mode::Evict),
+        }
+    }
+}
+
+async fn shutdown() {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate() => {},
+    }
+
+    log::info!("shutting down");
+}
+
+#[cfg(unix)]
+async fn terminate() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    terminate.recv().await;
+}
+
+// only ctrl-c shuts the server down on other platforms
+#[cfg(not(unix))]
+async fn terminate() {
+    std::future::pending().await
+}
+
+// country codes are compared in upper case
+fn parse_country(value: &str) -> std::result::Result<String, String> {
+    let country = value.trim();
+    if country.is_empty() {
+        return Err("empty country code".into());
+    }
+
+    Ok(country.to_uppercase())
+}
+
+fn parse_port_map(value: &str) -> std::result::Result<(String, u16), String> {
+    let (name, port) = value
+        .split_once('=')
+        .ok_or_else(|| "expected format name=port".to_string())?;
+
+    let port = port
+        .parse()
+        .map_err(|err| format!("invalid port: {}", err))?;
+    Ok((name.into(), port))
+}
+
+fn parse_basic_auth(value: &str) -> std::result::Result<(String, String, String), String> {
+    let (name, credentials) = value
+        .split_once('=')
+        .ok_or_else(|| "expected format name=user:password".to_string())?;
+    let (user, password) = credentials
+        .split_once(':')
+        .ok_or_else(|| "expected format name=user:password".to_string())?;
+
+    Ok((name.into(), user.into(), password.into()))
+}
+
+fn parse_size(value: &str) -> std::result::Result<u64, String> {
+    logs::parse_size(value).map_err(|err| err.to_string())
+}
+
+fn parse_duration(value: &str) -> std::result::Result<Duration, String> {
+    logs::parse_duration(value).map_err(|err| err.to_string())
+}
+
+fn parse_strategy(value: &str) -> std::result::Result<Strategy, String> {
+    match value {
+        "round-robin" => Ok(Strategy::RoundRobin),
+        "ip-hash" => Ok(Strategy::IpHash),
+        _ => Err("expected round-robin or ip-hash".into()),
+    }
+}
+
+fn parse_duplicate_login(value: &str) -> std::result::Result<DuplicateLogin, String> {
+    match value {
+        "allow" => Ok(DuplicateLogin::Allow),
+        "warn" => Ok(DuplicateLogin::Warn),
+        "disconnect" => Ok(DuplicateLogin::Disconnect),
+        _ => Err("expected allow, warn or disconnect".into()),
+    }
+}
+
+fn parse_balance_name(value: &str) -> std::result::Result<(String, Strategy), String> {
+    let (name, strategy) = value
+        .split_once('=')
+        .ok_or_else(|| "expected format name=strategy".to_string())?;
+
+    Ok((name.into(), parse_strategy(strategy)?))
+}
+
+fn parse_port_range(value: &str) -> std::result::Result<RangeInclusive<u16>, String> {
+    let (from, to) = value
+        .split_once('-')
+        .ok_or_else(|| "expected format from-to".to_string())?;
+
+    let from: u16 = from
+        .parse()
+        .map_err(|err| format!("invalid port: {}", err))?;
+    let to: u16 = to.parse().map_err(|err| format!("invalid port: {}", err))?;
+    if from > to {
+        return Err("invalid port range".into());
+    }
+
+    Ok(from..=to)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sandbox_files() {
+        let args = Args::try_parse_from([
+            "diglett-server",
+            "--sandbox",
+            "--token-secret",
+            "/tmp/secret.txt",
+            "--admin-listen",
+            "127.0.0.1:9090",
+            "--admin-token",
+            "/tmp/admin.token",
+        ])
+        .unwrap();
+
+        let files: Vec<_> = files(&args).collect();
+        assert!(files.contains(&&PathBuf::from("/tmp/secret.txt")));
+        assert!(files.contains(&&PathBuf::from("/tmp/admin.token")));
+    }
 }
@@ -0,0 +1,312 @@
+//! forwarding of local listeners over dialed connections, like `ssh -L`
+use std::{future::Future, sync::Arc};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener,
+    },
+    sync::{watch, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{
+    agent::{KEEPALIVE_INTERVAL, MISSED_KEEPALIVES, SHUTDOWN_TIMEOUT},
+    wire::{
+        self, Connection, Control, FrameReader, FrameStream, FrameWriter, Message, Reason,
+        Registration, Split, Stream, StreamMap, Termination,
+    },
+    Error, Result,
+};
+
+type Locals = Arc<Mutex<StreamMap<Local>>>;
+type Writer<W, F> = Arc<Mutex<Connection<W, F>>>;
+
+/// forward the connections of the listener over a dialed connection until
+/// the gateway disconnects, the listener can then be forwarded again over a
+/// new connection
+pub async fn forward<S: Split>(
+    connection: Connection<S, FrameStream>,
+    listener: &TcpListener,
+) -> Result<()> {
+    forward_until(connection, listener, std::future::pending::<()>()).await
+}
+
+/// forward like [`forward`] until the shutdown future resolves. On shutdown
+/// the open streams are closed and the gateway is told the client terminates
+pub async fn forward_until<S: Split, D: Future>(
+    connection: Connection<S, FrameStream>,
+    listener: &TcpListener,
+    shutdown: D,
+) -> Result<()> {
+    tokio::pin!(shutdown);
+    let locals: Locals = Arc::new(Mutex::new(StreamMap::new(connection.version())));
+    let (reader, writer) = connection.split();
+    let writer = Arc::new(Mutex::new(writer));
+
+    let mut upstream = tokio::spawn(upstream(reader, Arc::clone(&locals), Arc::clone(&writer)));
+
+    let result = loop {
+        tokio::select! {
+            result = &mut upstream => {
+                break result.unwrap_or_else(|err| Err(std::io::Error::other(err).into()));
+            }
+            _ = &mut shutdown => {
+                let terminate = terminate(&locals, &writer);
+                if tokio::time::timeout(SHUTDOWN_TIMEOUT, terminate).await.is_err() {
+                    log::warn!("timed out terminating the connection to the gateway");
+                }
+                break Ok(());
+            }
+            accepted = listener.accept() => {
+                let (stream, addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => break Err(err.into()),
+                };
+
+                let id = Stream::new(Registration::from(0), addr.port());
+                // the lock is held until the stream is open, so its data
+                // can't be received before
+                let mut streams = locals.lock().await;
+                if streams.state(&id).is_some() {
+                    log::debug!("stream [{}] is still in use, rejecting connection", id);
+                    continue;
+                }
+
+                log::debug!("connection [{}] from {}", id, addr);
+                let (down, up) = stream.into_split();
+                let (paused, gate) = watch::channel(false);
+                let handler = tokio::spawn(downstream(
+                    id,
+                    down,
+                    gate,
+                    Arc::clone(&locals),
+                    Arc::clone(&writer),
+                ));
+
+                let _ = streams.open(
+                    id,
+                    Local {
+                        write: up,
+                        paused,
+                        handler,
+                    },
+                );
+            }
+        }
+    };
+
+    upstream.abort();
+    locals.lock().await.clear();
+
+    result
+}
+
+// a local connection carried over the gateway connection
+struct Local {
+    write: OwnedWriteHalf,
+    // the connection is not read while the gateway paused the stream
+    paused: watch::Sender<bool>,
+    handler: JoinHandle<()>,
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        self.handler.abort();
+    }
+}
+
+// close the open streams and tell the gateway the client terminates
+async fn terminate<W, F>(locals: &Locals, writer: &Writer<W, F>) -> Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+    F: FrameWriter,
+{
+    let mut locals = locals.lock().await;
+    let mut writer = writer.lock().await;
+    for id in locals.open_ids() {
+        let _local = locals.close(id);
+        writer.control(Control::Close { id }).await?;
+    }
+
+    writer
+        .terminate(Termination::new(
+            Reason::Shutdown,
+            "client is shutting down",
+        ))
+        .await
+}
+
+// close a stream from our side and tell the gateway. The gateway lock is
+// acquired while the streams are still locked so its ack can't be processed
+// before the stream is half closed
+async fn close<W, F>(locals: &mut StreamMap<Local>, id: Stream, writer: &Writer<W, F>)
+where
+    W: AsyncWrite + Unpin + Send,
+    F: FrameWriter,
+{
+    let Some(_local) = locals.close(id) else {
+        return;
+    };
+
+    if let Err(err) = writer.lock().await.control(Control::Close { id }).await {
+        log::debug!("failed to send close of stream [{}]: {}", id, err);
+    }
+}
+
+// forward the data of the gateway to the local connections, the gateway
+// pings the client so it's dead once it's silent for a few intervals
+async fn upstream<R, F, W, G>(
+    mut reader: Connection<R, F>,
+    locals: Locals,
+    writer: Writer<W, G>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+    F: FrameReader,
+    W: AsyncWrite + Unpin + Send,
+    G: FrameWriter,
+{
+    let silence = KEEPALIVE_INTERVAL * MISSED_KEEPALIVES;
+    loop {
+        let message = tokio::time::timeout(silence, reader.read())
+            .await
+            .map_err(|_| Error::GatewayTimeout(silence))??;
+
+        match message {
+            Message::Payload { id, data } => {
+                let mut locals = locals.lock().await;
+                let Some(local) = locals.get_mut(&id) else {
+                    log::trace!("dropping data of closed stream [{}]", id);
+                    continue;
+                };
+
+                if let Err(err) = local.write.write_all(&data).await {
+                    log::debug!("failed to write to connection [{}]: {}", id, err);
+                    close(&mut locals, id, &writer).await;
+                }
+            }
+            Message::Control(Control::Close { id }) => {
+                let mut locals = locals.lock().await;
+                locals.remote_closed(id);
+                if locals.acknowledged() {
+                    writer
+                        .lock()
+                        .await
+                        .control(Control::CloseAck { id })
+                        .await?;
+                }
+            }
+            Message::Control(Control::CloseAck { id }) => locals.lock().await.acked(id),
+            Message::Control(Control::Pause { id }) => {
+                if let Some(local) = locals.lock().await.get(&id) {
+                    local.paused.send_replace(true);
+                }
+            }
+            Message::Control(Control::Resume { id }) => {
+                if let Some(local) = locals.lock().await.get(&id) {
+                    local.paused.send_replace(false);
+                }
+            }
+            Message::Control(Control::Probe(seq)) => {
+                writer
+                    .lock()
+                    .await
+                    .control(Control::ProbeReply(seq))
+                    .await?;
+            }
+            Message::Control(Control::Ping | Control::ProbeReply(_)) => {}
+            Message::Terminate(termination) => return Err(Error::Terminated(termination)),
+            unexpected => {
+                log::debug!("received an unexpected message: {:?}", unexpected);
+            }
+        }
+    }
+}
+
+// forward the data of a local connection to the gateway until it closes
+async fn downstream<W, F>(
+    id: Stream,
+    mut down: OwnedReadHalf,
+    mut paused: watch::Receiver<bool>,
+    locals: Locals,
+    writer: Writer<W, F>,
+) where
+    W: AsyncWrite + Unpin + Send,
+    F: FrameWriter,
+{
+    let mut buf = vec![0; wire::MAX_PAYLOAD_SIZE];
+    loop {
+        if paused.wait_for(|paused| !paused).await.is_err() {
+            return;
+        }
+
+        let n = match down.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) => {
+                log::debug!("failed to read connection [{}]: {}", id, err);
+                break;
+            }
+        };
+
+        if let Err(err) = writer.lock().await.write(id, &mut buf[..n]).await {
+            log::debug!("failed to forward connection [{}]: {}", id, err);
+            break;
+        }
+    }
+
+    // dropping the stream aborts this task, so it's the last thing it does
+    let mut locals = locals.lock().await;
+    close(&mut locals, id, &writer).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        agent::{serve_all, Backend, Options},
+        wire::record::pair,
+    };
+    use std::collections::HashMap;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn forward() {
+        // the backend of the agent echoes the data
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = backend.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.into_split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        // the gateway serves the client like an agent does
+        let (client, gateway) = pair(wire::VERSION);
+        let backends = HashMap::from([(Registration::from(0), Backend::from(address))]);
+        tokio::spawn(serve_all(gateway, backends, Options::default()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let forwarding =
+            tokio::spawn(async move { forward_until(client, &listener, stopped).await });
+
+        for message in [&b"hello"[..], b"world"] {
+            let mut stream = TcpStream::connect(local).await.unwrap();
+            stream.write_all(message).await.unwrap();
+            let mut buf = vec![0; message.len()];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, message);
+        }
+
+        stop.send(()).unwrap();
+        forwarding.await.unwrap().unwrap();
+    }
+}
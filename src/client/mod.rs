@@ -0,0 +1,81 @@
+//! Private access to the names served by agents, like `ssh -L`. A client
+//! logs in with a token of the user that owns the name, and each connection
+//! to its local listener is carried to the agent of the name through the
+//! gateway instead of a public listener:
+//!
+//! ```no_run
+//! # async fn example() -> diglett::Result<()> {
+//! use diglett::{client, wire::{keypair, Client}};
+//! use tokio::net::{TcpListener, TcpStream};
+//!
+//! let stream = TcpStream::connect("gateway.com:20000").await?;
+//! let mut connection = Client::new(stream, keypair()).negotiate().await?;
+//! client::dial(&mut connection, "db", "secret").await?;
+//!
+//! let listener = TcpListener::bind("127.0.0.1:5432").await?;
+//! client::forward_until(connection, &listener, tokio::signal::ctrl_c()).await
+//! # }
+//! ```
+//!
+//! Dialing is portable, in browsers (wasm32) the connection is a websocket
+//! to the gateway (see `wire::websocket`) and the streams are opened over
+//! the dialed connection directly
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    wire::{Connection, Control, FrameReader, FrameWriter, Metadata},
+    Error, Result,
+};
+
+// the streams are forwarded like the streams of an agent
+#[cfg(all(feature = "agent", not(target_arch = "wasm32")))]
+mod forward;
+
+#[cfg(all(feature = "agent", not(target_arch = "wasm32")))]
+pub use forward::{forward, forward_until};
+
+/// first wire version that supports clients
+pub const DIAL_VERSION: u8 = 7;
+
+/// dial the name and login, the streams of the connection then reach the
+/// agent of the name. The name must be served by an agent of the same user
+pub async fn dial<N: Into<String>, T: Into<String>, S, F>(
+    connection: &mut Connection<S, F>,
+    name: N,
+    token: T,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    F: FrameReader + FrameWriter,
+{
+    if connection.version() < DIAL_VERSION {
+        return Err(Error::InvalidVersion(connection.version()));
+    }
+
+    connection
+        .control(Control::Dial { name: name.into() })
+        .await?;
+    // a client logs in like an agent does, without labels
+    connection
+        .control(Control::Login {
+            token: token.into(),
+            labels: Metadata::default(),
+        })
+        .await?;
+    connection.read().await?.ok_or_err()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::record::pair;
+
+    #[tokio::test]
+    async fn old_gateway() {
+        let (mut client, _gateway) = pair(6);
+        assert!(matches!(
+            dial(&mut client, "db", "token").await,
+            Err(Error::InvalidVersion(6))
+        ));
+    }
+}
@@ -0,0 +1,160 @@
+//! Daemonize runs the binaries in the background on systems without a service
+//! manager (like systemd). The process forks twice to detach from its
+//! terminal and session, and its standard streams are redirected to
+//! `/dev/null`. The pid file records the pid of the detached process and is
+//! removed once it exits.
+//!
+//! It must be called before the tokio runtime (or any other thread) is
+//! started, since only the calling thread survives a fork
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::fd::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+};
+
+use crate::{Error, Result};
+
+/// Pidfile is removed when dropped
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// write the pid of the process to the file. It fails if the file
+    /// belongs to another running process
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(pid) = running(path) {
+            return Err(daemon_error(format!(
+                "already running with pid {} ({})",
+                pid,
+                path.display()
+            )));
+        }
+
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Self { path: path.into() })
+    }
+
+    /// path of the pid file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::debug!("failed to remove pid file {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// detach the process and write its pid to the pidfile if any. It only
+/// returns in the detached process, the calling process exits once the
+/// detached process is started (or failed to)
+pub fn daemonize(pidfile: Option<&Path>) -> Result<Option<Pidfile>> {
+    let (mut ready, mut started) = pipe()?;
+
+    // the calling process waits for the detached process to report
+    if fork()? != 0 {
+        drop(started);
+        let mut report = Vec::default();
+        let _ = ready.read_to_end(&mut report);
+        match report.as_slice() {
+            [0] => std::process::exit(0),
+            [] => eprintln!("failed to start in the background"),
+            message => eprintln!("{}", String::from_utf8_lossy(message)),
+        }
+        std::process::exit(1);
+    }
+    drop(ready);
+
+    // a new session without a controlling terminal, then fork again so the
+    // process can never acquire one
+    if unsafe { libc::setsid() } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if fork()? != 0 {
+        unsafe { libc::_exit(0) };
+    }
+
+    let pidfile = match pidfile.map(Pidfile::create).transpose() {
+        Ok(pidfile) => pidfile,
+        Err(err) => {
+            let _ = write!(started, "{}", err);
+            unsafe { libc::_exit(1) };
+        }
+    };
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    let _ = started.write_all(&[0]);
+    Ok(pidfile)
+}
+
+// pid of the running process recorded in the pidfile, if any
+fn running(path: &Path) -> Option<libc::pid_t> {
+    let pid: libc::pid_t = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    if pid <= 0 {
+        return None;
+    }
+
+    // the process exists even if it can't be signaled by this user
+    let alive = unsafe { libc::kill(pid, 0) } == 0
+        || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    alive.then_some(pid)
+}
+
+fn fork() -> Result<libc::pid_t> {
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        pid => Ok(pid),
+    }
+}
+
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+fn daemon_error(reason: String) -> Error {
+    Error::IO(std::io::Error::other(reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pidfile() {
+        let path = std::env::temp_dir().join(format!("diglett-pid-{}", std::process::id()));
+        // a stale pid file is replaced
+        std::fs::write(&path, "999999999\n").unwrap();
+
+        let pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        // this process is running
+        assert!(Pidfile::create(&path).is_err());
+
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,13 @@
+//! helpers of the dns wire format shared by the dns server of the gateway and
+//! the SRV discovery of the agent
+
+/// encode a name in the dns wire format
+pub(crate) fn encode(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        encoded.push(label.len() as u8);
+        encoded.extend(label.as_bytes());
+    }
+    encoded.push(0);
+    encoded
+}
@@ -0,0 +1,537 @@
+//! minimal http/1 support used by the http router, the admin api and the
+//! agent inspection ui, and a minimal client to post requests to external
+//! services (and query local ones over unix sockets)
+use std::{path::Path, time::Duration};
+#[cfg(feature = "server")]
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+#[cfg(feature = "server")]
+use tokio::io::ReadBuf;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+};
+use url::Url;
+
+use crate::{Error, Result};
+
+/// max size of the request head (request line and headers)
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+/// max time to wait for the request head
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// max size of a response read by the client
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+/// max size of a response of a local service, like a docker container inspection
+const MAX_LOCAL_RESPONSE_SIZE: u64 = 1024 * 1024;
+/// max time of a client request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Request head of an http request
+// the agent only needs the method and the path, the cookie is only read by
+// the oauth of the server (with tls)
+#[cfg_attr(not(all(feature = "server", feature = "tls")), allow(dead_code))]
+pub(crate) struct Request {
+    /// raw head as received, so it can be forwarded as is
+    pub head: Vec<u8>,
+    pub method: String,
+    pub path: String,
+    pub host: Option<String>,
+    /// value of the authorization header
+    pub authorization: Option<String>,
+    /// value of the cookie header
+    pub cookie: Option<String>,
+    // the client accepts json responses
+    pub json: bool,
+}
+
+impl Request {
+    pub(crate) fn parse(head: Vec<u8>) -> Self {
+        let mut host = None;
+        let mut authorization = None;
+        let mut cookie = None;
+        let mut json = false;
+
+        let text = String::from_utf8_lossy(&head);
+        let mut lines = text.split("\r\n");
+
+        let mut request = lines.next().unwrap_or_default().split_whitespace();
+        let method = request.next().unwrap_or_default().to_string();
+        let path = request.next().unwrap_or_default().to_string();
+
+        // the head can be followed by data of the body
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+
+            let value = value.trim();
+            if key.eq_ignore_ascii_case("host") {
+                host = Some(value.to_lowercase());
+            } else if key.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.to_string());
+            } else if key.eq_ignore_ascii_case("cookie") {
+                cookie = Some(value.to_string());
+            } else if key.eq_ignore_ascii_case("accept") {
+                json = value.contains("application/json");
+            }
+        }
+
+        Self {
+            head,
+            method,
+            path,
+            host,
+            authorization,
+            cookie,
+            json,
+        }
+    }
+
+    /// value of any header of the head
+    #[cfg(feature = "server")]
+    pub(crate) fn header(&self, name: &str) -> Option<String> {
+        String::from_utf8_lossy(&self.head)
+            .split("\r\n")
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().to_string())
+    }
+
+    /// the head with `Connection: close` instead of its connection headers, so
+    /// the service closes the connection after its response. The data read
+    /// after the head is returned as is
+    #[cfg(feature = "server")]
+    pub(crate) fn closing(&self) -> (Vec<u8>, &[u8]) {
+        let end = self
+            .head
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|end| end + 4)
+            .unwrap_or(self.head.len());
+
+        let text = String::from_utf8_lossy(&self.head[..end]);
+        let mut head = String::with_capacity(end + 32);
+        for line in text.split("\r\n").filter(|line| !line.is_empty()) {
+            let key = line.split_once(':').map(|(key, _)| key.trim());
+            if matches!(key, Some(key) if key.eq_ignore_ascii_case("connection") || key.eq_ignore_ascii_case("keep-alive"))
+            {
+                continue;
+            }
+            head.push_str(line);
+            head.push_str("\r\n");
+        }
+        head.push_str("Connection: close\r\n\r\n");
+
+        (head.into_bytes(), &self.head[end..])
+    }
+}
+
+/// Body of a request, it tracks which data after the request head is part
+/// of the request
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Body {
+    /// that many bytes are left
+    Length(u64),
+    /// a chunked body, it's complete once its trailer ends
+    Chunked(Chunked),
+}
+
+/// state of a chunked body
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Chunked {
+    // the size line of the next chunk, the extension (or line end) after
+    // the size is skipped
+    Size { size: u64, extension: bool },
+    Data(u64),
+    // the line end after the chunk data
+    DataEnd,
+    // the trailer after the last chunk, true at the start of a line
+    Trailer(bool),
+}
+
+#[cfg(feature = "server")]
+impl Body {
+    /// body of the request
+    pub(crate) fn of(request: &Request) -> Self {
+        let chunked = request
+            .header("transfer-encoding")
+            .map(|encoding| encoding.to_lowercase().contains("chunked"))
+            .unwrap_or(false);
+        if chunked {
+            return Self::Chunked(Chunked::Size {
+                size: 0,
+                extension: false,
+            });
+        }
+
+        Self::Length(
+            request
+                .header("content-length")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(0),
+        )
+    }
+
+    /// the whole body has been seen
+    pub(crate) fn done(&self) -> bool {
+        matches!(self, Self::Length(0))
+    }
+
+    /// consume the body from the data, returns how many bytes at the start of
+    /// data belong to the body. A malformed chunked body ends at the error
+    pub(crate) fn consume(&mut self, data: &[u8]) -> usize {
+        let mut used = 0;
+        while used < data.len() && !self.done() {
+            let left = (data.len() - used) as u64;
+            match self {
+                Self::Length(length) => {
+                    let n = left.min(*length);
+                    *length -= n;
+                    used += n as usize;
+                }
+                Self::Chunked(Chunked::Data(size)) => {
+                    let n = left.min(*size);
+                    *size -= n;
+                    used += n as usize;
+                    if *size == 0 {
+                        *self = Self::Chunked(Chunked::DataEnd);
+                    }
+                }
+                Self::Chunked(state) => {
+                    let byte = data[used];
+                    used += 1;
+                    *self = match (*state, byte) {
+                        (Chunked::Size { size: 0, .. }, b'\n') => {
+                            Self::Chunked(Chunked::Trailer(true))
+                        }
+                        (Chunked::Size { size, .. }, b'\n') => Self::Chunked(Chunked::Data(size)),
+                        (
+                            Chunked::Size {
+                                extension: true, ..
+                            },
+                            _,
+                        ) => continue,
+                        (Chunked::Size { size, .. }, byte) if byte.is_ascii_hexdigit() => {
+                            let digit = (byte as char).to_digit(16).unwrap_or_default() as u64;
+                            match size.checked_mul(16) {
+                                Some(size) => Self::Chunked(Chunked::Size {
+                                    size: size + digit,
+                                    extension: false,
+                                }),
+                                None => Self::Length(0),
+                            }
+                        }
+                        (Chunked::Size { size, .. }, b';' | b'\r' | b' ' | b'\t') => {
+                            Self::Chunked(Chunked::Size {
+                                size,
+                                extension: true,
+                            })
+                        }
+                        (Chunked::Size { .. }, _) => Self::Length(0),
+                        (Chunked::DataEnd, b'\n') => Self::Chunked(Chunked::Size {
+                            size: 0,
+                            extension: false,
+                        }),
+                        (Chunked::DataEnd, _) => continue,
+                        (Chunked::Trailer(true), b'\n') => Self::Length(0),
+                        (Chunked::Trailer(_), b'\n') => Self::Chunked(Chunked::Trailer(true)),
+                        (Chunked::Trailer(start), b'\r') => Self::Chunked(Chunked::Trailer(start)),
+                        (Chunked::Trailer(_), _) => Self::Chunked(Chunked::Trailer(false)),
+                        // data is consumed above
+                        (Chunked::Data(_), _) => continue,
+                    };
+                }
+            }
+        }
+
+        used
+    }
+}
+
+/// FirstRequest reads only the first request of a client stream (a head of
+/// that size and its body) and drops the data sent after it. The end of the
+/// stream is still passed through
+#[cfg(feature = "server")]
+pub(crate) struct FirstRequest<R> {
+    inner: R,
+    // bytes of the head that are not read yet, and the body after it.
+    // None reads the whole stream
+    request: Option<(usize, Body)>,
+}
+
+#[cfg(feature = "server")]
+impl<R> FirstRequest<R> {
+    pub(crate) fn new(inner: R, request: Option<(usize, Body)>) -> Self {
+        Self { inner, request }
+    }
+}
+
+#[cfg(feature = "server")]
+impl<R: AsyncRead + Unpin> AsyncRead for FirstRequest<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        let Some((head, body)) = &mut this.request else {
+            return Poll::Ready(Ok(()));
+        };
+
+        let read = &buf.filled()[start..];
+        if read.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+
+        let from_head = read.len().min(*head);
+        *head -= from_head;
+        let taken = from_head + body.consume(&read[from_head..]);
+        buf.set_filled(start + taken);
+        if taken == 0 {
+            // the read data is dropped, an empty read would end the stream
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// read the request head from the stream
+pub(crate) async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0; 1024];
+
+    tokio::time::timeout(HEAD_TIMEOUT, async {
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            if head.len() > MAX_HEAD_SIZE {
+                return Err(Error::InvalidRequest("request head is too large".into()));
+            }
+
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(Error::InvalidRequest("connection closed".into()));
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+
+        Ok(())
+    })
+    .await
+    .map_err(|_| Error::InvalidRequest("timed out reading request head".into()))??;
+
+    Ok(Request::parse(head))
+}
+
+/// write a full response and close the connection
+pub(crate) async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for (key, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    response.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    ));
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Response of a client request
+pub(crate) struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// post the body to the url. Https urls are only supported with the
+/// tls feature
+pub(crate) async fn post(url: &Url, content_type: &str, body: &str) -> Result<Response> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::Http(format!("url '{}' has no host", url)))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| Error::Http(format!("url '{}' has no port", url)))?;
+
+    // http/1.0 so the response is never chunked and ends with the connection
+    let request = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+        &url[url::Position::BeforePath..],
+        host,
+        content_type,
+        body.len(),
+        body
+    );
+
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let stream = TcpStream::connect((host, port)).await?;
+        match url.scheme() {
+            #[cfg(feature = "tls")]
+            "https" => {
+                use crate::tls;
+                let connector = tls::TlsConnector::from(tls::public_config());
+                let stream = connector.connect(tls::server_name(host)?, stream).await?;
+                exchange(stream, request.as_bytes(), MAX_RESPONSE_SIZE).await
+            }
+            "http" => exchange(stream, request.as_bytes(), MAX_RESPONSE_SIZE).await,
+            scheme => Err(Error::Http(format!("unsupported scheme '{}'", scheme))),
+        }
+    })
+    .await
+    .map_err(|_| Error::Http(format!("request to '{}' timed out", host)))??;
+
+    parse_response(&response)
+}
+
+/// get the path of the service listening on the unix socket
+pub(crate) async fn get_unix(socket: &Path, path: &str) -> Result<Response> {
+    let request = format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path);
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let stream = UnixStream::connect(socket).await?;
+        exchange(stream, request.as_bytes(), MAX_LOCAL_RESPONSE_SIZE).await
+    })
+    .await
+    .map_err(|_| Error::Http(format!("request to '{}' timed out", socket.display())))??;
+
+    parse_response(&response)
+}
+
+fn parse_response(response: &[u8]) -> Result<Response> {
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| Error::Http("invalid response".into()))?;
+
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| Error::Http("invalid response status".into()))?;
+
+    Ok(Response {
+        status,
+        body: response[split + 4..].to_vec(),
+    })
+}
+
+async fn exchange<S>(mut stream: S, request: &[u8], limit: u64) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request).await?;
+    stream.flush().await?;
+
+    let mut response = Vec::new();
+    stream.take(limit).read_to_end(&mut response).await?;
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let request = Request::parse(
+            b"GET /streams HTTP/1.1\r\nHOST: Web.Gateway.com\r\nAuthorization: Basic dXNlcg==\r\nAccept: application/json\r\n\r\n"
+                .to_vec(),
+        );
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/streams");
+        assert_eq!(request.host.as_deref(), Some("web.gateway.com"));
+        assert_eq!(request.authorization.as_deref(), Some("Basic dXNlcg=="));
+        assert!(request.json);
+
+        // headers can't be smuggled in the body
+        let request = Request::parse(
+            b"POST / HTTP/1.1\r\nHost: web\r\n\r\nAuthorization: Basic dXNlcg==\r\n".to_vec(),
+        );
+        assert_eq!(request.authorization, None);
+        #[cfg(feature = "server")]
+        assert_eq!(request.header("authorization"), None);
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn closing() {
+        let request = Request::parse(
+            b"GET / HTTP/1.1\r\nHost: web\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\n\r\nGET /next"
+                .to_vec(),
+        );
+
+        let (head, rest) = request.closing();
+        assert_eq!(
+            head,
+            b"GET / HTTP/1.1\r\nHost: web\r\nConnection: close\r\n\r\n"
+        );
+        assert_eq!(rest, b"GET /next");
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn body() {
+        let request = Request::parse(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n".to_vec());
+        let mut body = Body::of(&request);
+        assert_eq!(body.consume(b"hel"), 3);
+        assert_eq!(body.consume(b"loGET / HTTP/1.1"), 2);
+        assert!(body.done());
+
+        let request = Request::parse(b"GET / HTTP/1.1\r\n\r\n".to_vec());
+        assert!(Body::of(&request).done());
+
+        let request = Request::parse(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\nContent-Length: 1\r\n\r\n"
+                .to_vec(),
+        );
+        let mut body = Body::of(&request);
+        let chunked = b"5;ext=1\r\nhello\r\nA\r\n0123456789\r\n0\r\nTrailer: x\r\n\r\n";
+        // fed in pieces
+        assert_eq!(body.consume(&chunked[..7]), 7);
+        assert!(!body.done());
+        let mut data = chunked[7..].to_vec();
+        data.extend_from_slice(b"GET / HTTP/1.1");
+        assert_eq!(body.consume(&data), chunked.len() - 7);
+        assert!(body.done());
+
+        // malformed sizes end the body
+        let mut body = Body::Chunked(Chunked::Size {
+            size: 0,
+            extension: false,
+        });
+        assert_eq!(body.consume(b"zz\r\n"), 1);
+        assert!(body.done());
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn first_request() {
+        let data = b"GET / HTTP/1.1\r\n\r\nGET /next HTTP/1.1\r\n\r\n".to_vec();
+        let mut reader = FirstRequest::new(std::io::Cursor::new(data), Some((18, Body::Length(0))));
+
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"GET / HTTP/1.1\r\n\r\n");
+    }
+}
@@ -1,5 +1,36 @@
+#[cfg(all(feature = "agent", not(target_arch = "wasm32")))]
 pub mod agent;
+#[cfg(feature = "wire")]
+pub mod client;
+#[cfg(all(feature = "cli", unix))]
+pub mod daemon;
+#[cfg(all(
+    any(feature = "agent", feature = "server"),
+    not(target_arch = "wasm32")
+))]
+mod dns;
+#[cfg(all(
+    any(feature = "agent", feature = "server"),
+    not(target_arch = "wasm32")
+))]
+mod http;
+#[cfg(all(feature = "cli", not(target_arch = "wasm32")))]
+pub mod logs;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
 pub mod server;
+#[cfg(all(
+    any(feature = "agent", feature = "server"),
+    not(target_arch = "wasm32")
+))]
+pub mod shaping;
+#[cfg(all(feature = "tls", not(target_arch = "wasm32")))]
+pub mod tls;
+#[cfg(all(
+    any(feature = "agent", feature = "server"),
+    not(target_arch = "wasm32")
+))]
+mod window;
+#[cfg(feature = "wire")]
 pub mod wire;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -21,18 +52,228 @@ pub enum Error {
     #[error("remote error: {0}")]
     Remote(String),
 
+    #[cfg(feature = "wire")]
+    #[error("refused by remote ({0}): {1}")]
+    Refused(wire::Code, String),
+
+    #[cfg(feature = "wire")]
+    #[error("terminated by remote: {0}")]
+    Terminated(wire::Termination),
+
     #[error("authentication error: {0}")]
     AuthenticationError(String),
 
+    #[error("name '{0}' is in use by another user")]
+    NameInUse(String),
+
+    #[error("invalid name: {0}")]
+    InvalidName(String),
+
+    #[error("invalid http request: {0}")]
+    InvalidRequest(String),
+
+    #[error("http error: {0}")]
+    Http(String),
+
+    #[error("oauth error: {0}")]
+    OAuth(String),
+
+    #[error("replay diverged at frame {0}: {1}")]
+    Diverged(usize, String),
+
+    #[error("sandbox error: {0}")]
+    Sandbox(String),
+
+    #[error("unexpected server key: {0}")]
+    UnexpectedKey(String),
+
+    #[error("key of gateway '{0}' changed to {1}, it doesn't match the known hosts")]
+    KeyChanged(String, String),
+
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error("backend is unhealthy: {0}")]
+    Unhealthy(String),
+
+    #[error("gateway did not respond for {0:?}")]
+    GatewayTimeout(std::time::Duration),
+
+    #[error("proxy error: {0}")]
+    Proxy(String),
+
+    #[error("docker error: {0}")]
+    Docker(String),
+
+    #[error("dns error: {0}")]
+    Dns(String),
+
+    #[error("nats error: {0}")]
+    Nats(String),
+
     #[error("key exchange error: {0}")]
     Encryption(#[from] secp256k1::Error),
 
+    #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
     #[error("openssl error: {0}")]
     OpenSSLError(#[from] openssl::error::Error),
 
+    #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
     #[error("openssl error stack : {0}")]
     OpenSSLErrorStack(#[from] openssl::error::ErrorStack),
 
     #[error("io error: {0}")]
     IO(#[from] std::io::Error),
+
+    #[cfg(feature = "geoip")]
+    #[error("geoip error: {0}")]
+    GeoIP(#[from] maxminddb::MaxMindDBError),
+
+    #[cfg(feature = "tls")]
+    #[error("tls error: {0}")]
+    Tls(#[from] tokio_rustls::rustls::Error),
+
+    #[cfg(feature = "keyring")]
+    #[error("keyring error: {0}")]
+    Keyring(#[from] keyring::Error),
+}
+
+/// ErrorKind is the class of an [`Error`], so callers can decide whether to
+/// retry, back off or abort without matching all the errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// the token, the keys or the peer identity are refused
+    Auth,
+    /// the peer doesn't speak the same protocol (or the stream is corrupted)
+    Protocol,
+    /// a connection, a file or a dependency (backend, proxy, dns) failed
+    Io,
+    /// the remote end refused the request or terminated the session
+    Remote,
+    /// key exchange or tls failures
+    Crypto,
+    /// invalid configuration or arguments
+    Config,
+}
+
+impl Error {
+    /// the class of the error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::InvalidMagic
+            | Self::InvalidVersion(_)
+            | Self::InvalidHeader
+            | Self::UnexpectedMessage
+            | Self::InvalidRequest(_)
+            | Self::Http(_)
+            | Self::Diverged(..) => ErrorKind::Protocol,
+            Self::Remote(_) | Self::NameInUse(_) => ErrorKind::Remote,
+            #[cfg(feature = "wire")]
+            Self::Refused(..) | Self::Terminated(_) => ErrorKind::Remote,
+            Self::AuthenticationError(_)
+            | Self::OAuth(_)
+            | Self::UnexpectedKey(_)
+            | Self::KeyChanged(..) => ErrorKind::Auth,
+            Self::InvalidName(_) | Self::Config(_) => ErrorKind::Config,
+            Self::Encryption(_) => ErrorKind::Crypto,
+            #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+            Self::OpenSSLError(_) | Self::OpenSSLErrorStack(_) => ErrorKind::Crypto,
+            #[cfg(feature = "tls")]
+            Self::Tls(_) => ErrorKind::Crypto,
+            Self::Sandbox(_)
+            | Self::Unhealthy(_)
+            | Self::GatewayTimeout(_)
+            | Self::Proxy(_)
+            | Self::Docker(_)
+            | Self::Dns(_)
+            | Self::Nats(_)
+            | Self::IO(_) => ErrorKind::Io,
+            #[cfg(feature = "geoip")]
+            Self::GeoIP(_) => ErrorKind::Io,
+            #[cfg(feature = "keyring")]
+            Self::Keyring(_) => ErrorKind::Io,
+        }
+    }
+
+    /// whether the same operation can succeed later without any change, like
+    /// reconnecting after the connection dropped, the gateway restarted or
+    /// the network (or its dns) came back. Authentication, protocol, crypto
+    /// and configuration errors are never retryable, neither are io errors
+    /// that are caused by the local setup (like a missing permission)
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::IO(err) => !matches!(
+                err.kind(),
+                std::io::ErrorKind::PermissionDenied
+                    | std::io::ErrorKind::NotFound
+                    | std::io::ErrorKind::AlreadyExists
+                    | std::io::ErrorKind::InvalidInput
+                    | std::io::ErrorKind::InvalidData
+                    | std::io::ErrorKind::Unsupported
+                    | std::io::ErrorKind::OutOfMemory
+            ),
+            Self::Sandbox(_) => false,
+            // another agent of the user may disconnect
+            #[cfg(feature = "wire")]
+            Self::Refused(code, _) => *code == wire::Code::AgentLimit,
+            #[cfg(feature = "wire")]
+            Self::Terminated(termination) => matches!(
+                termination.reason,
+                wire::Reason::Shutdown | wire::Reason::Maintenance | wire::Reason::Error
+            ),
+            err => err.kind() == ErrorKind::Io,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "wire"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kind() {
+        assert_eq!(Error::InvalidHeader.kind(), ErrorKind::Protocol);
+        assert_eq!(
+            Error::AuthenticationError("invalid token".into()).kind(),
+            ErrorKind::Auth
+        );
+        assert_eq!(
+            Error::Config("no backends".into()).kind(),
+            ErrorKind::Config
+        );
+        assert_eq!(
+            Error::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset)).kind(),
+            ErrorKind::Io
+        );
+        assert_eq!(
+            Error::Terminated(wire::Termination::new(wire::Reason::Shutdown, "bye")).kind(),
+            ErrorKind::Remote
+        );
+    }
+
+    #[test]
+    fn retryable() {
+        let io = |kind: std::io::ErrorKind| Error::from(std::io::Error::from(kind));
+        assert!(io(std::io::ErrorKind::ConnectionRefused).is_retryable());
+        assert!(io(std::io::ErrorKind::UnexpectedEof).is_retryable());
+        assert!(!io(std::io::ErrorKind::PermissionDenied).is_retryable());
+        assert!(!io(std::io::ErrorKind::InvalidInput).is_retryable());
+        // network and name resolution failures
+        assert!(io(std::io::ErrorKind::Other).is_retryable());
+        assert!(Error::from(std::io::Error::other("failed to lookup address")).is_retryable());
+        assert!(Error::GatewayTimeout(std::time::Duration::from_secs(30)).is_retryable());
+
+        let terminated = |reason| Error::Terminated(wire::Termination::new(reason, ""));
+        assert!(terminated(wire::Reason::Shutdown).is_retryable());
+        assert!(terminated(wire::Reason::Maintenance).is_retryable());
+        assert!(!terminated(wire::Reason::Replaced).is_retryable());
+        assert!(!terminated(wire::Reason::Duplicate).is_retryable());
+
+        assert!(Error::Refused(wire::Code::AgentLimit, "".into()).is_retryable());
+        assert!(!Error::Refused(wire::Code::Upgrade, "".into()).is_retryable());
+        assert!(!Error::AuthenticationError("invalid token".into()).is_retryable());
+        assert!(!Error::InvalidVersion(99).is_retryable());
+        assert!(!Error::Remote("not authorized to use this domain".into()).is_retryable());
+    }
 }
@@ -0,0 +1,411 @@
+//! Logs of the binaries, on stderr or in a file for long running agents and
+//! servers without a log collector. The log file is rotated once it reaches
+//! a size (or an age), the rotated files are renamed `<file>.1`, `<file>.2`,
+//! ... (the most recent first) and only a few of them are kept so the logs
+//! don't fill the disk.
+//!
+//! With the [`Format::Json`] format each record is a json object on a line,
+//! with the `timestamp`, `level`, `target`, `module` and `message` fields and the key
+//! values of the record (like the `method` and `status` of the access logs)
+//! as fields of their own, so the logs can be ingested without parsing the
+//! messages
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use log::{
+    kv::{Key, Value, VisitSource},
+    LevelFilter, Log, Metadata, Record,
+};
+use serde_json::{Map, Number};
+use time::{format_description::FormatItem, OffsetDateTime};
+
+use crate::{Error, Result};
+
+/// default size of a log file before it's rotated
+pub const MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// default number of rotated log files that are kept
+pub const KEEP: usize = 5;
+
+// the timestamps of the stderr logs
+const TIMESTAMP: &[FormatItem] = time::macros::format_description!(
+    "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]Z"
+);
+
+/// format of the log lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// `<timestamp> <level> [<module>] <message>`
+    #[default]
+    Text,
+    /// a json object per line
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(Error::Config(format!(
+                "invalid log format '{}', expected text or json",
+                value
+            ))),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// when a log file is rotated, and how many rotated files are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// rotate the file once it reaches that size
+    pub size: Option<u64>,
+    /// rotate the file once it is that old, even if it's smaller
+    pub age: Option<Duration>,
+    /// rotated files that are kept, older files are removed
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            size: Some(MAX_SIZE),
+            age: None,
+            keep: KEEP,
+        }
+    }
+}
+
+/// LogFile appends to a file and rotates it
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    created: SystemTime,
+}
+
+impl LogFile {
+    /// open the log file, the logs are appended if it exists
+    pub fn open<P: AsRef<Path>>(path: P, rotation: Rotation) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        let created = metadata
+            .created()
+            .or_else(|_| metadata.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            size: metadata.len(),
+            created,
+        })
+    }
+
+    fn due(&self) -> bool {
+        let full = matches!(self.rotation.size, Some(size) if self.size >= size);
+        let old = matches!(
+            self.rotation.age,
+            Some(age) if self.created.elapsed().unwrap_or_default() >= age
+        );
+
+        self.size > 0 && (full || old)
+    }
+
+    // shift the rotated files and start a new file
+    fn rotate(&mut self) -> Result<()> {
+        let rotated = |index: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", index));
+            PathBuf::from(path)
+        };
+
+        if self.rotation.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(rotated(self.rotation.keep));
+            for index in (1..self.rotation.keep).rev() {
+                let _ = std::fs::rename(rotated(index), rotated(index + 1));
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+
+        *self = Self::open(&self.path, self.rotation)?;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.due() {
+            if let Err(err) = self.rotate() {
+                // keep logging to the current file
+                eprintln!("failed to rotate {}: {}", self.path.display(), err);
+            }
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// logs a line per record in the log file or on stderr
+struct Logger {
+    level: LevelFilter,
+    format: Format,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Logger {
+    // the line of the record in the format of the stderr logs
+    fn text(record: &Record) -> String {
+        format!(
+            "{} {:<5} [{}] {}\n",
+            timestamp(),
+            record.level(),
+            record.module_path().unwrap_or_else(|| record.target()),
+            record.args()
+        )
+    }
+
+    fn json(record: &Record) -> String {
+        let mut object = Map::default();
+        object.insert("timestamp".into(), timestamp().into());
+        object.insert("level".into(), record.level().as_str().into());
+        object.insert("target".into(), record.target().into());
+        if let Some(module) = record.module_path() {
+            object.insert("module".into(), module.into());
+        }
+        object.insert("message".into(), record.args().to_string().into());
+        let _ = record.key_values().visit(&mut Json(&mut object));
+
+        let mut line = serde_json::Value::Object(object).to_string();
+        line.push('\n');
+        line
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = match self.format {
+            Format::Text => Self::text(record),
+            Format::Json => Self::json(record),
+        };
+        // a line is written at once, so it's never split by a rotation
+        let _ = self.output.lock().unwrap().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = self.output.lock().unwrap().flush();
+    }
+}
+
+// adds the key values to a json object, they never replace the fields of
+// the record
+struct Json<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Json<'_> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: Value<'kvs>,
+    ) -> std::result::Result<(), log::kv::Error> {
+        let value = if let Some(value) = value.to_bool() {
+            value.into()
+        } else if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(number) = value.to_f64().and_then(Number::from_f64) {
+            number.into()
+        } else {
+            value.to_string().into()
+        };
+
+        self.0.entry(key.as_str()).or_insert(value);
+        Ok(())
+    }
+}
+
+fn timestamp() -> String {
+    OffsetDateTime::now_utc()
+        .format(&TIMESTAMP)
+        .unwrap_or_default()
+}
+
+/// set the logger of the process, the logs go to the file if any otherwise
+/// to stderr
+pub fn init(level: LevelFilter, format: Format, file: Option<LogFile>) -> Result<()> {
+    let output: Box<dyn Write + Send> = match (format, file) {
+        (_, Some(file)) => Box::new(file),
+        (Format::Json, None) => Box::new(std::io::stderr()),
+        (Format::Text, None) => {
+            return simple_logger::SimpleLogger::default()
+                .with_level(level)
+                .with_utc_timestamps()
+                .init()
+                .map_err(|err| Error::Config(err.to_string()))
+        }
+    };
+
+    log::set_boxed_logger(Box::new(Logger {
+        level,
+        format,
+        output: Mutex::new(output),
+    }))
+    .map_err(|err| Error::Config(err.to_string()))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// parse a size in bytes, with an optional `K`, `M` or `G` unit (like `10M`)
+pub fn parse_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1024),
+        Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    match number
+        .parse::<u64>()
+        .map(|number| number.checked_mul(multiplier))
+    {
+        Ok(Some(size)) if size > 0 => Ok(size),
+        _ => Err(Error::Config(format!("invalid size '{}'", value))),
+    }
+}
+
+/// parse a duration with a `s`, `m`, `h`, `d` or `w` unit (like `12h` or
+/// `30d`)
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let invalid = || Error::Config(format!("invalid duration '{}'", value));
+    let value = value.trim();
+    let seconds = match value.chars().last().ok_or_else(invalid)? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 24 * 3600,
+        'w' => 7 * 24 * 3600,
+        _ => return Err(invalid()),
+    };
+    let count: u64 = value[..value.len() - 1].parse().map_err(|_| invalid())?;
+    let seconds = count.checked_mul(seconds).ok_or_else(invalid)?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("10M").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("2k").unwrap(), 2048);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("18446744073709551615G").is_err());
+
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(
+            parse_duration("30d").unwrap(),
+            Duration::from_secs(30 * 24 * 3600)
+        );
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("18446744073709551615w").is_err());
+    }
+
+    #[test]
+    fn json() {
+        let fields = [
+            ("method", Value::from("GET")),
+            ("status", Value::from(404u16)),
+        ];
+        let record = Record::builder()
+            .level(log::Level::Info)
+            .target("access")
+            .args(format_args!("http method=GET status=404"))
+            .key_values(&fields)
+            .build();
+
+        let line = Logger::json(&record);
+        assert!(line.ends_with('\n'));
+        let object: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(object["level"], "INFO");
+        assert_eq!(object["target"], "access");
+        assert_eq!(object["message"], "http method=GET status=404");
+        assert_eq!(object["method"], "GET");
+        assert_eq!(object["status"], 404);
+        assert!(object["timestamp"].is_string());
+
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert!("yaml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn rotate() {
+        let dir = std::env::temp_dir().join(format!("diglett-logs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("diglett.log");
+        let rotation = Rotation {
+            size: Some(10),
+            age: None,
+            keep: 2,
+        };
+
+        let mut file = LogFile::open(&path, rotation).unwrap();
+        for line in ["first line\n", "second line\n", "third line\n", "last\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("diglett.log"), "last\n");
+        assert_eq!(read("diglett.log.1"), "third line\n");
+        assert_eq!(read("diglett.log.2"), "second line\n");
+        assert!(!dir.join("diglett.log.3").exists());
+
+        // the logs are appended to an existing file
+        let mut file = LogFile::open(&path, rotation).unwrap();
+        file.write_all(b"more\n").unwrap();
+        assert_eq!(read("diglett.log"), "last\nmore\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+//! admin api of the server. It's a small http api for operators. With a
+//! token, all requests must carry it as `Authorization: Bearer <token>`
+//!  - `GET /metrics` server metrics in prometheus text format
+//!  - `GET /streams` all open streams (json) sorted by forwarded bytes
+//!  - `GET /agents` all connected agents (json) with their labels
+use std::{hash::Hash, sync::Arc};
+
+use openssl::memcmp;
+use serde_json::json;
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{auth::Authenticate, register::Registerer, Server};
+use crate::{
+    http::{read_request, respond, Request},
+    Result,
+};
+
+pub(crate) async fn serve<A: Authenticate, R: Registerer>(
+    server: Arc<Server<A, R>>,
+    listener: TcpListener,
+) where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::error!("failed to accept admin connection: {}", err);
+                continue;
+            }
+        };
+
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(err) = handle(&server, stream).await {
+                log::debug!("failed to handle admin request: {}", err);
+            }
+        });
+    }
+}
+
+async fn handle<A: Authenticate, R: Registerer>(
+    server: &Server<A, R>,
+    mut stream: TcpStream,
+) -> Result<()>
+where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    let request = read_request(&mut stream).await?;
+    if !authorized(server.admin_token.as_deref(), &request) {
+        return respond(
+            &mut stream,
+            "401 Unauthorized",
+            &[("WWW-Authenticate", "Bearer")],
+            b"unauthorized",
+        )
+        .await;
+    }
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let metrics = server.metrics.render();
+            respond(
+                &mut stream,
+                "200 OK",
+                &[("Content-Type", "text/plain; version=0.0.4")],
+                metrics.as_bytes(),
+            )
+            .await
+        }
+        ("GET", "/streams") => {
+            let mut streams = server.streams();
+            streams.sort_by_key(|stream| std::cmp::Reverse(stream.up + stream.down));
+
+            let streams: Vec<_> = streams
+                .into_iter()
+                .map(|stream| {
+                    json!({
+                        "name": stream.name,
+                        "agent": stream.agent,
+                        "registration": u32::from(&stream.stream.registration()),
+                        "port": stream.stream.port(),
+                        "client": stream.client.to_string(),
+                        "up": stream.up,
+                        "down": stream.down,
+                        "duration": stream.duration.as_secs_f64(),
+                    })
+                })
+                .collect();
+
+            let body = serde_json::to_vec(&streams).unwrap_or_default();
+            respond(
+                &mut stream,
+                "200 OK",
+                &[("Content-Type", "application/json")],
+                &body,
+            )
+            .await
+        }
+        ("GET", "/agents") => {
+            let mut agents = server.agents();
+            agents.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+
+            let agents: Vec<_> = agents
+                .into_iter()
+                .map(|agent| {
+                    let labels: serde_json::Map<_, _> = agent
+                        .labels
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), json!(value)))
+                        .collect();
+
+                    json!({
+                        "id": agent.id,
+                        "name": agent.name,
+                        "peer": agent.peer.to_string(),
+                        "labels": labels,
+                        "duration": agent.duration.as_secs_f64(),
+                    })
+                })
+                .collect();
+
+            let body = serde_json::to_vec(&agents).unwrap_or_default();
+            respond(
+                &mut stream,
+                "200 OK",
+                &[("Content-Type", "application/json")],
+                &body,
+            )
+            .await
+        }
+        _ => respond(&mut stream, "404 Not Found", &[], b"not found").await,
+    }
+}
+
+// the request carries the bearer token, if the admin api requires one
+fn authorized(token: Option<&str>, request: &Request) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+
+    let Some(bearer) = request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    let bearer = bearer.trim().as_bytes();
+    bearer.len() == token.len() && memcmp::eq(bearer, token.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(headers: &str) -> Request {
+        Request::parse(format!("GET /metrics HTTP/1.1\r\n{}\r\n", headers).into_bytes())
+    }
+
+    #[test]
+    fn token() {
+        assert!(authorized(None, &request("")));
+        assert!(authorized(
+            Some("secret"),
+            &request("Authorization: Bearer secret\r\n")
+        ));
+        assert!(!authorized(Some("secret"), &request("")));
+        assert!(!authorized(
+            Some("secret"),
+            &request("Authorization: Bearer other\r\n")
+        ));
+        assert!(!authorized(
+            Some("secret"),
+            &request("Authorization: Basic secret\r\n")
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use super::hooks::Agent;
+use crate::wire::Metadata;
+
+/// A connected agent
+#[derive(Debug, Clone)]
+pub struct AgentInfo {
+    /// id of the agent connection
+    pub id: u64,
+    /// registered name
+    pub name: String,
+    /// remote address of the agent connection
+    pub peer: SocketAddr,
+    /// labels sent by the agent at login
+    pub labels: Metadata,
+    /// since when the agent is connected
+    pub duration: Duration,
+}
+
+// an agent is tracked by its id and name, since it can serve multiple names
+type Key = (u64, String);
+
+/// Agents keeps track of the agents that serve a registration, so operators
+/// can find which machine owns a name
+#[derive(Default)]
+pub(crate) struct Agents {
+    agents: Mutex<BTreeMap<Key, (Arc<Agent>, Instant)>>,
+}
+
+impl Agents {
+    /// track an agent until the returned guard is dropped
+    pub fn track(self: &Arc<Self>, id: u64, agent: &Arc<Agent>) -> AgentTracking {
+        let key = (id, agent.name.clone());
+        self.agents
+            .lock()
+            .unwrap()
+            .insert(key.clone(), (Arc::clone(agent), Instant::now()));
+
+        AgentTracking {
+            agents: Arc::clone(self),
+            key,
+        }
+    }
+
+    /// list all connected agents
+    pub fn list(&self) -> Vec<AgentInfo> {
+        let agents = self.agents.lock().unwrap();
+        agents
+            .iter()
+            .map(|((id, _), (agent, connected))| AgentInfo {
+                id: *id,
+                name: agent.name.clone(),
+                peer: agent.peer,
+                labels: agent.labels.clone(),
+                duration: connected.elapsed(),
+            })
+            .collect()
+    }
+}
+
+/// Tracking of a connected agent, the agent is dropped from the tracked
+/// agents with the guard
+pub(crate) struct AgentTracking {
+    agents: Arc<Agents>,
+    key: Key,
+}
+
+impl Drop for AgentTracking {
+    fn drop(&mut self) {
+        self.agents.agents.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn agents() {
+        let agents = Arc::new(Agents::default());
+        let agent = Arc::new(Agent {
+            peer: ([10, 0, 0, 1], 4000).into(),
+            name: "web".into(),
+            labels: Metadata::default().set("hostname", "node-1"),
+        });
+
+        let tracking = agents.track(7, &agent);
+
+        let list = agents.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, 7);
+        assert_eq!(list[0].name, "web");
+        assert_eq!(list[0].labels.get("hostname"), Some("node-1"));
+
+        // the same agent serving another name is listed once per name
+        let api = Arc::new(Agent {
+            name: "api".into(),
+            ..(*agent).clone()
+        });
+        let api_tracking = agents.track(7, &api);
+        assert_eq!(agents.list().len(), 2);
+
+        drop(api_tracking);
+        drop(tracking);
+        assert!(agents.list().is_empty());
+    }
+}
@@ -1,16 +1,54 @@
+#[cfg(any(test, feature = "testing"))]
+use std::collections::{HashMap, HashSet};
+use std::{net::SocketAddr, time::SystemTime};
+
 use crate::{Error, Result};
 
+#[non_exhaustive]
 pub struct User<U = u64> {
     pub id: U,
+    /// when the authentication expires (for example the expiry of a token).
+    /// The agent then need to re-login before that time otherwise its session
+    /// is terminated. None never expires
+    pub expires: Option<SystemTime>,
     // other user data that might be interesting
 }
 
+impl<U> User<U> {
+    /// a user that never expires
+    pub fn new(id: U) -> Self {
+        Self { id, expires: None }
+    }
+
+    /// expire the authentication of the user at that time
+    pub fn with_expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+}
+
+/// Peer of an agent connection
+#[derive(Debug, Clone)]
+pub struct Peer {
+    /// remote address of the agent
+    pub addr: SocketAddr,
+    /// subject (common name) of the agent certificate if the agent
+    /// connected over mutual tls
+    pub subject: Option<String>,
+}
+
 #[async_trait::async_trait]
 pub trait Authenticate: Send + Sync + 'static {
     type U: Send + 'static;
 
     async fn authenticate(&self, token: &str) -> Result<User<Self::U>>;
     async fn authorize(&self, user: &Self::U, name: &str) -> Result<bool>;
+
+    /// authenticate an agent connection. By default only the login token
+    /// is used, implementations can override it to use the peer information
+    async fn authenticate_peer(&self, _peer: &Peer, token: &str) -> Result<User<Self::U>> {
+        self.authenticate(token).await
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -25,10 +63,149 @@ impl Authenticate for AuthorizeAll {
             return Err(Error::AuthenticationError("invalid token".into()));
         }
 
-        Ok(User { id: () })
+        Ok(User::new(()))
     }
 
     async fn authorize(&self, _user: &Self::U, _name: &str) -> Result<bool> {
         Ok(true)
     }
 }
+
+type Authorizer = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// CertAuth authenticates agents by the subject of their client certificate,
+/// the subject is then the user id. It requires the server to use mutual tls
+/// and login tokens are ignored.
+pub struct CertAuth {
+    authorizer: Option<Authorizer>,
+}
+
+impl CertAuth {
+    /// create a cert authenticator that authorize all names
+    pub fn new() -> Self {
+        Self { authorizer: None }
+    }
+
+    /// only authorize the names for which `f(subject, name)` is true
+    pub fn authorize_with<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        self.authorizer = Some(Box::new(f));
+        self
+    }
+}
+
+impl Default for CertAuth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticate for CertAuth {
+    type U = String;
+
+    async fn authenticate(&self, _token: &str) -> Result<User<String>> {
+        Err(Error::AuthenticationError(
+            "client certificate is required".into(),
+        ))
+    }
+
+    async fn authenticate_peer(&self, peer: &Peer, token: &str) -> Result<User<String>> {
+        match &peer.subject {
+            Some(subject) => Ok(User::new(subject.clone())),
+            None => self.authenticate(token).await,
+        }
+    }
+
+    async fn authorize(&self, user: &String, name: &str) -> Result<bool> {
+        Ok(self
+            .authorizer
+            .as_ref()
+            .map(|authorizer| authorizer(user, name))
+            .unwrap_or(true))
+    }
+}
+
+/// StaticAuth authenticates agents with a fixed set of tokens, each token
+/// logs in a user that is only authorized to use its own domains. It lets
+/// the tests of a server setup run without an authentication service
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Clone, Default)]
+pub struct StaticAuth {
+    tokens: HashMap<String, String>,
+    domains: HashMap<String, HashSet<String>>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl StaticAuth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// accept the token as the login of the user
+    pub fn with_token<T: Into<String>, U: Into<String>>(mut self, token: T, user: U) -> Self {
+        self.tokens.insert(token.into(), user.into());
+        self
+    }
+
+    /// authorize the user to register the domain
+    pub fn with_domain<U: Into<String>, D: Into<String>>(mut self, user: U, domain: D) -> Self {
+        self.domains
+            .entry(user.into())
+            .or_default()
+            .insert(domain.into());
+        self
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+#[async_trait::async_trait]
+impl Authenticate for StaticAuth {
+    type U = String;
+
+    async fn authenticate(&self, token: &str) -> Result<User<String>> {
+        match self.tokens.get(token) {
+            Some(user) => Ok(User::new(user.clone())),
+            None => Err(Error::AuthenticationError("invalid token".into())),
+        }
+    }
+
+    async fn authorize(&self, user: &String, name: &str) -> Result<bool> {
+        Ok(self
+            .domains
+            .get(user)
+            .map(|domains| domains.contains(name))
+            .unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn static_auth() {
+        let auth = StaticAuth::new()
+            .with_token("secret", "alice")
+            .with_token("other", "bob")
+            .with_domain("alice", "web")
+            .with_domain("alice", "api");
+
+        let user = auth.authenticate("secret").await.unwrap();
+        assert_eq!(user.id, "alice");
+        assert!(user.expires.is_none());
+        assert!(matches!(
+            auth.authenticate("unknown").await,
+            Err(Error::AuthenticationError(_))
+        ));
+
+        assert!(auth.authorize(&user.id, "web").await.unwrap());
+        assert!(auth.authorize(&user.id, "api").await.unwrap());
+        assert!(!auth.authorize(&user.id, "blog").await.unwrap());
+        // bob logs in but has no domains
+        let bob = auth.authenticate("other").await.unwrap();
+        assert!(!auth.authorize(&bob.id, "web").await.unwrap());
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+};
+
+use super::registry::{Incoming, Listener};
+
+/// Strategy of distributing client connections between the agents that
+/// serve the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// each new connection goes to the next agent, agents get connections
+    /// in proportion to their weight
+    RoundRobin,
+    /// connections of the same client ip always go to the same agent (as long
+    /// as that agent is connected), clients are spread in proportion to the
+    /// agents weights. Clients of the http router are balanced by their own
+    /// ip as well
+    IpHash,
+}
+
+/// Balancing decides which names can be served by multiple agents at the
+/// same time. Names without a strategy are served by a single agent, and a new
+/// agent of the same user takes over the name. A name specific strategy
+/// overrides the global one
+#[derive(Debug, Clone, Default)]
+pub struct Balancing {
+    global: Option<Strategy>,
+    names: HashMap<String, Strategy>,
+}
+
+impl Balancing {
+    /// set strategy of all names
+    pub fn global(mut self, strategy: Strategy) -> Self {
+        self.global = Some(strategy);
+        self
+    }
+
+    /// set strategy of a single name
+    pub fn name<N: Into<String>>(mut self, name: N, strategy: Strategy) -> Self {
+        self.names.insert(name.into(), strategy);
+        self
+    }
+
+    /// strategy of that name, None if the name is not balanced
+    pub(crate) fn strategy(&self, name: &str) -> Option<Strategy> {
+        self.names.get(name).copied().or(self.global)
+    }
+}
+
+struct Slot {
+    agent: u64,
+    weight: u32,
+    // current weight of the smooth weighted round robin
+    current: i64,
+    sender: mpsc::UnboundedSender<Incoming>,
+}
+
+/// Members are the agents that serve a balanced registration
+pub(crate) struct Members {
+    strategy: Strategy,
+    agents: Mutex<Vec<Slot>>,
+    joined: Notify,
+}
+
+impl Members {
+    pub fn new(strategy: Strategy) -> Self {
+        Self {
+            strategy,
+            agents: Mutex::default(),
+            joined: Notify::new(),
+        }
+    }
+
+    /// add the agent to the members, the agent then receives its
+    /// connections from the returned member until it's dropped
+    pub fn join(self: &Arc<Self>, agent: u64, weight: u32) -> Member {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.agents.lock().unwrap().push(Slot {
+            agent,
+            weight: weight.max(1),
+            current: 0,
+            sender,
+        });
+        self.joined.notify_waiters();
+
+        Member {
+            agent,
+            members: Arc::clone(self),
+            receiver,
+        }
+    }
+
+    // wait until there is at least one member
+    async fn available(&self) {
+        loop {
+            let joined = self.joined.notified();
+            if !self.agents.lock().unwrap().is_empty() {
+                return;
+            }
+            joined.await;
+        }
+    }
+
+    // hand over the connection to one of the members. The connection is
+    // returned back if there are no members
+    fn dispatch(&self, mut incoming: Incoming) -> Option<Incoming> {
+        let mut agents = self.agents.lock().unwrap();
+        while !agents.is_empty() {
+            let index = match self.strategy {
+                Strategy::RoundRobin => smooth(&mut agents),
+                Strategy::IpHash => rendezvous(
+                    incoming.addr.ip(),
+                    agents.iter().map(|slot| (slot.agent, slot.weight)),
+                ),
+            };
+
+            match agents[index].sender.send(incoming) {
+                Ok(_) => return None,
+                Err(err) => {
+                    // the agent is gone
+                    incoming = err.0;
+                    agents.remove(index);
+                }
+            }
+        }
+
+        Some(incoming)
+    }
+}
+
+/// Member of a balanced registration
+pub(crate) struct Member {
+    agent: u64,
+    members: Arc<Members>,
+    receiver: mpsc::UnboundedReceiver<Incoming>,
+}
+
+impl Member {
+    /// next client connection handed over to this agent
+    pub async fn accept(&mut self) -> Option<Incoming> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Member {
+    fn drop(&mut self) {
+        self.members
+            .agents
+            .lock()
+            .unwrap()
+            .retain(|slot| slot.agent != self.agent);
+
+        // the connections that were handed over but not accepted yet go to
+        // the other members
+        self.receiver.close();
+        while let Ok(incoming) = self.receiver.try_recv() {
+            if let Some(incoming) = self.members.dispatch(incoming) {
+                log::debug!("dropping client '{}', no agents available", incoming.addr);
+            }
+        }
+    }
+}
+
+/// accept the registration connections and hand them over to the members.
+/// Connections are not accepted while there are no members, so they wait in
+/// the listener backlog until an agent joins
+pub(crate) fn dispatch(listener: Arc<Listener>, members: Arc<Members>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            members.available().await;
+
+            let incoming = match listener.accept().await {
+                Ok(incoming) => incoming,
+                Err(err) => {
+                    log::error!("error accepting new connections: {}", err);
+                    continue;
+                }
+            };
+
+            if let Some(incoming) = members.dispatch(incoming) {
+                log::debug!("dropping client '{}', no agents available", incoming.addr);
+            }
+        }
+    })
+}
+
+// smooth weighted round robin (as in nginx), spreads the picks of an agent
+// evenly instead of sending bursts of connections to the heavier agents
+fn smooth(agents: &mut [Slot]) -> usize {
+    let mut total = 0;
+    for slot in agents.iter_mut() {
+        slot.current += slot.weight as i64;
+        total += slot.weight as i64;
+    }
+
+    // the first agent wins ties
+    let best = agents
+        .iter()
+        .enumerate()
+        .max_by_key(|(index, slot)| (slot.current, Reverse(*index)))
+        .map(|(index, _)| index)
+        .unwrap_or_default();
+
+    agents[best].current -= total;
+    best
+}
+
+// pick the agent with the highest (weighted) score of the client ip, so a client
+// sticks to the same agent and only the clients of a leaving agent are moved
+fn rendezvous<I: Iterator<Item = (u64, u32)>>(ip: IpAddr, agents: I) -> usize {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    };
+
+    agents
+        .enumerate()
+        .map(|(index, (agent, weight))| {
+            let hash = Sha256::new()
+                .chain_update(ip)
+                .chain_update(agent.to_be_bytes())
+                .finalize();
+            let mut value = [0; 8];
+            value.copy_from_slice(&hash[..8]);
+
+            // map the hash into (0, 1) then weight it
+            let value = (u64::from_be_bytes(value) >> 11) as f64 + 1.0;
+            let unit = value / ((1u64 << 53) as f64 + 2.0);
+            (index, -(weight as f64) / unit.ln())
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    // a connected client connection from that address
+    async fn incoming(listener: &TcpListener, addr: [u8; 4]) -> Incoming {
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        Incoming::from((stream, (addr, 1000).into()))
+    }
+
+    #[tokio::test]
+    async fn requeue() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let members = Arc::new(Members::new(Strategy::RoundRobin));
+        let first = members.join(1, 1);
+        let mut second = members.join(2, 1);
+
+        // round robin starts with the first agent
+        assert!(members
+            .dispatch(incoming(&listener, [10, 0, 0, 1]).await)
+            .is_none());
+
+        // the first agent leaves before it accepted its connection
+        drop(first);
+        let accepted = second.accept().await.unwrap();
+        assert_eq!(accepted.addr, ([10, 0, 0, 1], 1000).into());
+
+        // the connections of the last member are dropped
+        assert!(members
+            .dispatch(incoming(&listener, [10, 0, 0, 2]).await)
+            .is_none());
+        drop(second);
+        assert!(members.agents.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn strategy() {
+        let balancing = Balancing::default()
+            .global(Strategy::RoundRobin)
+            .name("web", Strategy::IpHash);
+
+        assert_eq!(balancing.strategy("web"), Some(Strategy::IpHash));
+        assert_eq!(balancing.strategy("api"), Some(Strategy::RoundRobin));
+        assert_eq!(Balancing::default().strategy("web"), None);
+    }
+
+    #[test]
+    fn ip_hash() {
+        let ip: IpAddr = Ipv4Addr::new(10, 0, 0, 1).into();
+        let agents = [(1, 1), (2, 1), (3, 1)];
+        let picked = agents[rendezvous(ip, agents.into_iter())];
+        assert_eq!(agents[rendezvous(ip, agents.into_iter())], picked);
+
+        // removing another agent does not move the client
+        let other = agents.into_iter().find(|agent| *agent != picked).unwrap();
+        let rest: Vec<(u64, u32)> = agents.into_iter().filter(|agent| *agent != other).collect();
+        assert_eq!(rest[rendezvous(ip, rest.iter().copied())], picked);
+
+        // a new agent can only take over the client
+        let more = [(1, 1), (2, 1), (3, 1), (4, 1)];
+        assert!([picked, (4, 1)].contains(&more[rendezvous(ip, more.into_iter())]));
+    }
+
+    #[test]
+    fn weighted() {
+        let mut agents: Vec<Slot> = [5, 1]
+            .into_iter()
+            .enumerate()
+            .map(|(agent, weight)| Slot {
+                agent: agent as u64,
+                weight,
+                current: 0,
+                sender: mpsc::unbounded_channel().0,
+            })
+            .collect();
+
+        let picks: Vec<usize> = (0..6).map(|_| smooth(&mut agents)).collect();
+        assert_eq!(picks.iter().filter(|index| **index == 0).count(), 5);
+        // picks are spread instead of bursts
+        assert_eq!(picks, vec![0, 0, 0, 1, 0, 0]);
+
+        let heavy = [(1, 9), (2, 1)];
+        let on_heavy = (0..200u8)
+            .filter(|i| rendezvous(Ipv4Addr::new(10, 0, 1, *i).into(), heavy.into_iter()) == 0)
+            .count();
+        assert!(on_heavy > 150, "{}", on_heavy);
+    }
+}
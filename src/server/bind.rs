@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr},
+    ops::RangeInclusive,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+
+use crate::Result;
+
+/// Bind decides where the server opens the listener of a registration.
+#[derive(Debug, Clone, Default)]
+pub enum Bind {
+    /// listen on localhost over a random port. The registerer is then
+    /// responsible of exposing this port (for example via a reverse proxy)
+    #[default]
+    Local,
+    /// listen directly on a public interface, the endpoint of the listener
+    /// is reported back to the agent
+    Public(Public),
+}
+
+/// Public listeners configuration
+#[derive(Debug, Clone)]
+pub struct Public {
+    ip: IpAddr,
+    host: String,
+    ports: HashMap<String, u16>,
+    range: Option<RangeInclusive<u16>>,
+}
+
+impl Public {
+    /// create a public configuration that binds on `ip`. The `host` is the
+    /// advertised host name (or ip) that clients need to use to reach the
+    /// gateway.
+    pub fn new<H: Into<String>>(ip: IpAddr, host: H) -> Self {
+        Self {
+            ip,
+            host: host.into(),
+            ports: HashMap::default(),
+            range: None,
+        }
+    }
+
+    /// map a name to a fixed port
+    pub fn map<N: Into<String>>(mut self, name: N, port: u16) -> Self {
+        self.ports.insert(name.into(), port);
+        self
+    }
+
+    /// names that has no fixed port are given a stable port from that range
+    /// otherwise a random port is used.
+    pub fn range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    async fn listen(&self, name: &str) -> Result<(TcpListener, Option<String>)> {
+        let listener = if let Some(port) = self.ports.get(name) {
+            TcpListener::bind((self.ip, *port)).await?
+        } else if let Some(range) = &self.range {
+            self.listen_in_range(name, range).await?
+        } else {
+            TcpListener::bind((self.ip, 0)).await?
+        };
+
+        let port = listener.local_addr()?.port();
+        Ok((listener, Some(self.endpoint(port))))
+    }
+
+    fn endpoint(&self, port: u16) -> String {
+        format!("{}:{}", self.host, port)
+    }
+
+    async fn listen_in_range(
+        &self,
+        name: &str,
+        range: &RangeInclusive<u16>,
+    ) -> std::io::Result<TcpListener> {
+        let start = *range.start() as u32;
+        let size = *range.end() as u32 - start + 1;
+        let offset = stable_offset(name, size);
+
+        // we start from the stable port of that name, but if taken
+        // we walk the range until we find a free one
+        for i in 0..size {
+            let port = (start + (offset + i) % size) as u16;
+            match TcpListener::bind((self.ip, port)).await {
+                Ok(listener) => return Ok(listener),
+                Err(err) if err.kind() == ErrorKind::AddrInUse => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(std::io::Error::new(
+            ErrorKind::AddrInUse,
+            "no free ports left in range",
+        ))
+    }
+}
+
+impl Bind {
+    /// open a listener for the registration name. It also returns the
+    /// advertised endpoint of that listener if it's public
+    pub(crate) async fn listen(&self, name: &str) -> Result<(TcpListener, Option<String>)> {
+        match self {
+            Self::Local => Ok((TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?, None)),
+            Self::Public(public) => public.listen(name).await,
+        }
+    }
+
+    /// the advertised endpoint of a listener on that port (for example a
+    /// listener inherited from a previous server process)
+    pub(crate) fn endpoint(&self, port: u16) -> Option<String> {
+        match self {
+            Self::Local => None,
+            Self::Public(public) => Some(public.endpoint(port)),
+        }
+    }
+}
+
+fn stable_offset(name: &str, size: u32) -> u32 {
+    let hash = Sha256::digest(name.as_bytes());
+    let mut value: [u8; 4] = [0; 4];
+    value.copy_from_slice(&hash[..4]);
+
+    u32::from_be_bytes(value) % size
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stable() {
+        assert_eq!(
+            stable_offset("example", 1000),
+            stable_offset("example", 1000)
+        );
+        assert!(stable_offset("example", 10) < 10);
+    }
+
+    #[tokio::test]
+    async fn listen_public() {
+        let public = Public::new(Ipv4Addr::LOCALHOST.into(), "gateway.example.com");
+        let (listener, endpoint) = public.listen("name").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert_eq!(endpoint, Some(format!("gateway.example.com:{}", port)));
+    }
+}
@@ -0,0 +1,588 @@
+//! Build a server from a [`ServerConfig`]:
+//!
+//! ```no_run
+//! # async fn example() -> diglett::Result<()> {
+//! use std::time::Duration;
+//! use diglett::server::{Limits, Server, ServerConfig};
+//!
+//! let mut config = ServerConfig::default();
+//! config.lease = Some(Duration::from_secs(60));
+//! config.limits = Limits {
+//!     agents: Some(10),
+//!     names: Some(100),
+//! };
+//!
+//! let server = Server::builder()
+//!     .keypair(diglett::wire::keypair())
+//!     .config(config)
+//!     .build()?;
+//!
+//! server.start("0.0.0.0:20000").await
+//! # }
+//! ```
+//!
+//! The options that are not plain values (like the http router or the hooks)
+//! are set with the methods of the [`ServerBuilder`]
+use std::{hash::Hash, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use secp256k1::Keypair;
+
+#[cfg(unix)]
+use super::Listeners;
+#[cfg(target_os = "linux")]
+use super::Privileges;
+use super::{
+    auth::Authenticate,
+    limits::{IpConnections, Quotas},
+    logins::Logins,
+    middleware::Middlewares,
+    namespace::Namespace,
+    ratelimit::Limiter,
+    register::Registerer,
+    usage::{Accounting, UsageSink},
+    AuthorizeAll, Balancing, Bandwidth, Bind, ClientLimits, Denylist, Dns, DuplicateLogin,
+    GeoFilter, HttpRouter, Limits, PrintRegisterer, RateLimit, Server, ServerHooks, TrafficTap,
+    Validation, PROBE_INTERVAL, SHUTDOWN_TIMEOUT, STATS_INTERVAL,
+};
+use crate::{
+    agent::{KEEPALIVE_INTERVAL, MISSED_KEEPALIVES},
+    Error, Result,
+};
+
+/// ServerConfig holds the options of a [`Server`] that are plain values.
+/// Options are only added with their defaults, so a config is created with
+/// `ServerConfig::default()` and then changed
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ServerConfig {
+    /// where the registration listeners are opened. Default to [`Bind::Local`]
+    pub bind: Bind,
+    /// per user limits. Default to no limits
+    pub limits: Limits,
+    /// limits of concurrent connections per public client ip. Default to
+    /// no limits
+    pub client_limits: ClientLimits,
+    /// bandwidth of the registrations and their streams. Default to no limits
+    pub bandwidth: Bandwidth,
+    /// rate limit of handshake attempts per source ip. Default to no limit
+    pub handshake_limit: Option<RateLimit>,
+    /// what happens to a connected agent when another agent logs in with
+    /// the same identity. Default to [`DuplicateLogin::Warn`]
+    pub duplicate_login: DuplicateLogin,
+    /// refuse agents that can't speak at least that wire version. Default
+    /// to accepting all versions
+    pub min_version: u8,
+    /// how names are served by multiple agents. Default to a single agent
+    /// per name
+    pub balancing: Balancing,
+    /// how registered names are validated. Default to single label dns names
+    pub validation: Validation,
+    /// ttl of the registrations lease, at least 3 keep alive intervals of the
+    /// agents. Agents before wire version 2 have no lease. Default to no lease
+    pub lease: Option<Duration>,
+    /// how long the registration of a disconnected agent is kept. Default
+    /// to releasing it immediately
+    pub hold: Option<Duration>,
+    /// max time to wait for the agents connections to terminate on shutdown.
+    /// Default to [`SHUTDOWN_TIMEOUT`]
+    pub shutdown_timeout: Duration,
+    /// how often the registrations stats are delivered to their handlers.
+    /// Default to [`STATS_INTERVAL`]
+    pub stats_interval: Duration,
+    /// how often the round trip time and throughput of agents are measured
+    /// for the metrics. Default to 10 seconds
+    pub probe_interval: Duration,
+    /// address of the admin api (metrics and open streams). The api is only
+    /// served on a loopback address unless `admin_token` is set. Default to
+    /// no admin api
+    pub admin: Option<SocketAddr>,
+    /// bearer token that the requests of the admin api must carry
+    /// (`Authorization: Bearer <token>`). Default to no token
+    pub admin_token: Option<String>,
+    /// address of the websocket listener. Default to no websocket listener
+    pub websocket: Option<SocketAddr>,
+    /// record the frames of every agent session to `<ip>-<port>-<timestamp>.rec`
+    /// files in that directory, to reproduce issues with
+    /// [`wire::record`](crate::wire::record). Login tokens are not recorded
+    /// but the tunneled traffic is, so only enable it for debugging. Default
+    /// to no recordings
+    pub recordings: Option<PathBuf>,
+    /// unix socket where a new server process takes over the listeners with
+    /// [`Listeners::take_over`]. Once the listeners are handed over the server
+    /// shuts down, so a restart doesn't drop the agents and clients
+    /// connections waiting in the listeners backlog. Default to no handoff
+    #[cfg(unix)]
+    pub handoff: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: Bind::default(),
+            limits: Limits::default(),
+            client_limits: ClientLimits::default(),
+            bandwidth: Bandwidth::default(),
+            handshake_limit: None,
+            duplicate_login: DuplicateLogin::default(),
+            min_version: 1,
+            balancing: Balancing::default(),
+            validation: Validation::default(),
+            lease: None,
+            hold: None,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            stats_interval: STATS_INTERVAL,
+            probe_interval: PROBE_INTERVAL,
+            admin: None,
+            admin_token: None,
+            websocket: None,
+            recordings: None,
+            #[cfg(unix)]
+            handoff: None,
+        }
+    }
+}
+
+/// ServerBuilder builds a [`Server`]. The server authorizes all agents and
+/// prints the registrations unless `auth` and `registerer` are set
+pub struct ServerBuilder<A: Authenticate, R> {
+    kp: Option<Keypair>,
+    auth: A,
+    reg: R,
+    config: ServerConfig,
+    hooks: Option<Arc<dyn ServerHooks>>,
+    middlewares: Option<Box<dyn Middlewares>>,
+    tap: Option<Arc<dyn TrafficTap>>,
+    router: Option<HttpRouter>,
+    dns: Option<Dns>,
+    geoip: Option<GeoFilter>,
+    denylist: Option<Denylist>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
+    #[cfg(unix)]
+    listeners: Option<Listeners>,
+    #[cfg(target_os = "linux")]
+    privileges: Option<Privileges>,
+    // the options typed by the user of the auth
+    namespace: Option<Box<dyn Namespace<A::U>>>,
+    usage: Option<Arc<Accounting<A::U>>>,
+    // an option of the user was set before the auth changed the user type
+    dropped: Option<&'static str>,
+}
+
+impl Default for ServerBuilder<AuthorizeAll, PrintRegisterer> {
+    fn default() -> Self {
+        Self {
+            kp: None,
+            auth: AuthorizeAll,
+            reg: PrintRegisterer,
+            config: ServerConfig::default(),
+            hooks: None,
+            middlewares: None,
+            tap: None,
+            router: None,
+            dns: None,
+            geoip: None,
+            denylist: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(unix)]
+            listeners: None,
+            #[cfg(target_os = "linux")]
+            privileges: None,
+            namespace: None,
+            usage: None,
+            dropped: None,
+        }
+    }
+}
+
+impl<A, R> ServerBuilder<A, R>
+where
+    A: Authenticate,
+    A::U: Clone + Eq + Hash + Sync,
+    R: Registerer,
+{
+    /// key pair of the server, the agents pin its public key
+    pub fn keypair(mut self, keypair: Keypair) -> Self {
+        self.kp = Some(keypair);
+        self
+    }
+
+    /// authenticate the agents and authorize their names with `auth`. The
+    /// [`namespace`](Self::namespace) and the [`usage`](Self::usage) are
+    /// typed by the user of the auth, so they are set after it
+    pub fn auth<B: Authenticate>(self, auth: B) -> ServerBuilder<B, R> {
+        let dropped = match (&self.namespace, &self.usage) {
+            (Some(_), _) => Some("namespace"),
+            (_, Some(_)) => Some("usage"),
+            _ => self.dropped,
+        };
+
+        ServerBuilder {
+            kp: self.kp,
+            auth,
+            reg: self.reg,
+            config: self.config,
+            hooks: self.hooks,
+            middlewares: self.middlewares,
+            tap: self.tap,
+            router: self.router,
+            dns: self.dns,
+            geoip: self.geoip,
+            denylist: self.denylist,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            #[cfg(unix)]
+            listeners: self.listeners,
+            #[cfg(target_os = "linux")]
+            privileges: self.privileges,
+            namespace: None,
+            usage: None,
+            dropped,
+        }
+    }
+
+    /// register the served names with `registerer`
+    pub fn registerer<S: Registerer>(self, registerer: S) -> ServerBuilder<A, S> {
+        ServerBuilder {
+            kp: self.kp,
+            auth: self.auth,
+            reg: registerer,
+            config: self.config,
+            hooks: self.hooks,
+            middlewares: self.middlewares,
+            tap: self.tap,
+            router: self.router,
+            dns: self.dns,
+            geoip: self.geoip,
+            denylist: self.denylist,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            #[cfg(unix)]
+            listeners: self.listeners,
+            #[cfg(target_os = "linux")]
+            privileges: self.privileges,
+            namespace: self.namespace,
+            usage: self.usage,
+            dropped: self.dropped,
+        }
+    }
+
+    /// options of the server. Default to [`ServerConfig::default`]
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// hooks that are invoked on agents events. Default to no hooks
+    pub fn hooks<H: ServerHooks>(mut self, hooks: H) -> Self {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// middlewares that build a [`StreamMiddleware`](super::StreamMiddleware)
+    /// chain for each new stream. Default to no middlewares
+    pub fn middlewares<M: Middlewares>(mut self, middlewares: M) -> Self {
+        self.middlewares = Some(Box::new(middlewares));
+        self
+    }
+
+    /// tap that receives a copy of the traffic of the registrations it
+    /// selects. Default to no tap
+    pub fn tap<T: TrafficTap>(mut self, tap: T) -> Self {
+        self.tap = Some(Arc::new(tap));
+        self
+    }
+
+    /// serve all registrations over a single http listener, routing requests
+    /// by their host header. Default to no router
+    pub fn http_router(mut self, router: HttpRouter) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    /// answer the dns queries of the registered names. Default to no dns
+    /// responder
+    pub fn dns(mut self, dns: Dns) -> Self {
+        self.dns = Some(dns);
+        self
+    }
+
+    /// restrict which countries can connect to the registrations listeners.
+    /// Default to no restrictions
+    pub fn geoip(mut self, filter: GeoFilter) -> Self {
+        self.geoip = Some(filter);
+        self
+    }
+
+    /// refuse registering names that match the denylist. The denylist is
+    /// checked before authorizing the name. Default to no denylist
+    pub fn denylist(mut self, denylist: Denylist) -> Self {
+        self.denylist = Some(denylist);
+        self
+    }
+
+    /// require agents to connect over mutual tls with that configuration
+    /// (see [`crate::tls::server_config`]). Default to plain tcp
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<tokio_rustls::rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// serve over listeners inherited from a previous server process (or from
+    /// systemd socket activation) instead of opening new ones. An inherited
+    /// registration listener is used once an agent registers its name again
+    #[cfg(unix)]
+    pub fn listeners(mut self, listeners: Listeners) -> Self {
+        self.listeners = Some(listeners);
+        self
+    }
+
+    /// drop the root privileges to that user and group once the server
+    /// listeners are bound. Registrations listeners are opened afterwards so
+    /// they can't use privileged ports unless the system allows it
+    #[cfg(target_os = "linux")]
+    pub fn privileges(mut self, privileges: Privileges) -> Self {
+        self.privileges = Some(privileges);
+        self
+    }
+
+    /// scope the registered names per user (for example with
+    /// [`UserNamespace`](super::UserNamespace)). The scoping happens after
+    /// the name is authorized. Default to no scoping
+    pub fn namespace<N: Namespace<A::U>>(mut self, namespace: N) -> Self {
+        self.namespace = Some(Box::new(namespace));
+        self
+    }
+
+    /// account forwarded bytes per user across all their registrations. The
+    /// usage records are delivered to the sink every interval
+    pub fn usage<S: UsageSink<A::U>>(mut self, sink: S, interval: Duration) -> Self {
+        self.usage = Some(Arc::new(Accounting::new(sink, interval)));
+        self
+    }
+
+    pub fn build(self) -> Result<Server<A, R>> {
+        let kp = self
+            .kp
+            .ok_or_else(|| Error::Config("keypair is not set".into()))?;
+
+        if let Some(option) = self.dropped {
+            return Err(Error::Config(format!("{} must be set after auth", option)));
+        }
+
+        let config = self.config;
+        if config.stats_interval.is_zero() {
+            return Err(Error::Config("stats interval must not be zero".into()));
+        }
+
+        if config.probe_interval.is_zero() {
+            return Err(Error::Config("probe interval must not be zero".into()));
+        }
+
+        // the agents renew their lease with keep alive pings
+        if matches!(config.lease, Some(lease) if lease < KEEPALIVE_INTERVAL * MISSED_KEEPALIVES) {
+            return Err(Error::Config(format!(
+                "lease must not be shorter than {} seconds",
+                (KEEPALIVE_INTERVAL * MISSED_KEEPALIVES).as_secs()
+            )));
+        }
+
+        if matches!(config.admin, Some(listen) if !listen.ip().is_loopback())
+            && config.admin_token.is_none()
+        {
+            return Err(Error::Config(
+                "admin api on a non loopback address requires a token".into(),
+            ));
+        }
+
+        if matches!(&config.admin_token, Some(token) if token.is_empty()) {
+            return Err(Error::Config("admin token must not be empty".into()));
+        }
+
+        let mut server = Server::new(kp, self.auth, self.reg);
+        server.bind = config.bind;
+        server.quotas = Arc::new(Quotas::new(config.limits));
+        server.ip_connections = Arc::new(IpConnections::new(config.client_limits.global));
+        server.client_limits = config.client_limits;
+        server.bandwidth = config.bandwidth;
+        server.handshakes = config.handshake_limit.map(Limiter::new);
+        server.logins = Arc::new(Logins::new(config.duplicate_login));
+        server.min_version = config.min_version;
+        server.balancing = config.balancing;
+        server.validation = config.validation;
+        server.lease = config.lease;
+        server.hold = config.hold;
+        server.shutdown_timeout = config.shutdown_timeout;
+        server.stats = config.stats_interval;
+        server.probe = config.probe_interval;
+        server.admin = config.admin;
+        server.admin_token = config.admin_token;
+        server.websocket = config.websocket;
+        server.recordings = config.recordings;
+        #[cfg(unix)]
+        {
+            server.handoff = config.handoff;
+            server.listeners = self.listeners;
+        }
+
+        if let Some(hooks) = self.hooks {
+            server.hooks = hooks;
+        }
+
+        server.middlewares = self.middlewares;
+        server.tap = self.tap;
+        server.router = self.router.map(Arc::new);
+        server.dns = self.dns;
+        server.geoip = self.geoip;
+        server.denylist = self.denylist;
+        server.namespace = self.namespace;
+        server.usage = self.usage;
+        #[cfg(feature = "tls")]
+        {
+            server.tls = self.tls.map(tokio_rustls::TlsAcceptor::from);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            server.privileges = self.privileges;
+        }
+
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::keypair;
+
+    #[test]
+    fn build() {
+        assert!(matches!(Server::builder().build(), Err(Error::Config(_))));
+
+        let config = ServerConfig {
+            stats_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Server::builder().keypair(keypair()).config(config).build(),
+            Err(Error::Config(_))
+        ));
+
+        let config = ServerConfig {
+            lease: Some(Duration::from_secs(30)),
+            min_version: 3,
+            shutdown_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let server = Server::builder()
+            .keypair(keypair())
+            .auth(crate::server::StaticAuth::new())
+            .config(config)
+            .build()
+            .unwrap();
+
+        assert_eq!(server.lease, Some(Duration::from_secs(30)));
+        assert_eq!(server.min_version, 3);
+        assert_eq!(server.shutdown_timeout, Duration::from_secs(1));
+        assert_eq!(server.stats, STATS_INTERVAL);
+        assert_eq!(server.probe, PROBE_INTERVAL);
+    }
+
+    #[test]
+    fn options() {
+        let config = ServerConfig {
+            recordings: Some("/tmp/recordings".into()),
+            handshake_limit: Some(RateLimit {
+                rate: 1.0,
+                burst: 1,
+                ban: Duration::from_secs(1),
+            }),
+            ..Default::default()
+        };
+        let server = Server::builder()
+            .keypair(keypair())
+            .config(config)
+            .http_router(HttpRouter::new(
+                "127.0.0.1:8080".parse().unwrap(),
+                "gateway.com",
+            ))
+            .denylist(Denylist::new().name("admin"))
+            .auth(crate::server::StaticAuth::new())
+            .namespace(crate::server::UserNamespace)
+            .build()
+            .unwrap();
+
+        assert_eq!(server.recordings, Some("/tmp/recordings".into()));
+        assert!(server.handshakes.is_some());
+        assert!(server.router.is_some());
+        assert!(server.denylist.is_some());
+        assert!(server.namespace.is_some());
+        assert!(server.middlewares.is_none());
+        assert!(server.tap.is_none());
+        assert!(server.dns.is_none());
+        assert!(server.geoip.is_none());
+    }
+
+    #[test]
+    fn lease() {
+        let config = ServerConfig {
+            lease: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Server::builder().keypair(keypair()).config(config).build(),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn admin() {
+        let config = ServerConfig {
+            admin: Some("0.0.0.0:9090".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Server::builder().keypair(keypair()).config(config).build(),
+            Err(Error::Config(_))
+        ));
+
+        // a token is only required off the loopback address
+        let config = ServerConfig {
+            admin: Some("127.0.0.1:9090".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(Server::builder()
+            .keypair(keypair())
+            .config(config)
+            .build()
+            .is_ok());
+
+        let config = ServerConfig {
+            admin: Some("0.0.0.0:9090".parse().unwrap()),
+            admin_token: Some("secret".into()),
+            ..Default::default()
+        };
+        let server = Server::builder()
+            .keypair(keypair())
+            .config(config)
+            .build()
+            .unwrap();
+        assert_eq!(server.admin_token.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn namespace_before_auth() {
+        let builder = Server::builder()
+            .keypair(keypair())
+            .auth(crate::server::StaticAuth::new())
+            .namespace(crate::server::UserNamespace);
+
+        // the namespace of the previous auth users can't be used by the new auth
+        let Err(Error::Config(msg)) = builder.auth(crate::server::StaticAuth::new()).build() else {
+            panic!("expected a config error");
+        };
+        assert_eq!(msg, "namespace must be set after auth");
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+
+use regex::Regex;
+
+/// Denylist of names that can't be registered by any agent, for example
+/// `admin`, `www` or the gateway own host names. Names are matched case
+/// insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct Denylist {
+    names: HashSet<String>,
+    suffixes: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Denylist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// deny that exact name
+    pub fn name<N: AsRef<str>>(mut self, name: N) -> Self {
+        self.names.insert(name.as_ref().to_lowercase());
+        self
+    }
+
+    /// deny all names that ends with that suffix
+    pub fn suffix<S: AsRef<str>>(mut self, suffix: S) -> Self {
+        self.suffixes.push(suffix.as_ref().to_lowercase());
+        self
+    }
+
+    /// deny all names that matches the pattern
+    pub fn pattern(mut self, pattern: Regex) -> Self {
+        self.patterns.push(pattern);
+        self
+    }
+
+    /// check if name is denied
+    pub fn denied(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+
+        self.names.contains(&name)
+            || self.suffixes.iter().any(|suffix| name.ends_with(suffix))
+            || self.patterns.iter().any(|pattern| pattern.is_match(&name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn denied() {
+        let denylist = Denylist::new()
+            .name("admin")
+            .suffix(".gateway.com")
+            .pattern(Regex::new("^www[0-9]*$").unwrap());
+
+        assert!(denylist.denied("Admin"));
+        assert!(denylist.denied("api.gateway.com"));
+        assert!(denylist.denied("www2"));
+
+        assert!(!denylist.denied("administrator"));
+        assert!(!denylist.denied("wwwsite"));
+    }
+}
@@ -0,0 +1,142 @@
+//! private access to the names of a user. A client dials a name before it
+//! logs in, and the gateway then serves the streams of the client like an
+//! agent would, with the public listener of the name as the backend. The
+//! streams reach the agent of the name as any other client connection
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use super::{
+    auth::{Peer, User},
+    deadline, expired, names, terminated, Authenticate, Registerer, Server,
+};
+use crate::{
+    agent::{self, Backend, Options},
+    wire::{Code, Connection, FrameStream, Registration, Split},
+    Error, Result,
+};
+
+/// serve the client of an authenticated user that dialed the name
+pub(crate) async fn serve<A: Authenticate, R: Registerer, S: Split>(
+    server: &Arc<Server<A, R>>,
+    mut connection: Connection<S, FrameStream>,
+    user: &User<A::U>,
+    peer: &Peer,
+    name: String,
+) -> Result<()>
+where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    // names are normalized before they are checked, the port of a name is
+    // checked apart
+    let (name, port) = match names::port(&name) {
+        Ok((name, port)) => (server.validation.validate(name), port),
+        Err(err) => (Err(err), None),
+    };
+    let name = match name {
+        Ok(name) => name,
+        Err(err) => {
+            connection.refuse(Code::InvalidName, err).await?;
+            return Ok(());
+        }
+    };
+
+    match server.auth.authorize(&user.id, &name).await {
+        Ok(true) => {}
+        Ok(false) => {
+            connection
+                .error("not authorized to use this domain")
+                .await?;
+            return Ok(());
+        }
+        Err(err) => {
+            connection.error(err).await?;
+            return Ok(());
+        }
+    }
+
+    let name = match &server.namespace {
+        Some(namespace) => match server
+            .validation
+            .validate_scoped(&namespace.scope(&user.id, &name))
+        {
+            Ok(name) => name,
+            Err(err) => {
+                connection.refuse(Code::InvalidName, err).await?;
+                return Ok(());
+            }
+        },
+        None => name,
+    };
+    let name = match port {
+        Some(port) => format!("{}:{}", name, port),
+        None => name,
+    };
+
+    // the names of other users are not told apart from the names nobody serves
+    let target = server
+        .registry
+        .owned(&name, &user.id)
+        .await
+        .and_then(|registration| registration.listener.local_addr().ok());
+    let Some(target) = target else {
+        connection
+            .error(format!("no agent serves '{}'", name))
+            .await?;
+        return Ok(());
+    };
+
+    connection.ok().await?;
+    log::info!("client {} connected to '{}'", peer.addr, name);
+
+    let backends = HashMap::from([(Registration::from(0), Backend::from(local(target)))]);
+    let mut shutdown = server.shutdown.subscribe();
+    // the client is disconnected once its authentication expires
+    let stop = async {
+        tokio::select! {
+            _ = terminated(&mut shutdown) => {}
+            _ = expired(deadline(user.expires)) => log::info!("session of client {} expired", peer.addr),
+        }
+    };
+    let result = Box::pin(agent::serve_all_until(
+        connection,
+        backends,
+        Options::default(),
+        stop,
+    ))
+    .await;
+
+    match result {
+        // a client that terminates its connection is done with the name
+        Err(Error::Terminated(termination)) => {
+            log::info!(
+                "client {} of '{}' disconnected: {}",
+                peer.addr,
+                name,
+                termination
+            );
+            Ok(())
+        }
+        result => {
+            log::info!("client {} of '{}' disconnected", peer.addr, name);
+            result
+        }
+    }
+}
+
+// the listener address to connect to, a listener on all the interfaces is
+// reached over the loopback
+fn local(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V4(v4) if v4.ip().is_unspecified() => {
+            SocketAddr::from((Ipv4Addr::LOCALHOST, v4.port()))
+        }
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => {
+            SocketAddr::from((Ipv6Addr::LOCALHOST, v6.port()))
+        }
+        address => address,
+    }
+}
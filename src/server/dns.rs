@@ -0,0 +1,423 @@
+//! Dns is a small authoritative dns responder of the gateway zone. It answers
+//! the queries of `<name>.<zone>` while `name` is registered, so self hosted
+//! setups can delegate the zone to the gateway instead of automating an
+//! external dns.
+use std::{
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::net::UdpSocket;
+
+use super::{auth::Authenticate, register::Registerer, Server};
+use crate::dns::encode;
+
+/// default ttl of the answers
+pub const TTL: u32 = 60;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const RCODE_FORMERR: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+const HEADER_LEN: usize = 12;
+// compression pointer to the question name, which always follows the header
+const QUESTION_NAME: [u8; 2] = [0xc0, HEADER_LEN as u8];
+
+/// Dns answers A/AAAA (or CNAME) queries of the registered names with the
+/// gateway addresses. Queries of names that are not registered are answered
+/// with NXDOMAIN, and queries outside the zone are refused
+#[derive(Debug, Clone)]
+pub struct Dns {
+    listen: SocketAddr,
+    zone: String,
+    addresses: Vec<IpAddr>,
+    alias: Option<String>,
+    ttl: u32,
+}
+
+impl Dns {
+    /// create a responder that listens (udp) on `listen` and serves the
+    /// sub domains of `zone`
+    pub fn new<Z: Into<String>>(listen: SocketAddr, zone: Z) -> Self {
+        Self {
+            listen,
+            zone: zone.into().trim_end_matches('.').to_lowercase(),
+            addresses: Vec::default(),
+            alias: None,
+            ttl: TTL,
+        }
+    }
+
+    /// answer the registered names with that address of the gateway, can
+    /// be called multiple times (for example for an ipv4 and an ipv6 address)
+    pub fn address(mut self, ip: IpAddr) -> Self {
+        self.addresses.push(ip);
+        self
+    }
+
+    /// answer the registered names with a CNAME to that host (for example
+    /// the gateway own host name) instead of addresses
+    pub fn alias<H: Into<String>>(mut self, host: H) -> Self {
+        self.alias = Some(host.into().trim_end_matches('.').to_lowercase());
+        self
+    }
+
+    /// ttl in seconds of the answers
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn listen(&self) -> SocketAddr {
+        self.listen
+    }
+
+    /// the registration name of a queried name. Empty for the zone itself
+    /// and None if the name is outside the zone
+    pub(crate) fn name<'a>(&self, name: &'a str) -> Option<&'a str> {
+        if name == self.zone {
+            return Some("");
+        }
+
+        name.strip_suffix(self.zone.as_str())?.strip_suffix('.')
+    }
+
+    /// build the response of the query. `registered` tells if the queried
+    /// name is currently registered
+    pub(crate) fn answer(&self, query: &Query, registered: bool) -> Vec<u8> {
+        let mut response = Response::new(query);
+        if query.opcode() != 0 {
+            return response.finish(RCODE_NOTIMP);
+        }
+
+        if query.class != CLASS_IN {
+            return response.finish(RCODE_REFUSED);
+        }
+
+        let name = match self.name(&query.name) {
+            Some(name) => name,
+            None => return response.finish(RCODE_REFUSED),
+        };
+
+        if name.is_empty() {
+            // the zone itself is served by the gateway
+            if matches!(query.kind, TYPE_SOA | TYPE_ANY) {
+                response.record(TYPE_SOA, self.ttl, &self.soa());
+            }
+            self.addresses(&mut response, query.kind);
+        } else if !registered {
+            response.authority(TYPE_SOA, self.ttl, &self.soa());
+            return response.finish(RCODE_NXDOMAIN);
+        } else if let Some(alias) = &self.alias {
+            response.record(TYPE_CNAME, self.ttl, &encode(alias));
+        } else {
+            self.addresses(&mut response, query.kind);
+        }
+
+        if response.answers == 0 {
+            // no records of that type
+            response.authority(TYPE_SOA, self.ttl, &self.soa());
+        }
+
+        response.finish(0)
+    }
+
+    fn addresses(&self, response: &mut Response, kind: u16) {
+        for ip in &self.addresses {
+            match ip {
+                IpAddr::V4(ip) if matches!(kind, TYPE_A | TYPE_ANY) => {
+                    response.record(TYPE_A, self.ttl, &ip.octets())
+                }
+                IpAddr::V6(ip) if matches!(kind, TYPE_AAAA | TYPE_ANY) => {
+                    response.record(TYPE_AAAA, self.ttl, &ip.octets())
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn soa(&self) -> Vec<u8> {
+        let mut data = encode(&self.zone);
+        data.extend(encode(&format!("hostmaster.{}", self.zone)));
+        // serial, refresh, retry, expire and the negative answers ttl
+        for value in [1, 3600, 600, 86400, self.ttl] {
+            data.extend(u32::to_be_bytes(value));
+        }
+        data
+    }
+}
+
+pub(crate) async fn serve<A: Authenticate, R: Registerer>(
+    server: Arc<Server<A, R>>,
+    socket: UdpSocket,
+) where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    let Some(dns) = &server.dns else {
+        return;
+    };
+
+    let mut buf = [0; 512];
+    loop {
+        let (n, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                log::error!("failed to receive dns query: {}", err);
+                continue;
+            }
+        };
+
+        let packet = &buf[..n];
+        let response = match Query::parse(packet) {
+            Some(query) => {
+                let registered = match dns.name(query.name()) {
+                    Some(name) if !name.is_empty() => server.registry.lookup(name).await.is_some(),
+                    _ => false,
+                };
+
+                log::trace!("dns query of '{}' from '{}'", query.name(), peer);
+                dns.answer(&query, registered)
+            }
+            None => match malformed(packet) {
+                Some(response) => response,
+                None => continue,
+            },
+        };
+
+        if let Err(err) = socket.send_to(&response, peer).await {
+            log::debug!("failed to send dns response to '{}': {}", peer, err);
+        }
+    }
+}
+
+/// a parsed dns query with a single question
+#[derive(Debug)]
+pub(crate) struct Query {
+    id: u16,
+    flags: u16,
+    // lower case name without the trailing dot
+    name: String,
+    kind: u16,
+    class: u16,
+    // raw question section
+    question: Vec<u8>,
+}
+
+impl Query {
+    /// parse the query packet. None if the packet is not a valid query
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < HEADER_LEN {
+            return None;
+        }
+
+        let id = u16::from_be_bytes([packet[0], packet[1]]);
+        let flags = u16::from_be_bytes([packet[2], packet[3]]);
+        let questions = u16::from_be_bytes([packet[4], packet[5]]);
+        // responses and queries with multiple questions are not supported
+        if flags & 0x8000 != 0 || questions != 1 {
+            return None;
+        }
+
+        let mut labels = Vec::new();
+        let mut offset = HEADER_LEN;
+        loop {
+            let len = *packet.get(offset)? as usize;
+            offset += 1;
+            if len == 0 {
+                break;
+            }
+            // compression is not expected in the question
+            if len > 63 {
+                return None;
+            }
+
+            let label = packet.get(offset..offset + len)?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            offset += len;
+        }
+
+        let fields = packet.get(offset..offset + 4)?;
+        Some(Self {
+            id,
+            flags,
+            name: labels.join("."),
+            kind: u16::from_be_bytes([fields[0], fields[1]]),
+            class: u16::from_be_bytes([fields[2], fields[3]]),
+            question: packet[HEADER_LEN..offset + 4].to_vec(),
+        })
+    }
+
+    /// the queried name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn opcode(&self) -> u16 {
+        (self.flags >> 11) & 0xf
+    }
+}
+
+/// the error response of a packet that has a header but is not a valid
+/// query. None if the packet has no header (or is a response)
+pub(crate) fn malformed(packet: &[u8]) -> Option<Vec<u8>> {
+    if packet.len() < HEADER_LEN || packet[2] & 0x80 != 0 {
+        return None;
+    }
+
+    let mut response = packet[..HEADER_LEN].to_vec();
+    // keep opcode and rd, set qr, aa and the rcode
+    let flags = u16::from_be_bytes([packet[2], packet[3]]) & 0x7900 | 0x8400 | RCODE_FORMERR;
+    response[2..4].copy_from_slice(&flags.to_be_bytes());
+    response[4..HEADER_LEN].fill(0);
+    Some(response)
+}
+
+struct Response {
+    id: u16,
+    flags: u16,
+    question: Vec<u8>,
+    records: Vec<u8>,
+    answers: u16,
+    authorities: u16,
+}
+
+impl Response {
+    fn new(query: &Query) -> Self {
+        Self {
+            id: query.id,
+            // keep opcode and rd, set qr and aa
+            flags: query.flags & 0x7900 | 0x8400,
+            question: query.question.clone(),
+            records: Vec::new(),
+            answers: 0,
+            authorities: 0,
+        }
+    }
+
+    fn record(&mut self, kind: u16, ttl: u32, data: &[u8]) {
+        // answers always come before the authority records
+        debug_assert_eq!(self.authorities, 0);
+        self.push(kind, ttl, data);
+        self.answers += 1;
+    }
+
+    fn authority(&mut self, kind: u16, ttl: u32, data: &[u8]) {
+        self.push(kind, ttl, data);
+        self.authorities += 1;
+    }
+
+    fn push(&mut self, kind: u16, ttl: u32, data: &[u8]) {
+        self.records.extend(QUESTION_NAME);
+        self.records.extend(kind.to_be_bytes());
+        self.records.extend(CLASS_IN.to_be_bytes());
+        self.records.extend(ttl.to_be_bytes());
+        self.records.extend((data.len() as u16).to_be_bytes());
+        self.records.extend(data);
+    }
+
+    fn finish(self, rcode: u16) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + self.question.len() + self.records.len());
+        packet.extend(self.id.to_be_bytes());
+        packet.extend((self.flags | rcode).to_be_bytes());
+        for count in [1, self.answers, self.authorities, 0] {
+            packet.extend(u16::to_be_bytes(count));
+        }
+        packet.extend(self.question);
+        packet.extend(self.records);
+        packet
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn query(name: &str, kind: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        packet.extend(encode(name));
+        packet.extend(kind.to_be_bytes());
+        packet.extend(CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    // rcode, answers and authorities of the response
+    fn summary(response: &[u8]) -> (u16, u16, u16) {
+        (
+            u16::from_be_bytes([response[2], response[3]]) & 0xf,
+            u16::from_be_bytes([response[6], response[7]]),
+            u16::from_be_bytes([response[8], response[9]]),
+        )
+    }
+
+    #[test]
+    fn answer() {
+        let dns = Dns::new(([127, 0, 0, 1], 53).into(), "gw.example.com.")
+            .address(Ipv4Addr::new(10, 0, 0, 1).into())
+            .address(Ipv6Addr::LOCALHOST.into());
+
+        let packet = query("Web.GW.example.com", TYPE_A);
+        let parsed = Query::parse(&packet).unwrap();
+        assert_eq!(dns.name(parsed.name()), Some("web"));
+
+        let response = dns.answer(&parsed, true);
+        assert_eq!(&response[..2], &[0x12, 0x34]);
+        // qr, aa and rd are set
+        assert_eq!(response[2], 0x85);
+        assert_eq!(summary(&response), (0, 1, 0));
+        assert_eq!(&response[response.len() - 4..], &[10, 0, 0, 1]);
+
+        let parsed = Query::parse(&query("web.gw.example.com", TYPE_AAAA)).unwrap();
+        let response = dns.answer(&parsed, true);
+        assert_eq!(summary(&response), (0, 1, 0));
+        assert_eq!(
+            &response[response.len() - 16..],
+            &Ipv6Addr::LOCALHOST.octets()
+        );
+
+        // not registered
+        let response = dns.answer(&parsed, false);
+        assert_eq!(summary(&response), (RCODE_NXDOMAIN, 0, 1));
+
+        // the zone itself
+        let parsed = Query::parse(&query("gw.example.com", TYPE_SOA)).unwrap();
+        assert_eq!(summary(&dns.answer(&parsed, false)), (0, 1, 0));
+
+        // outside the zone
+        let parsed = Query::parse(&query("web.example.com", TYPE_A)).unwrap();
+        assert_eq!(dns.name(parsed.name()), None);
+        assert_eq!(summary(&dns.answer(&parsed, true)), (RCODE_REFUSED, 0, 0));
+    }
+
+    #[test]
+    fn alias() {
+        let dns = Dns::new(([127, 0, 0, 1], 53).into(), "gw.example.com").alias("host.example.com");
+
+        let parsed = Query::parse(&query("web.gw.example.com", TYPE_A)).unwrap();
+        let response = dns.answer(&parsed, true);
+        assert_eq!(summary(&response), (0, 1, 0));
+        assert!(response.ends_with(&encode("host.example.com")));
+    }
+
+    #[test]
+    fn malformed_query() {
+        let mut packet = query("web.gw.example.com", TYPE_A);
+        packet.truncate(packet.len() - 2);
+        assert!(Query::parse(&packet).is_none());
+
+        let response = malformed(&packet).unwrap();
+        assert_eq!(response.len(), HEADER_LEN);
+        assert_eq!(summary(&response), (RCODE_FORMERR, 0, 0));
+
+        assert!(malformed(&packet[..4]).is_none());
+    }
+}
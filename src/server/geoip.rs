@@ -0,0 +1,164 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+/// GeoLookup finds the country of an ip
+pub trait GeoLookup: Send + Sync + 'static {
+    /// returns the ISO code of the country of that ip if known
+    fn country(&self, ip: IpAddr) -> Option<String>;
+}
+
+/// MaxMind lookups countries from a MaxMind (or GeoLite2) country database
+#[cfg(feature = "geoip")]
+pub struct MaxMind {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl MaxMind {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> crate::Result<Self> {
+        Ok(Self {
+            reader: maxminddb::Reader::open_readfile(path)?,
+        })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl GeoLookup for MaxMind {
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let country: maxminddb::geoip2::Country = self.reader.lookup(ip).ok()?;
+        country
+            .country
+            .and_then(|country| country.iso_code)
+            .map(String::from)
+    }
+}
+
+/// Policy of which countries can connect. Country codes are case
+/// insensitive
+#[derive(Debug, Clone)]
+pub enum Policy {
+    /// only allow clients from those countries
+    Allow(HashSet<String>),
+    /// allow all clients except from those countries
+    Deny(HashSet<String>),
+}
+
+impl Policy {
+    // country codes in upper case, as returned by the lookups
+    fn normalized(self) -> Self {
+        let upper = |countries: HashSet<String>| {
+            countries
+                .into_iter()
+                .map(|country| country.trim().to_uppercase())
+                .collect()
+        };
+
+        match self {
+            Self::Allow(countries) => Self::Allow(upper(countries)),
+            Self::Deny(countries) => Self::Deny(upper(countries)),
+        }
+    }
+
+    fn allowed(&self, country: Option<&str>) -> bool {
+        match (self, country) {
+            (Self::Allow(countries), Some(country)) => countries.contains(country),
+            // unknown countries are not allowed if there is an allow list
+            (Self::Allow(_), None) => false,
+            (Self::Deny(countries), Some(country)) => !countries.contains(country),
+            (Self::Deny(_), None) => true,
+        }
+    }
+}
+
+/// GeoFilter restricts which countries can connect to the registrations
+/// listeners. A registration specific policy overrides the global one
+pub struct GeoFilter {
+    lookup: Box<dyn GeoLookup>,
+    global: Option<Policy>,
+    registrations: HashMap<String, Policy>,
+}
+
+impl GeoFilter {
+    pub fn new<L: GeoLookup>(lookup: L) -> Self {
+        Self {
+            lookup: Box::new(lookup),
+            global: None,
+            registrations: HashMap::default(),
+        }
+    }
+
+    /// set policy of all registrations
+    pub fn global(mut self, policy: Policy) -> Self {
+        self.global = Some(policy.normalized());
+        self
+    }
+
+    /// set policy of a single registration
+    pub fn registration<N: Into<String>>(mut self, name: N, policy: Policy) -> Self {
+        self.registrations.insert(name.into(), policy.normalized());
+        self
+    }
+
+    /// check if client with that ip can connect to registration name
+    pub(crate) fn allowed(&self, name: &str, ip: IpAddr) -> bool {
+        let policy = match self.registrations.get(name).or(self.global.as_ref()) {
+            Some(policy) => policy,
+            None => return true,
+        };
+
+        let country = self
+            .lookup
+            .country(ip)
+            .map(|country| country.to_uppercase());
+        let allowed = policy.allowed(country.as_deref());
+
+        log::info!(
+            target: "access",
+            allowed = allowed,
+            client:% = ip,
+            country = country.as_deref().unwrap_or("unknown"),
+            name = name;
+            "geoip {} client '{}' ({}) to '{}'",
+            if allowed { "allowed" } else { "denied" },
+            ip,
+            country.as_deref().unwrap_or("unknown"),
+            name,
+        );
+
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Static;
+
+    impl GeoLookup for Static {
+        fn country(&self, ip: IpAddr) -> Option<String> {
+            match ip {
+                IpAddr::V4(ip) if ip.octets()[0] == 1 => Some("NL".into()),
+                IpAddr::V4(ip) if ip.octets()[0] == 2 => Some("EG".into()),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let filter = GeoFilter::new(Static)
+            .global(Policy::Deny(["EG".to_string()].into()))
+            .registration("private", Policy::Allow(["eg".to_string()].into()));
+
+        assert!(filter.allowed("public", [1, 0, 0, 1].into()));
+        assert!(!filter.allowed("public", [2, 0, 0, 1].into()));
+        assert!(filter.allowed("public", [3, 0, 0, 1].into()));
+
+        assert!(!filter.allowed("private", [1, 0, 0, 1].into()));
+        assert!(filter.allowed("private", [2, 0, 0, 1].into()));
+        assert!(!filter.allowed("private", [3, 0, 0, 1].into()));
+    }
+}
@@ -0,0 +1,348 @@
+//! Handoff of the server listeners to a new server process, so the server can
+//! be restarted (or upgraded) without dropping the tcp sockets:
+//!
+//! - with systemd socket activation the agents listener is created by systemd
+//!   and passed to each server process, see [`Listeners::activated`]
+//! - a running server configured with a handoff socket sends its agents listener
+//!   and all the registrations listeners to a new process that calls
+//!   [`Listeners::take_over`], then it terminates its agents so they reconnect
+//!   to the new process. The new process adopts the listener of a name when an
+//!   agent registers it again, clients that connected meanwhile wait in the
+//!   listener backlog
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    mem::size_of,
+    net::TcpListener,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{Error, Result};
+
+/// name of the agents listener in the handoff, it can't be a registered name
+const AGENTS: &str = "";
+/// max number of listeners sent in a single message
+const BATCH: usize = 128;
+/// first file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+/// max time to wait for the new process to receive the listeners
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listeners inherited from a previous server process (or from systemd)
+#[derive(Debug, Default)]
+pub struct Listeners {
+    agents: Option<TcpListener>,
+    registrations: HashMap<String, TcpListener>,
+}
+
+impl Listeners {
+    /// listeners passed by systemd socket activation (`LISTEN_FDS`), the first
+    /// one is used as the agents listener. Returns None if the process was not
+    /// socket activated
+    pub fn activated() -> Result<Option<Self>> {
+        let pid = std::env::var("LISTEN_PID").ok();
+        if pid.as_deref() != Some(&std::process::id().to_string()) {
+            return Ok(None);
+        }
+
+        let count: RawFd = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0);
+
+        if count < 1 {
+            return Ok(None);
+        }
+
+        // the rest of the sockets are not used, they are closed
+        for fd in LISTEN_FDS_START + 1..LISTEN_FDS_START + count {
+            drop(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+
+        let agents = listener(unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) })?;
+        Ok(Some(Self {
+            agents: Some(agents),
+            registrations: HashMap::default(),
+        }))
+    }
+
+    /// take over the listeners of the server listening on the handoff socket
+    /// at `path`. The old server shuts down once the listeners are received
+    pub async fn take_over<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let socket = tokio::net::UnixStream::connect(path).await?.into_std()?;
+        // the listeners are received with blocking calls
+        tokio::task::spawn_blocking(move || Self::take_from(socket))
+            .await
+            .map_err(|err| Error::IO(io::Error::other(err)))?
+    }
+
+    fn take_from(mut socket: UnixStream) -> Result<Self> {
+        socket.set_nonblocking(false)?;
+        socket.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+        socket.set_write_timeout(Some(HANDOFF_TIMEOUT))?;
+
+        let mut listeners = Self::default();
+
+        loop {
+            let (names, fds) = receive(&socket)?;
+            if names.is_empty() {
+                break;
+            }
+
+            for (name, fd) in names.into_iter().zip(fds) {
+                let fd = listener(fd)?;
+                if name == AGENTS {
+                    listeners.agents = Some(fd);
+                } else {
+                    listeners.registrations.insert(name, fd);
+                }
+            }
+
+            // ready for the next batch
+            socket.write_all(&[1])?;
+        }
+
+        log::info!(
+            "took over the agents listener and {} registrations listeners",
+            listeners.registrations.len()
+        );
+
+        socket.write_all(&[1])?;
+        Ok(listeners)
+    }
+
+    pub(crate) fn split(self) -> (Option<TcpListener>, Adoption) {
+        (
+            self.agents,
+            Adoption {
+                listeners: Arc::new(Mutex::new(self.registrations)),
+            },
+        )
+    }
+}
+
+/// Registrations listeners waiting to be adopted by the agents that register
+/// their names again
+#[derive(Clone, Default)]
+pub(crate) struct Adoption {
+    listeners: Arc<Mutex<HashMap<String, TcpListener>>>,
+}
+
+impl Adoption {
+    /// take the inherited listener of name if any
+    pub fn adopt(&self, name: &str) -> Result<Option<tokio::net::TcpListener>> {
+        let listener = self.listeners.lock().unwrap().remove(name);
+        listener
+            .map(tokio::net::TcpListener::from_std)
+            .transpose()
+            .map_err(Error::IO)
+    }
+
+    /// close the listeners that are not adopted, their waiting clients are
+    /// dropped
+    pub fn abandon(&self) {
+        let mut listeners = self.listeners.lock().unwrap();
+        for name in listeners.keys() {
+            log::info!("closing the listener of '{}', no agent registered it", name);
+        }
+
+        listeners.clear();
+    }
+}
+
+// a std listener from a passed socket, it must be a listening tcp socket
+fn listener(fd: OwnedFd) -> Result<TcpListener> {
+    let listener = TcpListener::from(fd);
+    listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// hand over the agents listener and the registrations listeners (by name)
+/// over an accepted handoff connection. The sockets must stay open until this
+/// returns
+pub(crate) fn hand_over(
+    mut socket: UnixStream,
+    agents: RawFd,
+    registrations: &[(String, RawFd)],
+) -> Result<()> {
+    socket.set_nonblocking(false)?;
+    socket.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+    socket.set_write_timeout(Some(HANDOFF_TIMEOUT))?;
+
+    let mut listeners = vec![(AGENTS.to_string(), agents)];
+    listeners.extend_from_slice(registrations);
+
+    let mut ack = [0];
+    for batch in listeners.chunks(BATCH) {
+        let names: Vec<&str> = batch.iter().map(|(name, _)| name.as_str()).collect();
+        let fds: Vec<RawFd> = batch.iter().map(|(_, fd)| *fd).collect();
+        send(&socket, &names, &fds)?;
+        socket.read_exact(&mut ack)?;
+    }
+
+    // end of listeners, then wait until the new process has them all
+    send(&socket, &[], &[])?;
+    socket.read_exact(&mut ack)?;
+
+    Ok(())
+}
+
+// each message is the length of a json list of names followed by the list,
+// with the sockets of the names attached in the same order
+fn send(socket: &UnixStream, names: &[&str], fds: &[RawFd]) -> Result<()> {
+    let names = serde_json::to_vec(names).map_err(io::Error::other)?;
+    let mut data = (names.len() as u32).to_be_bytes().to_vec();
+    data.extend(names);
+
+    let fds_size = std::mem::size_of_val(fds);
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_size as u32) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+
+        unsafe {
+            let header = libc::CMSG_FIRSTHDR(&msg);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(fds_size as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(header) as *mut RawFd,
+                fds.len(),
+            );
+        }
+    }
+
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    // the sockets go with the first chunk, the rest (if any) is plain data
+    (&*socket).write_all(&data[sent as usize..])?;
+    Ok(())
+}
+
+fn receive(socket: &UnixStream) -> Result<(Vec<String>, Vec<OwnedFd>)> {
+    let mut data = vec![0u8; 64 * 1024];
+    let mut control =
+        vec![0u8; unsafe { libc::CMSG_SPACE((size_of::<RawFd>() * BATCH) as u32) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error().into());
+    } else if received == 0 {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+
+    let mut fds = vec![];
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&msg);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let count =
+                    ((*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / size_of::<RawFd>();
+                let first = libc::CMSG_DATA(header) as *const RawFd;
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(first.add(i).read_unaligned()));
+                }
+            }
+            header = libc::CMSG_NXTHDR(&msg, header);
+        }
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "handoff sockets are truncated").into());
+    }
+
+    // complete the message if it was split
+    let mut data = data[..received as usize].to_vec();
+    if data.len() < 4 {
+        let mut rest = vec![0; 4 - data.len()];
+        (&*socket).read_exact(&mut rest)?;
+        data.extend(rest);
+    }
+
+    let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if data.len() < len + 4 {
+        let mut rest = vec![0; len + 4 - data.len()];
+        (&*socket).read_exact(&mut rest)?;
+        data.extend(rest);
+    }
+
+    let names: Vec<String> = serde_json::from_slice(&data[4..len + 4])
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    if names.len() != fds.len() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("expected {} handoff sockets got {}", names.len(), fds.len()),
+        )
+        .into());
+    }
+
+    Ok((names, fds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn handoff() {
+        let dir = std::env::temp_dir().join(format!("diglett-handoff-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("handoff.sock");
+        let server = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let agents = TcpListener::bind("127.0.0.1:0").unwrap();
+        let web = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (agents_fd, web_fd) = (agents.as_raw_fd(), web.as_raw_fd());
+
+        let handler = std::thread::spawn(move || {
+            let (socket, _) = server.accept().unwrap();
+            hand_over(socket, agents_fd, &[("web".to_string(), web_fd)]).unwrap();
+        });
+
+        let taken = Listeners::take_over(&path).await.unwrap();
+        handler.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            taken.agents.unwrap().local_addr().unwrap(),
+            agents.local_addr().unwrap()
+        );
+
+        // clients of the old listener are accepted by the new one
+        let address = web.local_addr().unwrap();
+        drop(web);
+        let _client = std::net::TcpStream::connect(address).unwrap();
+        let inherited = &taken.registrations["web"];
+        inherited.set_nonblocking(false).unwrap();
+        assert!(inherited.accept().is_ok());
+    }
+}
@@ -0,0 +1,144 @@
+use std::net::SocketAddr;
+
+use super::stats::StreamStats;
+use crate::{
+    wire::{Metadata, Reason, Stream},
+    Error,
+};
+
+/// Information about a connected agent
+#[derive(Debug, Clone)]
+pub struct Agent {
+    /// remote address of the agent connection
+    pub peer: SocketAddr,
+    /// registered name
+    pub name: String,
+    /// labels sent by the agent at login (hostname, version, ...)
+    pub labels: Metadata,
+}
+
+/// Hooks are invoked by the server on important events in the life time
+/// of agents connections. All hooks have a default no-op implementation
+/// so an implementation only need to override what it's interested in.
+#[async_trait::async_trait]
+pub trait ServerHooks: Send + Sync + 'static {
+    /// agent has been authenticated and its registration is being served
+    async fn on_agent_connected(&self, _agent: &Agent) {}
+
+    /// agent disconnected and its registration is released
+    async fn on_agent_disconnected(&self, _agent: &Agent) {}
+
+    /// a new client connection has been accepted for this agent registration
+    async fn on_stream_opened(&self, _agent: &Agent, _stream: Stream, _client: SocketAddr) {}
+
+    /// a client connection has been closed
+    async fn on_stream_closed(&self, _agent: &Agent, _stream: Stream) {}
+
+    /// final stats of a closed client connection
+    async fn on_stream_stats(&self, _agent: &Agent, _stats: &StreamStats) {}
+
+    /// agent failed to authenticate
+    async fn on_auth_failed(&self, _peer: SocketAddr, _err: &Error) {}
+
+    /// a name has been registered and its listener is open. Agents that join or
+    /// take over a live registration don't register the name again
+    async fn on_registered(&self, _name: &str, _port: u16) {}
+
+    /// the registration of the name is released after its last agent
+    /// disconnected
+    async fn on_released(&self, _name: &str) {}
+
+    /// the server terminated the agent connection (for example on eviction,
+    /// or after the agent has been replaced)
+    async fn on_agent_kicked(&self, _agent: &Agent, _reason: Reason) {}
+
+    /// another agent logged in from `peer` with the same identity (token or
+    /// certificate) as this agent
+    async fn on_duplicate_login(&self, _agent: &Agent, _peer: SocketAddr) {}
+}
+
+/// NoHooks does nothing on all events
+#[derive(Debug, Clone)]
+pub struct NoHooks;
+
+impl ServerHooks for NoHooks {}
+
+/// HookSet invokes multiple hooks on each event, in the order they were added
+#[derive(Default)]
+pub struct HookSet {
+    hooks: Vec<Box<dyn ServerHooks>>,
+}
+
+impl HookSet {
+    pub fn with<H: ServerHooks>(mut self, hooks: H) -> Self {
+        self.hooks.push(Box::new(hooks));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+}
+
+#[async_trait::async_trait]
+impl ServerHooks for HookSet {
+    async fn on_agent_connected(&self, agent: &Agent) {
+        for hooks in &self.hooks {
+            hooks.on_agent_connected(agent).await;
+        }
+    }
+
+    async fn on_agent_disconnected(&self, agent: &Agent) {
+        for hooks in &self.hooks {
+            hooks.on_agent_disconnected(agent).await;
+        }
+    }
+
+    async fn on_stream_opened(&self, agent: &Agent, stream: Stream, client: SocketAddr) {
+        for hooks in &self.hooks {
+            hooks.on_stream_opened(agent, stream, client).await;
+        }
+    }
+
+    async fn on_stream_closed(&self, agent: &Agent, stream: Stream) {
+        for hooks in &self.hooks {
+            hooks.on_stream_closed(agent, stream).await;
+        }
+    }
+
+    async fn on_stream_stats(&self, agent: &Agent, stats: &StreamStats) {
+        for hooks in &self.hooks {
+            hooks.on_stream_stats(agent, stats).await;
+        }
+    }
+
+    async fn on_auth_failed(&self, peer: SocketAddr, err: &Error) {
+        for hooks in &self.hooks {
+            hooks.on_auth_failed(peer, err).await;
+        }
+    }
+
+    async fn on_registered(&self, name: &str, port: u16) {
+        for hooks in &self.hooks {
+            hooks.on_registered(name, port).await;
+        }
+    }
+
+    async fn on_released(&self, name: &str) {
+        for hooks in &self.hooks {
+            hooks.on_released(name).await;
+        }
+    }
+
+    async fn on_agent_kicked(&self, agent: &Agent, reason: Reason) {
+        for hooks in &self.hooks {
+            hooks.on_agent_kicked(agent, reason).await;
+        }
+    }
+
+    async fn on_duplicate_login(&self, agent: &Agent, peer: SocketAddr) {
+        for hooks in &self.hooks {
+            hooks.on_duplicate_login(agent, peer).await;
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Lease of an agent registrations. The lease is renewed each time the
+/// agent sends anything over the connection (including keep alive pings).
+/// If the lease expires the registrations are dropped even if the agent
+/// connection is still (half) open.
+pub(crate) struct Lease {
+    ttl: Option<Duration>,
+    start: Instant,
+    // milliseconds since start of last renewal
+    renewed: AtomicU64,
+}
+
+impl Lease {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            start: Instant::now(),
+            renewed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn renew(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.renewed.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn deadline(&self, ttl: Duration) -> Instant {
+        self.start + Duration::from_millis(self.renewed.load(Ordering::Relaxed)) + ttl
+    }
+
+    /// wait until the lease expires. If lease has no ttl it never expires
+    pub async fn expired(&self) {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return std::future::pending().await,
+        };
+
+        loop {
+            let deadline = self.deadline(ttl);
+            if deadline <= Instant::now() {
+                return;
+            }
+
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn expire() {
+        let lease = Lease::new(Some(Duration::from_secs(10)));
+
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        lease.renew();
+
+        let now = Instant::now();
+        lease.expired().await;
+        assert_eq!(now.elapsed().as_secs(), 10);
+    }
+}
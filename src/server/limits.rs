@@ -0,0 +1,281 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+/// Limits of a single user
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// max number of concurrent agents of a user
+    pub agents: Option<usize>,
+    /// max number of registered names of a user across all agents
+    pub names: Option<usize>,
+}
+
+/// Limits of concurrent connections of public clients, per client ip
+#[derive(Debug, Clone, Default)]
+pub struct ClientLimits {
+    /// max concurrent connections of a single ip to one registration
+    pub registration: Option<usize>,
+    /// max concurrent connections of a single ip to all registrations
+    pub global: Option<usize>,
+}
+
+/// IpConnections keeps track of the connections count of each client ip
+pub(crate) struct IpConnections {
+    limit: Option<usize>,
+    ips: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl IpConnections {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            ips: Mutex::default(),
+        }
+    }
+
+    /// acquire a connection for that ip, returns None if the ip reached
+    /// the limit. The connection is released when the guard is dropped
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpConnection> {
+        let mut ips = self.ips.lock().unwrap();
+        let count = ips.entry(ip).or_default();
+        if let Some(limit) = self.limit {
+            if *count >= limit {
+                if *count == 0 {
+                    // entry was just created
+                    ips.remove(&ip);
+                }
+                return None;
+            }
+        }
+
+        *count += 1;
+        Some(IpConnection {
+            connections: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+/// Connection of a client ip, released on drop
+pub(crate) struct IpConnection {
+    connections: Arc<IpConnections>,
+    ip: IpAddr,
+}
+
+impl Drop for IpConnection {
+    fn drop(&mut self) {
+        let mut ips = self.connections.ips.lock().unwrap();
+        if let Some(count) = ips.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                ips.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// A connected agent of a user
+struct Connected {
+    peer: SocketAddr,
+    names: Vec<String>,
+}
+
+impl Display for Connected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} [{}]", self.peer, self.names.join(", "))
+    }
+}
+
+#[derive(Default)]
+struct Used {
+    // connected agents by their id
+    agents: BTreeMap<u64, Connected>,
+    // registered names and how many agents of the user serve them
+    names: HashMap<String, usize>,
+}
+
+/// Quotas keeps track of what each user is currently using
+pub(crate) struct Quotas<U> {
+    limits: Limits,
+    users: Mutex<HashMap<U, Used>>,
+}
+
+impl<U> Quotas<U>
+where
+    U: Clone + Eq + Hash,
+{
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            users: Mutex::default(),
+        }
+    }
+
+    /// acquire an agent quota for the user. The quota is released when
+    /// the returned guard is dropped. If the user reached the limit the
+    /// error lists the currently connected agents, so the user can find
+    /// the stale one
+    pub fn agent(
+        self: &Arc<Self>,
+        user: &U,
+        id: u64,
+        peer: SocketAddr,
+    ) -> Result<Quota<U>, String> {
+        let mut users = self.users.lock().unwrap();
+        let used = users.entry(user.clone()).or_default();
+        if let Some(max) = self.limits.agents {
+            if used.agents.len() >= max {
+                if used.agents.is_empty() {
+                    // entry was just created
+                    users.remove(user);
+                    return Err(format!(
+                        "user reached the maximum of {} connected agents",
+                        max
+                    ));
+                }
+
+                let connected: Vec<String> = used
+                    .agents
+                    .iter()
+                    .map(|(id, agent)| format!("#{} {}", id, agent))
+                    .collect();
+
+                return Err(format!(
+                    "user reached the maximum of {} connected agents, connected agents: {}",
+                    max,
+                    connected.join("; ")
+                ));
+            }
+        }
+
+        used.agents.insert(
+            id,
+            Connected {
+                peer,
+                names: Vec::default(),
+            },
+        );
+
+        Ok(Quota {
+            quotas: Arc::clone(self),
+            user: user.clone(),
+            id,
+            names: Vec::default(),
+        })
+    }
+}
+
+/// Quota of a single agent
+pub(crate) struct Quota<U: Clone + Eq + Hash> {
+    quotas: Arc<Quotas<U>>,
+    user: U,
+    id: u64,
+    names: Vec<String>,
+}
+
+impl<U> Quota<U>
+where
+    U: Clone + Eq + Hash,
+{
+    /// acquire a name quota, names are released with the agent quota. A name
+    /// that is already registered by another agent of the user (for example
+    /// when the agent takes it over) is not counted again
+    pub fn name(&mut self, name: &str) -> Result<(), String> {
+        let mut users = self.quotas.users.lock().unwrap();
+        let used = users.entry(self.user.clone()).or_default();
+        if let Some(max) = self.quotas.limits.names {
+            if !used.names.contains_key(name) && used.names.len() >= max {
+                return Err(format!(
+                    "user reached the maximum of {} registered names",
+                    max
+                ));
+            }
+        }
+
+        *used.names.entry(name.into()).or_default() += 1;
+        if let Some(agent) = used.agents.get_mut(&self.id) {
+            agent.names.push(name.into());
+        }
+
+        self.names.push(name.into());
+        Ok(())
+    }
+}
+
+impl<U> Drop for Quota<U>
+where
+    U: Clone + Eq + Hash,
+{
+    fn drop(&mut self) {
+        let mut users = self.quotas.users.lock().unwrap();
+        if let Some(used) = users.get_mut(&self.user) {
+            used.agents.remove(&self.id);
+            for name in &self.names {
+                if let Some(count) = used.names.get_mut(name) {
+                    *count -= 1;
+                    if *count == 0 {
+                        used.names.remove(name);
+                    }
+                }
+            }
+
+            if used.agents.is_empty() {
+                users.remove(&self.user);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let quotas = Arc::new(Quotas::new(Limits {
+            agents: Some(2),
+            names: Some(1),
+        }));
+
+        let peer: SocketAddr = ([10, 0, 0, 1], 4000).into();
+        let mut first = quotas.agent(&1, 1, peer).unwrap();
+        let mut second = quotas.agent(&1, 2, peer).unwrap();
+        assert!(quotas.agent(&2, 3, peer).is_ok());
+
+        first.name("web").unwrap();
+        assert!(second.name("api").is_err());
+
+        let err = quotas.agent(&1, 4, peer).err().unwrap();
+        assert!(err.contains("#1 10.0.0.1:4000 [web]; #2 10.0.0.1:4000 []"));
+
+        drop(first);
+        second.name("api").unwrap();
+        let mut third = quotas.agent(&1, 5, peer).unwrap();
+
+        // taking over a name of the user doesn't count as a new name
+        third.name("api").unwrap();
+        drop(second);
+        assert!(third.name("web").is_err());
+        drop(third);
+        assert!(quotas.users.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn connections() {
+        let connections = Arc::new(IpConnections::new(Some(1)));
+        let ip: IpAddr = [127, 0, 0, 1].into();
+
+        let first = connections.acquire(ip).unwrap();
+        assert!(connections.acquire(ip).is_none());
+        assert!(connections.acquire([127, 0, 0, 2].into()).is_some());
+
+        drop(first);
+        assert!(connections.acquire(ip).is_some());
+        assert!(connections.ips.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use super::auth::Peer;
+
+/// What to do with a connected agent when another agent logs in with the
+/// same identity (the same token, or the same certificate with mutual tls)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateLogin {
+    /// both agents are served silently (for example multiple agents that
+    /// deliberately share a token to balance a name)
+    Allow,
+    /// both agents are served but the first session logs a warning and
+    /// invokes the duplicate login hook
+    #[default]
+    Warn,
+    /// the first agent is disconnected in favor of the new one
+    Disconnect,
+}
+
+/// identity of an agent, the subject of its certificate if it connected over
+/// mutual tls, otherwise a hash of its login token
+pub(crate) fn identity(peer: &Peer, token: &str) -> String {
+    match &peer.subject {
+        Some(subject) => format!("subject:{}", subject),
+        None => {
+            let hash = Sha256::digest(token.as_bytes());
+            let hash: String = hash[..8].iter().map(|b| format!("{:02x}", b)).collect();
+            format!("token:{}", hash)
+        }
+    }
+}
+
+// notifies a session with the address of the agents that logged in after it
+type Duplicates = watch::Sender<Option<SocketAddr>>;
+
+/// Logins keeps track of the sessions of each agent identity
+pub(crate) struct Logins {
+    policy: DuplicateLogin,
+    // sessions of each identity by agent id
+    sessions: Mutex<HashMap<String, BTreeMap<u64, Duplicates>>>,
+}
+
+impl Logins {
+    pub fn new(policy: DuplicateLogin) -> Self {
+        Self {
+            policy,
+            sessions: Mutex::default(),
+        }
+    }
+
+    pub fn policy(&self) -> DuplicateLogin {
+        self.policy
+    }
+
+    /// track the login of agent `id`, the sessions already logged in with the
+    /// same identity are notified. The login is tracked until the returned
+    /// guard is dropped
+    pub fn login(self: &Arc<Self>, identity: String, id: u64, peer: SocketAddr) -> Login {
+        let mut sessions = self.sessions.lock().unwrap();
+        let sessions = sessions.entry(identity.clone()).or_default();
+
+        if self.policy != DuplicateLogin::Allow {
+            for session in sessions.values() {
+                session.send_replace(Some(peer));
+            }
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        sessions.insert(id, sender);
+
+        Login {
+            logins: Arc::clone(self),
+            identity,
+            id,
+            duplicates: receiver,
+        }
+    }
+}
+
+/// Login of an agent, released on drop
+pub(crate) struct Login {
+    logins: Arc<Logins>,
+    identity: String,
+    id: u64,
+    duplicates: watch::Receiver<Option<SocketAddr>>,
+}
+
+impl Login {
+    /// receiver of the address of the last agent that logged in with the same
+    /// identity after this one
+    pub fn duplicates(&self) -> watch::Receiver<Option<SocketAddr>> {
+        self.duplicates.clone()
+    }
+}
+
+impl Drop for Login {
+    fn drop(&mut self) {
+        let mut sessions = self.logins.sessions.lock().unwrap();
+        if let Some(identity) = sessions.get_mut(&self.identity) {
+            identity.remove(&self.id);
+            if identity.is_empty() {
+                sessions.remove(&self.identity);
+            }
+        }
+    }
+}
+
+/// wait until another agent logs in with the same identity, returns its address
+pub(crate) async fn duplicated(duplicates: &mut watch::Receiver<Option<SocketAddr>>) -> SocketAddr {
+    loop {
+        if duplicates.changed().await.is_err() {
+            // login is gone
+            return std::future::pending().await;
+        }
+
+        if let Some(peer) = *duplicates.borrow_and_update() {
+            return peer;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn duplicates() {
+        let logins = Arc::new(Logins::new(DuplicateLogin::Warn));
+
+        let first = logins.login("token:a".into(), 1, peer(1000));
+        let other = logins.login("token:b".into(), 2, peer(2000));
+        let mut first_duplicates = first.duplicates();
+        let mut other_duplicates = other.duplicates();
+
+        let second = logins.login("token:a".into(), 3, peer(3000));
+        assert_eq!(duplicated(&mut first_duplicates).await, peer(3000));
+
+        // other identities and the new session are not notified
+        let mut second_duplicates = second.duplicates();
+        for duplicates in [&mut other_duplicates, &mut second_duplicates] {
+            assert!(
+                tokio::time::timeout(Duration::from_millis(10), duplicated(duplicates))
+                    .await
+                    .is_err()
+            );
+        }
+
+        drop(second);
+        drop(first);
+        assert_eq!(logins.sessions.lock().unwrap().len(), 1);
+
+        let logins = Arc::new(Logins::new(DuplicateLogin::Allow));
+        let first = logins.login("token:a".into(), 1, peer(1000));
+        let _second = logins.login("token:a".into(), 2, peer(2000));
+        assert!(!first.duplicates().has_changed().unwrap());
+    }
+
+    #[test]
+    fn identities() {
+        let tls = Peer {
+            addr: peer(1000),
+            subject: Some("agent-1".into()),
+        };
+        assert_eq!(identity(&tls, "token"), "subject:agent-1");
+
+        let plain = Peer {
+            addr: peer(1000),
+            subject: None,
+        };
+        assert_eq!(identity(&plain, "token"), identity(&plain, "token"));
+        assert_ne!(identity(&plain, "token"), identity(&plain, "other"));
+        assert!(!identity(&plain, "token").contains("token:token"));
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Mode of the server maintenance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// server operates normally
+    #[default]
+    Off,
+    /// server does not accept new agents, existing agents are kept
+    On,
+    /// server does not accept new agents and existing agents are
+    /// terminated so they reconnect elsewhere
+    Evict,
+}
+
+/// Maintenance is a handle to toggle the maintenance mode of a running
+/// server
+#[derive(Clone)]
+pub struct Maintenance {
+    mode: Arc<watch::Sender<Mode>>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            mode: Arc::new(watch::channel(Mode::Off).0),
+        }
+    }
+}
+
+impl Maintenance {
+    /// set maintenance mode
+    pub fn set(&self, mode: Mode) {
+        let old = self.mode.send_replace(mode);
+        if old != mode {
+            log::info!("maintenance mode changed to: {:?}", mode);
+        }
+    }
+
+    /// get current maintenance mode
+    pub fn mode(&self) -> Mode {
+        *self.mode.borrow()
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<Mode> {
+        self.mode.subscribe()
+    }
+}
+
+/// wait until existing agents need to be evicted
+pub(crate) async fn evicted(mode: &mut watch::Receiver<Mode>) {
+    let evict = mode.wait_for(|mode| *mode == Mode::Evict).await.is_ok();
+
+    if !evict {
+        // maintenance handle is gone
+        std::future::pending::<()>().await;
+    }
+}
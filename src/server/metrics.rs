@@ -0,0 +1,270 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use super::stats::Stats;
+
+/// Metrics of the server. The metrics can be rendered in the prometheus
+/// text format.
+#[derive(Default)]
+pub struct Metrics {
+    agents_rejected: AtomicU64,
+    names_rejected: AtomicU64,
+    clients_rejected: AtomicU64,
+    handshakes_rejected: AtomicU64,
+    links: Mutex<BTreeMap<u64, (String, Arc<Link>)>>,
+}
+
+impl Metrics {
+    pub(crate) fn agent_rejected(&self) {
+        self.agents_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn name_rejected(&self) {
+        self.names_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_rejected(&self) {
+        self.clients_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handshake_rejected(&self) {
+        self.handshakes_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// export the link metrics of the agent connection until the returned
+    /// guard is dropped
+    pub(crate) fn link(self: &Arc<Self>, agent: u64, name: &str) -> Linked {
+        let link = Arc::new(Link::default());
+        self.links
+            .lock()
+            .unwrap()
+            .insert(agent, (name.into(), Arc::clone(&link)));
+
+        Linked {
+            agent,
+            link,
+            metrics: Arc::clone(self),
+        }
+    }
+
+    /// render metrics in prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "diglett_agents_rejected_total",
+            "agents rejected because the user reached max agents",
+            self.agents_rejected.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "diglett_names_rejected_total",
+            "registrations rejected because the user reached max names",
+            self.names_rejected.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "diglett_clients_rejected_total",
+            "client connections rejected by the connection limits or geoip filter",
+            self.clients_rejected.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "diglett_handshakes_rejected_total",
+            "agent connections dropped by the handshake rate limit",
+            self.handshakes_rejected.load(Ordering::Relaxed),
+        );
+
+        let links = self.links.lock().unwrap();
+        gauge(
+            &mut out,
+            "diglett_agent_rtt_seconds",
+            "round trip time of the agent connection",
+            links.iter().filter_map(|(agent, (name, link))| {
+                link.rtt()
+                    .map(|rtt| (*agent, name.as_str(), rtt.as_secs_f64()))
+            }),
+        );
+        gauge(
+            &mut out,
+            "diglett_agent_up_bytes_per_second",
+            "current throughput from the agent to the clients",
+            links.iter().map(|(agent, (name, link))| {
+                (
+                    *agent,
+                    name.as_str(),
+                    link.up.load(Ordering::Relaxed) as f64,
+                )
+            }),
+        );
+        gauge(
+            &mut out,
+            "diglett_agent_down_bytes_per_second",
+            "current throughput from the clients to the agent",
+            links.iter().map(|(agent, (name, link))| {
+                (
+                    *agent,
+                    name.as_str(),
+                    link.down.load(Ordering::Relaxed) as f64,
+                )
+            }),
+        );
+
+        out
+    }
+}
+
+/// Link is the measured quality of a single agent connection
+#[derive(Default)]
+pub(crate) struct Link {
+    // round trip time in micro seconds, 0 until the first probe is answered
+    rtt: AtomicU64,
+    up: AtomicU64,
+    down: AtomicU64,
+    // the pending probe and when it was sent
+    probe: Mutex<Option<(u32, Instant)>>,
+    // the counters of the last throughput sample
+    sample: Mutex<Option<(Stats, Instant)>>,
+}
+
+impl Link {
+    /// a probe with that sequence number has been sent. An unanswered
+    /// previous probe is forgotten
+    pub fn probed(&self, seq: u32) {
+        *self.probe.lock().unwrap() = Some((seq, Instant::now()));
+    }
+
+    /// the agent answered the probe with that sequence number
+    pub fn replied(&self, seq: u32) {
+        let mut probe = self.probe.lock().unwrap();
+        if let Some((sent, at)) = *probe {
+            if sent == seq {
+                let rtt = at.elapsed().as_micros().max(1) as u64;
+                self.rtt.store(rtt, Ordering::Relaxed);
+                *probe = None;
+            }
+        }
+    }
+
+    /// update the throughput from the agent counters
+    pub fn sample(&self, stats: Stats) {
+        let now = Instant::now();
+        let mut sample = self.sample.lock().unwrap();
+        if let Some((last, at)) = *sample {
+            let elapsed = now.duration_since(at).as_secs_f64();
+            if elapsed > 0.0 {
+                let rate = |bytes: u64| (bytes as f64 / elapsed) as u64;
+                self.up
+                    .store(rate(stats.up.saturating_sub(last.up)), Ordering::Relaxed);
+                self.down.store(
+                    rate(stats.down.saturating_sub(last.down)),
+                    Ordering::Relaxed,
+                );
+            }
+        }
+        *sample = Some((stats, now));
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::Relaxed) {
+            0 => None,
+            rtt => Some(Duration::from_micros(rtt)),
+        }
+    }
+}
+
+/// Linked removes the link metrics of the agent when dropped
+pub(crate) struct Linked {
+    agent: u64,
+    link: Arc<Link>,
+    metrics: Arc<Metrics>,
+}
+
+impl Linked {
+    pub fn link(&self) -> Arc<Link> {
+        Arc::clone(&self.link)
+    }
+}
+
+impl std::ops::Deref for Linked {
+    type Target = Link;
+
+    fn deref(&self) -> &Self::Target {
+        &self.link
+    }
+}
+
+impl Drop for Linked {
+    fn drop(&mut self) {
+        self.metrics.links.lock().unwrap().remove(&self.agent);
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// gauge of the agents connections, the values are (agent, name, value)
+fn gauge<'a, I>(out: &mut String, name: &str, help: &str, values: I)
+where
+    I: Iterator<Item = (u64, &'a str, f64)>,
+{
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (agent, registration, value) in values {
+        let _ = writeln!(
+            out,
+            "{}{{agent=\"{}\",name=\"{}\"}} {}",
+            name, agent, registration, value
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn link() {
+        let metrics = Arc::new(Metrics::default());
+        let linked = metrics.link(7, "web");
+        assert!(!metrics.render().contains("diglett_agent_rtt_seconds{"));
+
+        linked.probed(1);
+        tokio::time::advance(Duration::from_millis(20)).await;
+        // stale replies are ignored
+        linked.replied(0);
+        assert_eq!(linked.rtt(), None);
+        linked.replied(1);
+        assert_eq!(linked.rtt(), Some(Duration::from_millis(20)));
+
+        linked.sample(Stats::default());
+        tokio::time::advance(Duration::from_secs(10)).await;
+        linked.sample(Stats {
+            streams: 1,
+            up: 5000,
+            down: 100,
+        });
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(r#"diglett_agent_rtt_seconds{agent="7",name="web"} 0.02"#));
+        assert!(rendered.contains(r#"diglett_agent_up_bytes_per_second{agent="7",name="web"} 500"#));
+        assert!(
+            rendered.contains(r#"diglett_agent_down_bytes_per_second{agent="7",name="web"} 10"#)
+        );
+
+        drop(linked);
+        assert!(!metrics.render().contains(r#"agent="7""#));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::{wire::Stream, Result};
+
+/// Direction of the traffic of a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// from the agent to the connected client
+    Up,
+    /// from the connected client to the agent
+    Down,
+}
+
+/// StreamMiddleware can observe and optionally transform the bytes
+/// flowing over a single stream. An instance is created for each stream
+/// so it can keep per stream state (for example to sniff the protocol
+/// from the first bytes)
+pub trait StreamMiddleware: Send + 'static {
+    /// process a chunk of data flowing in the given direction and return
+    /// the data that need to be forwarded instead. An empty buffer forwards
+    /// nothing. Returning an error closes the stream
+    fn process(&mut self, direction: Direction, data: Vec<u8>) -> Result<Vec<u8>>;
+}
+
+/// Middlewares builds the middleware chain of each new stream of
+/// a registration.
+pub trait Middlewares: Send + Sync + 'static {
+    /// build the chain of a new stream of the registration `name`. Down
+    /// traffic goes through the chain in order, while up traffic goes through
+    /// it in reverse order
+    fn chain(&self, name: &str, stream: Stream) -> Vec<Box<dyn StreamMiddleware>>;
+}
+
+impl<F> Middlewares for F
+where
+    F: Fn(&str, Stream) -> Vec<Box<dyn StreamMiddleware>> + Send + Sync + 'static,
+{
+    fn chain(&self, name: &str, stream: Stream) -> Vec<Box<dyn StreamMiddleware>> {
+        self(name, stream)
+    }
+}
+
+pub(crate) struct Chain(Vec<Box<dyn StreamMiddleware>>);
+
+impl Chain {
+    pub fn new(chain: Vec<Box<dyn StreamMiddleware>>) -> Self {
+        Self(chain)
+    }
+
+    pub fn process(&mut self, direction: Direction, mut data: Vec<u8>) -> Result<Vec<u8>> {
+        let chain: Box<dyn Iterator<Item = &mut Box<dyn StreamMiddleware>>> = match direction {
+            Direction::Down => Box::new(self.0.iter_mut()),
+            Direction::Up => Box::new(self.0.iter_mut().rev()),
+        };
+
+        for middleware in chain {
+            data = middleware.process(direction, data)?;
+            if data.is_empty() {
+                break;
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Tag(u8);
+
+    impl StreamMiddleware for Tag {
+        fn process(&mut self, _direction: Direction, mut data: Vec<u8>) -> Result<Vec<u8>> {
+            data.push(self.0);
+            Ok(data)
+        }
+    }
+
+    #[test]
+    fn chain_order() {
+        let mut chain = Chain::new(vec![Box::new(Tag(1)), Box::new(Tag(2))]);
+
+        assert_eq!(
+            chain.process(Direction::Down, vec![0]).unwrap(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            chain.process(Direction::Up, vec![0]).unwrap(),
+            vec![0, 2, 1]
+        );
+    }
+}
@@ -1,30 +1,160 @@
-use std::{collections::HashMap, io::ErrorKind, sync::Arc};
+use std::{
+    future::Future,
+    hash::Hash,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
 use crate::{
-    wire::{self, Connection, Control, FrameReader, FrameWriter, Message, Stream},
+    http::FirstRequest,
+    wire::{
+        self, Code, Connection, Control, FrameReader, FrameStream, FrameWriter, Message, Metadata,
+        Reason, Split, Stream, StreamMap, Termination,
+    },
     Error, Result,
 };
 use secp256k1::Keypair;
-use tokio::{io::AsyncRead, sync::Mutex};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    io::AsyncRead,
+    sync::{mpsc, oneshot, watch, Mutex},
+    task::JoinSet,
+};
 use tokio::{
     io::AsyncWrite,
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener, TcpStream, ToSocketAddrs,
-    },
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     task::JoinHandle,
 };
 
-use self::{auth::Authenticate, register::Registerer};
+use self::{
+    agents::Agents,
+    auth::{Authenticate, Peer},
+    balance::Member,
+    hooks::{Agent, NoHooks},
+    lease::Lease,
+    limits::{IpConnection, IpConnections, Quotas},
+    logins::{duplicated, identity, Login, Logins},
+    maintenance::{evicted, Mode},
+    metrics::Link,
+    middleware::{Chain, Direction, Middlewares},
+    namespace::Namespace,
+    ratelimit::Limiter,
+    register::{Handler, Registerer},
+    registry::{replaced, Incoming, Listener, Registration, Registry},
+    router::Gate,
+    shaping::Shaper,
+    stats::{Counters, StreamStats, Streams},
+    tap::Tap,
+    usage::Accounting,
+};
+use crate::window::Window;
+#[cfg(unix)]
+use handoff::Adoption;
 
+mod admin;
+pub mod agents;
 pub mod auth;
+pub mod balance;
+pub mod bind;
+mod builder;
+pub mod denylist;
+mod dial;
+pub mod dns;
+pub mod geoip;
+#[cfg(unix)]
+pub mod handoff;
+pub mod hooks;
+mod lease;
+pub mod limits;
+pub mod logins;
+pub mod maintenance;
+pub mod metrics;
+pub mod middleware;
+pub mod names;
+pub mod namespace;
+pub mod nats;
+#[cfg(feature = "tls")]
+pub mod oauth;
+pub mod pcap;
+pub mod ratelimit;
 pub mod register;
+mod registry;
+pub mod relay;
+pub mod router;
+#[cfg(target_os = "linux")]
+pub mod sandbox;
+pub mod stats;
+pub mod tap;
+pub mod token;
+pub mod usage;
+pub mod webhooks;
+mod websocket;
 
-pub use auth::AuthorizeAll;
+pub use crate::shaping;
+pub use agents::AgentInfo;
+#[cfg(any(test, feature = "testing"))]
+pub use auth::StaticAuth;
+pub use auth::{AuthorizeAll, CertAuth};
+pub use balance::Balancing;
+pub use bind::{Bind, Public};
+pub use builder::{ServerBuilder, ServerConfig};
+pub use denylist::Denylist;
+pub use dns::Dns;
+pub use geoip::GeoFilter;
+#[cfg(unix)]
+pub use handoff::Listeners;
+pub use hooks::{HookSet, ServerHooks};
+pub use limits::{ClientLimits, Limits};
+pub use logins::DuplicateLogin;
+pub use maintenance::Maintenance;
+pub use metrics::Metrics;
+pub use middleware::StreamMiddleware;
+pub use names::Validation;
+pub use namespace::UserNamespace;
+pub use nats::Nats;
+#[cfg(feature = "tls")]
+pub use oauth::OAuth;
+pub use pcap::Pcap;
+pub use ratelimit::RateLimit;
 pub use register::PrintRegisterer;
+#[cfg(any(test, feature = "testing"))]
+pub use register::RecordingRegisterer;
+pub use relay::Relay;
+pub use router::HttpRouter;
+#[cfg(target_os = "linux")]
+pub use sandbox::{Privileges, Sandbox};
+pub use shaping::Bandwidth;
+pub use stats::Stats;
+pub use tap::TrafficTap;
+pub use token::Signed;
+pub use usage::Usage;
+pub use webhooks::Webhooks;
+
+/// default interval of delivering registrations stats to their handlers
+pub const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// how often a draining agent checks if all its streams are closed
+const DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how often the round trip time and throughput of agents are measured
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// min time inherited registrations listeners wait for their agents to
+/// register the names again
+const ADOPTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// max time to wait for agents connections to terminate on shutdown
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct Server<A, R>
 where
@@ -32,93 +162,627 @@ where
     R: Registerer,
 {
     kp: Keypair,
-    auth: Arc<A>,
-    reg: Arc<R>,
+    auth: A,
+    reg: R,
+    bind: Bind,
+    lease: Option<Duration>,
+    stats: Duration,
+    probe: Duration,
+    shutdown_timeout: Duration,
+    hooks: Arc<dyn ServerHooks>,
+    middlewares: Option<Box<dyn Middlewares>>,
+    tap: Option<Arc<dyn TrafficTap>>,
+    recordings: Option<PathBuf>,
+    #[cfg(unix)]
+    handoff: Option<PathBuf>,
+    #[cfg(unix)]
+    listeners: Option<Listeners>,
+    #[cfg(unix)]
+    adoption: Adoption,
+    #[cfg(target_os = "linux")]
+    privileges: Option<Privileges>,
+    usage: Option<Arc<Accounting<A::U>>>,
+    quotas: Arc<Quotas<A::U>>,
+    logins: Arc<Logins>,
+    metrics: Arc<Metrics>,
+    client_limits: ClientLimits,
+    bandwidth: Bandwidth,
+    ip_connections: Arc<IpConnections>,
+    handshakes: Option<Limiter>,
+    maintenance: Maintenance,
+    geoip: Option<GeoFilter>,
+    namespace: Option<Box<dyn Namespace<A::U>>>,
+    denylist: Option<Denylist>,
+    validation: Validation,
+    router: Option<Arc<HttpRouter>>,
+    dns: Option<Dns>,
+    streams: Arc<Streams>,
+    connected: Arc<Agents>,
+    admin: Option<SocketAddr>,
+    admin_token: Option<String>,
+    websocket: Option<SocketAddr>,
+    hold: Option<Duration>,
+    balancing: Balancing,
+    min_version: u8,
+    #[cfg(feature = "tls")]
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    registry: Registry<A::U, R::Handler>,
+    agents: AtomicU64,
+    shutdown: watch::Sender<Option<Termination>>,
+}
+
+impl Server<AuthorizeAll, PrintRegisterer> {
+    pub fn builder() -> ServerBuilder<AuthorizeAll, PrintRegisterer> {
+        ServerBuilder::default()
+    }
 }
 
 impl<A, R> Server<A, R>
 where
     A: Authenticate,
+    A::U: Clone + Eq + Hash + Sync,
     R: Registerer,
 {
-    pub fn new(kp: Keypair, auth: A, registerer: R) -> Self {
+    // a server with the default options, the options are set by the builder
+    pub(crate) fn new(kp: Keypair, auth: A, registerer: R) -> Self {
         Self {
             kp,
-            auth: Arc::new(auth),
-            reg: Arc::new(registerer),
+            auth,
+            reg: registerer,
+            bind: Bind::default(),
+            lease: None,
+            stats: STATS_INTERVAL,
+            probe: PROBE_INTERVAL,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            hooks: Arc::new(NoHooks),
+            middlewares: None,
+            tap: None,
+            recordings: None,
+            #[cfg(unix)]
+            handoff: None,
+            #[cfg(unix)]
+            listeners: None,
+            #[cfg(unix)]
+            adoption: Adoption::default(),
+            #[cfg(target_os = "linux")]
+            privileges: None,
+            usage: None,
+            quotas: Arc::new(Quotas::new(Limits::default())),
+            logins: Arc::new(Logins::new(DuplicateLogin::default())),
+            metrics: Arc::default(),
+            client_limits: ClientLimits::default(),
+            bandwidth: Bandwidth::default(),
+            ip_connections: Arc::new(IpConnections::new(None)),
+            handshakes: None,
+            maintenance: Maintenance::default(),
+            geoip: None,
+            namespace: None,
+            denylist: None,
+            validation: Validation::default(),
+            router: None,
+            dns: None,
+            streams: Arc::default(),
+            connected: Arc::default(),
+            admin: None,
+            admin_token: None,
+            websocket: None,
+            hold: None,
+            balancing: Balancing::default(),
+            min_version: 1,
+            #[cfg(feature = "tls")]
+            tls: None,
+            registry: Registry::default(),
+            agents: AtomicU64::new(1),
+            shutdown: watch::channel(None).0,
+        }
+    }
+
+    // the inherited listener of a name, if any
+    fn adopt(&self, name: &str) -> Result<Option<TcpListener>> {
+        #[cfg(unix)]
+        return self.adoption.adopt(name);
+        #[cfg(not(unix))]
+        {
+            let _ = name;
+            Ok(None)
         }
     }
 
+    /// get the maintenance handle of the server, it can be used to toggle
+    /// the maintenance mode while the server is running
+    pub fn maintenance(&self) -> Maintenance {
+        self.maintenance.clone()
+    }
+
+    /// get the server metrics
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// list all connected agents with their labels
+    pub fn agents(&self) -> Vec<AgentInfo> {
+        self.connected.list()
+    }
+
+    /// list all open streams with their counters
+    pub fn streams(&self) -> Vec<StreamStats> {
+        self.streams.list()
+    }
+
     pub async fn start<D: ToSocketAddrs>(self, addr: D) -> Result<()> {
-        let listener = TcpListener::bind(addr).await?;
+        self.start_until(addr, std::future::pending()).await
+    }
+
+    /// start the server until the shutdown future resolves. On exit (shutdown
+    /// or fatal error) all connected agents are sent a terminate message so
+    /// they can reconnect immediately (possibly to another gateway)
+    pub async fn start_until<D, S>(mut self, addr: D, shutdown: S) -> Result<()>
+    where
+        D: ToSocketAddrs,
+        S: Future<Output = ()>,
+    {
+        #[cfg(unix)]
+        let inherited = {
+            let (inherited, adoption) = self
+                .listeners
+                .take()
+                .map(Listeners::split)
+                .unwrap_or_default();
+            self.adoption = adoption;
+            inherited
+        };
+        #[cfg(not(unix))]
+        let inherited = None;
+
+        let listener = match inherited {
+            Some(listener) => TcpListener::from_std(listener)?,
+            None => TcpListener::bind(addr).await?,
+        };
+
+        self.run(Source::<TcpStream>::Listener(listener), shutdown)
+            .await
+    }
+
+    /// serve a single agent over the stream (for example the stdin and stdout
+    /// of the process, see [`Pipes::stdio`](crate::wire::Pipes::stdio))
+    /// instead of listening for agents. It returns once the agent disconnects
+    /// or the shutdown future resolves
+    pub async fn serve_until<T, S>(self, stream: T, peer: SocketAddr, shutdown: S) -> Result<()>
+    where
+        T: Split,
+        S: Future<Output = ()>,
+    {
+        self.run(Source::Stream(stream, peer), shutdown).await
+    }
+
+    async fn run<T, S>(self, source: Source<T>, shutdown: S) -> Result<()>
+    where
+        T: Split,
+        S: Future<Output = ()>,
+    {
+        let (listener, stream) = match source {
+            Source::Listener(listener) => (Some(listener), None),
+            Source::Stream(stream, peer) => (None, Some((stream, peer))),
+        };
+
+        #[cfg(unix)]
+        let handoff = match &self.handoff {
+            // the listeners are only handed over by listening servers
+            Some(_) if listener.is_none() => None,
+            Some(path) => {
+                // left over by the previous process
+                let _ = std::fs::remove_file(path);
+                Some(UnixListener::bind(path)?)
+            }
+            None => None,
+        };
+        #[cfg(not(unix))]
+        let handoff = None;
 
-        while let Ok((socket, _)) = listener.accept().await {
-            // serve one agent
-            let auth = Arc::clone(&self.auth);
-            let reg = Arc::clone(&self.reg);
-            let kp = self.kp;
+        let server = Arc::new(self);
+
+        // inherited listeners of names that are not registered again in time
+        // are closed
+        #[cfg(unix)]
+        {
+            let adoption = server.adoption.clone();
+            let timeout = ADOPTION_TIMEOUT.max(server.hold.unwrap_or_default());
             tokio::spawn(async move {
-                if let Err(err) = handle_agent(kp, auth, reg, socket).await {
+                tokio::time::sleep(timeout).await;
+                adoption.abandon();
+            });
+        }
+
+        if let Some(usage) = &server.usage {
+            tokio::spawn(Arc::clone(usage).run());
+        }
+
+        let router = match &server.router {
+            Some(router) => {
+                let listener = TcpListener::bind(router.listen()).await?;
+                Some(tokio::spawn(serve_router(Arc::clone(&server), listener)))
+            }
+            None => None,
+        };
+
+        let dns = match &server.dns {
+            Some(dns) => {
+                let socket = UdpSocket::bind(dns.listen()).await?;
+                Some(tokio::spawn(dns::serve(Arc::clone(&server), socket)))
+            }
+            None => None,
+        };
+
+        let admin = match server.admin {
+            Some(listen) => {
+                let listener = TcpListener::bind(listen).await?;
+                Some(tokio::spawn(admin::serve(Arc::clone(&server), listener)))
+            }
+            None => None,
+        };
+
+        let websocket = match server.websocket {
+            Some(listen) => {
+                let listener = TcpListener::bind(listen).await?;
+                Some(tokio::spawn(websocket::serve(
+                    Arc::clone(&server),
+                    listener,
+                )))
+            }
+            None => None,
+        };
+
+        #[cfg(target_os = "linux")]
+        if let Some(privileges) = &server.privileges {
+            privileges.apply()?;
+            log::info!(
+                "dropped privileges to uid {} gid {}",
+                privileges.uid,
+                privileges.gid
+            );
+        }
+
+        let mut agents = JoinSet::new();
+        let mut restarting = false;
+        tokio::pin!(shutdown);
+
+        if let Some((stream, peer)) = stream {
+            let server = Arc::clone(&server);
+            agents.spawn(async move {
+                if let Err(err) = accept_agent(server, stream, peer).await {
                     log::error!("failed to handle agent connection: {}", err);
                 }
             });
         }
 
-        Ok(())
+        let result = loop {
+            tokio::select! {
+                _ = &mut shutdown => break Ok(()),
+                Some(socket) = accept_handoff(handoff.as_ref()) => {
+                    let Some(listener) = &listener else {
+                        continue;
+                    };
+                    match hand_over(&server, listener, socket).await {
+                        Ok(_) => {
+                            log::info!("listeners handed over to the new server process");
+                            restarting = true;
+                            break Ok(());
+                        }
+                        Err(err) => log::error!("failed to hand over the listeners: {}", err),
+                    }
+                }
+                accepted = accept(listener.as_ref()) => {
+                    let (socket, peer) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => break Err(Error::IO(err)),
+                    };
+
+                    if matches!(&server.handshakes, Some(limiter) if !limiter.allow(peer.ip())) {
+                        log::debug!("handshake of '{}' is rate limited", peer.ip());
+                        server.metrics.handshake_rejected();
+                        continue;
+                    }
+
+                    // serve one agent
+                    let server = Arc::clone(&server);
+                    agents.spawn(async move {
+                        if let Err(err) = accept_agent(server, socket, peer).await {
+                            log::error!("failed to handle agent connection: {}", err);
+                        }
+                    });
+                }
+                // clean up finished agents
+                Some(_) = agents.join_next(), if !agents.is_empty() => {
+                    // the single agent of the stream is gone
+                    if listener.is_none() && agents.is_empty() {
+                        break Ok(());
+                    }
+                }
+            }
+        };
+
+        let termination = match &result {
+            Ok(_) if restarting => Termination::new(Reason::Shutdown, "server is restarting"),
+            Ok(_) => Termination::new(Reason::Shutdown, "server is shutting down"),
+            Err(err) => Termination::new(Reason::Error, err.to_string()),
+        };
+
+        log::info!("terminating {} agent connections", agents.len());
+        server.shutdown.send_replace(Some(termination));
+
+        let _ = tokio::time::timeout(server.shutdown_timeout, async {
+            while agents.join_next().await.is_some() {}
+        })
+        .await;
+
+        for task in [router, dns, admin, websocket].into_iter().flatten() {
+            task.abort();
+        }
+
+        result
     }
 }
 
-async fn handle_agent<A: Authenticate, R: Registerer>(
-    kp: Keypair,
-    auth: Arc<A>,
-    reg: Arc<R>,
-    stream: TcpStream,
-) -> Result<()> {
-    let server = wire::Server::new(stream, kp);
+// the agents of a server, the connections accepted by the listener or a
+// single connection
+enum Source<T> {
+    Listener(TcpListener),
+    Stream(T, SocketAddr),
+}
+
+// accept a connection of an agent, if the server listens for agents
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// accept a connection of a new server process over the handoff socket (if any)
+#[cfg(unix)]
+async fn accept_handoff(listener: Option<&UnixListener>) -> Option<UnixStream> {
+    let Some(listener) = listener else {
+        return std::future::pending().await;
+    };
+
+    match listener.accept().await {
+        Ok((socket, _)) => Some(socket),
+        Err(err) => {
+            log::error!("failed to accept handoff connection: {}", err);
+            None
+        }
+    }
+}
+
+// listeners are only handed over on unix
+#[cfg(not(unix))]
+async fn accept_handoff(_listener: Option<&()>) -> Option<std::convert::Infallible> {
+    std::future::pending().await
+}
+
+// send the agents listener and the listeners of all live registrations to
+// the new server process
+#[cfg(unix)]
+async fn hand_over<A: Authenticate, R: Registerer>(
+    server: &Server<A, R>,
+    listener: &TcpListener,
+    socket: UnixStream,
+) -> Result<()>
+where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    use std::os::fd::AsRawFd;
+
+    // the registrations keep their listeners open until they are sent
+    let registrations = server.registry.live().await;
+    let listeners: Vec<_> = registrations
+        .iter()
+        .map(|(name, registration)| (name.clone(), registration.listener.as_raw_fd()))
+        .collect();
+
+    let agents = listener.as_raw_fd();
+    let socket = socket.into_std()?;
+    tokio::task::spawn_blocking(move || handoff::hand_over(socket, agents, &listeners))
+        .await
+        .map_err(|err| Error::IO(std::io::Error::other(err)))?
+}
+
+#[cfg(not(unix))]
+async fn hand_over<A: Authenticate, R: Registerer>(
+    _server: &Server<A, R>,
+    _listener: &TcpListener,
+    socket: std::convert::Infallible,
+) -> Result<()>
+where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    match socket {}
+}
+
+// accept_agent establishes the tls session (if enabled) before handling the agent
+async fn accept_agent<A: Authenticate, R: Registerer, S: Split>(
+    server: Arc<Server<A, R>>,
+    stream: S,
+    addr: SocketAddr,
+) -> Result<()>
+where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = &server.tls {
+        let stream = acceptor.accept(stream).await?;
+        let subject = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(crate::tls::subject);
+
+        return Box::pin(handle_agent(server, stream, Peer { addr, subject })).await;
+    }
+
+    // the agent handling future is boxed because it's too large to be
+    // moved around on the stack (especially in debug builds)
+    Box::pin(handle_agent(
+        server,
+        stream,
+        Peer {
+            addr,
+            subject: None,
+        },
+    ))
+    .await
+}
+
+async fn handle_agent<A: Authenticate, R: Registerer, S: Split>(
+    server: Arc<Server<A, R>>,
+    stream: S,
+    peer: Peer,
+) -> Result<()>
+where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    let hooks = Arc::clone(&server.hooks);
     // upgrade connection
     // this step accept client negotiation (if correct)
     // and then use the connection to forward traffic from now on
-    let mut connection = server.accept().await?;
+    let mut connection = wire::Server::new(stream, server.kp).accept().await?;
+
+    if let Some(dir) = &server.recordings {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = dir.join(format!(
+            "{}-{}-{}.rec",
+            peer.addr.ip(),
+            peer.addr.port(),
+            timestamp
+        ));
+        if let Err(err) = connection.record(&path) {
+            log::error!("failed to record session to {:?}: {}", path, err);
+        }
+    }
+
+    // 1 - receive login token, a client tells the name it dials first
+    let mut message = connection.read().await?;
+    let mut dial = None;
+    if let Message::Control(Control::Dial { name }) = message {
+        dial = Some(name);
+        message = connection.read().await?;
+    }
 
-    // 1 - receive login token
-    let token = match connection.read().await? {
-        Message::Control(Control::Login(token)) => token,
+    let (token, labels) = match message {
+        Message::Control(Control::Login { token, labels }) => (token, labels),
         _ => {
             connection.error(Error::UnexpectedMessage).await?;
             return Err(Error::UnexpectedMessage);
         }
     };
 
+    if connection.version() < server.min_version {
+        let version = connection.version();
+        log::warn!("refusing agent {} with wire version {}", peer.addr, version);
+        connection
+            .refuse(
+                Code::Upgrade,
+                format!(
+                    "wire version {} is not supported anymore, please upgrade the agent to support version {} or newer",
+                    version, server.min_version
+                ),
+            )
+            .await?;
+        return Err(Error::InvalidVersion(version));
+    }
+
+    if server.maintenance.mode() != Mode::Off {
+        connection
+            .terminate(Termination::new(
+                Reason::Maintenance,
+                "server is in maintenance, please reconnect elsewhere",
+            ))
+            .await?;
+        return Ok(());
+    }
+
     // 2 - authenticate the agent
-    let user = match auth.authenticate(&token).await {
+    let user = match server.auth.authenticate_peer(&peer, &token).await {
         Ok(user) => user,
         Err(err) => {
+            hooks.on_auth_failed(peer.addr, &err).await;
             connection.error(&err).await?;
             return Err(err);
         }
     };
 
-    // 3- send okay
+    // clients don't register names, they use the names of their user
+    if let Some(name) = dial {
+        return Box::pin(dial::serve(&server, connection, &user, &peer, name)).await;
+    }
+
+    // 3- check user limits then send okay
+    let agent_id = server.agents.fetch_add(1, Ordering::Relaxed);
+    let mut quota = match server.quotas.agent(&user.id, agent_id, peer.addr) {
+        Ok(quota) => quota,
+        Err(err) => {
+            server.metrics.agent_rejected();
+            connection.refuse(Code::AgentLimit, err).await?;
+            return Ok(());
+        }
+    };
+
+    // the agents that logged in before with the same identity are notified
+    let login = server
+        .logins
+        .login(identity(&peer, &token), agent_id, peer.addr);
+
     connection.ok().await?;
 
     // 4- receive all register messages, each successful registration is
     // followed by an okay from the server.
     // 5- wait for final finish-registration message
     let mut registrations = vec![];
+    // the names as they are authorized, before they are scoped
+    let mut authorized = vec![];
     while let Ok(message) = connection.read().await {
         match message {
             Message::Control(Control::Register { id, name }) => {
-                if registrations.len() == 1 {
-                    // we only allow one registration so far
+                if registrations
+                    .iter()
+                    .any(|(registration, _, _)| *registration == id)
+                {
+                    connection
+                        .error(format!("registration {} is already used", id))
+                        .await?;
+
+                    return Ok(());
+                }
+
+                // names are normalized before they are checked, the port of a
+                // name is checked apart
+                let (name, port) = match names::port(&name) {
+                    Ok((name, port)) => (server.validation.validate(name), port),
+                    Err(err) => (Err(err), None),
+                };
+                let name = match name {
+                    Ok(name) => name,
+                    Err(err) => {
+                        connection.refuse(Code::InvalidName, err).await?;
+
+                        return Ok(());
+                    }
+                };
+
+                if matches!(&server.denylist, Some(denylist) if denylist.denied(&name)) {
                     connection
-                        .error("only one name registration is allowed")
+                        .refuse(Code::Reserved, format!("name '{}' is reserved", name))
                         .await?;
 
                     return Ok(());
                 }
 
                 // authorize the domain registration
-                match auth.authorize(&user.id, &name).await {
+                match server.auth.authorize(&user.id, &name).await {
                     Ok(false) => {
                         connection
                             .error("not authorized to use this domain")
@@ -133,8 +797,77 @@ async fn handle_agent<A: Authenticate, R: Registerer>(
                     }
                     _ => {}
                 }
+                let unscoped = name.clone();
+
+                // the scoped name is validated again, it's built from the user id
+                let name = match &server.namespace {
+                    Some(namespace) => {
+                        match server
+                            .validation
+                            .validate_scoped(&namespace.scope(&user.id, &name))
+                        {
+                            Ok(name) => name,
+                            Err(err) => {
+                                connection.refuse(Code::InvalidName, err).await?;
+
+                                return Ok(());
+                            }
+                        }
+                    }
+                    None => name,
+                };
+                let name = match port {
+                    Some(port) => format!("{}:{}", name, port),
+                    None => name,
+                };
+
+                if !server.registry.available(&name, &user.id).await {
+                    connection.error(Error::NameInUse(name)).await?;
+
+                    return Ok(());
+                }
 
-                registrations.push((id, name));
+                if registrations
+                    .iter()
+                    .any(|(_, registered, _)| *registered == name)
+                {
+                    connection
+                        .error(format!("name '{}' is registered twice", name))
+                        .await?;
+
+                    return Ok(());
+                }
+
+                if let Err(err) = quota.name(&name) {
+                    server.metrics.name_rejected();
+                    connection.error(err).await?;
+
+                    return Ok(());
+                }
+
+                registrations.push((id, name, Metadata::default()));
+                if !authorized.contains(&unscoped) {
+                    authorized.push(unscoped);
+                }
+                connection.ok().await?;
+            }
+            Message::Control(Control::Metadata { id, metadata }) => {
+                let Some((_, _, current)) = registrations
+                    .iter_mut()
+                    .find(|(registration, _, _)| *registration == id)
+                else {
+                    connection
+                        .error("metadata of an unknown registration")
+                        .await?;
+                    return Ok(());
+                };
+
+                if let Err(err) = weight(&metadata) {
+                    connection.error(err).await?;
+                    return Ok(());
+                }
+
+                *current = metadata;
                 connection.ok().await?;
             }
             Message::Control(Control::FinishRegister) => break,
@@ -146,145 +879,1038 @@ async fn handle_agent<A: Authenticate, R: Registerer>(
         }
     }
 
-    if registrations.len() != 1 {
+    if registrations.is_empty() {
         connection.error("missing name registration").await?;
         return Ok(());
     }
 
-    // assume one registration
-    let bind = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let mut served: Vec<Served<R::Handler>> = Vec::with_capacity(registrations.len());
+    for (id, name, metadata) in registrations {
+        let registration = server
+            .registry
+            .acquire(&name, &user.id, agent_id, || async {
+                let (listener, endpoint) = match server.adopt(&name)? {
+                    Some(listener) => {
+                        log::info!("adopting the inherited listener of '{}'", name);
+                        let endpoint = server.bind.endpoint(listener.local_addr()?.port());
+                        (listener, endpoint)
+                    }
+                    None => server.bind.listen(&name).await?,
+                };
+                log::debug!(
+                    "accepting agent connections over: {:?}",
+                    listener.local_addr()
+                );
 
-    log::debug!("accepting agent connections over: {:?}", bind.local_addr());
-    let registration = &registrations[0];
+                let port = listener.local_addr()?.port();
+                let handler = server.reg.register(&name, port).await?;
+                server.hooks.on_registered(&name, port).await;
 
-    let registration_handler = reg
-        .register(&registration.1, bind.local_addr()?.port())
-        .await?;
+                let counters = Arc::new(Counters::with_parent(
+                    server.usage.as_ref().map(|usage| usage.counters(&user.id)),
+                ));
 
-    let (agent_reader, agent_writer) = connection.split();
+                let registration = Registration::new(
+                    listener,
+                    endpoint,
+                    handler,
+                    counters,
+                    Arc::new(IpConnections::new(server.client_limits.registration)),
+                );
 
-    let agent_writer = Arc::new(Mutex::new(agent_writer));
-    // up map is a map of streams and their write halfs
-    // it's used to write data sent from the agent up
-    let clients: Clients = Arc::new(Mutex::new(HashMap::default()));
+                let registration = match server.bandwidth.registration {
+                    Some(rate) => registration.shaped(rate),
+                    None => registration,
+                };
 
-    // start a process that forward all messages received from the agent to their corresponding
-    // up streams
-    let mut exited = upstream(Arc::clone(&clients), agent_reader).await;
+                Ok(match server.balancing.strategy(&name) {
+                    Some(strategy) => registration.balanced(strategy),
+                    None => registration,
+                })
+            })
+            .await;
 
-    loop {
-        tokio::select! {
-            _ = exited.recv() => {
-                log::debug!("agent disconnected");
-                break;
+        let registration = match registration {
+            Ok(registration) => registration,
+            Err(err) => {
+                // the names acquired so far are released right away
+                for served in served {
+                    release(
+                        served.registration,
+                        Duration::ZERO,
+                        server.shutdown.subscribe(),
+                        Arc::clone(&hooks),
+                        served.agent,
+                    )
+                    .await;
+                }
+
+                connection.error(&err).await?;
+                return Err(err);
             }
-            accepted = bind.accept() => {
-                log::trace!("accepted client connection for: {}", registration.1);
-                let (incoming, addr) = match accepted {
-                    Ok(accepted) => accepted,
-                    Err(err) => {
-                        log::error!("error accepting new connections: {}", err);
-                        break;
-                    }
-                };
+        };
 
-                let stream_id = Stream::new(registration.0, addr.port());
-                let (down, up) = incoming.into_split();
+        served.push(Served {
+            id,
+            registration,
+            agent: Arc::new(Agent {
+                peer: peer.addr,
+                name,
+                labels: labels.clone(),
+            }),
+            weight: weight(&metadata).unwrap_or(1),
+        });
+    }
 
-                let agent_writer = Arc::clone(&agent_writer);
+    // 6- report the session, the public endpoints (the public listener and the
+    // routed url) then a final okay
+    if connection.version() >= 6 {
+        connection.control(Control::Session(agent_id)).await?;
+    }
+    // agents of version 1 don't understand endpoints
+    let version = connection.version();
+    for served in served.iter().filter(|_| version >= 2) {
+        // the ports of a name are not routed over http
+        let url = server
+            .router
+            .as_ref()
+            .filter(|_| !served.agent.name.contains(':'))
+            .map(|router| router.url(&served.agent.name));
+        for address in served.registration.endpoint.iter().chain(url.as_ref()) {
+            connection
+                .control(Control::Endpoint {
+                    id: served.id,
+                    address: address.clone(),
+                })
+                .await?;
+        }
+    }
+    connection.ok().await?;
 
-                // this will be used to clean up the client connection if the client disconnected!
-                let clients_drop = Arc::clone(&clients);
+    let mut _connected = Vec::with_capacity(served.len());
+    for served in &served {
+        _connected.push(server.connected.track(agent_id, &served.agent));
+        hooks.on_agent_connected(&served.agent).await;
+    }
 
-                // before we spawn the downstream, we will acquire the lock first
-                // so the upstram does not proceed until we insert this client in the map
-                let mut clients = clients.lock().await;
+    let mut session = Session {
+        id: agent_id,
+        user: user.id,
+        peer,
+        expires: user.expires,
+        authorized,
+        login,
+    };
 
-                let handler = tokio::spawn(async move {
-                    log::trace!("staring client [{}] down stream", stream_id);
-                    if let Err(err) = downstream(stream_id, down, Arc::clone(&agent_writer)).await {
-                        log::debug!("failed to process down traffic: {}", err);
-                    }
+    // the serving future is large, it's boxed to keep it off the task stack
+    let result = Box::pin(serve_agent(&server, &mut session, &served, connection)).await;
 
-                    log::trace!("client connection stream [{}] close read", stream_id);
+    for served in served {
+        let released = release(
+            served.registration,
+            server.hold.unwrap_or_default(),
+            server.shutdown.subscribe(),
+            Arc::clone(&hooks),
+            Arc::clone(&served.agent),
+        );
 
-                    // also clean up the client connection completely!
-                    clients_drop.lock().await.remove(&stream_id);
-                    let _ = agent_writer.lock().await.control(Control::Close { id: stream_id }).await;
-                });
+        if server.hold.is_some() {
+            tokio::spawn(released);
+        } else {
+            released.await;
+        }
 
-                clients.insert(
-                    stream_id,
-                    Client {
-                        write: up,
-                        handler,
-                    },
-                );
-            }
-        };
+        hooks.on_agent_disconnected(&served.agent).await;
     }
 
-    clients.lock().await.clear();
-    drop(registration_handler);
-
-    Ok(())
+    result
 }
 
-type AgentWriter<W, F> = Arc<Mutex<Connection<W, F>>>;
-type Clients = Arc<Mutex<HashMap<Stream, Client>>>;
+// release the registration if no other agent is serving it. The registration
+// (and its listener) is held for a while first, so an agent that reconnects can take
+// it over including the client connections that are parked in the listener backlog
+async fn release<H: Handler>(
+    registration: Arc<Registration<H>>,
+    hold: Duration,
+    mut shutdown: watch::Receiver<Option<Termination>>,
+    hooks: Arc<dyn ServerHooks>,
+    agent: Arc<Agent>,
+) {
+    if !hold.is_zero() {
+        tokio::select! {
+            _ = tokio::time::sleep(hold) => {},
+            _ = terminated(&mut shutdown) => {},
+        }
+    }
 
-struct Client {
-    handler: JoinHandle<()>,
-    write: OwnedWriteHalf,
-}
+    // the last agent that serves the registration releases it
+    if let Some(registration) = Arc::into_inner(registration) {
+        registration
+            .handler
+            .stats(registration.counters.stats())
+            .await;
+        drop(registration);
 
-impl Drop for Client {
-    fn drop(&mut self) {
-        self.handler.abort();
+        hooks.on_released(&agent.name).await;
     }
 }
-// upstream de multiplex incoming traffic from the agent to the clients
-// that are connected locally
-async fn upstream<R, F>(
-    streams: Clients,
-    mut reader: Connection<R, F>,
-) -> tokio::sync::mpsc::Receiver<()>
+
+// serve_agent forwards client connections of the registrations over the agent connection
+// until the agent disconnects, or other agents take over all its registrations
+async fn serve_agent<A: Authenticate, R: Registerer, S: Split>(
+    server: &Arc<Server<A, R>>,
+    session: &mut Session<A::U>,
+    served: &[Served<R::Handler>],
+    connection: Connection<S, FrameStream>,
+) -> Result<()>
 where
-    R: AsyncRead + Unpin + Send + 'static,
-    F: FrameReader + Send + Sync + 'static,
+    A::U: Clone + Eq + Hash + Sync,
 {
-    let (close, notify) = tokio::sync::mpsc::channel::<()>(1);
-
-    tokio::spawn(async move {
-        loop {
-            let message = match reader.read().await {
-                Ok(message) => message,
-                Err(err) => {
-                    log::error!("failed to read stream from agent: {}", err);
-                    break;
-                }
-            };
+    let hooks = &server.hooks;
+    let version = connection.version();
+    let (agent_reader, agent_writer) = connection.split();
+    // the names served by the agent, for logging
+    let names = served
+        .iter()
+        .map(|served| served.agent.name.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
 
+    let agent_writer = Arc::new(Mutex::new(agent_writer));
+    // up map is a map of streams and their write halfs
+    // it's used to write data sent from the agent up
+    let clients: Clients = Arc::new(Mutex::new(StreamMap::new(version)));
+
+    // the lease is renewed by the upstream on each received message. Agents
+    // before version 2 don't send keep alive pings, an idle agent would lose
+    // its lease
+    let lease = Arc::new(Lease::new(server.lease.filter(|_| version >= 2)));
+    // the agent counters of each registration accumulate in the registration
+    // counters, they are sampled to measure the agent throughput
+    let counters: Vec<_> = served
+        .iter()
+        .map(|served| {
+            Arc::new(Counters::with_parent(Some(Arc::clone(
+                &served.registration.counters,
+            ))))
+        })
+        .collect();
+    let link = server.metrics.link(session.id, &names);
+
+    // start a process that forward all messages received from the agent to their corresponding
+    // up streams
+    let (upstream_handler, mut relogins) = upstream(
+        Arc::clone(&clients),
+        Arc::clone(&lease),
+        link.link(),
+        agent_reader,
+        Arc::clone(&agent_writer),
+    )
+    .await;
+
+    let mut stats =
+        tokio::time::interval_at(tokio::time::Instant::now() + server.stats, server.stats);
+
+    let mut shutdown = server.shutdown.subscribe();
+    let mut maintenance = server.maintenance.subscribe();
+    // client connections of all the registrations, each acceptor waits on
+    // its own slot
+    let (sender, mut accepted) = mpsc::channel(served.len());
+    let acceptors: Vec<_> = served
+        .iter()
+        .enumerate()
+        .map(|(index, served)| {
+            Acceptor::start(
+                index,
+                Arc::clone(&served.registration),
+                session.id,
+                served.weight,
+                server
+                    .router
+                    .as_ref()
+                    .and_then(|router| router.gate(&served.agent.name)),
+                sender.clone(),
+            )
+        })
+        .collect();
+    drop(sender);
+    // number of registrations taken over by other agents
+    let mut replaced = 0;
+    let mut draining = false;
+    let mut expires = deadline(session.expires);
+    let mut drain = tokio::time::interval(DRAIN_INTERVAL);
+    // the forwarding tasks of the open streams
+    let mut streams = JoinSet::new();
+    // the tasks that write the agent payloads to the clients
+    let mut writers = JoinSet::new();
+    let mut probe = tokio::time::interval(server.probe);
+    let mut seq: u32 = 0;
+    let mut duplicates = session.login.duplicates();
+    let taps: Vec<_> = served
+        .iter()
+        .map(|served| {
+            server
+                .tap
+                .as_ref()
+                .and_then(|tap| Tap::new(tap, &served.agent.name))
+        })
+        .collect();
+
+    loop {
+        tokio::select! {
+            // clean up finished streams
+            Some(_) = streams.join_next(), if !streams.is_empty() => {}
+            Some(_) = writers.join_next(), if !writers.is_empty() => {}
+            _ = stats.tick(), if !draining => {
+                for served in served {
+                    let registration = &served.registration;
+                    registration.handler.stats(registration.counters.stats()).await;
+                }
+            }
+            _ = probe.tick() => {
+                link.sample(counters.iter().fold(Stats::default(), |total, counters| {
+                    let stats = counters.stats();
+                    Stats {
+                        streams: total.streams + stats.streams,
+                        up: total.up + stats.up,
+                        down: total.down + stats.down,
+                    }
+                }));
+                // older agents don't understand probes
+                if version >= 3 {
+                    seq = seq.wrapping_add(1);
+                    link.probed(seq);
+                    if let Err(err) = agent_writer.lock().await.control(Control::Probe(seq)).await {
+                        log::debug!("failed to send probe: {}", err);
+                        break;
+                    }
+                }
+            }
+            relogin = relogins.recv() => {
+                let Some(token) = relogin else {
+                    log::debug!("agent disconnected");
+                    break;
+                };
+
+                // the new token must still allow all the registered names, a
+                // token with less domains revokes the others
+                let result = match server.auth.authenticate_peer(&session.peer, &token).await {
+                    Ok(user) if user.id != session.user => {
+                        agent_writer.lock().await.error("token belongs to another user").await
+                    }
+                    Ok(user) => match revoked(server, &user.id, &session.authorized).await {
+                        Ok(None) => {
+                            expires = deadline(user.expires);
+                            session.user = user.id;
+                            agent_writer.lock().await.ok().await
+                        }
+                        Ok(Some(name)) => {
+                            log::info!("relogin of '{}' revoked '{}'", names, name);
+                            kicked(hooks, served, Reason::Expired).await;
+                            let mut writer = agent_writer.lock().await;
+                            let reason = format!("not authorized to use '{}' anymore", name);
+                            let _ = writer.error(reason).await;
+                            let _ = writer
+                                .terminate(Termination::new(
+                                    Reason::Expired,
+                                    "authorization revoked, please login again",
+                                ))
+                                .await;
+                            break;
+                        }
+                        Err(err) => agent_writer.lock().await.error(err).await,
+                    },
+                    Err(err) => {
+                        hooks.on_auth_failed(session.peer.addr, &err).await;
+                        agent_writer.lock().await.error(err).await
+                    }
+                };
+
+                if let Err(err) = result {
+                    log::debug!("failed to reply to relogin: {}", err);
+                    break;
+                }
+            }
+            _ = lease.expired() => {
+                log::info!("lease of '{}' expired", names);
+                break;
+            }
+            _ = expired(expires) => {
+                log::info!("session of '{}' expired", names);
+                kicked(hooks, served, Reason::Expired).await;
+                let _ = agent_writer
+                    .lock()
+                    .await
+                    .terminate(Termination::new(
+                        Reason::Expired,
+                        "authentication expired, please login again",
+                    ))
+                    .await;
+                break;
+            }
+            peer = duplicated(&mut duplicates) => {
+                for served in served {
+                    hooks.on_duplicate_login(&served.agent, peer).await;
+                }
+                if server.logins.policy() != DuplicateLogin::Disconnect {
+                    log::warn!(
+                        "agent of '{}' ({}) logged in again from {} with the same identity",
+                        names, session.peer.addr, peer
+                    );
+                    continue;
+                }
+
+                log::info!("disconnecting agent of '{}' for a new login from {}", names, peer);
+                kicked(hooks, served, Reason::Duplicate).await;
+                let _ = agent_writer
+                    .lock()
+                    .await
+                    .terminate(Termination::new(
+                        Reason::Duplicate,
+                        "another agent logged in with the same identity",
+                    ))
+                    .await;
+                break;
+            }
+            _ = evicted(&mut maintenance) => {
+                log::info!("evicting agent of '{}' for maintenance", names);
+                kicked(hooks, served, Reason::Maintenance).await;
+                let _ = agent_writer
+                    .lock()
+                    .await
+                    .terminate(Termination::new(
+                        Reason::Maintenance,
+                        "server is in maintenance, please reconnect elsewhere",
+                    ))
+                    .await;
+                break;
+            }
+            termination = terminated(&mut shutdown) => {
+                if let Some(termination) = termination {
+                    let _ = agent_writer.lock().await.terminate(termination).await;
+                }
+                break;
+            }
+            _ = drain.tick(), if draining => {
+                if clients.lock().await.is_empty() {
+                    kicked(hooks, served, Reason::Replaced).await;
+                    let _ = agent_writer
+                        .lock()
+                        .await
+                        .terminate(Termination::new(
+                            Reason::Replaced,
+                            "registration has been taken over by another agent",
+                        ))
+                        .await;
+                    break;
+                }
+            }
+            Some(accepted) = accepted.recv(), if !draining => {
+                let (index, incoming) = match accepted {
+                    Accepted::Client(index, Ok(incoming)) => (index, incoming),
+                    Accepted::Client(_, Err(err)) => {
+                        log::error!("error accepting new connections: {}", err);
+                        break;
+                    }
+                    Accepted::Replaced(index) => {
+                        // another agent took over the registration, its open
+                        // streams are served until they finish
+                        log::info!("agent of '{}' is replaced", served[index].agent.name);
+                        replaced += 1;
+                        if replaced == served.len() {
+                            // we wait for the open streams to finish
+                            log::info!("draining agent of '{}'", names);
+                            draining = true;
+                        }
+                        continue;
+                    }
+                };
+
+                let Served { id, registration, agent, .. } = &served[index];
+                let Incoming { stream: incoming, addr, head, first } = incoming;
+                let tap = &taps[index];
+                log::trace!("accepted client connection for: {}", agent.name);
+
+                if matches!(&server.geoip, Some(geoip) if !geoip.allowed(&agent.name, addr.ip())) {
+                    server.metrics.client_rejected();
+                    continue;
+                }
+
+                let connections = match (
+                    registration.connections.acquire(addr.ip()),
+                    server.ip_connections.acquire(addr.ip()),
+                ) {
+                    (Some(local), Some(global)) => [local, global],
+                    _ => {
+                        log::debug!("client '{}' reached max connections", addr.ip());
+                        server.metrics.client_rejected();
+                        continue;
+                    }
+                };
+
+                let stream_id = Stream::new(*id, addr.port());
+                // the id is still used by a stream that is not closed on both sides yet
+                if clients.lock().await.state(&stream_id).is_some() {
+                    log::debug!("stream [{}] is still in use, rejecting client", stream_id);
+                    server.metrics.client_rejected();
+                    continue;
+                }
+
+                let (down, up) = incoming.into_split();
+                // the data already read by the router is forwarded first
+                let down = FirstRequest::new(std::io::Cursor::new(head).chain(down), first);
+
+                hooks.on_stream_opened(agent, stream_id, addr).await;
+
+                // each stream has its own counters that accumulates in the registration counters
+                let counters = Arc::new(Counters::with_parent(Some(Arc::clone(&counters[index]))));
+                counters.opened();
+                let tracking = server.streams.track(session.id, stream_id, &agent.name, addr, Arc::clone(&counters));
+
+                let agent_writer = Arc::clone(&agent_writer);
+                let down_counters = Arc::clone(&counters);
+                let chain = server.middlewares.as_ref().map(|middlewares| {
+                    Arc::new(std::sync::Mutex::new(Chain::new(
+                        middlewares.chain(&agent.name, stream_id),
+                    )))
+                });
+                let down_chain = chain.clone();
+                let shaper = Shaper::new(&server.bandwidth, registration.limit.as_ref());
+                let down_shaper = shaper.clone();
+                let down_tap = tap.clone();
+
+                // the payloads of the agent are written to the client by the stream writer
+                let (sender, receiver) = mpsc::unbounded_channel();
+                let window = Arc::new(Window::new(version >= 9));
+                writers.spawn(client_writer(
+                    stream_id,
+                    up,
+                    receiver,
+                    sender.downgrade(),
+                    Arc::clone(&window),
+                    shaper,
+                    Arc::clone(&counters),
+                    Arc::clone(&clients),
+                    Arc::clone(&agent_writer),
+                ));
+
+                // this will be used to clean up the client connection if the client disconnected!
+                let clients_drop = Arc::clone(&clients);
+
+                // before we spawn the downstream, we will acquire the lock first
+                // so the upstram does not proceed until we insert this client in the map
+                let mut clients = clients.lock().await;
+
+                let (paused, gate) = watch::channel(false);
+                let (stop, stopped) = oneshot::channel::<()>();
+                let stream_agent = Arc::clone(agent);
+                let stream_hooks = Arc::clone(hooks);
+                streams.spawn(async move {
+                    let forward = async {
+                        log::trace!("staring client [{}] down stream", stream_id);
+                        if let Err(err) = downstream(stream_id, down, Arc::clone(&agent_writer), down_counters, down_chain, down_shaper, down_tap, gate).await {
+                            log::debug!("failed to process down traffic: {}", err);
+                        }
+
+                        log::trace!("client connection stream [{}] close read", stream_id);
+
+                        // also clean up the client connection completely!
+                        let mut clients = clients_drop.lock().await;
+                        let _client = close(&mut clients, stream_id, &agent_writer).await;
+                    };
+
+                    // the stream is stopped once its client is dropped
+                    tokio::select! {
+                        _ = forward => {},
+                        _ = stopped => {},
+                    }
+
+                    // the hooks are invoked before the stream is untracked, and
+                    // before the agent disconnected hook
+                    stream_hooks.on_stream_closed(&stream_agent, stream_id).await;
+                    if let Some(stats) = tracking.stats() {
+                        stream_hooks.on_stream_stats(&stream_agent, &stats).await;
+                    }
+                });
+
+                let _ = clients.open(
+                    stream_id,
+                    Client {
+                        id: stream_id,
+                        sender,
+                        window,
+                        chain,
+                        tap: tap.clone(),
+                        paused,
+                        counters,
+                        _stop: stop,
+                        _connections: connections,
+                    },
+                );
+            }
+        };
+    }
+
+    // the acceptors hold the registrations, they must be gone before the
+    // registrations are released
+    for acceptor in acceptors {
+        acceptor.stop().await;
+    }
+
+    // the upstream can still be blocked on a wedged connection
+    upstream_handler.abort();
+    let mut clients = clients.lock().await;
+    if clients.half_closed() > 0 {
+        log::debug!(
+            "agent of '{}' left with {} unacknowledged stream closes",
+            names,
+            clients.half_closed()
+        );
+    }
+    clients.clear();
+    drop(clients);
+
+    // the dropped clients stop their streams
+    while streams.join_next().await.is_some() {}
+
+    // the payloads that are still queued are written to the clients, unless
+    // the clients are stuck
+    let _ = tokio::time::timeout(server.shutdown_timeout, async {
+        while writers.join_next().await.is_some() {}
+    })
+    .await;
+
+    Ok(())
+}
+
+// the server kicked the agent out of all its registrations
+async fn kicked<H>(hooks: &Arc<dyn ServerHooks>, served: &[Served<H>], reason: Reason) {
+    for served in served {
+        hooks.on_agent_kicked(&served.agent, reason).await;
+    }
+}
+
+// a registration served by an agent
+struct Served<H> {
+    id: wire::Registration,
+    registration: Arc<Registration<H>>,
+    // the agent as reported to the hooks of that name
+    agent: Arc<Agent>,
+    // weight of the agent in a balanced registration
+    weight: u32,
+}
+
+enum Accepted {
+    // a client connection of the registration at that index
+    Client(usize, std::io::Result<Incoming>),
+    // the registration at that index has been taken over by another agent
+    Replaced(usize),
+}
+
+// Acceptor accepts the client connections of one registration of an agent
+// until the registration is taken over by another agent
+struct Acceptor {
+    handler: JoinHandle<()>,
+}
+
+impl Acceptor {
+    fn start<H: Send + Sync + 'static>(
+        index: usize,
+        registration: Arc<Registration<H>>,
+        agent: u64,
+        weight: u32,
+        gate: Option<Gate>,
+        sender: mpsc::Sender<Accepted>,
+    ) -> Self {
+        let handler = tokio::spawn(async move {
+            let mut owner = registration.owner();
+            // agents of balanced registrations receive their connections from the dispatcher
+            let mut member = registration
+                .members
+                .as_ref()
+                .map(|members| members.join(agent, weight));
+            // clients that are checked by the gate, they are aborted with the acceptor
+            let mut checks = JoinSet::new();
+
+            loop {
+                // a connection is only accepted once the agent can take it, so
+                // connections are left in the backlog when the agent leaves
+                let Ok(permit) = sender.reserve().await else {
+                    return;
+                };
+
+                tokio::select! {
+                    _ = replaced(&mut owner, agent), if member.is_none() => {
+                        permit.send(Accepted::Replaced(index));
+                        return;
+                    }
+                    Some(checked) = checks.join_next(), if !checks.is_empty() => {
+                        if let Ok(Some(incoming)) = checked {
+                            permit.send(Accepted::Client(index, Ok(incoming)));
+                        }
+                    }
+                    accepted = next_client(&registration.listener, member.as_mut()) => {
+                        match (accepted, &gate) {
+                            (Ok(mut incoming), Some(gate)) => {
+                                let gate = gate.clone();
+                                checks.spawn(async move {
+                                    match gate.check(&mut incoming).await {
+                                        Ok(true) => Some(incoming),
+                                        Ok(false) => None,
+                                        Err(err) => {
+                                            log::debug!("failed to check client '{}': {}", incoming.addr, err);
+                                            None
+                                        }
+                                    }
+                                });
+                            }
+                            (accepted, _) => {
+                                let failed = accepted.is_err();
+                                permit.send(Accepted::Client(index, accepted));
+                                if failed {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        });
+
+        Self { handler }
+    }
+
+    async fn stop(self) {
+        self.handler.abort();
+        let _ = self.handler.await;
+    }
+}
+
+// accept the next client connection of the registration
+async fn next_client(
+    listener: &Listener,
+    member: Option<&mut Member>,
+) -> std::io::Result<Incoming> {
+    match member {
+        Some(member) => member.accept().await.ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotConnected, "agent left the registration")
+        }),
+        None => listener.accept().await,
+    }
+}
+
+// Session of an authenticated agent connection
+struct Session<U> {
+    // unique id of the agent connection
+    id: u64,
+    user: U,
+    peer: Peer,
+    // expiry of the authentication
+    expires: Option<SystemTime>,
+    // the names authorized at login, before they are scoped
+    authorized: Vec<String>,
+    // released when the session ends
+    login: Login,
+}
+
+// the first name that the user is not authorized to use anymore
+async fn revoked<A: Authenticate, R: Registerer>(
+    server: &Server<A, R>,
+    user: &A::U,
+    names: &[String],
+) -> Result<Option<String>> {
+    for name in names {
+        if !server.auth.authorize(user, name).await? {
+            return Ok(Some(name.clone()));
+        }
+    }
+
+    Ok(None)
+}
+
+// weight of the agent as declared in the registration metadata
+fn weight(metadata: &Metadata) -> std::result::Result<u32, String> {
+    match metadata.get("weight") {
+        None => Ok(1),
+        Some(weight) => match weight.parse() {
+            Ok(weight) if weight > 0 => Ok(weight),
+            _ => Err(format!("invalid weight '{}'", weight)),
+        },
+    }
+}
+
+// the instant the authentication expires at on the runtime clock, it is
+// taken once so a session doesn't have to wait for the whole time left
+// every time the select loop comes around
+fn deadline(expires: Option<SystemTime>) -> Option<tokio::time::Instant> {
+    expires.map(|expires| {
+        let left = expires
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        tokio::time::Instant::now() + left
+    })
+}
+
+// wait until the authentication expires
+async fn expired(deadline: Option<tokio::time::Instant>) {
+    let Some(deadline) = deadline else {
+        return std::future::pending().await;
+    };
+
+    tokio::time::sleep_until(deadline).await;
+}
+
+// serve_router routes http requests to the registrations listeners
+async fn serve_router<A: Authenticate, R: Registerer>(
+    server: Arc<Server<A, R>>,
+    listener: TcpListener,
+) where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::error!("failed to accept http connection: {}", err);
+                continue;
+            }
+        };
+
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(err) = route(&server, stream, addr).await {
+                log::debug!("failed to route http request: {}", err);
+            }
+        });
+    }
+}
+
+// route the client to the agents of the registration of the request host.
+// The connection is handed over to the registration (instead of connecting to
+// its listener) so the client limits and filters apply to the real client
+async fn route<A: Authenticate, R: Registerer>(
+    server: &Server<A, R>,
+    mut stream: TcpStream,
+    addr: SocketAddr,
+) -> Result<()>
+where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    let Some(router) = &server.router else {
+        return Ok(());
+    };
+
+    let request = crate::http::read_request(&mut stream).await?;
+    let name = request.host.as_deref().and_then(|host| router.name(host));
+
+    let registration = match name {
+        Some(name) => server.registry.lookup(name).await,
+        None => None,
+    };
+
+    let Some(registration) = registration else {
+        return router.offline(stream, &request).await;
+    };
+
+    let incoming = Incoming {
+        stream,
+        addr,
+        head: request.head.clone(),
+        first: None,
+    };
+
+    match registration.route(incoming) {
+        Ok(_) => Ok(()),
+        Err(incoming) => {
+            log::debug!("too many routed clients are waiting, rejecting '{}'", addr);
+            router.offline(incoming.stream, &request).await
+        }
+    }
+}
+
+// wait until the server is shutting down
+async fn terminated(shutdown: &mut watch::Receiver<Option<Termination>>) -> Option<Termination> {
+    let termination = shutdown
+        .wait_for(Option::is_some)
+        .await
+        .map(|termination| termination.clone());
+
+    match termination {
+        Ok(termination) => termination,
+        // server is gone without shutdown
+        Err(_) => std::future::pending().await,
+    }
+}
+
+type AgentWriter<W, F> = Arc<Mutex<Connection<W, F>>>;
+type Clients = Arc<Mutex<StreamMap<Client>>>;
+type StreamChain = Option<Arc<std::sync::Mutex<Chain>>>;
+
+struct Client {
+    id: Stream,
+    // the payloads of the agent, written to the client by the stream writer
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+    window: Arc<Window>,
+    chain: StreamChain,
+    tap: Option<Tap>,
+    // the client connection is not read while the agent paused the stream
+    paused: watch::Sender<bool>,
+    counters: Arc<Counters>,
+    // dropping the client stops the stream downstream
+    _stop: oneshot::Sender<()>,
+    // released when the client is dropped
+    _connections: [IpConnection; 2],
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.counters.closed();
+        if let Some(tap) = &self.tap {
+            tap.closed(self.id);
+        }
+    }
+}
+// close a client stream from the server side and tell the agent, the agent
+// lock is acquired while the clients are still locked so the agent ack can't
+// be processed before the stream is half closed. The client is returned to be
+// dropped by the caller (dropping it aborts the stream downstream)
+async fn close<W, F>(
+    clients: &mut StreamMap<Client>,
+    id: Stream,
+    writer: &AgentWriter<W, F>,
+) -> Option<Client>
+where
+    W: AsyncWrite + Unpin + Send,
+    F: FrameWriter,
+{
+    let client = clients.close(id)?;
+    if let Err(err) = writer.lock().await.control(Control::Close { id }).await {
+        log::debug!("failed to send close of stream [{}]: {}", id, err);
+    }
+
+    Some(client)
+}
+
+// upstream de multiplex incoming traffic from the agent to the clients
+// that are connected locally
+// the returned receiver yields the relogin tokens sent by the agent and is
+// closed once the agent disconnects
+async fn upstream<R, F, W, G>(
+    streams: Clients,
+    lease: Arc<Lease>,
+    link: Arc<Link>,
+    mut reader: Connection<R, F>,
+    writer: AgentWriter<W, G>,
+) -> (JoinHandle<()>, tokio::sync::mpsc::Receiver<String>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    F: FrameReader + Send + Sync + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+    G: FrameWriter + Send + 'static,
+{
+    let (relogin, notify) = tokio::sync::mpsc::channel::<String>(1);
+
+    let handler = tokio::spawn(async move {
+        loop {
+            let message = match reader.read().await {
+                Ok(message) => message,
+                Err(err) => {
+                    log::error!("failed to read stream from agent: {}", err);
+                    break;
+                }
+            };
+
+            lease.renew();
             match message {
-                Message::Terminate => return,
+                Message::Terminate(_) => return,
                 Message::Payload { id, data } => {
                     let mut streams = streams.lock().await;
-                    if let Some(client) = streams.get_mut(&id) {
-                        // received a message for a stream
-                        log::trace!("forwarding [{}] of data from [{}]", data.len(), id);
-                        if let Err(err) = client.write.write_all(&data).await {
-                            // this error can happen if the client connection has been closed
-                            if !err.closed() {
-                                log::error!("failed to forward traffic up: {}", err);
-                            }
-                            log::trace!("client connection stream [{}] write close", id);
-                            // the socket is probably dead, we probably should drop from map
-                            streams.remove(&id);
+                    let Some(client) = streams.get_mut(&id) else {
+                        continue;
+                    };
+
+                    let data = match &client.chain {
+                        None => Ok(data),
+                        Some(chain) => chain.lock().unwrap().process(Direction::Up, data),
+                    };
+
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(err) => {
+                            log::debug!("middleware closed stream [{}]: {}", id, err);
+                            close(&mut streams, id, &writer).await;
+                            continue;
+                        }
+                    };
+
+                    if let Some(tap) = &client.tap {
+                        tap.copy(id, Direction::Up, &data);
+                    }
+
+                    // the agent stops sending before the queue is full, the
+                    // streams of older agents are closed once their client
+                    // can't keep up, instead of holding up the other streams
+                    if client.window.overflowed() {
+                        log::warn!("client of stream [{}] is too slow, closing it", id);
+                        close(&mut streams, id, &writer).await;
+                        continue;
+                    }
+
+                    let sender = client.sender.clone();
+                    let window = Arc::clone(&client.window);
+                    drop(streams);
+
+                    if window.queued() {
+                        if let Err(err) = window.pause(id, &writer).await {
+                            log::debug!("failed to pause stream [{}]: {}", id, err);
                         }
                     }
+
+                    // the payload is written to the client by the stream writer,
+                    // so a slow (or shaped) client doesn't hold up the other streams
+                    log::trace!("forwarding [{}] of data from [{}]", data.len(), id);
+                    if sender.send(data).is_err() {
+                        log::trace!("dropping data of closed stream [{}]", id);
+                    }
                 }
                 Message::Control(Control::Close { id }) => {
-                    streams.lock().await.remove(&id);
+                    let mut streams = streams.lock().await;
+                    streams.remote_closed(id);
+                    if streams.acknowledged() {
+                        if let Err(err) =
+                            writer.lock().await.control(Control::CloseAck { id }).await
+                        {
+                            log::debug!("failed to acknowledge close of stream [{}]: {}", id, err);
+                        }
+                    }
+                }
+                Message::Control(Control::CloseAck { id }) => {
+                    streams.lock().await.acked(id);
+                }
+                Message::Control(Control::Pause { id }) => {
+                    if let Some(client) = streams.lock().await.get(&id) {
+                        log::trace!("stream [{}] paused by agent", id);
+                        client.paused.send_replace(true);
+                    }
+                }
+                Message::Control(Control::Resume { id }) => {
+                    if let Some(client) = streams.lock().await.get(&id) {
+                        log::trace!("stream [{}] resumed by agent", id);
+                        client.paused.send_replace(false);
+                    }
+                }
+                Message::Control(Control::Ping) => {}
+                Message::Control(Control::ProbeReply(seq)) => link.replied(seq),
+                // the agent measures the round trip time as well
+                Message::Control(Control::Probe(seq)) => {
+                    if let Err(err) = writer.lock().await.control(Control::ProbeReply(seq)).await {
+                        log::debug!("failed to reply to probe: {}", err);
+                    }
+                }
+                Message::Control(Control::Relogin(token)) => {
+                    if relogin.send(token).await.is_err() {
+                        break;
+                    }
                 }
                 msg => {
                     log::debug!("received unexpected message: {:?}", msg);
@@ -292,24 +1918,88 @@ where
             }
         }
 
-        drop(close);
+        drop(relogin);
     });
 
-    notify
+    (handler, notify)
+}
+
+// write the payloads of the agent to the client of the stream. The payloads
+// queued when the stream is closed are still written, and the client write half
+// is closed once the stream is gone
+#[allow(clippy::too_many_arguments)]
+async fn client_writer<W, F>(
+    id: Stream,
+    mut write: OwnedWriteHalf,
+    mut receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    stream: mpsc::WeakUnboundedSender<Vec<u8>>,
+    window: Arc<Window>,
+    shaper: Shaper,
+    counters: Arc<Counters>,
+    clients: Clients,
+    writer: AgentWriter<W, F>,
+) where
+    W: AsyncWrite + Unpin + Send,
+    F: FrameWriter,
+{
+    while let Some(data) = receiver.recv().await {
+        // the queue fills up while the stream waits for its bandwidth, which
+        // pauses the stream on the agent
+        shaper.up(data.len()).await;
+
+        if let Err(err) = write.write_all(&data).await {
+            // this error can happen if the client connection has been closed
+            if !err.closed() {
+                log::error!("failed to forward traffic up: {}", err);
+            }
+            log::trace!("client connection stream [{}] write close", id);
+            // close the stream unless it's already gone, its id could be reused meanwhile
+            let mut clients = clients.lock().await;
+            let current = match (clients.get(&id), stream.upgrade()) {
+                (Some(client), Some(sender)) => client.sender.same_channel(&sender),
+                _ => false,
+            };
+            if current {
+                close(&mut clients, id, &writer).await;
+            }
+            return;
+        }
+
+        counters.up(data.len());
+        if window.written() {
+            if let Err(err) = window.resume(id, &writer).await {
+                log::debug!("failed to resume stream [{}]: {}", id, err);
+            }
+        }
+    }
 }
 
-async fn downstream<W, F>(
+#[allow(clippy::too_many_arguments)]
+async fn downstream<D, W, F>(
     id: Stream,
-    mut down: OwnedReadHalf,
+    mut down: D,
     writer: AgentWriter<W, F>,
+    counters: Arc<Counters>,
+    chain: StreamChain,
+    shaper: Shaper,
+    tap: Option<Tap>,
+    mut paused: watch::Receiver<bool>,
 ) -> Result<()>
 where
+    D: AsyncRead + Unpin,
     W: AsyncWrite + Unpin + Send,
     F: FrameWriter,
 {
     let mut buf: [u8; wire::MAX_PAYLOAD_SIZE] = [0; wire::MAX_PAYLOAD_SIZE];
 
     loop {
+        // not reading the client while the stream is paused pushes back on
+        // the client through its tcp window
+        if paused.wait_for(|paused| !paused).await.is_err() {
+            // the client is gone
+            return Ok(());
+        }
+
         let n = match down.read(&mut buf).await {
             Ok(n) => n,
             Err(err) if err.closed() => return Ok(()),
@@ -320,8 +2010,30 @@ where
             // hit end of connection. I have to disconnect!
             return Ok(());
         }
+        if let Some(tap) = &tap {
+            tap.copy(id, Direction::Down, &buf[..n]);
+        }
+
+        // delaying the next read slows down the client
+        shaper.down(n).await;
+
         log::trace!("forwarding [{}] of data to [{}]", n, id);
-        writer.lock().await.write(id, &mut buf[..n]).await?;
+        match &chain {
+            None => writer.lock().await.write(id, &mut buf[..n]).await?,
+            Some(chain) => {
+                let mut data = chain
+                    .lock()
+                    .unwrap()
+                    .process(Direction::Down, buf[..n].to_vec())?;
+
+                // the middlewares can grow the data beyond a single frame
+                for chunk in data.chunks_mut(wire::MAX_PAYLOAD_SIZE) {
+                    writer.lock().await.write(id, chunk).await?;
+                }
+                data.len()
+            }
+        };
+        counters.down(n);
     }
 }
 
@@ -337,3 +2049,818 @@ impl IsClosed for std::io::Error {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{agent, wire::keypair};
+    use tokio::io::DuplexStream;
+
+    type AgentConnection = Connection<DuplexStream, FrameStream>;
+
+    // serve a single agent over an in-memory stream, and return the agent end
+    // of the connection after it registered `name`
+    async fn serve<A: Authenticate, R: Registerer>(
+        server: Server<A, R>,
+        name: &str,
+    ) -> AgentConnection
+    where
+        A::U: Clone + Eq + Hash + Sync,
+    {
+        let client = spawn(server, false);
+        let mut connection = Box::pin(wire::Client::new(client, keypair()).negotiate())
+            .await
+            .unwrap();
+        agent::login(&mut connection, "token").await.unwrap();
+        agent::register(&mut connection, name).await.unwrap();
+        connection
+    }
+
+    // serve a single agent over an in-memory stream and return the agent end
+    // of the stream. The server runs on its own thread, its futures are too
+    // large for the test thread stack in debug builds. A paused clock skips
+    // ahead whenever the server is idle
+    fn spawn<A: Authenticate, R: Registerer>(server: Server<A, R>, paused: bool) -> DuplexStream
+    where
+        A::U: Clone + Eq + Hash + Sync,
+    {
+        let (client, stream) = tokio::io::duplex(64 * 1024);
+        let peer = ([10, 0, 0, 1], 4000).into();
+        std::thread::Builder::new()
+            .stack_size(16 * 1024 * 1024)
+            .spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .start_paused(paused)
+                    .build()
+                    .unwrap();
+                // returns once the agent disconnects
+                runtime.block_on(server.serve_until(stream, peer, std::future::pending()))
+            })
+            .unwrap();
+
+        client
+    }
+
+    // run the test against a single agent server on its own thread. Both
+    // share a paused clock, it skips ahead whenever the server and the test
+    // are idle
+    fn paused<A, R, T, F>(server: Server<A, R>, test: T)
+    where
+        A::U: Clone + Eq + Hash + Sync,
+        A: Authenticate,
+        R: Registerer,
+        T: FnOnce(DuplexStream) -> F + Send + 'static,
+        F: Future<Output = ()>,
+    {
+        let (client, stream) = tokio::io::duplex(64 * 1024);
+        let peer = ([10, 0, 0, 1], 4000).into();
+        std::thread::Builder::new()
+            .stack_size(16 * 1024 * 1024)
+            .spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .start_paused(true)
+                    .build()
+                    .unwrap();
+                runtime.block_on(async {
+                    // the server returns once the test drops the agent
+                    let server = server.serve_until(stream, peer, std::future::pending());
+                    let _ = tokio::join!(server, test(client));
+                })
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    // next message of the agent that is not a probe
+    async fn next<S, F>(agent: &mut Connection<S, F>) -> Message
+    where
+        S: AsyncRead + Unpin + Send,
+        F: wire::FrameReader,
+    {
+        loop {
+            match agent.read().await.unwrap() {
+                Message::Control(Control::Probe(_)) => continue,
+                msg => return msg,
+            }
+        }
+    }
+
+    // wait until the condition holds
+    async fn eventually<F: FnMut() -> bool>(mut condition: F) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition was not met in time");
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let registerer = RecordingRegisterer::new();
+        let server = Server::builder()
+            .keypair(keypair())
+            .registerer(registerer.clone())
+            .config(ServerConfig {
+                stats_interval: Duration::from_millis(20),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+
+        let mut agent = serve(server, "web").await;
+        let (_, port) = registerer.registered()[0];
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        let id = match next(&mut agent).await {
+            Message::Payload { id, data } => {
+                assert_eq!(data, b"hello");
+                id
+            }
+            msg => panic!("expected payload got: {:?}", msg),
+        };
+        agent.write(id, &mut b"world!".to_vec()).await.unwrap();
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world!");
+
+        let expected = Stats {
+            streams: 1,
+            up: 6,
+            down: 5,
+        };
+        eventually(|| registerer.stats("web") == Some(expected)).await;
+
+        drop(client);
+        match next(&mut agent).await {
+            Message::Control(Control::Close { id: closed }) => assert_eq!(closed, id),
+            msg => panic!("expected close got: {:?}", msg),
+        }
+        eventually(|| matches!(registerer.stats("web"), Some(stats) if stats.streams == 0)).await;
+    }
+
+    // closes the stream on data that starts with `bad`
+    struct Reject;
+
+    impl StreamMiddleware for Reject {
+        fn process(&mut self, _: Direction, data: Vec<u8>) -> Result<Vec<u8>> {
+            if data.starts_with(b"bad") {
+                return Err(Error::InvalidRequest("rejected".into()));
+            }
+            Ok(data)
+        }
+    }
+
+    #[tokio::test]
+    async fn middleware_close() {
+        let registerer = RecordingRegisterer::new();
+        let server = Server::builder()
+            .keypair(keypair())
+            .registerer(registerer.clone())
+            .middlewares(|_: &str, _: Stream| -> Vec<Box<dyn StreamMiddleware>> {
+                vec![Box::new(Reject)]
+            })
+            .build()
+            .unwrap();
+
+        let mut agent = serve(server, "web").await;
+        let (_, port) = registerer.registered()[0];
+
+        // rejected up traffic, from the agent to the client
+        // a small receive buffer fills up quickly
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let mut client = socket.connect(([127, 0, 0, 1], port).into()).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let id = match next(&mut agent).await {
+            Message::Payload { id, .. } => id,
+            msg => panic!("expected payload got: {:?}", msg),
+        };
+        agent.write(id, &mut b"bad".to_vec()).await.unwrap();
+        match next(&mut agent).await {
+            Message::Control(Control::Close { id: closed }) => assert_eq!(closed, id),
+            msg => panic!("expected close got: {:?}", msg),
+        }
+        agent.control(Control::CloseAck { id }).await.unwrap();
+        assert_eq!(client.read(&mut [0; 16]).await.unwrap(), 0);
+
+        // rejected down traffic, from the client to the agent
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client.write_all(b"bad").await.unwrap();
+        let id = match next(&mut agent).await {
+            Message::Control(Control::Close { id }) => id,
+            msg => panic!("expected close got: {:?}", msg),
+        };
+        agent.control(Control::CloseAck { id }).await.unwrap();
+        assert_eq!(client.read(&mut [0; 16]).await.unwrap(), 0);
+    }
+
+    // a free local address to listen on
+    fn free_addr() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    // records the clients addresses of the opened streams
+    #[derive(Clone, Default)]
+    struct Clients(Arc<std::sync::Mutex<Vec<SocketAddr>>>);
+
+    #[async_trait::async_trait]
+    impl ServerHooks for Clients {
+        async fn on_stream_opened(&self, _agent: &Agent, _stream: Stream, client: SocketAddr) {
+            self.0.lock().unwrap().push(client);
+        }
+    }
+
+    #[tokio::test]
+    async fn routed() {
+        let clients = Clients::default();
+        let listen = free_addr();
+        let server = Server::builder()
+            .keypair(keypair())
+            .hooks(clients.clone())
+            .http_router(HttpRouter::new(listen, "gateway.com"))
+            .build()
+            .unwrap();
+
+        let mut agent = serve(server, "web").await;
+        let mut client = TcpStream::connect(listen).await.unwrap();
+        let request = b"GET / HTTP/1.1\r\nHost: web.gateway.com\r\n\r\n";
+        client.write_all(request).await.unwrap();
+
+        let id = match next(&mut agent).await {
+            Message::Payload { id, data } => {
+                assert_eq!(data, request);
+                id
+            }
+            msg => panic!("expected payload got: {:?}", msg),
+        };
+        // the stream is opened for the real client, not the router
+        assert_eq!(
+            *clients.0.lock().unwrap(),
+            vec![client.local_addr().unwrap()]
+        );
+        assert_eq!(id.port(), client.local_addr().unwrap().port());
+
+        agent
+            .write(id, &mut b"HTTP/1.1 200 OK\r\n\r\n".to_vec())
+            .await
+            .unwrap();
+        let mut buf = [0; 19];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn basic_auth() {
+        let registerer = RecordingRegisterer::new();
+        let server = Server::builder()
+            .keypair(keypair())
+            .registerer(registerer.clone())
+            .http_router(
+                HttpRouter::new(free_addr(), "gateway.com").basic_auth("web", "alice", "secret"),
+            )
+            .build()
+            .unwrap();
+
+        let mut agent = serve(server, "web").await;
+        let (_, port) = registerer.registered()[0];
+
+        // the registration listener requires the credentials as well
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: web.gateway.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+
+        // only the first request of an authorized client is forwarded
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client
+            .write_all(b"POST / HTTP/1.1\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\nContent-Length: 2\r\n\r\nokGET /admin HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let (id, data) = match next(&mut agent).await {
+            Message::Payload { id, data } => (id, data),
+            msg => panic!("expected payload got: {:?}", msg),
+        };
+        assert_eq!(
+            data,
+            b"POST / HTTP/1.1\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), next(&mut agent))
+                .await
+                .is_err()
+        );
+
+        agent
+            .write(id, &mut b"HTTP/1.1 200 OK\r\n\r\n".to_vec())
+            .await
+            .unwrap();
+        let mut buf = [0; 19];
+        client.read_exact(&mut buf).await.unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn oauth() {
+        let registerer = RecordingRegisterer::new();
+        let provider = OAuth::new(
+            url::Url::parse("https://idp.example.com/authorize").unwrap(),
+            url::Url::parse("https://idp.example.com/token").unwrap(),
+            "client",
+            "secret",
+        )
+        .unwrap();
+        let server = Server::builder()
+            .keypair(keypair())
+            .registerer(registerer.clone())
+            .http_router(
+                HttpRouter::new(free_addr(), "gateway.com").oauth("web", Arc::new(provider)),
+            )
+            .build()
+            .unwrap();
+
+        let _agent = serve(server, "web").await;
+        let (_, port) = registerer.registered()[0];
+
+        // the registration listener requires the login as well
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: web.gateway.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 302"), "{}", response);
+        assert!(response.contains("Location: https://idp.example.com/authorize?"));
+        assert!(response.contains("Set-Cookie: diglett_state="));
+    }
+
+    // records the stream and agent hooks in order
+    #[derive(Clone, Default)]
+    struct Events(Arc<std::sync::Mutex<Vec<&'static str>>>);
+
+    #[async_trait::async_trait]
+    impl ServerHooks for Events {
+        async fn on_agent_disconnected(&self, _agent: &Agent) {
+            self.0.lock().unwrap().push("disconnected");
+        }
+
+        async fn on_stream_closed(&self, _agent: &Agent, _stream: Stream) {
+            self.0.lock().unwrap().push("closed");
+        }
+
+        async fn on_stream_stats(&self, _agent: &Agent, _stats: &StreamStats) {
+            self.0.lock().unwrap().push("stats");
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_hooks() {
+        let registerer = RecordingRegisterer::new();
+        let events = Events::default();
+        let server = Server::builder()
+            .keypair(keypair())
+            .registerer(registerer.clone())
+            .hooks(events.clone())
+            .build()
+            .unwrap();
+
+        let mut agent = serve(server, "web").await;
+        let (_, port) = registerer.registered()[0];
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        assert!(matches!(next(&mut agent).await, Message::Payload { .. }));
+
+        // the stream is still open when the agent disconnects
+        drop(agent);
+        eventually(|| events.0.lock().unwrap().len() == 3).await;
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            vec!["closed", "stats", "disconnected"]
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_client() {
+        let registerer = RecordingRegisterer::new();
+        let server = Server::builder()
+            .keypair(keypair())
+            .registerer(registerer.clone())
+            .build()
+            .unwrap();
+
+        let mut agent = serve(server, "web").await;
+        let (_, port) = registerer.registered()[0];
+        // a small receive buffer fills up quickly
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let mut client = socket.connect(([127, 0, 0, 1], port).into()).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let id = match next(&mut agent).await {
+            Message::Payload { id, .. } => id,
+            msg => panic!("expected payload got: {:?}", msg),
+        };
+
+        // the client doesn't read, so its queue fills up once the socket
+        // buffers are full and the stream is paused
+        let (mut reader, mut writer) = agent.split();
+        let paused = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writing = {
+            let paused = Arc::clone(&paused);
+            tokio::spawn(async move {
+                let mut count = 0;
+                while !paused.load(std::sync::atomic::Ordering::Relaxed) {
+                    let mut data = vec![0; wire::MAX_PAYLOAD_SIZE];
+                    writer.write(id, &mut data).await.unwrap();
+                    count += 1;
+                }
+                count
+            })
+        };
+
+        assert!(matches!(
+            next(&mut reader).await,
+            Message::Control(Control::Pause { id: paused }) if paused == id
+        ));
+        paused.store(true, std::sync::atomic::Ordering::Relaxed);
+        let count = writing.await.unwrap();
+
+        // the stream is resumed once the client caught up
+        let reading = tokio::spawn(async move {
+            let mut data = vec![0; count * wire::MAX_PAYLOAD_SIZE];
+            client.read_exact(&mut data).await.unwrap();
+        });
+        assert!(matches!(
+            next(&mut reader).await,
+            Message::Control(Control::Resume { id: resumed }) if resumed == id
+        ));
+        reading.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn slow_client_unpaused() {
+        let registerer = RecordingRegisterer::new();
+        let server = Server::builder()
+            .keypair(keypair())
+            .registerer(registerer.clone())
+            .build()
+            .unwrap();
+
+        // agents older than version 9 can't be paused
+        let client = spawn(server, false);
+        let mut agent = Box::pin(
+            wire::Client::new(client, keypair())
+                .with_version(8)
+                .negotiate(),
+        )
+        .await
+        .unwrap();
+        agent::login(&mut agent, "token").await.unwrap();
+        agent::register(&mut agent, "web").await.unwrap();
+
+        let (_, port) = registerer.registered()[0];
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let mut slow = socket.connect(([127, 0, 0, 1], port).into()).await.unwrap();
+        slow.write_all(b"hello").await.unwrap();
+        let id = match next(&mut agent).await {
+            Message::Payload { id, .. } => id,
+            msg => panic!("expected payload got: {:?}", msg),
+        };
+
+        // the client doesn't read, its stream is closed once the queue is
+        // full instead of holding up the other streams
+        let (mut reader, mut writer) = agent.split();
+        let closed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writing = {
+            let closed = Arc::clone(&closed);
+            tokio::spawn(async move {
+                while !closed.load(std::sync::atomic::Ordering::Relaxed) {
+                    let mut data = vec![0; wire::MAX_PAYLOAD_SIZE];
+                    writer.write(id, &mut data).await.unwrap();
+                }
+                writer
+            })
+        };
+
+        loop {
+            match next(&mut reader).await {
+                Message::Control(Control::Close { id: closed }) if closed == id => break,
+                Message::Control(Control::Pause { .. }) => panic!("agent can't be paused"),
+                _ => {}
+            }
+        }
+        closed.store(true, std::sync::atomic::Ordering::Relaxed);
+        let mut writer = writing.await.unwrap();
+
+        // other streams are still served
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let other = loop {
+            if let Message::Payload { id, .. } = next(&mut reader).await {
+                break id;
+            }
+        };
+        assert_ne!(other, id);
+        writer.write(other, &mut b"world".to_vec()).await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn min_version() {
+        let config = ServerConfig {
+            min_version: 5,
+            ..Default::default()
+        };
+        let server = Server::builder()
+            .keypair(keypair())
+            .config(config)
+            .build()
+            .unwrap();
+
+        // the agent is refused right after its login
+        let client = spawn(server, false);
+        let mut connection = Box::pin(
+            wire::Client::new(client, keypair())
+                .with_version(4)
+                .negotiate(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(connection.version(), 4);
+        assert!(matches!(
+            agent::login(&mut connection, "token").await,
+            Err(Error::Refused(Code::Upgrade, _))
+        ));
+
+        let server = Server::builder()
+            .keypair(keypair())
+            .config(ServerConfig {
+                min_version: 5,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let client = spawn(server, false);
+        let mut connection = Box::pin(
+            wire::Client::new(client, keypair())
+                .with_version(5)
+                .negotiate(),
+        )
+        .await
+        .unwrap();
+        agent::login(&mut connection, "token").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn version_one() {
+        let registerer = RecordingRegisterer::new();
+        let server = Server::builder()
+            .keypair(keypair())
+            .registerer(registerer.clone())
+            .http_router(HttpRouter::new(free_addr(), "gateway.com"))
+            .build()
+            .unwrap();
+
+        // an agent of version 1 doesn't understand the endpoints
+        let client = spawn(server, false);
+        let mut connection = Box::pin(
+            wire::Client::new(client, keypair())
+                .with_version(1)
+                .negotiate(),
+        )
+        .await
+        .unwrap();
+        agent::login(&mut connection, "token").await.unwrap();
+        let endpoints = agent::register(&mut connection, "web").await.unwrap();
+        assert!(endpoints.is_empty());
+        assert_eq!(registerer.registered().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn lease() {
+        let build = || {
+            Server::builder()
+                .keypair(keypair())
+                .config(ServerConfig {
+                    lease: Some(Duration::from_secs(30)),
+                    ..Default::default()
+                })
+                .build()
+                .unwrap()
+        };
+        let connect = |version| async move {
+            let client = spawn(build(), true);
+            let mut connection = Box::pin(
+                wire::Client::new(client, keypair())
+                    .with_version(version)
+                    .negotiate(),
+            )
+            .await
+            .unwrap();
+            agent::login(&mut connection, "token").await.unwrap();
+            agent::register(&mut connection, "web").await.unwrap();
+            connection
+        };
+
+        // the clock of the server skips ahead, the agent doesn't ping and
+        // loses its lease
+        let mut agent = connect(wire::VERSION).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Ok(message) = agent.read().await {
+                if let Message::Terminate(_) = message {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("the lease did not expire");
+
+        // agents of version 1 don't ping and have no lease
+        let mut agent = connect(1).await;
+        let read = tokio::time::timeout(Duration::from_millis(500), agent.read()).await;
+        assert!(read.is_err(), "unexpected message: {:?}", read);
+    }
+
+    #[tokio::test]
+    async fn relogin() {
+        use super::token::Claims;
+
+        let signed = Signed::new("0123456789abcdef0123456789abcdef").unwrap();
+        let token = |claims: Claims| signed.mint(&claims);
+        let alice = || Claims::new("alice").domain("web").domain("api");
+
+        let registerer = RecordingRegisterer::new();
+        let server = Server::builder()
+            .keypair(keypair())
+            .auth(signed.clone())
+            .registerer(registerer.clone())
+            .build()
+            .unwrap();
+        let client = spawn(server, false);
+        let mut agent = Box::pin(wire::Client::new(client, keypair()).negotiate())
+            .await
+            .unwrap();
+        agent::login(&mut agent, token(alice())).await.unwrap();
+        agent::register(&mut agent, "web").await.unwrap();
+
+        // a refreshed token of the same user
+        let refreshed = token(alice().expires_in(Duration::from_secs(3600)));
+        agent.control(Control::Relogin(refreshed)).await.unwrap();
+        assert!(next(&mut agent).await.ok_or_err().is_ok());
+
+        // the token of another user is refused, the agent stays connected
+        let bob = token(Claims::new("bob").domain("web"));
+        agent.control(Control::Relogin(bob)).await.unwrap();
+        assert!(matches!(
+            next(&mut agent).await,
+            Message::Control(Control::Error(_, reason)) if reason == "token belongs to another user"
+        ));
+
+        // a token without the registered name revokes it
+        let narrowed = token(Claims::new("alice").domain("api"));
+        agent.control(Control::Relogin(narrowed)).await.unwrap();
+        assert!(matches!(
+            next(&mut agent).await,
+            Message::Control(Control::Error(..))
+        ));
+        assert!(matches!(
+            next(&mut agent).await,
+            Message::Terminate(termination) if termination.reason == Reason::Expired
+        ));
+        eventually(|| registerer.registered().is_empty()).await;
+    }
+
+    #[test]
+    fn expired() {
+        use super::token::Claims;
+
+        let signed = Signed::new("0123456789abcdef0123456789abcdef").unwrap();
+        let token = signed.mint(
+            &Claims::new("alice")
+                .domain("web")
+                .expires_in(Duration::from_secs(5)),
+        );
+
+        let registerer = RecordingRegisterer::new();
+        let server = Server::builder()
+            .keypair(keypair())
+            .auth(signed)
+            .registerer(registerer.clone())
+            .build()
+            .unwrap();
+
+        paused(server, move |client| async move {
+            let mut agent = Box::pin(wire::Client::new(client, keypair()).negotiate())
+                .await
+                .unwrap();
+            agent::login(&mut agent, token).await.unwrap();
+            agent::register(&mut agent, "web").await.unwrap();
+            assert_eq!(registerer.registered().len(), 1);
+
+            let start = tokio::time::Instant::now();
+            assert!(matches!(
+                next(&mut agent).await,
+                Message::Terminate(termination) if termination.reason == Reason::Expired
+            ));
+            assert!(start.elapsed() >= Duration::from_secs(3));
+            eventually(|| registerer.registered().is_empty()).await;
+        });
+    }
+
+    #[test]
+    fn relogin_extends() {
+        use super::token::Claims;
+
+        let signed = Signed::new("0123456789abcdef0123456789abcdef").unwrap();
+        let token = signed.mint(
+            &Claims::new("alice")
+                .domain("web")
+                .expires_in(Duration::from_secs(5)),
+        );
+        let refreshed = signed.mint(
+            &Claims::new("alice")
+                .domain("web")
+                .expires_in(Duration::from_secs(3600)),
+        );
+
+        let registerer = RecordingRegisterer::new();
+        let server = Server::builder()
+            .keypair(keypair())
+            .auth(signed)
+            .registerer(registerer.clone())
+            .build()
+            .unwrap();
+
+        paused(server, move |client| async move {
+            let mut agent = Box::pin(wire::Client::new(client, keypair()).negotiate())
+                .await
+                .unwrap();
+            agent::login(&mut agent, token).await.unwrap();
+            agent::register(&mut agent, "web").await.unwrap();
+
+            let start = tokio::time::Instant::now();
+            agent.control(Control::Relogin(refreshed)).await.unwrap();
+            assert!(next(&mut agent).await.ok_or_err().is_ok());
+
+            // the session outlives the first token
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            assert_eq!(registerer.registered().len(), 1);
+
+            // and expires with the refreshed one
+            assert!(matches!(
+                next(&mut agent).await,
+                Message::Terminate(termination) if termination.reason == Reason::Expired
+            ));
+            assert!(start.elapsed() >= Duration::from_secs(3500));
+            eventually(|| registerer.registered().is_empty()).await;
+        });
+    }
+
+    #[tokio::test]
+    async fn maintenance() {
+        // new agents are refused while in maintenance
+        let server = Server::builder().keypair(keypair()).build().unwrap();
+        server.maintenance().set(Mode::On);
+        let client = spawn(server, false);
+        let mut agent = Box::pin(wire::Client::new(client, keypair()).negotiate())
+            .await
+            .unwrap();
+        assert!(matches!(
+            agent::login(&mut agent, "token").await,
+            Err(Error::Terminated(termination)) if termination.reason == Reason::Maintenance
+        ));
+
+        // registered agents are kept, and evicted on demand
+        let registerer = RecordingRegisterer::new();
+        let server = Server::builder()
+            .keypair(keypair())
+            .registerer(registerer.clone())
+            .build()
+            .unwrap();
+        let maintenance = server.maintenance();
+        let mut agent = serve(server, "web").await;
+        maintenance.set(Mode::On);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(registerer.registered().len(), 1);
+
+        maintenance.set(Mode::Evict);
+        assert!(matches!(
+            next(&mut agent).await,
+            Message::Terminate(termination) if termination.reason == Reason::Maintenance
+        ));
+        eventually(|| registerer.registered().is_empty()).await;
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{Error, Result};
+
+/// max length of a single dns label
+const MAX_LABEL_LEN: usize = 63;
+/// max length of a full dns name
+const MAX_NAME_LEN: usize = 253;
+
+/// Validation of the registered names. Names are normalized (lower case and
+/// punycode for international names) and must be dns safe, so they can be safely
+/// templated by registerers into proxies configurations.
+#[derive(Debug, Clone, Default)]
+pub struct Validation {
+    dots: bool,
+}
+
+impl Validation {
+    /// allow names with multiple labels (for example `api.example`). By
+    /// default a name can only be a single label
+    pub fn allow_dots(mut self) -> Self {
+        self.dots = true;
+        self
+    }
+
+    /// validate the name and return its normalized form
+    pub fn validate(&self, name: &str) -> Result<String> {
+        let normalized = idna::domain_to_ascii(name)
+            .map_err(|_| invalid(name, "not a valid international name"))?;
+
+        if normalized.is_empty() || normalized.len() > MAX_NAME_LEN {
+            return Err(invalid(name, "invalid length"));
+        }
+
+        if !self.dots && normalized.contains('.') {
+            return Err(invalid(name, "dots are not allowed"));
+        }
+
+        for label in normalized.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_LEN {
+                return Err(invalid(name, "invalid label length"));
+            }
+
+            if label.starts_with('-') || label.ends_with('-') {
+                return Err(invalid(name, "labels can't start or end with a hyphen"));
+            }
+
+            if !label
+                .bytes()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-')
+            {
+                return Err(invalid(name, "invalid characters"));
+            }
+        }
+
+        Ok(normalized)
+    }
+
+    /// validate a name scoped by a [`Namespace`](super::namespace::Namespace),
+    /// the namespace can add labels to the already validated name
+    pub(crate) fn validate_scoped(&self, name: &str) -> Result<String> {
+        Self { dots: true }.validate(name)
+    }
+}
+
+/// split the port of a `name:port` registration (since wire version 8), so a
+/// name exposes one service per port. The port is part of the registered
+/// name, but the name alone is validated and authorized
+pub fn port(name: &str) -> Result<(&str, Option<u16>)> {
+    let Some((host, port)) = name.rsplit_once(':') else {
+        return Ok((name, None));
+    };
+
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => Ok((host, Some(port))),
+        _ => Err(invalid(name, "invalid port")),
+    }
+}
+
+fn invalid(name: &str, reason: &str) -> Error {
+    Error::InvalidName(format!("'{}' {}", name.escape_debug(), reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let validation = Validation::default();
+
+        assert_eq!(validation.validate("Example").unwrap(), "example");
+        assert_eq!(validation.validate("bücher").unwrap(), "xn--bcher-kva");
+
+        assert!(validation.validate("").is_err());
+        assert!(validation.validate("-example").is_err());
+        assert!(validation.validate("api.example").is_err());
+        assert!(validation.validate("exa_mple").is_err());
+        assert!(validation.validate("ex ample;").is_err());
+        assert!(validation.validate(&"a".repeat(64)).is_err());
+
+        let validation = Validation::default().allow_dots();
+        assert_eq!(validation.validate("api.example").unwrap(), "api.example");
+        assert!(validation.validate("api..example").is_err());
+
+        let validation = Validation::default();
+        assert_eq!(
+            validation.validate_scoped("web.alice").unwrap(),
+            "web.alice"
+        );
+        assert!(validation.validate_scoped("web.alice@example").is_err());
+    }
+
+    #[test]
+    fn port() {
+        assert_eq!(super::port("web").unwrap(), ("web", None));
+        assert_eq!(super::port("web:22").unwrap(), ("web", Some(22)));
+        assert!(super::port("web:0").is_err());
+        assert!(super::port("web:ssh").is_err());
+        assert!(super::port("web:").is_err());
+    }
+}
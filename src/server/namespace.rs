@@ -0,0 +1,41 @@
+use std::fmt::Display;
+
+/// Namespace scopes the registered names per user, so tenants can't
+/// collide or squat each other names. The scoped name is what is served
+/// and handed over to the registerer.
+pub trait Namespace<U>: Send + Sync + 'static {
+    /// full name of `name` registered by `user`
+    fn scope(&self, user: &U, name: &str) -> String;
+}
+
+impl<U, F> Namespace<U> for F
+where
+    F: Fn(&U, &str) -> String + Send + Sync + 'static,
+{
+    fn scope(&self, user: &U, name: &str) -> String {
+        self(user, name)
+    }
+}
+
+/// UserNamespace scopes names under the user id as `<name>.<user>`
+#[derive(Debug, Clone)]
+pub struct UserNamespace;
+
+impl<U: Display> Namespace<U> for UserNamespace {
+    fn scope(&self, user: &U, name: &str) -> String {
+        format!("{}.{}", name, user)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scope() {
+        assert_eq!(UserNamespace.scope(&"alice", "web"), "web.alice");
+
+        let prefixed = |user: &u64, name: &str| format!("u{}-{}", user, name);
+        assert_eq!(prefixed.scope(&10, "web"), "u10-web");
+    }
+}
@@ -0,0 +1,317 @@
+//! Nats publishes the server events (agents sessions, registrations and
+//! streams stats) as json to a NATS server, so dashboards and automation can
+//! subscribe to them instead of polling the admin api.
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::mpsc,
+};
+use url::Url;
+
+use super::{hooks::Agent, stats::StreamStats, ServerHooks};
+use crate::{wire::Reason, Error, Result};
+
+/// default subjects prefix
+pub const PREFIX: &str = "diglett";
+/// max number of pending events, new events are dropped once reached
+const QUEUE_SIZE: usize = 4096;
+/// delay between reconnect attempts
+const RECONNECT: Duration = Duration::from_secs(2);
+
+/// Nats are server hooks that publish the events to `<prefix>.<subject>`
+/// where the subjects are
+/// - `agent.connected`, `agent.disconnected`, `agent.kicked` and `agent.duplicate`
+/// - `registration.registered` and `registration.released`
+/// - `stream.closed` with the final stats of the stream
+///
+/// The connection is re-established if lost, events are queued meanwhile
+/// (up to a limit)
+pub struct Nats {
+    prefix: String,
+    events: mpsc::Sender<(String, Value)>,
+}
+
+impl Nats {
+    /// start publishing to the nats server url (`nats://[user:password@]host[:port]`
+    /// or `nats://token@host[:port]`). It needs to be called within a tokio runtime
+    pub fn new<P: Into<String>>(url: Url, prefix: P) -> Result<Self> {
+        if url.scheme() != "nats" {
+            return Err(Error::Nats(format!(
+                "unsupported scheme '{}'",
+                url.scheme()
+            )));
+        }
+
+        let (events, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(publish(url, receiver));
+
+        Ok(Self {
+            prefix: prefix.into(),
+            events,
+        })
+    }
+
+    fn publish(&self, subject: &str, mut event: Value) {
+        event["timestamp"] = json!(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs());
+
+        let subject = format!("{}.{}", self.prefix, subject);
+        if self.events.try_send((subject, event)).is_err() {
+            log::warn!("nats queue is full, dropping event");
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ServerHooks for Nats {
+    async fn on_agent_connected(&self, agent: &Agent) {
+        let labels: serde_json::Map<_, _> = agent
+            .labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), json!(value)))
+            .collect();
+
+        self.publish(
+            "agent.connected",
+            json!({
+                "name": agent.name,
+                "agent": agent.peer.to_string(),
+                "labels": labels,
+            }),
+        );
+    }
+
+    async fn on_agent_disconnected(&self, agent: &Agent) {
+        self.publish(
+            "agent.disconnected",
+            json!({"name": agent.name, "agent": agent.peer.to_string()}),
+        );
+    }
+
+    async fn on_agent_kicked(&self, agent: &Agent, reason: Reason) {
+        self.publish(
+            "agent.kicked",
+            json!({
+                "name": agent.name,
+                "agent": agent.peer.to_string(),
+                "reason": reason.to_string(),
+            }),
+        );
+    }
+
+    async fn on_duplicate_login(&self, agent: &Agent, peer: SocketAddr) {
+        self.publish(
+            "agent.duplicate",
+            json!({
+                "name": agent.name,
+                "agent": agent.peer.to_string(),
+                "duplicate": peer.to_string(),
+            }),
+        );
+    }
+
+    async fn on_registered(&self, name: &str, port: u16) {
+        self.publish(
+            "registration.registered",
+            json!({"name": name, "port": port}),
+        );
+    }
+
+    async fn on_released(&self, name: &str) {
+        self.publish("registration.released", json!({"name": name}));
+    }
+
+    async fn on_stream_stats(&self, agent: &Agent, stats: &StreamStats) {
+        self.publish(
+            "stream.closed",
+            json!({
+                "name": stats.name,
+                "agent": agent.peer.to_string(),
+                "client": stats.client.to_string(),
+                "up": stats.up,
+                "down": stats.down,
+                "duration": stats.duration.as_secs_f64(),
+            }),
+        );
+    }
+}
+
+async fn publish(url: Url, mut events: mpsc::Receiver<(String, Value)>) {
+    // the event that was being written when the connection failed
+    let mut pending = None;
+    loop {
+        match session(&url, &mut events, &mut pending).await {
+            // all senders are gone
+            Ok(()) => return,
+            Err(err) => log::error!("nats connection failed: {}", err),
+        }
+
+        tokio::time::sleep(RECONNECT).await;
+    }
+}
+
+// publish the events over a single connection until it fails. An event is
+// kept in `pending` until it's written, so it's published again by the next
+// session if the connection fails meanwhile
+async fn session(
+    url: &Url,
+    events: &mut mpsc::Receiver<(String, Value)>,
+    pending: &mut Option<(String, Value)>,
+) -> Result<()> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::Nats("nats url has no host".into()))?;
+    let stream = TcpStream::connect((host, url.port().unwrap_or(4222))).await?;
+    let addr: Option<SocketAddr> = stream.peer_addr().ok();
+
+    let (reader, mut writer) = stream.into_split();
+    // next_line is cancel safe, a line that is partially read when an event
+    // is published is completed by the next read
+    let mut lines = BufReader::new(reader).lines();
+
+    // the server starts with its info
+    let line = lines.next_line().await?.unwrap_or_default();
+    if !line.starts_with("INFO") {
+        return Err(Error::Nats(format!(
+            "unexpected nats greeting: {}",
+            line.trim()
+        )));
+    }
+
+    writer
+        .write_all(format!("CONNECT {}\r\n", connect(url)).as_bytes())
+        .await?;
+    log::info!("connected to nats server {:?}", addr);
+
+    if let Some((subject, event)) = pending {
+        send(&mut writer, subject, event).await?;
+        *pending = None;
+    }
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else {
+                    return Ok(());
+                };
+
+                let (subject, event) = pending.insert(event);
+                send(&mut writer, subject, event).await?;
+                *pending = None;
+            }
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    return Err(Error::Nats("nats server closed the connection".into()));
+                };
+
+                if line.starts_with("PING") {
+                    writer.write_all(b"PONG\r\n").await?;
+                } else if line.starts_with("-ERR") {
+                    return Err(Error::Nats(line.trim().into()));
+                }
+            }
+        }
+    }
+}
+
+async fn send(writer: &mut OwnedWriteHalf, subject: &str, event: &Value) -> Result<()> {
+    let payload = event.to_string();
+    let message = format!("PUB {} {}\r\n{}\r\n", subject, payload.len(), payload);
+    writer.write_all(message.as_bytes()).await?;
+    Ok(())
+}
+
+fn connect(url: &Url) -> Value {
+    let mut options = json!({
+        "verbose": false,
+        "pedantic": false,
+        "name": "diglett",
+        "lang": "rust",
+    });
+
+    match (url.username(), url.password()) {
+        ("", _) => {}
+        (token, None) => options["auth_token"] = json!(token),
+        (user, Some(password)) => {
+            options["user"] = json!(user);
+            options["pass"] = json!(password);
+        }
+    }
+
+    options
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    #[tokio::test]
+    async fn publish() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let url = Url::parse(&format!(
+            "nats://user:secret@{}",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+
+        let nats = Nats::new(url, "gw").unwrap();
+        nats.on_released("web").await;
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        stream.write_all(b"INFO {}\r\nPING\r\n").await.unwrap();
+
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("CONNECT "));
+        assert!(line.contains(r#""user":"user""#));
+
+        // the published event and the reply to our ping, in any order
+        let mut received = String::new();
+        while !(received.contains("PONG") && received.contains("released")) {
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+
+        assert!(received.contains("PUB gw.registration.released "));
+        assert!(received.contains(r#""name":"web""#));
+    }
+
+    #[tokio::test]
+    async fn partial_line() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let url = Url::parse(&format!("nats://{}", listener.local_addr().unwrap())).unwrap();
+        let nats = Nats::new(url, "gw").unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        stream.write_all(b"INFO {}\r\nPI").await.unwrap();
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("CONNECT "));
+
+        // an event is published while the ping is partially read
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        nats.on_released("web").await;
+        line.clear();
+        stream.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("PUB gw.registration.released "));
+        line.clear();
+        stream.read_line(&mut line).await.unwrap();
+
+        stream.write_all(b"NG\r\n").await.unwrap();
+        line.clear();
+        stream.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "PONG\r\n");
+    }
+}
@@ -0,0 +1,470 @@
+//! OAuth gates routed names behind an OAuth2/OIDC login at the gateway. Clients
+//! without a valid session are redirected to the identity provider, and after
+//! login the gateway exchanges the authorization code, checks the identity and
+//! sets a signed session cookie for the routed host.
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use tokio::net::TcpStream;
+use url::{form_urlencoded, Url};
+
+use crate::http::{post, respond, Request};
+use crate::{Error, Result};
+
+/// path of the redirect uri on every protected host. It must be allowed
+/// as redirect uri by the identity provider
+pub const CALLBACK_PATH: &str = "/_diglett/oauth/callback";
+/// default life time of a login session
+pub const SESSION_TTL: Duration = Duration::from_secs(12 * 3600);
+
+const COOKIE: &str = "diglett_session";
+/// cookie of the login in progress, the state must be returned to the same
+/// browser that started the login
+const STATE_COOKIE: &str = "diglett_state";
+/// max time between the redirect to the identity provider and the callback
+const STATE_TTL: u64 = 600;
+
+/// OAuth provider configuration
+#[derive(Clone)]
+pub struct OAuth {
+    authorize: Url,
+    token: Url,
+    client_id: String,
+    client_secret: String,
+    scopes: String,
+    scheme: String,
+    domains: HashSet<String>,
+    session: Duration,
+    key: Vec<u8>,
+}
+
+impl OAuth {
+    /// create a provider from its authorization and token endpoints. Sessions
+    /// are signed with a random key, so they don't survive a server restart
+    pub fn new<I, S>(authorize: Url, token: Url, client_id: I, client_secret: S) -> Result<Self>
+    where
+        I: Into<String>,
+        S: Into<String>,
+    {
+        let mut key = vec![0; 32];
+        openssl::rand::rand_bytes(&mut key)?;
+
+        Ok(Self {
+            authorize,
+            token,
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scopes: "openid email".into(),
+            scheme: "https".into(),
+            domains: HashSet::default(),
+            session: SESSION_TTL,
+            key,
+        })
+    }
+
+    /// only allow users with a (verified) email of that domain. Can be called
+    /// multiple times, if never called any user of the provider is allowed
+    pub fn allow_domain<D: Into<String>>(mut self, domain: D) -> Self {
+        self.domains.insert(domain.into().to_lowercase());
+        self
+    }
+
+    /// scheme of the public urls of the routed names, used to build the
+    /// redirect uri. Default to https (tls terminated in front of the router)
+    pub fn scheme<S: Into<String>>(mut self, scheme: S) -> Self {
+        self.scheme = scheme.into();
+        self
+    }
+
+    /// life time of a login session. Default to [`SESSION_TTL`]
+    pub fn session(mut self, ttl: Duration) -> Self {
+        self.session = ttl;
+        self
+    }
+
+    /// gate the request. Returns true if the request has a valid session and
+    /// can be forwarded, otherwise the response (login redirect or error) has
+    /// been written to the stream
+    pub(crate) async fn gate(
+        &self,
+        stream: &mut TcpStream,
+        request: &Request,
+        host: &str,
+    ) -> Result<bool> {
+        let path = request.path.split_once('?').map(|(path, _)| path);
+        if path.unwrap_or(&request.path) == CALLBACK_PATH {
+            if let Err(err) = self.callback(stream, request, host).await {
+                log::debug!("oauth login for '{}' failed: {}", host, err);
+                respond(
+                    stream,
+                    "403 Forbidden",
+                    &[("Content-Type", "text/plain; charset=utf-8")],
+                    b"login failed",
+                )
+                .await?;
+            }
+            return Ok(false);
+        }
+
+        if self.session_of(request, host, now()).is_some() {
+            return Ok(true);
+        }
+
+        let mut nonce = [0; 16];
+        openssl::rand::rand_bytes(&mut nonce)?;
+        let nonce = URL_SAFE_NO_PAD.encode(nonce);
+
+        let mut location = self.authorize.clone();
+        location
+            .query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri(host))
+            .append_pair("scope", &self.scopes)
+            .append_pair("state", &self.state(host, &request.path, &nonce, now()));
+
+        let cookie = self.cookie(STATE_COOKIE, &nonce, STATE_TTL);
+        respond(
+            stream,
+            "302 Found",
+            &[
+                ("Location", location.as_str()),
+                ("Set-Cookie", &cookie),
+                ("Cache-Control", "no-store"),
+            ],
+            b"",
+        )
+        .await?;
+
+        Ok(false)
+    }
+
+    async fn callback(&self, stream: &mut TcpStream, request: &Request, host: &str) -> Result<()> {
+        let query = request
+            .path
+            .split_once('?')
+            .map(|(_, query)| query)
+            .unwrap_or_default();
+
+        let mut code = None;
+        let mut state = None;
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "code" => code = Some(value.into_owned()),
+                "state" => state = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        let (Some(code), Some(state)) = (code, state) else {
+            return Err(Error::OAuth("missing code or state".into()));
+        };
+
+        // the state is only accepted from the browser that started the login,
+        // so a user can't be logged in with the session of another user
+        let nonce = cookie(request, STATE_COOKIE)
+            .ok_or_else(|| Error::OAuth("missing state cookie".into()))?;
+        let path = self
+            .verify_state(host, &state, nonce, now())
+            .ok_or_else(|| Error::OAuth("invalid or expired state".into()))?;
+
+        let email = self.exchange(host, &code).await?;
+        if !self.allowed(email.as_deref()) {
+            return Err(Error::OAuth(format!(
+                "user '{}' is not allowed",
+                email.unwrap_or_default()
+            )));
+        }
+
+        let email = email.unwrap_or_default();
+        log::info!("oauth login of '{}' to '{}'", email, host);
+
+        let expires = now() + self.session.as_secs();
+        let session = self.cookie(
+            COOKIE,
+            &self.sign_session(host, &email, expires),
+            self.session.as_secs(),
+        );
+
+        respond(
+            stream,
+            "302 Found",
+            &[
+                ("Location", &path),
+                ("Set-Cookie", &session),
+                ("Set-Cookie", &self.cookie(STATE_COOKIE, "", 0)),
+                ("Cache-Control", "no-store"),
+            ],
+            b"",
+        )
+        .await
+    }
+
+    fn cookie(&self, name: &str, value: &str, ttl: u64) -> String {
+        let mut cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+            name, value, ttl
+        );
+        if self.scheme == "https" {
+            cookie.push_str("; Secure");
+        }
+
+        cookie
+    }
+
+    /// exchange the authorization code and return the email of the user
+    /// (if the provider returns an id token)
+    async fn exchange(&self, host: &str, code: &str) -> Result<Option<String>> {
+        let form = form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", &self.redirect_uri(host))
+            .append_pair("client_id", &self.client_id)
+            .append_pair("client_secret", &self.client_secret)
+            .finish();
+
+        let response = post(&self.token, "application/x-www-form-urlencoded", &form).await?;
+        if response.status != 200 {
+            return Err(Error::OAuth(format!(
+                "token endpoint replied with {}",
+                response.status
+            )));
+        }
+
+        let response: serde_json::Value = serde_json::from_slice(&response.body)
+            .map_err(|err| Error::OAuth(format!("invalid token response: {}", err)))?;
+
+        if response.get("access_token").is_none() {
+            return Err(Error::OAuth("token response has no access token".into()));
+        }
+
+        Ok(response
+            .get("id_token")
+            .and_then(|token| token.as_str())
+            .and_then(email))
+    }
+
+    fn allowed(&self, email: Option<&str>) -> bool {
+        if self.domains.is_empty() {
+            return true;
+        }
+
+        email
+            .and_then(|email| email.rsplit_once('@'))
+            .map(|(_, domain)| self.domains.contains(&domain.to_lowercase()))
+            .unwrap_or(false)
+    }
+
+    fn redirect_uri(&self, host: &str) -> String {
+        format!("{}://{}{}", self.scheme, host, CALLBACK_PATH)
+    }
+
+    fn session_of(&self, request: &Request, host: &str, now: u64) -> Option<String> {
+        let value = cookie(request, COOKIE)?;
+
+        let mut parts = value.splitn(3, '.');
+        let expires: u64 = parts.next()?.parse().ok()?;
+        let email = String::from_utf8(URL_SAFE_NO_PAD.decode(parts.next()?).ok()?).ok()?;
+        let signature = URL_SAFE_NO_PAD.decode(parts.next()?).ok()?;
+
+        let expected = self.sign(&format!("session|{}|{}|{}", host, expires, email));
+        if expires < now || !equal(&signature, &expected) {
+            return None;
+        }
+
+        Some(email)
+    }
+
+    fn sign_session(&self, host: &str, email: &str, expires: u64) -> String {
+        let signature = self.sign(&format!("session|{}|{}|{}", host, expires, email));
+        format!(
+            "{}.{}.{}",
+            expires,
+            URL_SAFE_NO_PAD.encode(email),
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    // the state carries the original path of the request so the user is sent
+    // back to it after login. It's signed and bound to the host so it can't be
+    // forged or replayed on another host, and to the nonce of the state cookie
+    // of the browser
+    fn state(&self, host: &str, path: &str, nonce: &str, now: u64) -> String {
+        let signature = self.sign(&format!("state|{}|{}|{}|{}", host, now, nonce, path));
+        format!(
+            "{}.{}.{}",
+            now,
+            URL_SAFE_NO_PAD.encode(path),
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    fn verify_state(&self, host: &str, state: &str, nonce: &str, now: u64) -> Option<String> {
+        let mut parts = state.splitn(3, '.');
+        let issued: u64 = parts.next()?.parse().ok()?;
+        let path = String::from_utf8(URL_SAFE_NO_PAD.decode(parts.next()?).ok()?).ok()?;
+        let signature = URL_SAFE_NO_PAD.decode(parts.next()?).ok()?;
+
+        let expected = self.sign(&format!("state|{}|{}|{}|{}", host, issued, nonce, path));
+        if nonce.is_empty() || issued + STATE_TTL < now || !equal(&signature, &expected) {
+            return None;
+        }
+
+        // only redirect to local paths
+        if !path.starts_with('/') || path.starts_with("//") {
+            return None;
+        }
+
+        Some(path)
+    }
+
+    fn sign(&self, data: &str) -> Vec<u8> {
+        // hmac can't fail with a valid key and digest
+        let key = PKey::hmac(&self.key).expect("valid hmac key");
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("valid hmac signer");
+        signer
+            .sign_oneshot_to_vec(data.as_bytes())
+            .expect("hmac signature")
+    }
+}
+
+// the secrets are not printed
+impl std::fmt::Debug for OAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OAuth")
+            .field("authorize", &self.authorize.as_str())
+            .field("token", &self.token.as_str())
+            .field("client_id", &self.client_id)
+            .field("domains", &self.domains)
+            .finish()
+    }
+}
+
+// value of the cookie of the request
+fn cookie<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .cookie
+        .as_deref()?
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// email claim of an id token, only verified emails are returned. The token
+/// is received directly from the token endpoint over tls so its signature does
+/// not need to be verified
+fn email(token: &str) -> Option<String> {
+    let payload = token.split('.').nth(1)?;
+    let claims: serde_json::Value =
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+
+    if claims.get("email_verified").and_then(|v| v.as_bool()) != Some(true) {
+        return None;
+    }
+
+    claims.get("email")?.as_str().map(String::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn provider() -> OAuth {
+        OAuth::new(
+            Url::parse("https://idp.example.com/authorize").unwrap(),
+            Url::parse("https://idp.example.com/token").unwrap(),
+            "client",
+            "secret",
+        )
+        .unwrap()
+        .allow_domain("Example.com")
+    }
+
+    #[test]
+    fn state() {
+        let oauth = provider();
+        let state = oauth.state("web.gateway.com", "/page?x=1", "nonce", 1000);
+
+        assert_eq!(
+            oauth.verify_state("web.gateway.com", &state, "nonce", 1100),
+            Some("/page?x=1".into())
+        );
+        // other host
+        assert_eq!(
+            oauth.verify_state("api.gateway.com", &state, "nonce", 1100),
+            None
+        );
+        // expired
+        assert_eq!(
+            oauth.verify_state("web.gateway.com", &state, "nonce", 2000),
+            None
+        );
+        // started by another browser
+        assert_eq!(
+            oauth.verify_state("web.gateway.com", &state, "other", 1100),
+            None
+        );
+    }
+
+    #[test]
+    fn session() {
+        let oauth = provider();
+        let cookie = oauth.sign_session("web.gateway.com", "alice@example.com", 1000);
+        let request = Request::parse(
+            format!(
+                "GET / HTTP/1.1\r\nHost: web.gateway.com\r\nCookie: a=b; {}={}\r\n\r\n",
+                COOKIE, cookie
+            )
+            .into_bytes(),
+        );
+
+        assert_eq!(
+            oauth.session_of(&request, "web.gateway.com", 900),
+            Some("alice@example.com".into())
+        );
+        assert_eq!(oauth.session_of(&request, "api.gateway.com", 900), None);
+        assert_eq!(oauth.session_of(&request, "web.gateway.com", 1001), None);
+        // signed by another server
+        assert_eq!(
+            provider().session_of(&request, "web.gateway.com", 900),
+            None
+        );
+    }
+
+    #[test]
+    fn id_token() {
+        let claims =
+            URL_SAFE_NO_PAD.encode(r#"{"email":"alice@example.com","email_verified":true}"#);
+        let token = format!("header.{}.signature", claims);
+        let oauth = provider();
+
+        assert_eq!(email(&token), Some("alice@example.com".into()));
+        // unverified emails are not trusted
+        for claims in [
+            r#"{"email":"alice@example.com"}"#,
+            r#"{"email":"alice@example.com","email_verified":false}"#,
+        ] {
+            let token = format!("header.{}.signature", URL_SAFE_NO_PAD.encode(claims));
+            assert_eq!(email(&token), None);
+        }
+        assert!(oauth.allowed(Some("alice@EXAMPLE.com")));
+        assert!(!oauth.allowed(Some("mallory@other.com")));
+        assert!(!oauth.allowed(None));
+    }
+}
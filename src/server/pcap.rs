@@ -0,0 +1,439 @@
+//! Pcap is a debugging traffic tap that writes each stream of the selected
+//! registrations to its own pcapng file. The bytes are wrapped in synthetic
+//! ipv4/tcp packets (including a handshake and a fin) between a fake client
+//! and server, so the protocol inside the tunnel can be analyzed in Wireshark
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+
+use super::{middleware::Direction, TrafficTap};
+use crate::{wire::Stream, Result};
+
+/// max number of pending chunks, new chunks are dropped once reached
+const QUEUE_SIZE: usize = 4096;
+/// max payload of a synthetic tcp segment
+const MSS: usize = 1460;
+
+const CLIENT_IP: [u8; 4] = [10, 0, 0, 1];
+const SERVER_IP: [u8; 4] = [10, 0, 0, 2];
+
+/// link type of raw ipv4 packets
+const LINKTYPE_IPV4: u16 = 228;
+
+const SYN: u8 = 0x02;
+const FIN: u8 = 0x01;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+enum Event {
+    Chunk {
+        name: String,
+        stream: Stream,
+        direction: Direction,
+        data: Vec<u8>,
+    },
+    Closed {
+        name: String,
+        stream: Stream,
+    },
+}
+
+/// Pcap writes the streams of the selected registrations to
+/// `<dir>/<name>-<registration>-<client port>-<timestamp>.pcapng`. The
+/// synthetic client uses the real client port, and the server uses the
+/// configured port (default 80) so Wireshark picks the right dissector
+pub struct Pcap {
+    names: HashSet<String>,
+    dir: PathBuf,
+    port: u16,
+    events: Option<mpsc::Sender<Event>>,
+}
+
+impl Pcap {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            names: HashSet::default(),
+            dir: dir.into(),
+            port: 80,
+            events: None,
+        }
+    }
+
+    /// capture the streams of the registration `name`
+    pub fn name<N: Into<String>>(mut self, name: N) -> Self {
+        self.names.insert(name.into());
+        self
+    }
+
+    /// port of the synthetic server
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// start writing the captures. It needs to be called within a tokio runtime
+    pub fn start(mut self) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(write(self.dir.clone(), self.port, receiver));
+        self.events = Some(sender);
+        self
+    }
+
+    fn send(&self, event: Event) {
+        if let Some(events) = &self.events {
+            if events.try_send(event).is_err() {
+                log::warn!("pcap queue is full, dropping traffic");
+            }
+        }
+    }
+}
+
+impl TrafficTap for Pcap {
+    fn tapped(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    fn chunk(&self, name: &str, stream: Stream, direction: Direction, data: &[u8]) {
+        self.send(Event::Chunk {
+            name: name.into(),
+            stream,
+            direction,
+            data: data.to_vec(),
+        });
+    }
+
+    fn closed(&self, name: &str, stream: Stream) {
+        self.send(Event::Closed {
+            name: name.into(),
+            stream,
+        });
+    }
+}
+
+async fn write(dir: PathBuf, port: u16, mut events: mpsc::Receiver<Event>) {
+    let mut captures: HashMap<(String, Stream), Capture> = HashMap::default();
+
+    while let Some(event) = events.recv().await {
+        let result = match event {
+            Event::Chunk {
+                name,
+                stream,
+                direction,
+                data,
+            } => {
+                let key = (name, stream);
+                if !captures.contains_key(&key) {
+                    match Capture::create(&dir, &key.0, stream, port).await {
+                        Ok(capture) => captures.insert(key.clone(), capture),
+                        Err(err) => {
+                            log::error!("failed to create pcap file of '{}': {}", key.0, err);
+                            continue;
+                        }
+                    };
+                }
+
+                let capture = captures.get_mut(&key).unwrap();
+                match capture.data(direction, &data).await {
+                    Ok(()) => Ok(()),
+                    Err(err) => {
+                        captures.remove(&key);
+                        Err(err)
+                    }
+                }
+            }
+            Event::Closed { name, stream } => match captures.remove(&(name, stream)) {
+                Some(capture) => capture.close().await,
+                None => Ok(()),
+            },
+        };
+
+        if let Err(err) = result {
+            log::error!("failed to write pcap file: {}", err);
+        }
+    }
+}
+
+struct Capture {
+    file: BufWriter<File>,
+    flow: Flow,
+}
+
+impl Capture {
+    async fn create(dir: &std::path::Path, name: &str, stream: Stream, port: u16) -> Result<Self> {
+        let path = dir.join(format!(
+            "{}-{}-{}-{}.pcapng",
+            name,
+            stream.registration(),
+            stream.port(),
+            timestamp() / 1_000_000
+        ));
+        log::debug!("capturing stream {} of '{}' to {:?}", stream, name, path);
+
+        let mut capture = Self {
+            file: BufWriter::new(File::create(path).await?),
+            flow: Flow::new(stream.port(), port),
+        };
+
+        capture.file.write_all(&section_header()).await?;
+        capture.file.write_all(&interface_description()).await?;
+
+        let handshake = [
+            capture.flow.segment(true, SYN, &[]),
+            capture.flow.segment(false, SYN | ACK, &[]),
+            capture.flow.segment(true, ACK, &[]),
+        ];
+        for packet in handshake {
+            capture.file.write_all(&packet_block(&packet)).await?;
+        }
+
+        Ok(capture)
+    }
+
+    async fn data(&mut self, direction: Direction, data: &[u8]) -> Result<()> {
+        let from_client = direction == Direction::Down;
+        for chunk in data.chunks(MSS) {
+            let packet = self.flow.segment(from_client, PSH | ACK, chunk);
+            self.file.write_all(&packet_block(&packet)).await?;
+        }
+
+        self.file.flush().await?;
+        Ok(())
+    }
+
+    async fn close(mut self) -> Result<()> {
+        let fin = [
+            self.flow.segment(true, FIN | ACK, &[]),
+            self.flow.segment(false, FIN | ACK, &[]),
+            self.flow.segment(true, ACK, &[]),
+        ];
+        for packet in fin {
+            self.file.write_all(&packet_block(&packet)).await?;
+        }
+
+        self.file.flush().await?;
+        Ok(())
+    }
+}
+
+// the tcp state of the synthetic connection
+struct Flow {
+    client_port: u16,
+    server_port: u16,
+    // next sequence numbers of each side
+    client: u32,
+    server: u32,
+}
+
+impl Flow {
+    fn new(client_port: u16, server_port: u16) -> Self {
+        Self {
+            client_port,
+            server_port,
+            client: 1000,
+            server: 5000,
+        }
+    }
+
+    // build the ipv4 packet of a tcp segment and advance the sender sequence
+    fn segment(&mut self, from_client: bool, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let (src, dst, sport, dport, seq, ack) = if from_client {
+            (
+                CLIENT_IP,
+                SERVER_IP,
+                self.client_port,
+                self.server_port,
+                self.client,
+                self.server,
+            )
+        } else {
+            (
+                SERVER_IP,
+                CLIENT_IP,
+                self.server_port,
+                self.client_port,
+                self.server,
+                self.client,
+            )
+        };
+
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend(sport.to_be_bytes());
+        tcp.extend(dport.to_be_bytes());
+        tcp.extend(seq.to_be_bytes());
+        // the first syn acknowledges nothing
+        tcp.extend(if flags & ACK != 0 { ack } else { 0 }.to_be_bytes());
+        tcp.extend([5 << 4, flags]);
+        tcp.extend(u16::MAX.to_be_bytes());
+        tcp.extend([0, 0, 0, 0]);
+        tcp.extend(payload);
+
+        let mut pseudo = Vec::with_capacity(12);
+        pseudo.extend(src);
+        pseudo.extend(dst);
+        pseudo.extend([0, 6]);
+        pseudo.extend((tcp.len() as u16).to_be_bytes());
+        let sum = checksum(&[&pseudo, &tcp]);
+        tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+
+        let mut ip = Vec::with_capacity(20 + tcp.len());
+        ip.extend([0x45, 0]);
+        ip.extend(((20 + tcp.len()) as u16).to_be_bytes());
+        // id, don't fragment, ttl and protocol (tcp)
+        ip.extend([0, 0, 0x40, 0, 64, 6, 0, 0]);
+        ip.extend(src);
+        ip.extend(dst);
+        let sum = checksum(&[&ip]);
+        ip[10..12].copy_from_slice(&sum.to_be_bytes());
+        ip.extend(tcp);
+
+        let advance = payload.len() as u32 + u32::from(flags & (SYN | FIN) != 0);
+        if from_client {
+            self.client = self.client.wrapping_add(advance);
+        } else {
+            self.server = self.server.wrapping_add(advance);
+        }
+
+        ip
+    }
+}
+
+// internet checksum of the concatenated parts
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut bytes = parts.iter().flat_map(|part| part.iter().copied());
+    while let Some(high) = bytes.next() {
+        let low = bytes.next().unwrap_or(0);
+        sum += u32::from(u16::from_be_bytes([high, low]));
+    }
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+// microseconds since the epoch
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let padded = body.len().next_multiple_of(4);
+    let len = (12 + padded) as u32;
+
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend(kind.to_le_bytes());
+    block.extend(len.to_le_bytes());
+    block.extend(body);
+    block.resize(8 + padded, 0);
+    block.extend(len.to_le_bytes());
+    block
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::with_capacity(16);
+    body.extend(0x1a2b3c4d_u32.to_le_bytes());
+    // version 1.0 and unknown section length
+    body.extend(1_u16.to_le_bytes());
+    body.extend(0_u16.to_le_bytes());
+    body.extend((-1_i64).to_le_bytes());
+    block(0x0a0d0d0a, &body)
+}
+
+fn interface_description() -> Vec<u8> {
+    let mut body = Vec::with_capacity(8);
+    body.extend(LINKTYPE_IPV4.to_le_bytes());
+    body.extend(0_u16.to_le_bytes());
+    // no snap length
+    body.extend(0_u32.to_le_bytes());
+    block(1, &body)
+}
+
+// enhanced packet block of the packet captured now
+fn packet_block(packet: &[u8]) -> Vec<u8> {
+    let timestamp = timestamp();
+    let mut body = Vec::with_capacity(20 + packet.len());
+    body.extend(0_u32.to_le_bytes());
+    body.extend(((timestamp >> 32) as u32).to_le_bytes());
+    body.extend((timestamp as u32).to_le_bytes());
+    body.extend((packet.len() as u32).to_le_bytes());
+    body.extend((packet.len() as u32).to_le_bytes());
+    body.extend(packet);
+    block(6, &body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn segment() {
+        let mut flow = Flow::new(40000, 80);
+        let packet = flow.segment(true, PSH | ACK, b"GET /");
+
+        assert_eq!(packet.len(), 45);
+        // valid checksums sum up to zero
+        assert_eq!(checksum(&[&packet[..20]]), 0);
+        let mut pseudo = Vec::new();
+        pseudo.extend(CLIENT_IP);
+        pseudo.extend(SERVER_IP);
+        pseudo.extend([0, 6, 0, 25]);
+        assert_eq!(checksum(&[&pseudo, &packet[20..]]), 0);
+
+        assert_eq!(flow.client, 1005);
+        flow.segment(false, FIN | ACK, &[]);
+        assert_eq!(flow.server, 5001);
+    }
+
+    #[tokio::test]
+    async fn capture() {
+        let dir = std::env::temp_dir().join(format!("diglett-pcap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let pcap = Pcap::new(&dir).name("web").start();
+        assert!(!pcap.tapped("api"));
+
+        let stream = Stream::new(1.into(), 40000);
+        pcap.chunk("web", stream, Direction::Down, &[1; 2000]);
+        pcap.chunk("web", stream, Direction::Up, b"ok");
+        pcap.closed("web", stream);
+
+        let mut blocks = 0;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let Some(file) = std::fs::read_dir(&dir).unwrap().next() else {
+                continue;
+            };
+
+            let content = std::fs::read(file.unwrap().path()).unwrap();
+            let mut offset = 0;
+            blocks = 0;
+            while offset + 8 <= content.len() {
+                let len = u32::from_le_bytes(content[offset + 4..offset + 8].try_into().unwrap());
+                offset += len as usize;
+                blocks += 1;
+            }
+
+            // header, interface, handshake, 3 data segments and fin
+            if blocks == 11 {
+                break;
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(blocks, 11);
+    }
+}
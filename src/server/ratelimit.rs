@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// buckets are cleaned up when there are more than that
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+/// Rate limit of handshake attempts per source ip. It works as a leaky bucket
+/// that leaks `rate` attempts per second and can hold up to `burst` attempts.
+/// A source that overflows its bucket is banned for the `ban` duration.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+    pub ban: Duration,
+}
+
+struct Bucket {
+    level: f64,
+    updated: Instant,
+    banned: Option<Instant>,
+}
+
+impl Bucket {
+    fn idle(&self, now: Instant) -> bool {
+        self.level <= 0.0 && !matches!(self.banned, Some(until) if until > now)
+    }
+}
+
+pub(crate) struct Limiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl Limiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// check if a new attempt from that ip is allowed
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(ip).or_insert_with(|| Bucket {
+            level: 0.0,
+            updated: now,
+            banned: None,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.level = (bucket.level - elapsed * self.limit.rate).max(0.0);
+        bucket.updated = now;
+
+        let allowed = match bucket.banned {
+            Some(until) if until > now => false,
+            _ => {
+                bucket.banned = None;
+                bucket.level += 1.0;
+                if bucket.level > self.limit.burst as f64 {
+                    log::warn!("banning '{}' for too many handshake attempts", ip);
+                    bucket.banned = Some(now + self.limit.ban);
+                    bucket.level = 0.0;
+                    false
+                } else {
+                    true
+                }
+            }
+        };
+
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            buckets.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+                bucket.level = (bucket.level - elapsed * self.limit.rate).max(0.0);
+                bucket.updated = now;
+                !bucket.idle(now)
+            });
+        }
+
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ban() {
+        let limiter = Limiter::new(RateLimit {
+            rate: 1.0,
+            burst: 2,
+            ban: Duration::from_secs(10),
+        });
+
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let now = Instant::now();
+
+        assert!(limiter.allow_at(ip, now));
+        assert!(limiter.allow_at(ip, now));
+        // overflow
+        assert!(!limiter.allow_at(ip, now));
+        // other ips are not affected
+        assert!(limiter.allow_at([10, 0, 0, 2].into(), now));
+        // still banned
+        assert!(!limiter.allow_at(ip, now + Duration::from_secs(5)));
+        assert!(limiter.allow_at(ip, now + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn leak() {
+        let limiter = Limiter::new(RateLimit {
+            rate: 1.0,
+            burst: 1,
+            ban: Duration::from_secs(10),
+        });
+
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let now = Instant::now();
+        for i in 0..10 {
+            assert!(limiter.allow_at(ip, now + Duration::from_secs(i)));
+        }
+    }
+}
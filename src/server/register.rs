@@ -1,17 +1,34 @@
+#[cfg(any(test, feature = "testing"))]
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
 use crate::Result;
 
+use super::stats::Stats;
+
 /// trait to register a domain. Normally this should expose the domain
-/// to the given port
+/// to the given port. The services of a name exposed on its ports are
+/// registered as `name:port`
 #[async_trait::async_trait]
 pub trait Registerer: Send + Sync + 'static {
     // The handler is returned when a registration happens
     // the point is when the handler is dropped, this must take care
     // of auto removal of the registration
-    type Handler: Send + Sync + 'static;
+    type Handler: Handler;
 
     async fn register(&self, domain: &str, port: u16) -> Result<Self::Handler>;
 }
 
+/// Handler of a single registration
+#[async_trait::async_trait]
+pub trait Handler: Send + Sync + 'static {
+    /// called periodically with the statistics of the registration, and a final
+    /// time right before the handler is dropped
+    async fn stats(&self, _stats: Stats) {}
+}
+
 #[derive(Debug, Clone)]
 pub struct PrintRegisterer;
 
@@ -31,8 +48,173 @@ pub struct PrintHandler {
     name: String,
 }
 
+#[async_trait::async_trait]
+impl Handler for PrintHandler {
+    async fn stats(&self, stats: Stats) {
+        log::debug!(
+            "domain '{}' streams: {}, up: {}, down: {}",
+            self.name,
+            stats.streams,
+            stats.up,
+            stats.down
+        );
+    }
+}
+
 impl Drop for PrintHandler {
     fn drop(&mut self) {
         log::info!("unregister domain '{}'", self.name);
     }
 }
+
+/// a call made to a [`RecordingRegisterer`]
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    Register {
+        domain: String,
+        port: u16,
+    },
+    /// the handler of the registration was dropped
+    Unregister {
+        domain: String,
+        port: u16,
+    },
+}
+
+/// RecordingRegisterer records the registrations of the server so tests of
+/// a server setup can inspect them. Clones share the same records
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Clone, Default)]
+pub struct RecordingRegisterer {
+    calls: Arc<Mutex<Vec<Call>>>,
+    stats: Arc<Mutex<HashMap<String, Stats>>>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl RecordingRegisterer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// all register and unregister calls in order
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// the domains and ports that are registered right now
+    pub fn registered(&self) -> Vec<(String, u16)> {
+        let mut registered = Vec::new();
+        for call in self.calls.lock().unwrap().iter() {
+            match call {
+                Call::Register { domain, port } => registered.push((domain.clone(), *port)),
+                Call::Unregister { domain, port } => {
+                    if let Some(index) = registered
+                        .iter()
+                        .position(|(name, at)| name == domain && at == port)
+                    {
+                        registered.remove(index);
+                    }
+                }
+            }
+        }
+
+        registered
+    }
+
+    /// the last stats delivered to the handler of the domain
+    pub fn stats(&self, domain: &str) -> Option<Stats> {
+        self.stats.lock().unwrap().get(domain).copied()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+#[async_trait::async_trait]
+impl Registerer for RecordingRegisterer {
+    type Handler = RecordingHandler;
+    async fn register(&self, domain: &str, port: u16) -> Result<Self::Handler> {
+        self.calls.lock().unwrap().push(Call::Register {
+            domain: domain.into(),
+            port,
+        });
+
+        Ok(RecordingHandler {
+            domain: domain.into(),
+            port,
+            calls: Arc::clone(&self.calls),
+            stats: Arc::clone(&self.stats),
+        })
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub struct RecordingHandler {
+    domain: String,
+    port: u16,
+    calls: Arc<Mutex<Vec<Call>>>,
+    stats: Arc<Mutex<HashMap<String, Stats>>>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+#[async_trait::async_trait]
+impl Handler for RecordingHandler {
+    async fn stats(&self, stats: Stats) {
+        self.stats
+            .lock()
+            .unwrap()
+            .insert(self.domain.clone(), stats);
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Drop for RecordingHandler {
+    fn drop(&mut self) {
+        self.calls.lock().unwrap().push(Call::Unregister {
+            domain: std::mem::take(&mut self.domain),
+            port: self.port,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn recording() {
+        let registerer = RecordingRegisterer::new();
+        let web = registerer.register("web", 80).await.unwrap();
+        let api = registerer.clone().register("api", 443).await.unwrap();
+        assert_eq!(
+            registerer.registered(),
+            vec![("web".into(), 80), ("api".into(), 443)]
+        );
+
+        drop(web);
+        assert_eq!(registerer.registered(), vec![("api".into(), 443)]);
+        drop(api);
+        assert!(registerer.registered().is_empty());
+
+        assert_eq!(
+            registerer.calls(),
+            vec![
+                Call::Register {
+                    domain: "web".into(),
+                    port: 80
+                },
+                Call::Register {
+                    domain: "api".into(),
+                    port: 443
+                },
+                Call::Unregister {
+                    domain: "web".into(),
+                    port: 80
+                },
+                Call::Unregister {
+                    domain: "api".into(),
+                    port: 443
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,526 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+
+use super::{
+    balance::{self, Members, Strategy},
+    limits::IpConnections,
+    shaping::Limit,
+    stats::Counters,
+};
+use crate::{http::Body, Error, Result};
+
+/// max number of routed connections waiting to be accepted by the agents
+/// of a registration
+const ROUTED_BACKLOG: usize = 128;
+
+/// a client connection of a registration
+pub(crate) struct Incoming {
+    pub stream: TcpStream,
+    /// address of the client
+    pub addr: SocketAddr,
+    /// data already read from the client (the request head read by the http
+    /// router), it's sent to the agent before the rest of the stream
+    pub head: Vec<u8>,
+    /// only the first request of the client (a head of that size and its
+    /// body) is sent to the agent. None sends the whole stream
+    pub first: Option<(usize, Body)>,
+}
+
+impl From<(TcpStream, SocketAddr)> for Incoming {
+    fn from((stream, addr): (TcpStream, SocketAddr)) -> Self {
+        Self {
+            stream,
+            addr,
+            head: Vec::default(),
+            first: None,
+        }
+    }
+}
+
+/// Listener of the client connections of a registration. Clients either
+/// connect to its tcp listener or are routed to it (by the http router)
+pub(crate) struct Listener {
+    tcp: TcpListener,
+    routed: tokio::sync::Mutex<mpsc::Receiver<Incoming>>,
+}
+
+impl Listener {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.local_addr()
+    }
+
+    /// accept the next client connection
+    pub async fn accept(&self) -> io::Result<Incoming> {
+        tokio::select! {
+            accepted = self.tcp.accept() => accepted.map(Incoming::from),
+            Some(incoming) = async { self.routed.lock().await.recv().await } => Ok(incoming),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for Listener {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.tcp.as_raw_fd()
+    }
+}
+
+/// Registration of a name. The registration is shared between all agents
+/// that serve the same name. Only one agent (the owner) accepts new client
+/// connections, the other agents only drain their open streams. This allows
+/// a new agent to take over a name without dropping live connections.
+///
+/// A balanced registration has no owner, instead the client connections are
+/// distributed between all its member agents.
+pub(crate) struct Registration<H> {
+    pub listener: Arc<Listener>,
+    pub endpoint: Option<String>,
+    pub handler: H,
+    pub counters: Arc<Counters>,
+    pub connections: Arc<IpConnections>,
+    pub members: Option<Arc<Members>>,
+    pub limit: Option<Arc<Limit>>,
+    owner: watch::Sender<u64>,
+    dispatcher: Option<JoinHandle<()>>,
+    routes: mpsc::Sender<Incoming>,
+}
+
+impl<H> Registration<H> {
+    pub fn new(
+        listener: TcpListener,
+        endpoint: Option<String>,
+        handler: H,
+        counters: Arc<Counters>,
+        connections: Arc<IpConnections>,
+    ) -> Self {
+        let (routes, routed) = mpsc::channel(ROUTED_BACKLOG);
+        Self {
+            listener: Arc::new(Listener {
+                tcp: listener,
+                routed: tokio::sync::Mutex::new(routed),
+            }),
+            endpoint,
+            handler,
+            counters,
+            connections,
+            members: None,
+            limit: None,
+            owner: watch::channel(0).0,
+            dispatcher: None,
+            routes,
+        }
+    }
+
+    /// distribute the client connections between all the agents that
+    /// serve the registration with that strategy
+    pub fn balanced(mut self, strategy: Strategy) -> Self {
+        let members = Arc::new(Members::new(strategy));
+        self.dispatcher = Some(balance::dispatch(
+            Arc::clone(&self.listener),
+            Arc::clone(&members),
+        ));
+        self.members = Some(members);
+        self
+    }
+
+    /// limit the bandwidth (bytes per second) of all the streams of the
+    /// registration
+    pub fn shaped(mut self, rate: u64) -> Self {
+        self.limit = Some(Arc::new(Limit::new(rate)));
+        self
+    }
+
+    /// subscribe to changes of the owner agent of the registration
+    pub fn owner(&self) -> watch::Receiver<u64> {
+        self.owner.subscribe()
+    }
+
+    /// hand over a client connection to the agents of the registration as if
+    /// it was accepted by its listener. The connection is returned back if
+    /// too many routed connections are waiting already
+    pub fn route(&self, incoming: Incoming) -> std::result::Result<(), Incoming> {
+        self.routes.try_send(incoming).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(incoming)
+            | mpsc::error::TrySendError::Closed(incoming) => incoming,
+        })
+    }
+}
+
+impl<H> Drop for Registration<H> {
+    fn drop(&mut self) {
+        if let Some(dispatcher) = &self.dispatcher {
+            dispatcher.abort();
+        }
+    }
+}
+
+enum Slot<H> {
+    // the registration is being created, the receiver is closed once it's
+    // created or failed
+    Reserved(watch::Receiver<()>),
+    Live(Weak<Registration<H>>),
+}
+
+impl<H> Slot<H> {
+    fn live(&self) -> Option<Arc<Registration<H>>> {
+        match self {
+            Self::Reserved(_) => None,
+            Self::Live(registration) => registration.upgrade(),
+        }
+    }
+
+    fn gone(&self) -> bool {
+        matches!(self, Self::Live(registration) if registration.strong_count() == 0)
+    }
+}
+
+// the user that owns the name and its registration
+type Entry<U, H> = (U, Slot<H>);
+
+/// Registry of all live registrations. The registry is never locked while
+/// a registration is created, so a slow registerer only delays the agents
+/// of that name
+pub(crate) struct Registry<U, H> {
+    names: Mutex<HashMap<String, Entry<U, H>>>,
+}
+
+impl<U, H> Default for Registry<U, H> {
+    fn default() -> Self {
+        Self {
+            names: Mutex::default(),
+        }
+    }
+}
+
+impl<U, H> Registry<U, H>
+where
+    U: Eq + Hash + Clone,
+{
+    /// check if a name can be used by that user. A name can only
+    /// be used by a single user at a time
+    pub async fn available(&self, name: &str, user: &U) -> bool {
+        let names = self.names.lock().unwrap();
+        match names.get(name) {
+            Some((owner, slot)) => owner == user || slot.gone(),
+            None => true,
+        }
+    }
+
+    /// get the live registration of name if any
+    pub async fn lookup(&self, name: &str) -> Option<Arc<Registration<H>>> {
+        let names = self.names.lock().unwrap();
+        names.get(name).and_then(|(_, slot)| slot.live())
+    }
+
+    /// get the live registration of name if it's owned by that user
+    pub async fn owned(&self, name: &str, user: &U) -> Option<Arc<Registration<H>>> {
+        let names = self.names.lock().unwrap();
+        names
+            .get(name)
+            .filter(|(owner, _)| owner == user)
+            .and_then(|(_, slot)| slot.live())
+    }
+
+    /// all live registrations by name
+    pub async fn live(&self) -> Vec<(String, Arc<Registration<H>>)> {
+        let names = self.names.lock().unwrap();
+        names
+            .iter()
+            .filter_map(|(name, (_, slot))| {
+                slot.live().map(|registration| (name.clone(), registration))
+            })
+            .collect()
+    }
+
+    /// acquire the registration of name for that agent. If the name is
+    /// already registered by the same user, the agent takes over the existing
+    /// registration. Otherwise a new registration is created. The name is
+    /// reserved while it's created, other agents of the user wait for it
+    pub async fn acquire<F, Fut>(
+        &self,
+        name: &str,
+        user: &U,
+        agent: u64,
+        create: F,
+    ) -> Result<Arc<Registration<H>>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Registration<H>>>,
+    {
+        let reservation = loop {
+            let mut created = {
+                let mut names = self.names.lock().unwrap();
+                names.retain(|_, (_, slot)| !slot.gone());
+
+                match names.get(name) {
+                    Some((owner, _)) if owner != user => {
+                        return Err(Error::NameInUse(name.into()));
+                    }
+                    Some((_, Slot::Reserved(created))) => created.clone(),
+                    Some((_, Slot::Live(registration))) => {
+                        // dead registrations are already removed
+                        let Some(registration) = registration.upgrade() else {
+                            continue;
+                        };
+
+                        if registration.members.is_some() {
+                            log::info!("agent '{}' joins '{}'", agent, name);
+                            return Ok(registration);
+                        }
+
+                        log::info!("agent '{}' takes over '{}'", agent, name);
+                        registration.owner.send_replace(agent);
+                        return Ok(registration);
+                    }
+                    None => {
+                        let (created, reserved) = watch::channel(());
+                        names.insert(name.into(), (user.clone(), Slot::Reserved(reserved)));
+                        break Reservation {
+                            registry: self,
+                            name,
+                            _created: created,
+                        };
+                    }
+                }
+            };
+
+            // another agent of the user is creating the registration
+            let _ = created.changed().await;
+        };
+
+        let registration = Arc::new(create().await?);
+        registration.owner.send_replace(agent);
+        self.names.lock().unwrap().insert(
+            name.into(),
+            (user.clone(), Slot::Live(Arc::downgrade(&registration))),
+        );
+        drop(reservation);
+
+        Ok(registration)
+    }
+}
+
+// the reservation of a name that is being created. The name is released if the
+// registration is not created (it failed, or the agent is gone)
+struct Reservation<'a, U, H> {
+    registry: &'a Registry<U, H>,
+    name: &'a str,
+    // dropped after the reservation is released, wakes up the waiting agents
+    _created: watch::Sender<()>,
+}
+
+impl<U, H> Drop for Reservation<'_, U, H> {
+    fn drop(&mut self) {
+        let mut names = self.registry.names.lock().unwrap();
+        if matches!(names.get(self.name), Some((_, Slot::Reserved(_)))) {
+            names.remove(self.name);
+        }
+    }
+}
+
+/// wait until the registration is taken over by an agent other than `agent`
+pub(crate) async fn replaced(owner: &mut watch::Receiver<u64>, agent: u64) {
+    // the ref is not held across await points
+    let replaced = owner.wait_for(|owner| *owner != agent).await.is_ok();
+    if !replaced {
+        // registration is gone
+        std::future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    async fn registration() -> Result<Registration<()>> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        Ok(Registration::new(
+            listener,
+            None,
+            (),
+            Arc::new(Counters::default()),
+            Arc::new(IpConnections::new(None)),
+        ))
+    }
+
+    #[tokio::test]
+    async fn takeover() {
+        let registry: Registry<&str, ()> = Registry::default();
+
+        let first = registry
+            .acquire("name", &"user", 1, registration)
+            .await
+            .unwrap();
+        let mut owner = first.owner();
+        assert_eq!(*owner.borrow(), 1);
+
+        assert!(!registry.available("name", &"other").await);
+        assert!(matches!(
+            registry.acquire("name", &"other", 2, registration).await,
+            Err(Error::NameInUse(_))
+        ));
+
+        let second = registry
+            .acquire("name", &"user", 3, registration)
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        replaced(&mut owner, 1).await;
+        assert_eq!(*owner.borrow(), 3);
+
+        assert!(registry.owned("name", &"user").await.is_some());
+        assert!(registry.owned("name", &"other").await.is_none());
+
+        drop(first);
+        drop(second);
+        assert!(registry.available("name", &"other").await);
+    }
+
+    #[tokio::test]
+    async fn reserved() {
+        let registry: Arc<Registry<&str, ()>> = Arc::default();
+
+        let (release, created) = tokio::sync::oneshot::channel::<()>();
+        let slow = tokio::spawn({
+            let registry = Arc::clone(&registry);
+            async move {
+                registry
+                    .acquire("slow", &"user", 1, || async move {
+                        let _ = created.await;
+                        registration().await
+                    })
+                    .await
+            }
+        });
+        while registry.available("slow", &"other").await {
+            tokio::task::yield_now().await;
+        }
+
+        // the registry is not locked while the name is created
+        registry
+            .acquire("fast", &"other", 2, registration)
+            .await
+            .unwrap();
+        assert!(matches!(
+            registry.acquire("slow", &"other", 3, registration).await,
+            Err(Error::NameInUse(_))
+        ));
+
+        // another agent of the user waits for the reserved name
+        let waiting = tokio::spawn({
+            let registry = Arc::clone(&registry);
+            async move { registry.acquire("slow", &"user", 4, registration).await }
+        });
+        release.send(()).unwrap();
+        let first = slow.await.unwrap().unwrap();
+        let second = waiting.await.unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(*first.owner().borrow(), 4);
+
+        // a name is released if its agent is gone while it's created
+        let gone = tokio::spawn({
+            let registry = Arc::clone(&registry);
+            async move {
+                registry
+                    .acquire("gone", &"user", 5, std::future::pending)
+                    .await
+            }
+        });
+        while registry.available("gone", &"other").await {
+            tokio::task::yield_now().await;
+        }
+        gone.abort();
+        let _ = gone.await;
+        assert!(registry.available("gone", &"other").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held() {
+        use super::super::{
+            hooks::{Agent, NoHooks},
+            register::{RecordingHandler, RecordingRegisterer, Registerer},
+            release,
+        };
+        use std::time::Duration;
+
+        async fn recorded(
+            registerer: &RecordingRegisterer,
+        ) -> Result<Registration<RecordingHandler>> {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+            let port = listener.local_addr()?.port();
+            Ok(Registration::new(
+                listener,
+                None,
+                registerer.register("name", port).await?,
+                Arc::new(Counters::default()),
+                Arc::new(IpConnections::new(None)),
+            ))
+        }
+
+        let registerer = RecordingRegisterer::new();
+        let registry: Registry<&str, RecordingHandler> = Registry::default();
+        let agent = Arc::new(Agent {
+            peer: (Ipv4Addr::LOCALHOST, 4000).into(),
+            name: "name".into(),
+            labels: Default::default(),
+        });
+        let (_shutdown, terminated) = watch::channel(None);
+        let hold = Duration::from_secs(10);
+
+        // the agent disconnects, its name is held for the grace period
+        let first = registry
+            .acquire("name", &"user", 1, || recorded(&registerer))
+            .await
+            .unwrap();
+        let address = first.listener.local_addr().unwrap();
+        tokio::spawn(release(
+            first,
+            hold,
+            terminated.clone(),
+            Arc::new(NoHooks),
+            Arc::clone(&agent),
+        ));
+        tokio::time::sleep(hold / 2).await;
+        assert!(!registry.available("name", &"other").await);
+        assert!(matches!(
+            registry
+                .acquire("name", &"other", 2, || recorded(&registerer))
+                .await,
+            Err(Error::NameInUse(_))
+        ));
+
+        // the reconnecting agent of the user reclaims the registration
+        let second = registry
+            .acquire("name", &"user", 3, || recorded(&registerer))
+            .await
+            .unwrap();
+        assert_eq!(second.listener.local_addr().unwrap(), address);
+        assert_eq!(*second.owner().borrow(), 3);
+        assert_eq!(registerer.registered().len(), 1);
+
+        // the name outlives the grace period of the first agent
+        tokio::time::sleep(hold).await;
+        assert!(!registry.available("name", &"other").await);
+
+        // and is released once the grace period of the second agent is over
+        tokio::spawn(release(second, hold, terminated, Arc::new(NoHooks), agent));
+        tokio::time::sleep(hold / 2).await;
+        assert!(!registry.available("name", &"other").await);
+        tokio::time::sleep(hold).await;
+        assert!(registry.available("name", &"other").await);
+        assert!(registerer.registered().is_empty());
+    }
+}
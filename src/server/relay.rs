@@ -0,0 +1,164 @@
+//! Relay forwards the agent connections to another gateway, for agents in
+//! regions where the gateway is not reachable directly. The relay checks the
+//! handshake of the agent and then copies the frames both ways as is: the
+//! keys are negotiated between the agent and the gateway, so the relay can
+//! neither read nor alter the tunnel
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    task::JoinSet,
+};
+
+use crate::{
+    wire::{read_handshake, HANDSHAKE_SIZE},
+    Error, Result,
+};
+
+/// how long the relay waits for the handshake of an agent
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Relay forwards the agents to the upstream gateway
+#[derive(Debug, Clone)]
+pub struct Relay {
+    upstream: String,
+}
+
+impl Relay {
+    /// relay the agents to the gateway at `upstream` (host:port), which may
+    /// itself be a relay
+    pub fn new<U: Into<String>>(upstream: U) -> Self {
+        Self {
+            upstream: upstream.into(),
+        }
+    }
+
+    /// accept the agents on `addr` until the shutdown future resolves, the
+    /// relayed connections are then closed so the agents reconnect
+    pub async fn start_until<D, S>(self, addr: D, shutdown: S) -> Result<()>
+    where
+        D: ToSocketAddrs,
+        S: Future<Output = ()>,
+    {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!(
+            "relaying agents on {} to {}",
+            listener.local_addr()?,
+            self.upstream
+        );
+
+        self.serve_until(listener, shutdown).await
+    }
+
+    async fn serve_until<S>(self, listener: TcpListener, shutdown: S) -> Result<()>
+    where
+        S: Future<Output = ()>,
+    {
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                accepted = listener.accept() => {
+                    let (stream, peer) = accepted?;
+                    let upstream = self.upstream.clone();
+                    connections.spawn(async move {
+                        if let Err(err) = relay(&upstream, stream, peer).await {
+                            log::debug!("failed to relay agent {}: {}", peer, err);
+                        }
+                    });
+                }
+                // clean up the closed connections
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            }
+        }
+
+        log::info!("closing {} relayed connections", connections.len());
+        Ok(())
+    }
+}
+
+async fn relay(upstream: &str, mut agent: TcpStream, peer: SocketAddr) -> Result<()> {
+    // only diglett agents are relayed, the handshake is forwarded as is
+    let mut handshake = [0; HANDSHAKE_SIZE];
+    let (version, _) = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        read_handshake(&mut agent, &mut handshake),
+    )
+    .await
+    .map_err(|_| Error::IO(std::io::ErrorKind::TimedOut.into()))??;
+
+    let mut gateway = TcpStream::connect(upstream).await?;
+    gateway.write_all(&handshake).await?;
+    log::debug!(
+        "relaying agent {} (wire version {}) to {}",
+        peer,
+        version,
+        upstream
+    );
+
+    match tokio::io::copy_bidirectional(&mut agent, &mut gateway).await {
+        Ok((sent, received)) => log::debug!(
+            "relayed agent {} closed, sent {} bytes, received {} bytes",
+            peer,
+            sent,
+            received
+        ),
+        // one side is gone before the other could be shut down
+        Err(err) if err.kind() == std::io::ErrorKind::NotConnected => {
+            log::debug!("relayed agent {} closed", peer)
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::{self, keypair, Message, Registration, Stream};
+
+    #[tokio::test]
+    async fn relay() {
+        let gateway = keypair();
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = upstream.accept().await.unwrap();
+            let mut con = wire::Server::new(stream, gateway).accept().await.unwrap();
+            match con.read().await.unwrap() {
+                Message::Payload { id, mut data } => con.write(id, &mut data).await.unwrap(),
+                msg => panic!("expected payload got: {:?}", msg),
+            };
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = listener.local_addr().unwrap();
+        tokio::spawn(Relay::new(address.to_string()).serve_until(listener, std::future::pending()));
+
+        // the keys are negotiated with the gateway through the relay
+        let stream = TcpStream::connect(relay).await.unwrap();
+        let mut con = wire::Client::new(stream, keypair())
+            .with_pin(gateway.public_key())
+            .negotiate()
+            .await
+            .unwrap();
+
+        let id = Stream::new(Registration::from(0), 1);
+        con.write(id, &mut b"hello".to_vec()).await.unwrap();
+        match con.read().await.unwrap() {
+            Message::Payload { data, .. } => assert_eq!(data, b"hello"),
+            msg => panic!("expected payload got: {:?}", msg),
+        }
+
+        // other protocols are not relayed
+        let mut stream = TcpStream::connect(relay).await.unwrap();
+        stream.write_all(&[b'G'; HANDSHAKE_SIZE]).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await;
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+}
@@ -0,0 +1,302 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::net::TcpStream;
+
+#[cfg(feature = "tls")]
+use super::oauth::OAuth;
+use super::registry::Incoming;
+use crate::http::{read_request, respond, Body, Request};
+use crate::Result;
+
+const OFFLINE_HTML: &str = "<!DOCTYPE html>
+<html><head><title>Tunnel offline</title></head>
+<body><h1>Tunnel offline</h1><p>The service behind this domain is currently offline, please try again later.</p></body>
+</html>
+";
+const OFFLINE_JSON: &str = r#"{"error":"tunnel offline"}"#;
+
+/// HttpRouter serves all registrations over a single http listener. Requests
+/// are routed to the registration by their `Host` header, so `<name>.<domain>`
+/// is served by the agent that registered `name`.
+#[derive(Debug, Clone)]
+pub struct HttpRouter {
+    listen: SocketAddr,
+    domain: String,
+    scheme: String,
+    html: String,
+    json: String,
+    credentials: HashMap<String, (String, String)>,
+    #[cfg(feature = "tls")]
+    oauth: HashMap<String, Arc<OAuth>>,
+}
+
+impl HttpRouter {
+    /// create a router that listens on `listen` and serves the sub domains
+    /// of `domain`
+    pub fn new<D: Into<String>>(listen: SocketAddr, domain: D) -> Self {
+        Self {
+            listen,
+            domain: domain.into().to_lowercase(),
+            scheme: "http".into(),
+            html: OFFLINE_HTML.into(),
+            json: OFFLINE_JSON.into(),
+            credentials: HashMap::default(),
+            #[cfg(feature = "tls")]
+            oauth: HashMap::default(),
+        }
+    }
+
+    /// scheme of the public urls of the routed names reported to the agents.
+    /// Default to http, https assumes tls is terminated in front of the router
+    pub fn scheme<S: Into<String>>(mut self, scheme: S) -> Self {
+        self.scheme = scheme.into();
+        self
+    }
+
+    /// html page returned (with 503) for domains that are not served
+    /// by any agent
+    pub fn offline_html<S: Into<String>>(mut self, html: S) -> Self {
+        self.html = html.into();
+        self
+    }
+
+    /// json body returned (with 503) instead of the html page if the
+    /// client accepts json (api clients)
+    pub fn offline_json<S: Into<String>>(mut self, json: S) -> Self {
+        self.json = json.into();
+        self
+    }
+
+    /// require basic-auth credentials for requests to the registration `name`
+    /// before they are forwarded to the agent. The credentials are required
+    /// from all clients of the name (including the clients of its listener),
+    /// and a client connection is only served a single request
+    pub fn basic_auth<N, U, P>(mut self, name: N, user: U, password: P) -> Self
+    where
+        N: Into<String>,
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.credentials
+            .insert(name.into().to_lowercase(), (user.into(), password.into()));
+        self
+    }
+
+    /// require an oauth login with the provider for requests to the
+    /// registration `name` before they are forwarded to the agent. Like
+    /// [`HttpRouter::basic_auth`] the login is required from all clients of
+    /// the name, and a client connection is only served a single request
+    #[cfg(feature = "tls")]
+    pub fn oauth<N: Into<String>>(mut self, name: N, provider: Arc<OAuth>) -> Self {
+        self.oauth.insert(name.into().to_lowercase(), provider);
+        self
+    }
+
+    pub fn listen(&self) -> SocketAddr {
+        self.listen
+    }
+
+    /// public url of the registration `name`. The port of the listener is only
+    /// part of plain http urls, https is served by a proxy in front of the router
+    pub(crate) fn url(&self, name: &str) -> String {
+        let port = self.listen.port();
+        match self.scheme.as_str() {
+            "http" if port != 80 => format!("http://{}.{}:{}", name, self.domain, port),
+            scheme => format!("{}://{}.{}", scheme, name, self.domain),
+        }
+    }
+
+    /// the registration name of the request host. None if the host is not
+    /// a sub domain of the router domain
+    pub(crate) fn name<'a>(&self, host: &'a str) -> Option<&'a str> {
+        // strip the port
+        let host = match host.rsplit_once(':') {
+            Some((host, port)) if port.bytes().all(|c| c.is_ascii_digit()) => host,
+            _ => host,
+        };
+
+        let name = host.strip_suffix(self.domain.as_str())?.strip_suffix('.')?;
+        if name.is_empty() {
+            return None;
+        }
+
+        Some(name)
+    }
+
+    /// check the request credentials of the registration `name`. Registrations
+    /// without basic-auth are always authorized
+    pub(crate) fn authorized(&self, name: &str, request: &Request) -> bool {
+        let Some((user, password)) = self.credentials.get(name) else {
+            return true;
+        };
+
+        let Some(decoded) = request
+            .authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|value| STANDARD.decode(value.trim()).ok())
+        else {
+            return false;
+        };
+
+        let expected = format!("{}:{}", user, password);
+        equal(&decoded, expected.as_bytes())
+    }
+
+    /// gate of the clients of the registration `name`, if it requires
+    /// credentials or a login
+    pub(crate) fn gate(self: &Arc<Self>, name: &str) -> Option<Gate> {
+        #[cfg(feature = "tls")]
+        let protected = self.credentials.contains_key(name) || self.oauth.contains_key(name);
+        #[cfg(not(feature = "tls"))]
+        let protected = self.credentials.contains_key(name);
+        if !protected {
+            return None;
+        }
+
+        Some(Gate {
+            router: Arc::clone(self),
+            name: name.into(),
+        })
+    }
+
+    /// ask the client for credentials
+    async fn unauthorized(&self, stream: &mut TcpStream, name: &str) -> Result<()> {
+        let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", name);
+        respond(
+            stream,
+            "401 Unauthorized",
+            &[
+                ("Content-Type", "text/plain; charset=utf-8"),
+                ("WWW-Authenticate", &challenge),
+            ],
+            b"unauthorized",
+        )
+        .await
+    }
+
+    /// reply with the offline page
+    pub(crate) async fn offline(&self, mut stream: TcpStream, request: &Request) -> Result<()> {
+        let (content, body) = if request.json {
+            ("application/json", &self.json)
+        } else {
+            ("text/html; charset=utf-8", &self.html)
+        };
+
+        respond(
+            &mut stream,
+            "503 Service Unavailable",
+            &[("Content-Type", content), ("Retry-After", "30")],
+            body.as_bytes(),
+        )
+        .await
+    }
+}
+
+/// Gate checks the clients of a registration that requires credentials (or an
+/// oauth login) before they are served by its agents, whether they come through the router or
+/// connect to the registration listener directly. A client is only served a
+/// single request, so every request is checked
+#[derive(Clone)]
+pub(crate) struct Gate {
+    router: Arc<HttpRouter>,
+    name: String,
+}
+
+impl Gate {
+    /// check the request of the client. Returns true if the client can be
+    /// served, otherwise the response has been written to the client
+    pub(crate) async fn check(&self, incoming: &mut Incoming) -> Result<bool> {
+        let request = if incoming.head.is_empty() {
+            read_request(&mut incoming.stream).await?
+        } else {
+            Request::parse(std::mem::take(&mut incoming.head))
+        };
+
+        if !self.router.authorized(&self.name, &request) {
+            self.router
+                .unauthorized(&mut incoming.stream, &self.name)
+                .await?;
+            return Ok(false);
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(oauth) = self.router.oauth.get(&self.name) {
+            // the session and the login are bound to the host
+            let Some(host) = &request.host else {
+                respond(
+                    &mut incoming.stream,
+                    "400 Bad Request",
+                    &[("Content-Type", "text/plain; charset=utf-8")],
+                    b"missing host",
+                )
+                .await?;
+                return Ok(false);
+            };
+
+            if !oauth.gate(&mut incoming.stream, &request, host).await? {
+                return Ok(false);
+            }
+        }
+
+        let (head, rest) = request.closing();
+        incoming.first = Some((head.len(), Body::of(&request)));
+        incoming.head = [head.as_slice(), rest].concat();
+
+        Ok(true)
+    }
+}
+
+// compare in constant time (for equal lengths) so the credentials
+// can't be guessed by timing the responses
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn route() {
+        let router = HttpRouter::new(([127, 0, 0, 1], 80).into(), "Gateway.com");
+
+        assert_eq!(router.name("web.gateway.com"), Some("web"));
+        assert_eq!(router.name("web.alice.gateway.com:8080"), Some("web.alice"));
+        assert_eq!(router.name("gateway.com"), None);
+        assert_eq!(router.name("webgateway.com"), None);
+        assert_eq!(router.name("web.other.com"), None);
+    }
+
+    #[test]
+    fn url() {
+        let router = HttpRouter::new(([127, 0, 0, 1], 80).into(), "gateway.com");
+        assert_eq!(router.url("web"), "http://web.gateway.com");
+
+        let router = HttpRouter::new(([127, 0, 0, 1], 8080).into(), "gateway.com");
+        assert_eq!(router.url("web"), "http://web.gateway.com:8080");
+
+        let router = router.scheme("https");
+        assert_eq!(router.url("web"), "https://web.gateway.com");
+    }
+
+    #[test]
+    fn basic_auth() {
+        let router = HttpRouter::new(([127, 0, 0, 1], 80).into(), "gateway.com")
+            .basic_auth("Web", "alice", "secret");
+
+        let request = |auth: &str| {
+            Request::parse(
+                format!("GET / HTTP/1.1\r\nHost: web.gateway.com\r\n{}\r\n", auth).into_bytes(),
+            )
+        };
+
+        // base64 of alice:secret
+        assert!(router.authorized("web", &request("Authorization: Basic YWxpY2U6c2VjcmV0\r\n")));
+        // base64 of alice:wrong
+        assert!(!router.authorized("web", &request("Authorization: Basic YWxpY2U6d3Jvbmc=\r\n")));
+        assert!(!router.authorized("web", &request("")));
+        assert!(router.authorized("other", &request("")));
+    }
+}
@@ -0,0 +1,429 @@
+//! Hardening of the server process for gateways exposed to the internet:
+//!
+//! - [`Privileges`] drops the root privileges once the server listeners are
+//!   bound (so they can be on privileged ports)
+//! - [`Sandbox`] restricts the process with Landlock to the network and a few
+//!   paths, and with seccomp denies the system calls a gateway never needs
+//!   (executing programs, mounting, loading modules, tracing, ...)
+use std::{
+    ffi::CString,
+    fs::OpenOptions,
+    io,
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::PathBuf,
+};
+
+use crate::{Error, Result};
+
+/// User and group the server process runs as once its listeners are bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Privileges {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl Privileges {
+    /// look up a user and optionally a group (by name or numeric id). The
+    /// group defaults to the primary group of the user
+    pub fn lookup(user: &str, group: Option<&str>) -> Result<Self> {
+        let (uid, primary) = match passwd(user)? {
+            Some((uid, gid)) => (uid, Some(gid)),
+            None => (
+                user.parse()
+                    .map_err(|_| Error::Sandbox(format!("unknown user '{}'", user)))?,
+                None,
+            ),
+        };
+
+        let gid = match group {
+            Some(group) => match group_id(group)? {
+                Some(gid) => gid,
+                None => group
+                    .parse()
+                    .map_err(|_| Error::Sandbox(format!("unknown group '{}'", group)))?,
+            },
+            None => primary.ok_or_else(|| {
+                Error::Sandbox(format!("user '{}' has no primary group, set a group", user))
+            })?,
+        };
+
+        Ok(Self { uid, gid })
+    }
+
+    /// switch all the threads of the process to the user and group. Fails if
+    /// the root privileges can still be regained afterwards
+    pub fn apply(&self) -> Result<()> {
+        let groups = [self.gid];
+        if unsafe { libc::setgroups(groups.len(), groups.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        if unsafe { libc::setgid(self.gid) } != 0 || unsafe { libc::setuid(self.uid) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        if self.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(Error::Sandbox("root privileges can be regained".into()));
+        }
+
+        Ok(())
+    }
+}
+
+// uid and primary gid of a user name
+fn passwd(user: &str) -> Result<Option<(libc::uid_t, libc::gid_t)>> {
+    let name = CString::new(user).map_err(|_| Error::Sandbox("invalid user name".into()))?;
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 16 * 1024];
+    let mut result = std::ptr::null_mut();
+
+    let code = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code).into());
+    }
+
+    Ok((!result.is_null()).then_some((entry.pw_uid, entry.pw_gid)))
+}
+
+fn group_id(group: &str) -> Result<Option<libc::gid_t>> {
+    let name = CString::new(group).map_err(|_| Error::Sandbox("invalid group name".into()))?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 16 * 1024];
+    let mut result = std::ptr::null_mut();
+
+    let code = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code).into());
+    }
+
+    Ok((!result.is_null()).then_some(entry.gr_gid))
+}
+
+/// Sandbox of the server process. The network is not restricted, but the
+/// filesystem is only accessible under the allowed paths
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// allow reading the file (or everything under the directory) at path
+    pub fn read<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.read.push(path.into());
+        self
+    }
+
+    /// allow reading and writing the file (or everything under the directory)
+    /// at path
+    pub fn write<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.write.push(path.into());
+        self
+    }
+
+    /// restrict the process. Landlock only restricts the calling thread and
+    /// the threads it creates afterwards, so it must be applied before the
+    /// runtime is started. Returns false if the kernel doesn't support
+    /// Landlock, the filesystem is then not restricted
+    pub fn apply(&self) -> Result<bool> {
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let restricted = self.landlock()?;
+        seccomp()?;
+
+        Ok(restricted)
+    }
+
+    fn landlock(&self) -> Result<bool> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+
+        if abi < 1 {
+            log::warn!("landlock is not supported by the kernel, the filesystem is not restricted");
+            return Ok(false);
+        }
+
+        let mut handled = FS_ABI_1;
+        if abi >= 2 {
+            handled |= FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= FS_TRUNCATE;
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        let read = self
+            .read
+            .iter()
+            .map(|path| (path, FS_READ_FILE | FS_READ_DIR));
+        let write = self.write.iter().map(|path| (path, handled & !FS_EXECUTE));
+        for (path, access) in read.chain(write) {
+            let file = match OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+                .open(path)
+            {
+                Ok(file) => file,
+                Err(err) => {
+                    log::debug!("sandbox path '{}' is skipped: {}", path.display(), err);
+                    continue;
+                }
+            };
+
+            // directory only rights can't be granted on a file
+            let access = match file.metadata()?.is_dir() {
+                true => access,
+                false => access & handled & FS_FILE,
+            };
+
+            let rule = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: file.as_raw_fd(),
+            };
+
+            let code = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule,
+                    0,
+                )
+            };
+            if code != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(true)
+    }
+}
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const FS_EXECUTE: u64 = 1 << 0;
+const FS_WRITE_FILE: u64 = 1 << 1;
+const FS_READ_FILE: u64 = 1 << 2;
+const FS_READ_DIR: u64 = 1 << 3;
+// all the rights of the first landlock version
+const FS_ABI_1: u64 = (1 << 13) - 1;
+const FS_REFER: u64 = 1 << 13;
+const FS_TRUNCATE: u64 = 1 << 14;
+// rights that apply to files
+const FS_FILE: u64 = FS_EXECUTE | FS_WRITE_FILE | FS_READ_FILE | FS_TRUNCATE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+// system calls a gateway never needs, they fail with EPERM
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_personality,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// BPF_LD | BPF_W | BPF_ABS
+const BPF_LD_W_ABS: u16 = 0x20;
+// BPF_JMP | BPF_JEQ | BPF_K
+const BPF_JMP_JEQ_K: u16 = 0x15;
+// BPF_JMP | BPF_JGE | BPF_K
+const BPF_JMP_JGE_K: u16 = 0x35;
+// BPF_RET | BPF_K
+const BPF_RET_K: u16 = 0x06;
+
+// offsets in the seccomp data
+const SECCOMP_NR: u32 = 0;
+const SECCOMP_ARCH: u32 = 4;
+
+fn statement(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+// jump over the next instruction unless the accumulator matches k
+fn unless(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 1,
+        k,
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn filter() -> Vec<libc::sock_filter> {
+    let deny = statement(BPF_RET_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+    let mut filter = vec![
+        statement(BPF_LD_W_ABS, SECCOMP_ARCH),
+        libc::sock_filter {
+            code: BPF_JMP_JEQ_K,
+            jt: 1,
+            jf: 0,
+            k: AUDIT_ARCH,
+        },
+        statement(BPF_RET_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD_W_ABS, SECCOMP_NR),
+    ];
+
+    // the x32 abi of the same system calls
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([unless(BPF_JMP_JGE_K, 0x4000_0000), deny]);
+
+    for nr in DENIED {
+        filter.extend([unless(BPF_JMP_JEQ_K, *nr as u32), deny]);
+    }
+
+    filter.push(statement(BPF_RET_K, libc::SECCOMP_RET_ALLOW));
+    filter
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp() -> Result<()> {
+    let mut filter = filter();
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+
+    // applied to all the threads of the process
+    let code = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program,
+        )
+    };
+
+    if code != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp() -> Result<()> {
+    Err(Error::Sandbox(
+        "seccomp is not supported on this architecture".into(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let root = Privileges { uid: 0, gid: 0 };
+        assert_eq!(Privileges::lookup("root", None).unwrap(), root);
+        assert_eq!(Privileges::lookup("0", Some("0")).unwrap(), root);
+        assert_eq!(Privileges::lookup("root", Some("root")).unwrap(), root);
+
+        // a numeric user that doesn't exist has no primary group
+        assert!(Privileges::lookup("4242420", None).is_err());
+        assert_eq!(
+            Privileges::lookup("4242420", Some("4242420")).unwrap(),
+            Privileges {
+                uid: 4242420,
+                gid: 4242420
+            }
+        );
+        assert!(Privileges::lookup("no-such-user", None).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::wire::Stream;
+
+/// Statistics of a single registration. Bytes counters are accumulated
+/// since the registration was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// number of currently open streams
+    pub streams: usize,
+    /// bytes forwarded up, from the agent to the connected clients
+    pub up: u64,
+    /// bytes forwarded down, from the connected clients to the agent
+    pub down: u64,
+}
+
+/// Counters are updated by the forwarding loops. Counters can have a parent
+/// that accumulates the counters of multiple registrations.
+#[derive(Default)]
+pub(crate) struct Counters {
+    streams: AtomicUsize,
+    up: AtomicU64,
+    down: AtomicU64,
+    parent: Option<Arc<Counters>>,
+}
+
+impl Counters {
+    pub fn with_parent(parent: Option<Arc<Counters>>) -> Self {
+        Self {
+            parent,
+            ..Default::default()
+        }
+    }
+
+    pub fn opened(&self) {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.opened();
+        }
+    }
+
+    pub fn closed(&self) {
+        self.streams.fetch_sub(1, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.closed();
+        }
+    }
+
+    pub fn up(&self, n: usize) {
+        self.up.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.up(n);
+        }
+    }
+
+    pub fn down(&self, n: usize) {
+        self.down.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.down(n);
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            streams: self.streams.load(Ordering::Relaxed),
+            up: self.up.load(Ordering::Relaxed),
+            down: self.down.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Statistics of a single open stream (client connection)
+#[derive(Debug, Clone)]
+pub struct StreamStats {
+    /// name of the registration
+    pub name: String,
+    /// id of the agent connection that serves the stream
+    pub agent: u64,
+    pub stream: Stream,
+    /// address of the connected client
+    pub client: SocketAddr,
+    /// bytes forwarded up, from the agent to the client
+    pub up: u64,
+    /// bytes forwarded down, from the client to the agent
+    pub down: u64,
+    /// since when the stream is open
+    pub duration: Duration,
+}
+
+struct Tracked {
+    name: String,
+    client: SocketAddr,
+    opened: Instant,
+    counters: Arc<Counters>,
+}
+
+/// Streams keeps track of all open streams of the server, so operators can
+/// find which stream is saturating an agent connection
+#[derive(Default)]
+pub(crate) struct Streams {
+    streams: Mutex<HashMap<(u64, Stream), Tracked>>,
+}
+
+impl Streams {
+    /// track a stream until the returned guard is dropped
+    pub fn track(
+        self: &Arc<Self>,
+        agent: u64,
+        stream: Stream,
+        name: &str,
+        client: SocketAddr,
+        counters: Arc<Counters>,
+    ) -> Tracking {
+        self.streams.lock().unwrap().insert(
+            (agent, stream),
+            Tracked {
+                name: name.into(),
+                client,
+                opened: Instant::now(),
+                counters,
+            },
+        );
+
+        Tracking {
+            streams: Arc::clone(self),
+            key: (agent, stream),
+        }
+    }
+
+    /// list all open streams
+    pub fn list(&self) -> Vec<StreamStats> {
+        let streams = self.streams.lock().unwrap();
+        streams
+            .iter()
+            .map(|(key, tracked)| tracked.stats(key))
+            .collect()
+    }
+}
+
+impl Tracked {
+    fn stats(&self, (agent, stream): &(u64, Stream)) -> StreamStats {
+        let stats = self.counters.stats();
+        StreamStats {
+            name: self.name.clone(),
+            agent: *agent,
+            stream: *stream,
+            client: self.client,
+            up: stats.up,
+            down: stats.down,
+            duration: self.opened.elapsed(),
+        }
+    }
+}
+
+/// Tracking of a single stream, the stream is dropped from the tracked
+/// streams with the guard
+pub(crate) struct Tracking {
+    streams: Arc<Streams>,
+    key: (u64, Stream),
+}
+
+impl Tracking {
+    /// current stats of the tracked stream
+    pub fn stats(&self) -> Option<StreamStats> {
+        let streams = self.streams.streams.lock().unwrap();
+        streams
+            .get(&self.key)
+            .map(|tracked| tracked.stats(&self.key))
+    }
+}
+
+impl Drop for Tracking {
+    fn drop(&mut self) {
+        self.streams.streams.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn streams() {
+        let registration = Arc::new(Counters::default());
+        let streams = Arc::new(Streams::default());
+
+        let counters = Arc::new(Counters::with_parent(Some(Arc::clone(&registration))));
+        let stream = Stream::new(0.into(), 4000);
+        let tracking = streams.track(
+            1,
+            stream,
+            "web",
+            ([10, 0, 0, 1], 4000).into(),
+            Arc::clone(&counters),
+        );
+
+        counters.opened();
+        counters.up(10);
+        counters.down(20);
+
+        let list = streams.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!((list[0].up, list[0].down), (10, 20));
+        assert_eq!(
+            registration.stats(),
+            Stats {
+                streams: 1,
+                up: 10,
+                down: 20
+            }
+        );
+
+        drop(tracking);
+        assert!(streams.list().is_empty());
+    }
+}
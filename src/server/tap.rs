@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use super::middleware::Direction;
+use crate::wire::Stream;
+
+/// TrafficTap receives a copy of the bytes of the streams of selected
+/// registrations, as they are exchanged with the connected clients (so
+/// before the middlewares in the down direction, and after them in the up
+/// direction). It can feed debugging proxies, intrusion detection or
+/// traffic logs without touching the forwarding.
+///
+/// The tap is called from the forwarding loops so it must not block, slow
+/// processing should be handed over (for example over a channel) to
+/// another task
+pub trait TrafficTap: Send + Sync + 'static {
+    /// tell if the streams of the registration `name` are tapped
+    fn tapped(&self, name: &str) -> bool;
+
+    /// a chunk of the stream of the registration `name` flowing in the
+    /// given direction
+    fn chunk(&self, name: &str, stream: Stream, direction: Direction, data: &[u8]);
+
+    /// the stream of the registration `name` is closed
+    fn closed(&self, _name: &str, _stream: Stream) {}
+}
+
+/// Tap of the streams of a single registration
+#[derive(Clone)]
+pub(crate) struct Tap {
+    tap: Arc<dyn TrafficTap>,
+    name: Arc<str>,
+}
+
+impl Tap {
+    /// tap of the registration `name`, None if the registration is not tapped
+    pub fn new(tap: &Arc<dyn TrafficTap>, name: &str) -> Option<Self> {
+        if !tap.tapped(name) {
+            return None;
+        }
+
+        Some(Self {
+            tap: Arc::clone(tap),
+            name: name.into(),
+        })
+    }
+
+    pub fn copy(&self, stream: Stream, direction: Direction, data: &[u8]) {
+        if !data.is_empty() {
+            self.tap.chunk(&self.name, stream, direction, data);
+        }
+    }
+
+    pub fn closed(&self, stream: Stream) {
+        self.tap.closed(&self.name, stream);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(String, Direction, Vec<u8>)>>);
+
+    impl TrafficTap for Recorder {
+        fn tapped(&self, name: &str) -> bool {
+            name == "web"
+        }
+
+        fn chunk(&self, name: &str, _stream: Stream, direction: Direction, data: &[u8]) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((name.into(), direction, data.to_vec()));
+        }
+    }
+
+    #[test]
+    fn tap() {
+        let recorder = Arc::new(Recorder::default());
+        let tap: Arc<dyn TrafficTap> = recorder.clone();
+
+        assert!(Tap::new(&tap, "api").is_none());
+
+        let web = Tap::new(&tap, "web").unwrap();
+        let stream = Stream::new(1.into(), 1000);
+        web.copy(stream, Direction::Down, b"GET /");
+        web.copy(stream, Direction::Up, b"");
+        web.copy(stream, Direction::Up, b"200 OK");
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                ("web".into(), Direction::Down, b"GET /".to_vec()),
+                ("web".into(), Direction::Up, b"200 OK".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,384 @@
+//! Signed authenticates agents with self contained tokens (HS256 JWTs) signed
+//! by a secret shared with the server. The token carries the user and the
+//! domains it can register, so small deployments don't need a separate
+//! control plane to onboard agents. Tokens are minted with
+//! `diglett-server token` or with [`Signed::mint`]:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use diglett::server::{token::Claims, Signed};
+//!
+//! # fn example() -> diglett::Result<()> {
+//! let signed = Signed::new("a long random secret shared with the server")?
+//!     .with_domain("example.com");
+//! let claims = Claims::new("alice")
+//!     .domain("*.alice.example.com")
+//!     .expires_in(Duration::from_secs(30 * 24 * 3600));
+//! println!("{}", signed.mint(&claims));
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    fmt::Display,
+    hash::{Hash, Hasher},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use serde::{Deserialize, Serialize};
+
+use super::auth::{Authenticate, User};
+use crate::{Error, Result};
+
+/// header of the minted tokens, only HS256 tokens are accepted
+const HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+/// min size of the secret in bytes, a HS256 key must be at least as long as
+/// the hash
+pub const MIN_SECRET_SIZE: usize = 32;
+
+/// claims of a token, the subject is the user id
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Claims {
+    /// the user of the token
+    pub sub: String,
+    /// patterns of the domains the user can register, a pattern either
+    /// matches a domain exactly or starts with `*.` to match its subdomains
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// expiry of the token in seconds since the unix epoch, none never
+    /// expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+}
+
+impl Claims {
+    /// claims of a user that can't register any domain yet
+    pub fn new<S: Into<String>>(user: S) -> Self {
+        Self {
+            sub: user.into(),
+            domains: Vec::default(),
+            exp: None,
+        }
+    }
+
+    /// the user of the token
+    pub fn user(&self) -> &str {
+        &self.sub
+    }
+
+    /// allow the user to register the domains matching the pattern
+    pub fn domain<D: Into<String>>(mut self, pattern: D) -> Self {
+        self.domains.push(pattern.into().to_lowercase());
+        self
+    }
+
+    /// expire the token after that duration from now
+    pub fn expires_in(mut self, ttl: Duration) -> Self {
+        self.exp = Some(now().saturating_add(ttl.as_secs()));
+        self
+    }
+
+    /// if the domain matches any of the patterns of the claims
+    pub fn allows(&self, domain: &str) -> bool {
+        let domain = domain.to_lowercase();
+        self.domains
+            .iter()
+            .any(|pattern| match pattern.strip_prefix('*') {
+                Some("") => true,
+                Some(suffix) => {
+                    suffix.starts_with('.')
+                        && domain.len() > suffix.len()
+                        && domain.ends_with(suffix)
+                }
+                None => *pattern == domain,
+            })
+    }
+}
+
+// the user id, so the claims can be scoped by a namespace
+impl Display for Claims {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.sub)
+    }
+}
+
+/// Identity is the user of an agent authenticated by [`Signed`]. The server
+/// tracks the agents of a user (their quotas and the names they hold) by
+/// identity, so identities are compared by user only: the agents of two
+/// tokens of the same user (like a refreshed token) are of the same user.
+/// The names are authorized by the claims of the identity
+#[derive(Debug, Clone)]
+pub struct Identity {
+    claims: Claims,
+}
+
+impl Identity {
+    /// the user of the identity
+    pub fn user(&self) -> &str {
+        self.claims.user()
+    }
+
+    /// the claims of the token the agent authenticated with
+    pub fn claims(&self) -> &Claims {
+        &self.claims
+    }
+}
+
+impl From<Claims> for Identity {
+    fn from(claims: Claims) -> Self {
+        Self { claims }
+    }
+}
+
+impl PartialEq for Identity {
+    fn eq(&self, other: &Self) -> bool {
+        self.user() == other.user()
+    }
+}
+
+impl Eq for Identity {}
+
+impl Hash for Identity {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.user().hash(state);
+    }
+}
+
+// the user id, so the names can be scoped by a namespace
+impl Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.user())
+    }
+}
+
+/// Signed authenticates agents by the tokens signed with its secret, see the
+/// [module](self) documentation
+#[derive(Clone)]
+pub struct Signed {
+    secret: Vec<u8>,
+    domain: Option<String>,
+    scoped: bool,
+}
+
+impl Signed {
+    /// create an authenticator of the tokens signed with that secret. The
+    /// secret must be at least [`MIN_SECRET_SIZE`] bytes long
+    pub fn new<S: AsRef<[u8]>>(secret: S) -> Result<Self> {
+        let secret = secret.as_ref();
+        if secret.len() < MIN_SECRET_SIZE {
+            return Err(Error::Config(format!(
+                "token secret must be at least {} bytes long",
+                MIN_SECRET_SIZE
+            )));
+        }
+
+        Ok(Self {
+            secret: secret.to_vec(),
+            domain: None,
+            scoped: false,
+        })
+    }
+
+    /// names are registered under that domain (like the domain of the http
+    /// router), so the claims are matched against `<name>.<domain>`. Default
+    /// to matching the name itself
+    pub fn with_domain<D: Into<String>>(mut self, domain: D) -> Self {
+        self.domain = Some(domain.into().to_lowercase());
+        self
+    }
+
+    /// names are scoped under the user (the server uses the
+    /// [`UserNamespace`](super::UserNamespace)), so the claims are matched
+    /// against `<name>.<user>` (then followed by the domain if any)
+    pub fn scoped(mut self) -> Self {
+        self.scoped = true;
+        self
+    }
+
+    /// mint a token of the claims
+    pub fn mint(&self, claims: &Claims) -> String {
+        // claims are always serializable
+        let payload = serde_json::to_vec(claims).expect("serializable claims");
+        let data = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(HEADER),
+            URL_SAFE_NO_PAD.encode(payload)
+        );
+        let signature = URL_SAFE_NO_PAD.encode(self.sign(&data));
+
+        format!("{}.{}", data, signature)
+    }
+
+    /// verify the token and return its claims
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        let invalid = || Error::AuthenticationError("invalid token".into());
+
+        let (data, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let (header, payload) = data.split_once('.').ok_or_else(invalid)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+        let expected = self.sign(data);
+        if signature.len() != expected.len() || !memcmp::eq(&signature, &expected) {
+            return Err(invalid());
+        }
+
+        let header: serde_json::Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).map_err(|_| invalid())?)
+                .map_err(|_| invalid())?;
+        if header.get("alg").and_then(|alg| alg.as_str()) != Some("HS256") {
+            return Err(invalid());
+        }
+
+        let claims: Claims =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?)
+                .map_err(|_| invalid())?;
+        if matches!(claims.exp, Some(exp) if exp <= now()) {
+            return Err(Error::AuthenticationError("token expired".into()));
+        }
+
+        Ok(claims)
+    }
+
+    fn sign(&self, data: &str) -> Vec<u8> {
+        // hmac can't fail with a valid key and digest
+        let key = PKey::hmac(&self.secret).expect("valid hmac key");
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("valid hmac signer");
+        signer
+            .sign_oneshot_to_vec(data.as_bytes())
+            .expect("hmac signature")
+    }
+}
+
+// the secret is not printed
+impl std::fmt::Debug for Signed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Signed")
+            .field("domain", &self.domain)
+            .field("scoped", &self.scoped)
+            .finish()
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticate for Signed {
+    type U = Identity;
+
+    async fn authenticate(&self, token: &str) -> Result<User<Identity>> {
+        let claims = self.verify(token)?;
+        let expires = claims.exp.map(|exp| UNIX_EPOCH + Duration::from_secs(exp));
+
+        Ok(User {
+            id: claims.into(),
+            expires,
+        })
+    }
+
+    async fn authorize(&self, user: &Identity, name: &str) -> Result<bool> {
+        let mut domain = name.to_string();
+        if self.scoped {
+            domain = format!("{}.{}", domain, user.user());
+        }
+        if let Some(suffix) = &self.domain {
+            domain = format!("{}.{}", domain, suffix);
+        }
+
+        Ok(user.claims().allows(&domain))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+    const OTHER: &str = "fedcba9876543210fedcba9876543210";
+
+    #[test]
+    fn secret() {
+        assert!(matches!(Signed::new(""), Err(Error::Config(_))));
+        assert!(matches!(Signed::new("secret"), Err(Error::Config(_))));
+        assert!(Signed::new(SECRET).is_ok());
+    }
+
+    #[test]
+    fn identity() {
+        use std::collections::HashSet;
+
+        // a refreshed token has other claims but is of the same user
+        let claims = Claims::new("alice").domain("*.alice.example.com");
+        let refreshed = Claims::new("alice").expires_in(Duration::from_secs(60));
+        assert_ne!(claims, refreshed);
+        assert_eq!(refreshed.user(), "alice");
+
+        let identity = Identity::from(claims.clone());
+        assert_eq!(identity, Identity::from(refreshed.clone()));
+        assert_eq!(HashSet::from([identity.clone(), refreshed.into()]).len(), 1);
+        assert_ne!(identity, Identity::from(Claims::new("bob")));
+        assert_eq!(identity.claims(), &claims);
+
+        // the expiry doesn't overflow
+        assert_eq!(
+            Claims::new("alice").expires_in(Duration::MAX).exp,
+            Some(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn allows() {
+        let claims = Claims::new("alice")
+            .domain("*.alice.example.com")
+            .domain("Alice.com");
+
+        assert!(claims.allows("web.alice.example.com"));
+        assert!(claims.allows("a.b.alice.example.com"));
+        assert!(claims.allows("alice.com"));
+        assert!(!claims.allows("alice.example.com"));
+        assert!(!claims.allows("web.malice.example.com"));
+        assert!(!claims.allows("web.alice.com"));
+        assert!(Claims::new("admin").domain("*").allows("anything"));
+    }
+
+    #[tokio::test]
+    async fn mint() {
+        let signed = Signed::new(SECRET).unwrap().with_domain("example.com");
+        let claims = Claims::new("alice")
+            .domain("*.alice.example.com")
+            .expires_in(Duration::from_secs(60));
+        let token = signed.mint(&claims);
+
+        let user = signed.authenticate(&token).await.unwrap();
+        assert_eq!(user.id.claims(), &claims);
+        assert!(user.expires.is_some());
+        assert!(!signed.authorize(&user.id, "web").await.unwrap());
+        let scoped = signed.clone().scoped();
+        assert!(scoped.authorize(&user.id, "web").await.unwrap());
+        let bob = Claims::new("bob").domain("*.alice.example.com").into();
+        assert!(!scoped.authorize(&bob, "web").await.unwrap());
+
+        // signed with another secret
+        let other = Signed::new(OTHER).unwrap();
+        assert!(other.verify(&token).is_err());
+        // tampered claims
+        let forged = other.mint(&Claims::new("alice").domain("*"));
+        let payload = forged.split('.').nth(1).unwrap();
+        let mut parts: Vec<&str> = token.split('.').collect();
+        parts[1] = payload;
+        assert!(signed.verify(&parts.join(".")).is_err());
+
+        let expired = Claims {
+            exp: Some(now() - 1),
+            ..claims
+        };
+        assert!(matches!(
+            signed.verify(&signed.mint(&expired)),
+            Err(Error::AuthenticationError(reason)) if reason == "token expired"
+        ));
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    hash::Hash,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use tokio::io::AsyncWriteExt;
+
+use super::stats::Counters;
+use crate::Result;
+
+/// Usage record of a single user over a period of time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Usage<U> {
+    pub user: U,
+    /// start of the period
+    pub from: SystemTime,
+    /// end of the period
+    pub to: SystemTime,
+    /// bytes forwarded up (from the agents to the clients) during that period
+    pub up: u64,
+    /// bytes forwarded down (from the clients to the agents) during that period
+    pub down: u64,
+}
+
+/// UsageSink receives the usage records of all users that had traffic over
+/// their registrations.
+#[async_trait::async_trait]
+pub trait UsageSink<U>: Send + Sync + 'static {
+    async fn record(&self, usage: Vec<Usage<U>>) -> Result<()>;
+}
+
+/// FileSink appends usage records to a file, one record per line in the
+/// format `<from> <to> <user> <up> <down>` where from and to are unix
+/// timestamps
+pub struct FileSink {
+    path: PathBuf,
+}
+
+impl FileSink {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait::async_trait]
+impl<U> UsageSink<U> for FileSink
+where
+    U: Display + Send + Sync + 'static,
+{
+    async fn record(&self, usage: Vec<Usage<U>>) -> Result<()> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+
+        let mut buf = String::new();
+        for record in usage {
+            buf.push_str(&format!(
+                "{} {} {} {} {}\n",
+                unix(record.from),
+                unix(record.to),
+                record.user,
+                record.up,
+                record.down
+            ));
+        }
+
+        file.write_all(buf.as_bytes()).await?;
+        file.flush().await?;
+
+        Ok(())
+    }
+}
+
+fn unix(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+struct Account {
+    counters: Arc<Counters>,
+    // totals that has been already recorded
+    up: u64,
+    down: u64,
+}
+
+/// Accounting keeps the counters of all users and periodically
+/// flushes the usage to the sink
+pub(crate) struct Accounting<U> {
+    sink: Box<dyn UsageSink<U>>,
+    interval: Duration,
+    users: Mutex<HashMap<U, Account>>,
+}
+
+impl<U> Accounting<U>
+where
+    U: Clone + Eq + Hash + Send + Sync + 'static,
+{
+    pub fn new<S: UsageSink<U>>(sink: S, interval: Duration) -> Self {
+        Self {
+            sink: Box::new(sink),
+            interval,
+            users: Mutex::default(),
+        }
+    }
+
+    /// get the counters of that user, the registrations counters of the user
+    /// must use this as a parent
+    pub fn counters(&self, user: &U) -> Arc<Counters> {
+        let mut users = self.users.lock().unwrap();
+        let account = users.entry(user.clone()).or_insert_with(|| Account {
+            counters: Arc::default(),
+            up: 0,
+            down: 0,
+        });
+
+        Arc::clone(&account.counters)
+    }
+
+    fn collect(&self, from: SystemTime, to: SystemTime) -> Vec<Usage<U>> {
+        let mut users = self.users.lock().unwrap();
+        let mut records = vec![];
+        for (user, account) in users.iter_mut() {
+            let stats = account.counters.stats();
+            if stats.up == account.up && stats.down == account.down {
+                continue;
+            }
+
+            records.push(Usage {
+                user: user.clone(),
+                from,
+                to,
+                up: stats.up - account.up,
+                down: stats.down - account.down,
+            });
+            account.up = stats.up;
+            account.down = stats.down;
+        }
+
+        // accounts that are not used by any registration anymore
+        // are fully recorded and can be dropped
+        users.retain(|_, account| Arc::strong_count(&account.counters) > 1);
+
+        records
+    }
+
+    /// run forever flushing usage records to the sink
+    pub async fn run(self: Arc<Self>) {
+        let mut from = SystemTime::now();
+        let mut interval = tokio::time::interval(self.interval);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            let to = SystemTime::now();
+            let records = self.collect(from, to);
+            from = to;
+
+            if records.is_empty() {
+                continue;
+            }
+
+            if let Err(err) = self.sink.record(records).await {
+                log::error!("failed to record usage: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Nothing;
+
+    #[async_trait::async_trait]
+    impl UsageSink<u64> for Nothing {
+        async fn record(&self, _usage: Vec<Usage<u64>>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn collect() {
+        let accounting = Accounting::new(Nothing, Duration::from_secs(60));
+        let now = SystemTime::now();
+
+        let reg1 = Counters::with_parent(Some(accounting.counters(&1)));
+        let reg2 = Counters::with_parent(Some(accounting.counters(&1)));
+        reg1.up(10);
+        reg2.up(5);
+        reg2.down(7);
+
+        let records = accounting.collect(now, now);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].up, 15);
+        assert_eq!(records[0].down, 7);
+
+        // only the delta is recorded
+        reg1.down(3);
+        let records = accounting.collect(now, now);
+        assert_eq!(records[0].up, 0);
+        assert_eq!(records[0].down, 3);
+
+        drop(reg1);
+        drop(reg2);
+        assert!(accounting.collect(now, now).is_empty());
+        assert!(accounting.users.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+//! Webhooks post the life cycle events of registrations as json to external
+//! urls, so other systems (for example dns automation or chat alerts) can
+//! react to them.
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::json;
+use tokio::sync::mpsc;
+use url::Url;
+
+use super::{hooks::Agent, ServerHooks};
+use crate::http::post;
+use crate::wire::Reason;
+
+/// max number of attempts to deliver an event to a url
+const ATTEMPTS: u32 = 5;
+/// delay before the first retry, it doubles on each retry
+const BACKOFF: Duration = Duration::from_secs(1);
+/// max number of pending events per url, new events are dropped once reached
+const QUEUE_SIZE: usize = 1024;
+
+/// Event is a life cycle event of a registration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// a name has been registered and is served on that (local) port
+    Registered { name: String, port: u16 },
+    /// the registration of the name has been released
+    Released { name: String },
+    /// the server terminated an agent connection
+    Kicked {
+        name: String,
+        agent: SocketAddr,
+        reason: Reason,
+    },
+}
+
+impl Event {
+    fn to_json(&self, timestamp: u64) -> serde_json::Value {
+        match self {
+            Self::Registered { name, port } => json!({
+                "event": "registered",
+                "name": name,
+                "port": port,
+                "timestamp": timestamp,
+            }),
+            Self::Released { name } => json!({
+                "event": "released",
+                "name": name,
+                "timestamp": timestamp,
+            }),
+            Self::Kicked {
+                name,
+                agent,
+                reason,
+            } => json!({
+                "event": "kicked",
+                "name": name,
+                "agent": agent.to_string(),
+                "reason": reason.to_string(),
+                "timestamp": timestamp,
+            }),
+        }
+    }
+}
+
+/// Webhooks are server hooks that post the events to all the urls. Each url
+/// receives the events in order, a failed delivery is retried with a backoff
+/// before the event is dropped
+pub struct Webhooks {
+    urls: Vec<mpsc::Sender<Event>>,
+}
+
+impl Webhooks {
+    /// start delivering events to these urls. It needs to be called within
+    /// a tokio runtime
+    pub fn new<I: IntoIterator<Item = Url>>(urls: I) -> Self {
+        let urls = urls
+            .into_iter()
+            .map(|url| {
+                let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+                tokio::spawn(deliver(url, receiver));
+                sender
+            })
+            .collect();
+
+        Self { urls }
+    }
+
+    fn notify(&self, event: Event) {
+        for url in &self.urls {
+            if url.try_send(event.clone()).is_err() {
+                log::warn!("webhook queue is full, dropping event {:?}", event);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ServerHooks for Webhooks {
+    async fn on_registered(&self, name: &str, port: u16) {
+        self.notify(Event::Registered {
+            name: name.into(),
+            port,
+        });
+    }
+
+    async fn on_released(&self, name: &str) {
+        self.notify(Event::Released { name: name.into() });
+    }
+
+    async fn on_agent_kicked(&self, agent: &Agent, reason: Reason) {
+        self.notify(Event::Kicked {
+            name: agent.name.clone(),
+            agent: agent.peer,
+            reason,
+        });
+    }
+}
+
+async fn deliver(url: Url, mut events: mpsc::Receiver<Event>) {
+    while let Some(event) = events.recv().await {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let body = event.to_json(timestamp).to_string();
+
+        let mut backoff = BACKOFF;
+        for attempt in 1..=ATTEMPTS {
+            let err = match post(&url, "application/json", &body).await {
+                Ok(response) if (200..300).contains(&response.status) => break,
+                Ok(response) => format!("status {}", response.status),
+                Err(err) => err.to_string(),
+            };
+
+            if attempt == ATTEMPTS {
+                log::error!("dropping webhook event to '{}': {}", url, err);
+                break;
+            }
+
+            log::debug!("failed to deliver webhook event to '{}': {}", url, err);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn event() {
+        let event = Event::Kicked {
+            name: "web".into(),
+            agent: ([127, 0, 0, 1], 1000).into(),
+            reason: Reason::Replaced,
+        };
+
+        assert_eq!(
+            event.to_json(10),
+            json!({
+                "event": "kicked",
+                "name": "web",
+                "agent": "127.0.0.1:1000",
+                "reason": "replaced",
+                "timestamp": 10,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn retry() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+
+        let hooks = Webhooks::new([url]);
+        hooks.on_released("web").await;
+
+        // the first attempt fails, so the event is delivered again
+        for status in ["500 Internal Server Error", "200 OK"] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..n]);
+            assert!(request.starts_with("POST /hook HTTP/1.0"));
+            assert!(request.contains(r#""event":"released""#));
+
+            let response = format!("HTTP/1.0 {}\r\nContent-Length: 0\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+}
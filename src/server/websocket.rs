@@ -0,0 +1,269 @@
+//! websocket listener of the server (rfc 6455), so agents and clients that
+//! can't open tcp connections (like browsers) reach the gateway. The binary
+//! messages of a websocket carry the wire protocol as a byte stream in both
+//! directions, the connection is then served like any other agent connection
+use std::{hash::Hash, net::SocketAddr, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use super::{handle_agent, Authenticate, Peer, Registerer, Server};
+use crate::{
+    http::{read_request, respond},
+    Error, Result,
+};
+
+/// key of the upgrade handshake
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// max size of a received message, browsers split the stream in small
+/// messages anyway
+const MAX_MESSAGE_SIZE: u64 = 1024 * 1024;
+
+/// size of the buffer between the websocket and the agent connection
+const BUFFER: usize = 64 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+pub(crate) async fn serve<A: Authenticate, R: Registerer>(
+    server: Arc<Server<A, R>>,
+    listener: TcpListener,
+) where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::error!("failed to accept websocket connection: {}", err);
+                continue;
+            }
+        };
+
+        if matches!(&server.handshakes, Some(limiter) if !limiter.allow(peer.ip())) {
+            log::debug!("handshake of '{}' is rate limited", peer.ip());
+            server.metrics.handshake_rejected();
+            continue;
+        }
+
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(err) = handle(server, stream, peer).await {
+                log::error!("failed to handle websocket connection: {}", err);
+            }
+        });
+    }
+}
+
+async fn handle<A: Authenticate, R: Registerer>(
+    server: Arc<Server<A, R>>,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+) -> Result<()>
+where
+    A::U: Clone + Eq + Hash + Sync,
+{
+    let request = read_request(&mut stream).await?;
+    let upgrade = request
+        .header("upgrade")
+        .filter(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let key = request.header("sec-websocket-key");
+    let (Some(_), Some(key)) = (upgrade, key) else {
+        respond(
+            &mut stream,
+            "426 Upgrade Required",
+            &[],
+            b"websocket only\n",
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept(&key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    let (connection, relayed) = tokio::io::duplex(BUFFER);
+    tokio::spawn(async move {
+        if let Err(err) = relay(stream, relayed).await {
+            log::debug!("websocket of {} closed: {}", peer, err);
+        }
+    });
+
+    // tls of browsers is terminated by the websocket (wss) in front of the
+    // server, so the connection is never wrapped in the tls of the server
+    Box::pin(handle_agent(
+        server,
+        connection,
+        Peer {
+            addr: peer,
+            subject: None,
+        },
+    ))
+    .await
+}
+
+// the accept key of the handshake
+fn accept(key: &str) -> String {
+    STANDARD.encode(openssl::sha::sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+// relay the messages of the websocket to the stream and back, until either
+// side is closed
+async fn relay(socket: TcpStream, stream: DuplexStream) -> Result<()> {
+    let (mut socket_read, mut socket_write) = socket.into_split();
+    let (mut stream_read, mut stream_write) = tokio::io::split(stream);
+    // the control frames answered by the reading side
+    let (control, mut controls) = mpsc::channel(8);
+
+    let inbound = async {
+        loop {
+            let (opcode, payload) = read_frame(&mut socket_read).await?;
+            match opcode {
+                CONTINUATION | TEXT | BINARY => stream_write.write_all(&payload).await?,
+                PING => {
+                    let _ = control.send((PONG, payload)).await;
+                }
+                CLOSE => {
+                    let _ = control.send((CLOSE, Vec::default())).await;
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let outbound = async {
+        let mut buf = vec![0; BUFFER];
+        loop {
+            tokio::select! {
+                read = stream_read.read(&mut buf) => {
+                    let count = read?;
+                    if count == 0 {
+                        write_frame(&mut socket_write, CLOSE, &[]).await?;
+                        return Ok(());
+                    }
+                    write_frame(&mut socket_write, BINARY, &buf[..count]).await?;
+                }
+                Some((opcode, payload)) = controls.recv() => {
+                    write_frame(&mut socket_write, opcode, &payload).await?;
+                    if opcode == CLOSE {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        result = inbound => result,
+        result = outbound => result,
+    }
+}
+
+// read a frame sent by a client, it returns its opcode and its unmasked
+// payload
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header).await?;
+    let opcode = header[0] & 0x0f;
+    let masked = header[1] & 0x80 != 0;
+    let size = match header[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        size => size as u64,
+    };
+
+    if !masked {
+        return Err(Error::InvalidRequest(
+            "websocket frame is not masked".into(),
+        ));
+    }
+    if size > MAX_MESSAGE_SIZE {
+        return Err(Error::InvalidRequest("websocket frame is too large".into()));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0; size as usize];
+    reader.read_exact(&mut payload).await?;
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+
+    Ok((opcode, payload))
+}
+
+// write an unmasked final frame, like servers do
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        size if size < 126 => frame.push(size as u8),
+        size if size <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(size as u16).to_be_bytes());
+        }
+        size => {
+            frame.push(127);
+            frame.extend_from_slice(&(size as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accept_key() {
+        // the example of rfc 6455
+        assert_eq!(
+            accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn frames() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        // a masked client frame
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x80 | BINARY, 0x80 | 5];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"hello".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        client.write_all(&frame).await.unwrap();
+
+        let (opcode, payload) = read_frame(&mut server).await.unwrap();
+        assert_eq!((opcode, payload.as_slice()), (BINARY, &b"hello"[..]));
+
+        // unmasked frames of clients are refused
+        client.write_all(&[0x80 | BINARY, 1, 0]).await.unwrap();
+        assert!(read_frame(&mut server).await.is_err());
+
+        write_frame(&mut server, BINARY, &[7; 300]).await.unwrap();
+        let mut header = [0; 4];
+        client.read_exact(&mut header).await.unwrap();
+        assert_eq!(header, [0x80 | BINARY, 126, 1, 44]);
+    }
+}
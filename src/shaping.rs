@@ -0,0 +1,155 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Bandwidth limits in bytes per second, each direction (up and down) is
+/// limited separately. The forwarding loops delay their reads once a limit
+/// is reached, so a bulk transfer slows down instead of saturating the
+/// gateway link
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bandwidth {
+    /// max rate of all the streams of a registration (shared between all
+    /// agents that serve the name)
+    pub registration: Option<u64>,
+    /// max rate of a single stream
+    pub stream: Option<u64>,
+}
+
+/// token bucket that refills at `rate` bytes per second and holds up to one
+/// second worth of tokens
+struct Bucket {
+    rate: f64,
+    // available tokens and when they were last refilled. Tokens go negative
+    // when a chunk is bigger than what is available, the caller then waits
+    // until the debt is paid
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            rate,
+            tokens: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    // take n tokens, returns how long to wait before the n bytes can be sent
+    fn reserve(&self, n: usize) -> Duration {
+        let mut tokens = self.tokens.lock().unwrap();
+        let (available, updated) = *tokens;
+        let now = Instant::now();
+
+        let refilled = now.saturating_duration_since(updated).as_secs_f64() * self.rate;
+        let available = (available + refilled).min(self.rate) - n as f64;
+        *tokens = (available, now);
+
+        if available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-available / self.rate)
+        }
+    }
+
+    async fn take(&self, n: usize) {
+        let wait = self.reserve(n);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Limit is the rate limit of both directions of a registration or a stream
+pub(crate) struct Limit {
+    up: Bucket,
+    down: Bucket,
+}
+
+impl Limit {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            up: Bucket::new(rate),
+            down: Bucket::new(rate),
+        }
+    }
+}
+
+/// Shaper delays the forwarding of a stream to keep it under its own limit
+/// and the limit of its registration
+#[derive(Clone, Default)]
+pub(crate) struct Shaper {
+    limits: Vec<Arc<Limit>>,
+}
+
+impl Shaper {
+    /// shaper of a new stream of a registration with that limit
+    pub fn new(bandwidth: &Bandwidth, registration: Option<&Arc<Limit>>) -> Self {
+        let limits = bandwidth
+            .stream
+            .map(|rate| Arc::new(Limit::new(rate)))
+            .into_iter()
+            .chain(registration.cloned())
+            .collect();
+
+        Self { limits }
+    }
+
+    /// wait until n bytes can be forwarded up (from the agent to the client)
+    pub async fn up(&self, n: usize) {
+        for limit in &self.limits {
+            limit.up.take(n).await;
+        }
+    }
+
+    /// wait until n bytes can be forwarded down (from the client to the agent)
+    pub async fn down(&self, n: usize) {
+        for limit in &self.limits {
+            limit.down.take(n).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn shape() {
+        let registration = Arc::new(Limit::new(1000));
+        let bandwidth = Bandwidth {
+            registration: Some(1000),
+            stream: Some(500),
+        };
+
+        let first = Shaper::new(&bandwidth, Some(&registration));
+
+        // the stream limit is reached first
+        let start = Instant::now();
+        first.down(500).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        first.down(500).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        // the registration bucket is shared between the streams, only half
+        // of it is left
+        let second = Shaper::new(&Bandwidth::default(), Some(&registration));
+        let start = Instant::now();
+        second.down(1000).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+
+        // directions are limited separately
+        let start = Instant::now();
+        second.up(1000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // no limits never wait
+        let unlimited = Shaper::new(&Bandwidth::default(), None);
+        let start = Instant::now();
+        unlimited.up(1 << 30).await;
+        unlimited.down(1 << 30).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,242 @@
+//! mutual tls between agents and the server. Agents authenticate with a
+//! client certificate signed by a ca the server trusts, and the server
+//! authenticates with a certificate signed by a ca the agent trusts. The
+//! agent also connects over tls to its https backends.
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::UnixTime,
+    server::WebPkiClientVerifier,
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+pub use tokio_rustls::{
+    rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    TlsAcceptor, TlsConnector,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{Error, Result};
+
+/// load all certificates from a pem file
+pub fn certificates<P: AsRef<Path>>(path: P) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<std::io::Result<_>>()?;
+
+    Ok(certs)
+}
+
+/// load the first private key from a pem file
+pub fn private_key<P: AsRef<Path>>(path: P) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| general("no private key found"))
+}
+
+/// server configuration that requires agents to present a certificate
+/// signed by one of the client ca certificates
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_ca: Vec<CertificateDer<'static>>,
+) -> Result<Arc<ServerConfig>> {
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots(client_ca)?))
+        .build()
+        .map_err(general)?;
+
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
+/// agent configuration that verifies the server against the ca certificates
+/// and authenticates the agent with its own certificate
+pub fn client_config(
+    ca: Vec<CertificateDer<'static>>,
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<ClientConfig>> {
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots(ca)?)
+        .with_client_auth_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
+/// client configuration that verifies public servers (for example identity
+/// providers) against the bundled web pki roots
+pub fn public_config() -> Arc<ClientConfig> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Arc::new(config)
+}
+
+/// client configuration of tls backends, the backends are verified against
+/// the ca certificates if set or the web pki roots otherwise. With insecure
+/// the backend certificate is not verified at all
+pub fn backend_config(
+    ca: Option<Vec<CertificateDer<'static>>>,
+    insecure: bool,
+) -> Result<Arc<ClientConfig>> {
+    if insecure {
+        let provider = Arc::new(ring::default_provider());
+        let config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(Insecure(provider)))
+            .with_no_client_auth();
+
+        return Ok(Arc::new(config));
+    }
+
+    let config = match ca {
+        Some(ca) => ClientConfig::builder()
+            .with_root_certificates(roots(ca)?)
+            .with_no_client_auth(),
+        None => return Ok(public_config()),
+    };
+
+    Ok(Arc::new(config))
+}
+
+// accepts any server certificate, the handshake signatures are still checked
+#[derive(Debug)]
+struct Insecure(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for Insecure {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// parse the name used to verify the server certificate
+pub fn server_name(host: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(host.to_string()).map_err(general)
+}
+
+/// common name of the certificate subject
+pub fn subject(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+
+    Some(name.into())
+}
+
+fn roots(certs: Vec<CertificateDer<'static>>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots.add(cert)?;
+    }
+
+    Ok(roots)
+}
+
+fn general<E: ToString>(err: E) -> Error {
+    Error::Tls(tokio_rustls::rustls::Error::General(err.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer;
+
+    struct Issued {
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    }
+
+    fn issue(name: &str, ca: &rcgen::Certificate, ca_key: &KeyPair) -> Issued {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![name.into()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        let cert = params.signed_by(&key, ca, ca_key).unwrap();
+
+        Issued {
+            cert: cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn mutual() {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let server = issue("localhost", &ca, &ca_key);
+        let agent = issue("agent-1", &ca, &ca_key);
+
+        let acceptor = TlsAcceptor::from(
+            server_config(vec![server.cert], server.key, vec![ca.der().clone()]).unwrap(),
+        );
+        let connector = TlsConnector::from(
+            client_config(vec![ca.der().clone()], vec![agent.cert], agent.key).unwrap(),
+        );
+
+        let (client, server) = tokio::io::duplex(4096);
+        let (accepted, connected) = tokio::join!(
+            acceptor.accept(server),
+            connector.connect(server_name("localhost").unwrap(), client)
+        );
+        connected.unwrap();
+        let accepted = accepted.unwrap();
+
+        let subject = accepted
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(subject);
+
+        assert_eq!(subject.as_deref(), Some("agent-1"));
+    }
+}
@@ -0,0 +1,130 @@
+//! flow control of the streams of a tunnel. Each side writes the payloads of
+//! a stream from its own queue, and asks the other side to pause the stream
+//! once the queue fills up
+use tokio::{io::AsyncWrite, sync::Mutex};
+
+use crate::{
+    wire::{Connection, Control, FrameWriter, Stream},
+    Result,
+};
+
+/// payloads queued for a stream while its peer (a backend or a client) is
+/// written, a stream that can't be paused is closed once its queue is full
+pub(crate) const STREAM_QUEUE: usize = 64;
+
+/// queued payloads of a stream that pause it, the rest of the queue is left
+/// for the payloads that are already in flight
+pub(crate) const PAUSE_AT: usize = STREAM_QUEUE * 3 / 4;
+
+/// queued payloads of a paused stream that resume it
+pub(crate) const RESUME_AT: usize = STREAM_QUEUE / 4;
+
+/// Window tracks the payloads of a stream that are queued for its peer.
+/// The other side of the tunnel is asked to pause the stream once the queue
+/// fills up, and to resume it once the peer drained the queue. If the other
+/// side can't be paused (its wire version is too old) the stream is closed
+/// once the queue overflows, so a slow peer doesn't hold up the other streams
+pub(crate) struct Window {
+    enabled: bool,
+    state: std::sync::Mutex<WindowState>,
+}
+
+#[derive(Default)]
+struct WindowState {
+    queued: usize,
+    paused: bool,
+}
+
+impl Window {
+    /// a window of a stream, `enabled` if the other side can be paused
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            state: Default::default(),
+        }
+    }
+
+    /// tells if the queue is full and the stream can't be paused, the
+    /// stream is closed instead of queueing more payloads
+    pub fn overflowed(&self) -> bool {
+        !self.enabled && self.state.lock().unwrap().queued >= STREAM_QUEUE
+    }
+
+    /// a payload is queued, tells if the stream must be paused
+    pub fn queued(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.queued += 1;
+        self.enabled && !state.paused && state.queued >= PAUSE_AT
+    }
+
+    /// a payload is written to the peer, tells if the stream must be resumed
+    pub fn written(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.queued = state.queued.saturating_sub(1);
+        state.paused && state.queued <= RESUME_AT
+    }
+
+    // the state only changes while the writer is locked, so a pause and a
+    // resume are sent in the order they happened
+    pub async fn pause<W, F>(&self, id: Stream, writer: &Mutex<Connection<W, F>>) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+        F: FrameWriter,
+    {
+        let mut writer = writer.lock().await;
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.paused || state.queued < PAUSE_AT {
+                return Ok(());
+            }
+            state.paused = true;
+        }
+
+        log::trace!("pausing stream [{}]", id);
+        writer.control(Control::Pause { id }).await
+    }
+
+    pub async fn resume<W, F>(&self, id: Stream, writer: &Mutex<Connection<W, F>>) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+        F: FrameWriter,
+    {
+        let mut writer = writer.lock().await;
+        {
+            let mut state = self.state.lock().unwrap();
+            if !state.paused || state.queued > RESUME_AT {
+                return Ok(());
+            }
+            state.paused = false;
+        }
+
+        log::trace!("resuming stream [{}]", id);
+        writer.control(Control::Resume { id }).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limited() {
+        let window = Window::new(false);
+        for _ in 0..STREAM_QUEUE {
+            assert!(!window.overflowed());
+            assert!(!window.queued());
+        }
+
+        // the queue is full until a payload is written
+        assert!(window.overflowed());
+        assert!(!window.written());
+        assert!(!window.overflowed());
+
+        // streams that can be paused are never limited
+        let window = Window::new(true);
+        for _ in 0..STREAM_QUEUE * 2 {
+            assert!(!window.overflowed());
+            window.queued();
+        }
+    }
+}
@@ -1,11 +1,28 @@
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    path::Path,
+    str::FromStr,
+};
+
 use crate::Result;
-use openssl::cipher::Cipher;
-pub use openssl::cipher_ctx::CipherCtx;
-use secp256k1::{ecdh, rand, Keypair, PublicKey, Secp256k1};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    ChaCha20Legacy,
+};
+use secp256k1::{ecdh, rand, Keypair, PublicKey, Secp256k1, SecretKey};
 
 pub const SHARED_KEY_LEN: usize = 64;
 
-use sha2::{Digest, Sha512};
+/// size of the keystream chunks generated ahead by a pipelined cipher
+const KEYSTREAM_CHUNK: usize = 16 * 1024;
+/// keystream chunks generated ahead by a pipelined cipher
+const KEYSTREAM_AHEAD: usize = 16;
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use sha2::{Digest, Sha256, Sha512};
 type Hasher = Sha512;
 
 pub type SharedKey = [u8; SHARED_KEY_LEN];
@@ -17,6 +34,44 @@ pub fn keypair() -> Keypair {
     Keypair::from_secret_key(&secp, &sk)
 }
 
+/// load the keypair from the hex encoded secret key in the file at path. The
+/// file is created with a new random keypair if it doesn't exist, so the
+/// public key (which agents can pin) survives restarts
+pub fn keypair_from_file<P: AsRef<Path>>(path: P) -> Result<Keypair> {
+    let path = path.as_ref();
+    match std::fs::read_to_string(path) {
+        Ok(secret) => {
+            let secret = SecretKey::from_str(secret.trim())?;
+            Ok(Keypair::from_secret_key(&Secp256k1::new(), &secret))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let kp = keypair();
+            keypair_to_file(&kp, path)?;
+            Ok(kp)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// write the hex encoded secret key of the keypair to a new file at path,
+/// only readable by its owner. It fails if the file already exists
+pub fn keypair_to_file<P: AsRef<Path>>(kp: &Keypair, path: P) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", kp.display_secret())?;
+    Ok(())
+}
+
+/// fingerprint of a public key, the base64 sha256 digest of the key
+/// (`SHA256:...`) like ssh fingerprints
+pub fn fingerprint(key: &PublicKey) -> String {
+    let digest = Sha256::digest(key.serialize());
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(digest))
+}
+
 /// generate a shared key from secure key and a public key
 pub fn shared(kp: &Keypair, pk: PublicKey) -> SharedKey {
     // we take the x coordinate of the secret point.
@@ -28,28 +83,127 @@ pub fn shared(kp: &Keypair, pk: PublicKey) -> SharedKey {
     sh.finalize().into()
 }
 
-pub(crate) fn encryptor_from_key(key: &SharedKey) -> Result<CipherCtx> {
-    let mut ctx = CipherCtx::new()?;
+/// chacha20 cipher of one direction of a connection. The last 16 bytes of
+/// the shared key are the initial block counter (64 bits, little endian)
+/// followed by the nonce, like the original chacha20 (and openssl) lay them
+/// out. It's the same for both directions and decrypting is encrypting
+pub(crate) type Cipher = ChaCha20Legacy;
+
+pub(crate) fn cipher_from_key(key: &SharedKey) -> Cipher {
+    let mut secret = [0; 32];
+    secret.copy_from_slice(&key[..32]);
+    let mut counter = [0; 8];
+    counter.copy_from_slice(&key[32..40]);
+    let mut nonce = [0; 8];
+    nonce.copy_from_slice(&key[40..48]);
+
+    let mut cipher = Cipher::new(&secret.into(), &nonce.into());
+    cipher.seek(u64::from_le_bytes(counter) as u128 * 64);
+    cipher
+}
+
+/// bytes per second encrypted by one core with the cipher of the
+/// connections, measured for that long
+#[cfg(all(feature = "agent", not(target_arch = "wasm32")))]
+pub(crate) fn cipher_rate(duration: std::time::Duration) -> f64 {
+    let mut cipher = cipher_from_key(&[7; SHARED_KEY_LEN]);
+    let mut data = vec![0; KEYSTREAM_CHUNK];
+    let started = std::time::Instant::now();
+    let mut total = 0;
+    while started.elapsed() < duration {
+        cipher.apply_keystream(&mut data);
+        total += data.len();
+    }
+
+    total as f64 / started.elapsed().as_secs_f64()
+}
+
+/// Chacha encrypts (or decrypts) the frames of one direction of a connection
+/// in order
+pub(crate) enum Chacha {
+    Inline(Cipher),
+    Pipelined(Keystream),
+}
+
+impl Chacha {
+    pub(crate) async fn apply(&mut self, data: &mut [u8]) -> Result<()> {
+        match self {
+            Self::Inline(cipher) => {
+                cipher.apply_keystream(data);
+                Ok(())
+            }
+            Self::Pipelined(keystream) => keystream.apply(data).await,
+        }
+    }
+
+    /// generate the keystream ahead on a worker thread from now on
+    pub(crate) fn pipeline(&mut self) -> Result<()> {
+        if let Self::Inline(cipher) = self {
+            let placeholder = Cipher::new(&Default::default(), &Default::default());
+            let cipher = std::mem::replace(cipher, placeholder);
+            *self = Self::Pipelined(Keystream::start(cipher)?);
+        }
 
-    ctx.encrypt_init(
-        Some(Cipher::chacha20()),
-        Some(&key[..32]),
-        Some(&key[32..48]),
-    )?;
+        Ok(())
+    }
+}
 
-    Ok(ctx)
+/// Keystream generates the chacha20 keystream of a cipher on a worker thread,
+/// the (cheap) xor with the frames is then the only work left to the
+/// connection tasks. The keystream is independent of the data so it's
+/// generated ahead while the connection waits for the network
+pub(crate) struct Keystream {
+    chunks: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    offset: usize,
 }
 
-pub(crate) fn decryptor_from_key(key: &SharedKey) -> Result<CipherCtx> {
-    let mut ctx = CipherCtx::new()?;
+impl Keystream {
+    fn start(mut cipher: Cipher) -> Result<Self> {
+        let (sender, chunks) = tokio::sync::mpsc::channel(KEYSTREAM_AHEAD);
+        std::thread::Builder::new()
+            .name("diglett-keystream".into())
+            .spawn(move || loop {
+                // the keystream is the encryption of zeros
+                let mut chunk = vec![0; KEYSTREAM_CHUNK];
+                cipher.apply_keystream(&mut chunk);
+                // the connection is gone
+                if sender.blocking_send(chunk).is_err() {
+                    break;
+                }
+            })?;
+
+        Ok(Self {
+            chunks,
+            chunk: Vec::default(),
+            offset: 0,
+        })
+    }
+
+    async fn apply(&mut self, data: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < data.len() {
+            if self.offset == self.chunk.len() {
+                self.chunk = self
+                    .chunks
+                    .recv()
+                    .await
+                    .ok_or_else(|| std::io::Error::other("keystream worker stopped"))?;
+                self.offset = 0;
+            }
+
+            let count = (data.len() - done).min(self.chunk.len() - self.offset);
+            let keystream = &self.chunk[self.offset..self.offset + count];
+            for (byte, key) in data[done..done + count].iter_mut().zip(keystream) {
+                *byte ^= key;
+            }
 
-    ctx.decrypt_init(
-        Some(Cipher::chacha20()),
-        Some(&key[..32]),
-        Some(&key[32..48]),
-    )?;
+            done += count;
+            self.offset += count;
+        }
 
-    Ok(ctx)
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -67,4 +221,40 @@ mod test {
 
         assert_eq!(server_key, client_key);
     }
+
+    // the keystream of the shared key must stay the same, it's what the
+    // deployed agents and servers (that used openssl) speak
+    #[test]
+    fn keystream() {
+        let mut key = [0; SHARED_KEY_LEN];
+        for (index, byte) in key.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+
+        let mut data = [0; 32];
+        cipher_from_key(&key).apply_keystream(&mut data);
+        let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(
+            hex,
+            "c7936a8709bb1e3c3c923bf0a1d065c51d0ef90d51a13b9e47f0b60327a2a11a"
+        );
+    }
+
+    #[test]
+    fn keypair_file() {
+        let path = std::env::temp_dir().join(format!("diglett-key-{}", std::process::id()));
+        let created = keypair_from_file(&path).unwrap();
+        let loaded = keypair_from_file(&path).unwrap();
+        // an existing key is never overwritten
+        assert!(keypair_to_file(&keypair(), &path).is_err());
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(
+            &std::fs::metadata(&path).unwrap().permissions(),
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(created.public_key(), loaded.public_key());
+        #[cfg(unix)]
+        assert_eq!(mode & 0o777, 0o600);
+    }
 }
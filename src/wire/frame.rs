@@ -1,13 +1,19 @@
+use std::sync::Arc;
+
 use binary_layout::prelude::*;
 use secp256k1::constants;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{Error, Result};
 
-use super::encrypt::{decryptor_from_key, encryptor_from_key, CipherCtx, SharedKey};
+use super::{
+    encrypt::{cipher_from_key, Chacha, SharedKey},
+    record::{Direction, Recorder},
+};
 
 const MAGIC: u32 = 0x6469676c;
-const VERSION: u8 = 1;
+/// highest wire version supported by this implementation
+pub const VERSION: u8 = 9;
 
 pub const HANDSHAKE_SIZE: usize = 38;
 pub const FRAME_HEADER_SIZE: usize = 7;
@@ -23,6 +29,7 @@ define_layout!(handshake, BigEndian, {
 pub async fn write_handshake<W>(
     writer: &mut W,
     buf: &mut [u8; HANDSHAKE_SIZE],
+    version: u8,
     key: [u8; constants::PUBLIC_KEY_SIZE],
 ) -> Result<()>
 where
@@ -31,17 +38,18 @@ where
     let mut view = handshake::View::new(&mut buf[..]);
 
     view.magic_mut().write(MAGIC);
-    view.version_mut().write(VERSION);
+    view.version_mut().write(version);
     view.key_mut().copy_from_slice(&key);
     writer.write_all(&buf[..]).await?;
 
     writer.flush().await.map_err(Error::IO)
 }
 
+#[allow(clippy::needless_lifetimes)]
 pub async fn read_handshake<'a, R>(
     reader: &mut R,
     buf: &'a mut [u8; HANDSHAKE_SIZE],
-) -> Result<[u8; constants::PUBLIC_KEY_SIZE]>
+) -> Result<(u8, [u8; constants::PUBLIC_KEY_SIZE])>
 where
     R: AsyncRead + Unpin,
 {
@@ -54,14 +62,16 @@ where
         return Err(Error::InvalidMagic);
     }
 
+    // the version is the highest version supported by the sender, version
+    // negotiation is then done by the caller
     let version = view.version().read();
-    if version != VERSION {
+    if version == 0 {
         return Err(Error::InvalidVersion(version));
     }
 
     key.copy_from_slice(view.key());
 
-    Ok(key)
+    Ok((version, key))
 }
 
 define_layout!(frame, BigEndian, {
@@ -86,8 +96,31 @@ pub enum Kind {
     Close = 5,
     // terminating and drop connection
     Terminate = 6,
-    // Login message
+    // Login message, carries the agent labels after the token since version 5
     Login = 7,
+    // public endpoint of a registration (since version 2)
+    Endpoint = 8,
+    // keep alive, renews the agent lease (since version 2)
+    Ping = 9,
+    // refresh the login token (since version 2)
+    Relogin = 10,
+    // metadata of a registration (since version 2)
+    Metadata = 11,
+    // round trip time probe sent by the server (since version 3)
+    Probe = 12,
+    // agent answer of a probe (since version 3)
+    ProbeReply = 13,
+    // acknowledge a close of a stream (since version 4)
+    CloseAck = 14,
+    // stop sending payloads of a stream (since version 6, sent by the server
+    // as well since version 9)
+    Pause = 15,
+    // continue sending payloads of a paused stream (since version 6)
+    Resume = 16,
+    // id of the agent connection on the server (since version 6)
+    Session = 17,
+    // name of the agent a client reaches privately (since version 7)
+    Dial = 18,
 }
 
 impl TryFrom<u8> for Kind {
@@ -102,6 +135,17 @@ impl TryFrom<u8> for Kind {
             5 => Self::Close,
             6 => Self::Terminate,
             7 => Self::Login,
+            8 => Self::Endpoint,
+            9 => Self::Ping,
+            10 => Self::Relogin,
+            11 => Self::Metadata,
+            12 => Self::Probe,
+            13 => Self::ProbeReply,
+            14 => Self::CloseAck,
+            15 => Self::Pause,
+            16 => Self::Resume,
+            17 => Self::Session,
+            18 => Self::Dial,
             _ => return Err("invalid frame type"),
         };
 
@@ -135,14 +179,16 @@ pub trait FrameReader {
 
 pub struct FrameReaderHalf {
     buffer: [u8; MAX_PAYLOAD_SIZE],
-    chacha: CipherCtx,
+    chacha: Chacha,
+    recorder: Option<Arc<Recorder>>,
 }
 
 impl FrameReaderHalf {
     pub fn new(key: &SharedKey) -> Self {
         Self {
             buffer: [0; MAX_PAYLOAD_SIZE],
-            chacha: decryptor_from_key(key).unwrap(),
+            chacha: Chacha::Inline(cipher_from_key(key)),
+            recorder: None,
         }
     }
 }
@@ -157,14 +203,11 @@ impl FrameReader for FrameReaderHalf {
         reader.read_exact(header).await?;
 
         // decrypt
-        self.chacha.cipher_update_inplace(header, header.len())?;
+        self.chacha.apply(header).await?;
 
         let view = frame::View::new(header);
-        let kind: Kind = view
-            .kind()
-            .read()
-            .try_into()
-            .map_err(|_| Error::InvalidHeader)?;
+        let raw = view.kind().read();
+        let kind: Kind = raw.try_into().map_err(|_| Error::InvalidHeader)?;
         let id = view.id().read();
         let size = view.size().read() as usize;
 
@@ -174,25 +217,31 @@ impl FrameReader for FrameReaderHalf {
             let data = &mut self.buffer[..size];
 
             reader.read_exact(data).await?;
-            self.chacha.cipher_update_inplace(data, data.len())?;
+            self.chacha.apply(data).await?;
 
             Some(data as &[u8])
         };
 
+        if let Some(recorder) = &self.recorder {
+            recorder.append(Direction::Received, raw, id, payload);
+        }
+
         Ok((Frame { kind, id }, payload))
     }
 }
 
 pub struct FrameWriterHalf {
     header: [u8; FRAME_HEADER_SIZE],
-    chacha: CipherCtx,
+    chacha: Chacha,
+    recorder: Option<Arc<Recorder>>,
 }
 
 impl FrameWriterHalf {
     pub fn new(key: &SharedKey) -> Self {
         Self {
             header: [0; FRAME_HEADER_SIZE],
-            chacha: encryptor_from_key(key).unwrap(),
+            chacha: Chacha::Inline(cipher_from_key(key)),
+            recorder: None,
         }
     }
 }
@@ -208,8 +257,14 @@ impl FrameWriter for FrameWriterHalf {
     where
         W: AsyncWrite + Unpin + Send,
     {
+        let kind = frm.kind as u8;
+        // recorded before the payload is encrypted in place
+        if let Some(recorder) = &self.recorder {
+            recorder.append(Direction::Sent, kind, frm.id, payload.as_deref());
+        }
+
         let mut view = frame::View::new(&mut self.header[..]);
-        view.kind_mut().write(frm.kind as u8);
+        view.kind_mut().write(kind);
         view.id_mut().write(frm.id);
         if let Some(data) = &payload {
             view.size_mut().write(data.len() as u16);
@@ -218,11 +273,10 @@ impl FrameWriter for FrameWriterHalf {
         }
 
         // encrypt header
-        self.chacha
-            .cipher_update_inplace(&mut self.header[..], FRAME_HEADER_SIZE)?;
+        self.chacha.apply(&mut self.header[..]).await?;
         writer.write_all(&self.header[..]).await?;
         if let Some(data) = payload {
-            self.chacha.cipher_update_inplace(data, data.len())?;
+            self.chacha.apply(data).await?;
             writer.write_all(data).await?;
         }
 
@@ -243,6 +297,20 @@ impl FrameStream {
         }
     }
 
+    /// record all frames read and written from now on
+    pub fn record(&mut self, recorder: Recorder) {
+        let recorder = Arc::new(recorder);
+        self.read_half.recorder = Some(Arc::clone(&recorder));
+        self.write_half.recorder = Some(recorder);
+    }
+
+    /// generate the keystreams of both directions ahead on worker threads,
+    /// so the read and write loops only xor the frames with them
+    pub fn pipeline(&mut self) -> Result<()> {
+        self.read_half.chacha.pipeline()?;
+        self.write_half.chacha.pipeline()
+    }
+
     pub fn split(self) -> (FrameReaderHalf, FrameWriterHalf) {
         (self.read_half, self.write_half)
     }
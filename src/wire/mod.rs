@@ -1,10 +1,17 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
 use crate::{Error, Result};
 use binary_layout::prelude::*;
 use secp256k1::{constants, Keypair, PublicKey};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::{
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    io::{Stdin, Stdout},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpStream,
@@ -15,13 +22,24 @@ use self::{
     encrypt::{shared, SharedKey},
     frame::{Frame, FrameReaderHalf, FrameWriterHalf, Kind},
 };
-pub use types::{Registration, Stream};
+pub use types::{Code, Metadata, Reason, Registration, Stream, Termination};
 
 mod encrypt;
 mod frame;
-
-pub use encrypt::keypair;
-pub use frame::{FrameReader, FrameStream, FrameWriter, MAX_PAYLOAD_SIZE};
+pub mod record;
+mod state;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(target_arch = "wasm32")]
+pub mod websocket;
+
+#[cfg(all(feature = "agent", not(target_arch = "wasm32")))]
+pub(crate) use encrypt::cipher_rate;
+pub use encrypt::{fingerprint, keypair, keypair_from_file, keypair_to_file};
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub(crate) use frame::{read_handshake, HANDSHAKE_SIZE};
+pub use frame::{FrameReader, FrameStream, FrameWriter, MAX_PAYLOAD_SIZE, VERSION};
+pub use state::{StreamMap, StreamState};
 
 define_layout!(handshake, BigEndian, {
     magic: u32,
@@ -32,6 +50,8 @@ define_layout!(handshake, BigEndian, {
 pub struct Client<S> {
     inner: S,
     kp: Keypair,
+    pin: Option<PublicKey>,
+    version: u8,
 }
 
 impl<S> Client<S>
@@ -39,23 +59,61 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     pub fn new(stream: S, kp: Keypair) -> Self {
-        Client { inner: stream, kp }
+        Client {
+            inner: stream,
+            kp,
+            pin: None,
+            version: VERSION,
+        }
+    }
+
+    /// offer that version instead of [`VERSION`] in the handshake. Gateways
+    /// of version 1 refuse any other version, so agents need it to reach them
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// only accept a server with that public key, the handshake fails
+    /// otherwise
+    pub fn with_pin(mut self, key: PublicKey) -> Self {
+        self.pin = Some(key);
+        self
     }
 
     pub async fn negotiate(mut self) -> Result<Connection<S, FrameStream>> {
+        if self.version == 0 || self.version > VERSION {
+            return Err(Error::InvalidVersion(self.version));
+        }
+
         let mut buf: [u8; frame::HANDSHAKE_SIZE] = [0; frame::HANDSHAKE_SIZE];
 
-        // send the handshake request with self public key
-        frame::write_handshake(&mut self.inner, &mut buf, self.kp.public_key().serialize()).await?;
+        // send the handshake request with self public key and the highest
+        // version we support
+        frame::write_handshake(
+            &mut self.inner,
+            &mut buf,
+            self.version,
+            self.kp.public_key().serialize(),
+        )
+        .await?;
 
-        // read the server handshake and extract public key of server
-        let server_pk =
-            PublicKey::from_slice(&frame::read_handshake(&mut self.inner, &mut buf).await?)?;
+        // read the server handshake and extract public key of server. The server
+        // replies with the negotiated version which can't be higher than ours
+        let (version, key) = frame::read_handshake(&mut self.inner, &mut buf).await?;
+        if version > self.version {
+            return Err(Error::InvalidVersion(version));
+        }
+
+        let server_pk = PublicKey::from_slice(&key)?;
+        if matches!(self.pin, Some(pin) if pin != server_pk) {
+            return Err(Error::UnexpectedKey(server_pk.to_string()));
+        }
 
         // compute shared
         let shared = encrypt::shared(&self.kp, server_pk);
 
-        Ok(Connection::new(self.inner, &shared))
+        Ok(Connection::new(self.inner, &shared, version, server_pk))
     }
 }
 
@@ -76,47 +134,153 @@ where
         let mut buf: [u8; frame::HANDSHAKE_SIZE] = [0; frame::HANDSHAKE_SIZE];
 
         // read client handshake request and extract client public key
-        let client_pk =
-            PublicKey::from_slice(&frame::read_handshake(&mut self.inner, &mut buf).await?)?;
+        let (version, key) = frame::read_handshake(&mut self.inner, &mut buf).await?;
+        let client_pk = PublicKey::from_slice(&key)?;
+
+        // the negotiated version is the highest version supported by both sides
+        let version = version.min(VERSION);
 
         // send server handshake request with self public key
-        frame::write_handshake(&mut self.inner, &mut buf, self.kp.public_key().serialize()).await?;
+        frame::write_handshake(
+            &mut self.inner,
+            &mut buf,
+            version,
+            self.kp.public_key().serialize(),
+        )
+        .await?;
 
         // compute shared
         let shared = shared(&self.kp, client_pk);
 
-        Ok(Connection::new(self.inner, &shared))
+        Ok(Connection::new(self.inner, &shared, version, client_pk))
     }
 }
 
-#[derive(Debug)]
 pub enum Control {
     // An OK control message
     Ok,
-    // An error control message
-    Error(String),
+    // An error control message with its code
+    Error(Code, String),
     // A register control message (unique agent id and name of domain)
-    Register { id: Registration, name: String },
+    Register {
+        id: Registration,
+        name: String,
+    },
     // Tells server that all registrations requests has been provided
     FinishRegister,
     // Close a 'stream' with that stream id
-    Close { id: Stream },
-    // Send login token to server
-    Login(String),
+    Close {
+        id: Stream,
+    },
+    // Send login token to server with the agent labels (hostname, version, ...).
+    // Labels are only sent since version 5
+    Login {
+        token: String,
+        labels: Metadata,
+    },
+    // Public endpoint (host:port) where the registration is reachable
+    Endpoint {
+        id: Registration,
+        address: String,
+    },
+    // Keep alive sent by the agent to renew its lease
+    Ping,
+    // Refresh the login token of an established connection
+    Relogin(String),
+    // Metadata of a registration, sent after its register message
+    Metadata {
+        id: Registration,
+        metadata: Metadata,
+    },
+    // Round trip time probe sent by the server with a sequence number
+    Probe(u32),
+    // Answer of the agent to the probe with the same sequence number
+    ProbeReply(u32),
+    // Acknowledge the close of a 'stream', its id can be reused after
+    CloseAck {
+        id: Stream,
+    },
+    // Stop sending payloads of a 'stream' until it's resumed, sent by the
+    // agent while the backend of the stream is slower than the client, and
+    // by the server (since version 9) while the client is slower than the backend
+    Pause {
+        id: Stream,
+    },
+    // Continue sending payloads of a paused 'stream'
+    Resume {
+        id: Stream,
+    },
+    // Id of the agent connection on the server, sent with the endpoints
+    Session(u64),
+    // Sent by a client before its login, the gateway then connects the
+    // streams of the client to the agent that serves the name
+    Dial {
+        name: String,
+    },
+}
+
+// the login tokens are redacted, so they don't leak in the logs
+impl std::fmt::Debug for Control {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redacted = format_args!("<redacted>");
+        match self {
+            Control::Ok => f.write_str("Ok"),
+            Control::Error(code, message) => {
+                f.debug_tuple("Error").field(code).field(message).finish()
+            }
+            Control::Register { id, name } => f
+                .debug_struct("Register")
+                .field("id", id)
+                .field("name", name)
+                .finish(),
+            Control::FinishRegister => f.write_str("FinishRegister"),
+            Control::Close { id } => f.debug_struct("Close").field("id", id).finish(),
+            Control::Login { labels, .. } => f
+                .debug_struct("Login")
+                .field("token", &redacted)
+                .field("labels", labels)
+                .finish(),
+            Control::Endpoint { id, address } => f
+                .debug_struct("Endpoint")
+                .field("id", id)
+                .field("address", address)
+                .finish(),
+            Control::Ping => f.write_str("Ping"),
+            Control::Relogin(_) => f.debug_tuple("Relogin").field(&redacted).finish(),
+            Control::Metadata { id, metadata } => f
+                .debug_struct("Metadata")
+                .field("id", id)
+                .field("metadata", metadata)
+                .finish(),
+            Control::Probe(sequence) => f.debug_tuple("Probe").field(sequence).finish(),
+            Control::ProbeReply(sequence) => f.debug_tuple("ProbeReply").field(sequence).finish(),
+            Control::CloseAck { id } => f.debug_struct("CloseAck").field("id", id).finish(),
+            Control::Pause { id } => f.debug_struct("Pause").field("id", id).finish(),
+            Control::Resume { id } => f.debug_struct("Resume").field("id", id).finish(),
+            Control::Session(session) => f.debug_tuple("Session").field(session).finish(),
+            Control::Dial { name } => f.debug_struct("Dial").field("name", name).finish(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Message {
     Control(Control),
     Payload { id: Stream, data: Vec<u8> },
-    Terminate,
+    Terminate(Termination),
 }
 
 impl Message {
     pub fn ok_or_err(&self) -> Result<()> {
         match self {
             Message::Control(Control::Ok) => Ok(()),
-            Message::Control(Control::Error(remote)) => Err(Error::Remote(remote.into())),
+            Message::Control(Control::Error(Code::Unknown, remote)) => {
+                Err(Error::Remote(remote.into()))
+            }
+            Message::Control(Control::Error(code, remote)) => {
+                Err(Error::Refused(*code, remote.into()))
+            }
+            Message::Terminate(termination) => Err(Error::Terminated(termination.clone())),
             _ => Err(Error::UnexpectedMessage),
         }
     }
@@ -125,17 +289,49 @@ impl Message {
 pub struct Connection<S, FrameStream> {
     inner: S,
     frame: FrameStream,
+    version: u8,
+    remote: PublicKey,
 }
 
 impl<S> Connection<S, FrameStream> {
     // this is private because only client or server should
     // be able to create it
-    fn new(stream: S, key: &SharedKey) -> Self {
+    fn new(stream: S, key: &SharedKey, version: u8, remote: PublicKey) -> Self {
         Connection {
             inner: stream,
             frame: FrameStream::new(key),
+            version,
+            remote,
         }
     }
+
+    /// record all the frames of the session from now on (after decryption) to
+    /// the file at `path`, see [`record`]
+    pub fn record<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.frame
+            .record(record::Recorder::create(path, self.version)?);
+        Ok(())
+    }
+
+    /// encrypt and decrypt the frames with keystreams generated ahead on
+    /// worker threads (one per direction), the connection tasks then only xor
+    /// the frames. It improves the throughput of busy connections on multi
+    /// core hosts at the cost of two threads per connection
+    pub fn pipeline(&mut self) -> Result<()> {
+        self.frame.pipeline()
+    }
+}
+
+impl<S, F> Connection<S, F> {
+    /// the wire version negotiated during the handshake
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// public key of the remote side
+    pub fn remote_key(&self) -> PublicKey {
+        self.remote
+    }
 }
 
 impl<S, F> Connection<S, F>
@@ -145,6 +341,7 @@ where
 {
     // send a control message to remote side
     pub async fn control(&mut self, ctl: Control) -> Result<()> {
+        let version = self.version;
         let (frm, mut payload) = match ctl {
             Control::Ok => (
                 Frame {
@@ -153,10 +350,10 @@ where
                 },
                 None,
             ),
-            Control::Error(msg) => (
+            Control::Error(code, msg) => (
                 Frame {
                     kind: Kind::Error,
-                    id: 0,
+                    id: code as u32,
                 },
                 Some(msg),
             ),
@@ -181,13 +378,95 @@ where
                 },
                 None,
             ),
-            Control::Login(token) => (
+            Control::Login { token, labels } => (
                 Frame {
                     kind: Kind::Login,
                     id: 0,
                 },
+                // labels follow the token on the next lines
+                Some(if version >= 5 && !labels.is_empty() {
+                    format!("{}\n{}", token, labels)
+                } else {
+                    token
+                }),
+            ),
+            Control::Endpoint { id, address } => (
+                Frame {
+                    kind: Kind::Endpoint,
+                    id: (&id).into(),
+                },
+                Some(address),
+            ),
+            Control::Ping => (
+                Frame {
+                    kind: Kind::Ping,
+                    id: 0,
+                },
+                None,
+            ),
+            Control::Relogin(token) => (
+                Frame {
+                    kind: Kind::Relogin,
+                    id: 0,
+                },
                 Some(token),
             ),
+            Control::Metadata { id, metadata } => (
+                Frame {
+                    kind: Kind::Metadata,
+                    id: (&id).into(),
+                },
+                Some(metadata.to_string()),
+            ),
+            Control::Probe(seq) => (
+                Frame {
+                    kind: Kind::Probe,
+                    id: seq,
+                },
+                None,
+            ),
+            Control::ProbeReply(seq) => (
+                Frame {
+                    kind: Kind::ProbeReply,
+                    id: seq,
+                },
+                None,
+            ),
+            Control::CloseAck { id } => (
+                Frame {
+                    kind: Kind::CloseAck,
+                    id: id.into(),
+                },
+                None,
+            ),
+            Control::Pause { id } => (
+                Frame {
+                    kind: Kind::Pause,
+                    id: id.into(),
+                },
+                None,
+            ),
+            Control::Resume { id } => (
+                Frame {
+                    kind: Kind::Resume,
+                    id: id.into(),
+                },
+                None,
+            ),
+            Control::Session(id) => (
+                Frame {
+                    kind: Kind::Session,
+                    id: 0,
+                },
+                Some(id.to_string()),
+            ),
+            Control::Dial { name } => (
+                Frame {
+                    kind: Kind::Dial,
+                    id: 0,
+                },
+                Some(name),
+            ),
         };
 
         self.frame
@@ -208,7 +487,30 @@ where
 
     /// a shortcut to send an err message
     pub async fn error<D: Display>(&mut self, msg: D) -> Result<()> {
-        self.control(Control::Error(msg.to_string())).await
+        self.control(Control::Error(Code::Unknown, msg.to_string()))
+            .await
+    }
+
+    /// send an err message with a code the remote side can act on
+    pub async fn refuse<D: Display>(&mut self, code: Code, msg: D) -> Result<()> {
+        self.control(Control::Error(code, msg.to_string())).await
+    }
+
+    /// terminate the connection. The remote side should drop the connection
+    pub async fn terminate(&mut self, termination: Termination) -> Result<()> {
+        let mut payload = termination.to_bytes();
+        self.frame
+            .write(
+                &mut self.inner,
+                Frame {
+                    kind: Kind::Terminate,
+                    id: 0,
+                },
+                Some(&mut payload),
+            )
+            .await?;
+
+        self.inner.flush().await.map_err(Error::IO)
     }
 
     /// write data to a specific stream, return number of bytes that
@@ -249,15 +551,51 @@ where
 
         let msg = match frm.kind {
             Kind::Ok => Message::Control(Control::Ok),
-            Kind::Error => Message::Control(Control::Error(option_to_str(payload))),
+            Kind::Error => {
+                Message::Control(Control::Error(Code::from(frm.id), option_to_str(payload)))
+            }
             Kind::Close => Message::Control(Control::Close { id: frm.id.into() }),
             Kind::Register => Message::Control(Control::Register {
                 id: Registration::from(frm.id as u16),
                 name: option_to_str(payload),
             }),
             Kind::FinishRegister => Message::Control(Control::FinishRegister),
-            Kind::Terminate => Message::Terminate,
-            Kind::Login => Message::Control(Control::Login(option_to_str(payload))),
+            Kind::Terminate => Message::Terminate(Termination::from_bytes(payload)),
+            Kind::Login => {
+                let payload = option_to_str(payload);
+                let (token, labels) = match payload.split_once('\n') {
+                    Some((token, labels)) if self.version >= 5 => {
+                        (token.into(), Metadata::parse(labels))
+                    }
+                    _ => (payload, Metadata::default()),
+                };
+
+                Message::Control(Control::Login { token, labels })
+            }
+            Kind::Endpoint => Message::Control(Control::Endpoint {
+                id: Registration::from(frm.id as u16),
+                address: option_to_str(payload),
+            }),
+            Kind::Ping => Message::Control(Control::Ping),
+            Kind::Relogin => Message::Control(Control::Relogin(option_to_str(payload))),
+            Kind::Metadata => Message::Control(Control::Metadata {
+                id: Registration::from(frm.id as u16),
+                metadata: Metadata::parse(&option_to_str(payload)),
+            }),
+            Kind::Probe => Message::Control(Control::Probe(frm.id)),
+            Kind::ProbeReply => Message::Control(Control::ProbeReply(frm.id)),
+            Kind::CloseAck => Message::Control(Control::CloseAck { id: frm.id.into() }),
+            Kind::Pause => Message::Control(Control::Pause { id: frm.id.into() }),
+            Kind::Resume => Message::Control(Control::Resume { id: frm.id.into() }),
+            Kind::Session => {
+                let id = option_to_str(payload)
+                    .parse()
+                    .map_err(|_| Error::UnexpectedMessage)?;
+                Message::Control(Control::Session(id))
+            }
+            Kind::Dial => Message::Control(Control::Dial {
+                name: option_to_str(payload),
+            }),
             Kind::Payload => Message::Payload {
                 id: frm.id.into(),
                 // todo: no copy?
@@ -269,23 +607,144 @@ where
     }
 }
 
-impl Connection<TcpStream, FrameStream> {
+/// Split a stream into owned read and write halves so both directions
+/// of a connection can be used concurrently
+pub trait Split: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    type Read: AsyncRead + Unpin + Send + 'static;
+    type Write: AsyncWrite + Unpin + Send + 'static;
+
+    fn split(self) -> (Self::Read, Self::Write);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Split for TcpStream {
+    type Read = OwnedReadHalf;
+    type Write = OwnedWriteHalf;
+
+    fn split(self) -> (Self::Read, Self::Write) {
+        self.into_split()
+    }
+}
+
+impl Split for DuplexStream {
+    type Read = ReadHalf<Self>;
+    type Write = WriteHalf<Self>;
+
+    fn split(self) -> (Self::Read, Self::Write) {
+        tokio::io::split(self)
+    }
+}
+
+/// Pipes joins a reader and a writer into a single stream, like the stdin and
+/// stdout of the process, so the tunnel can be carried over an existing
+/// transport (for example `ssh gateway diglett-server --stdio`)
+pub struct Pipes<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Pipes<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Pipes<Stdin, Stdout> {
+    /// the stdin and stdout of the process
+    pub fn stdio() -> Self {
+        Self::new(tokio::io::stdin(), tokio::io::stdout())
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for Pipes<R, W> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for Pipes<R, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+impl<R, W> Split for Pipes<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    type Read = R;
+    type Write = W;
+
+    fn split(self) -> (Self::Read, Self::Write) {
+        (self.reader, self.writer)
+    }
+}
+
+#[cfg(all(feature = "tls", not(target_arch = "wasm32")))]
+impl<S> Split for tokio_rustls::server::TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Read = ReadHalf<Self>;
+    type Write = WriteHalf<Self>;
+
+    fn split(self) -> (Self::Read, Self::Write) {
+        tokio::io::split(self)
+    }
+}
+
+#[cfg(all(feature = "tls", not(target_arch = "wasm32")))]
+impl<S> Split for tokio_rustls::client::TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Read = ReadHalf<Self>;
+    type Write = WriteHalf<Self>;
+
+    fn split(self) -> (Self::Read, Self::Write) {
+        tokio::io::split(self)
+    }
+}
+
+impl<S: Split> Connection<S, FrameStream> {
     pub fn split(
         self,
     ) -> (
-        Connection<OwnedReadHalf, FrameReaderHalf>,
-        Connection<OwnedWriteHalf, FrameWriterHalf>,
+        Connection<S::Read, FrameReaderHalf>,
+        Connection<S::Write, FrameWriterHalf>,
     ) {
         let (fread, fwrite) = self.frame.split();
-        let (read, write) = self.inner.into_split();
+        let (read, write) = Split::split(self.inner);
         (
             Connection {
                 inner: read,
                 frame: fread,
+                version: self.version,
+                remote: self.remote,
             },
             Connection {
                 inner: write,
                 frame: fwrite,
+                version: self.version,
+                remote: self.remote,
             },
         )
     }
@@ -306,7 +765,7 @@ fn option_to_vec(opt: Option<&'_ [u8]>) -> Vec<u8> {
 }
 
 mod types {
-    use std::fmt::Display;
+    use std::{collections::BTreeMap, fmt::Display};
 
     #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
     pub struct Registration(u16);
@@ -370,6 +829,182 @@ mod types {
             write!(f, "({}, {})", self.registration(), self.port())
         }
     }
+
+    /// Reason of terminating a connection
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    #[repr(u8)]
+    pub enum Reason {
+        Unknown = 0,
+        // server (or agent) is shutting down
+        Shutdown = 1,
+        // server hit a fatal error
+        Error = 2,
+        // server is in maintenance, agent should reconnect elsewhere
+        Maintenance = 3,
+        // registration has been taken over by another agent
+        Replaced = 4,
+        // authentication expired without a relogin
+        Expired = 5,
+        // another agent logged in with the same identity
+        Duplicate = 6,
+    }
+
+    impl From<u8> for Reason {
+        fn from(value: u8) -> Self {
+            match value {
+                1 => Self::Shutdown,
+                2 => Self::Error,
+                3 => Self::Maintenance,
+                4 => Self::Replaced,
+                5 => Self::Expired,
+                6 => Self::Duplicate,
+                _ => Self::Unknown,
+            }
+        }
+    }
+
+    impl Display for Reason {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let reason = match self {
+                Self::Unknown => "unknown",
+                Self::Shutdown => "shutdown",
+                Self::Error => "error",
+                Self::Maintenance => "maintenance",
+                Self::Replaced => "replaced",
+                Self::Expired => "expired",
+                Self::Duplicate => "duplicate login",
+            };
+
+            f.write_str(reason)
+        }
+    }
+
+    /// Code of an error reply, so the agent can tell apart the errors it can
+    /// act on. On the wire it's carried in the id of the error frame
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    #[repr(u8)]
+    pub enum Code {
+        Unknown = 0,
+        // name is reserved and can't be registered
+        Reserved = 1,
+        // name is not a valid dns name
+        InvalidName = 2,
+        // user reached the max number of connected agents
+        AgentLimit = 3,
+        // agent wire version is below the minimum accepted by the server
+        Upgrade = 4,
+    }
+
+    impl From<u32> for Code {
+        fn from(value: u32) -> Self {
+            match value {
+                1 => Self::Reserved,
+                2 => Self::InvalidName,
+                3 => Self::AgentLimit,
+                4 => Self::Upgrade,
+                _ => Self::Unknown,
+            }
+        }
+    }
+
+    impl Display for Code {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let code = match self {
+                Self::Unknown => "unknown",
+                Self::Reserved => "reserved",
+                Self::InvalidName => "invalid name",
+                Self::AgentLimit => "agent limit",
+                Self::Upgrade => "upgrade required",
+            };
+
+            f.write_str(code)
+        }
+    }
+
+    /// Termination is the payload of a terminate message. On the wire it's
+    /// encoded as one byte reason followed by an optional message
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub struct Termination {
+        pub reason: Reason,
+        pub message: String,
+    }
+
+    impl Termination {
+        pub fn new<M: Into<String>>(reason: Reason, message: M) -> Self {
+            Self {
+                reason,
+                message: message.into(),
+            }
+        }
+
+        pub(crate) fn to_bytes(&self) -> Vec<u8> {
+            let mut buf = Vec::with_capacity(self.message.len() + 1);
+            buf.push(self.reason as u8);
+            buf.extend_from_slice(self.message.as_bytes());
+            buf
+        }
+
+        pub(crate) fn from_bytes(payload: Option<&[u8]>) -> Self {
+            match payload {
+                None | Some([]) => Self::new(Reason::Unknown, ""),
+                Some(data) => Self::new(Reason::from(data[0]), String::from_utf8_lossy(&data[1..])),
+            }
+        }
+    }
+
+    /// Metadata of a registration as key value pairs. On the wire it's encoded
+    /// as `key=value` lines, so keys can't have `=` and neither keys nor values
+    /// can have new lines
+    #[derive(Debug, PartialEq, Eq, Clone, Default)]
+    pub struct Metadata(BTreeMap<String, String>);
+
+    impl Metadata {
+        pub fn set<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+            self.0.insert(key.into(), value.into());
+            self
+        }
+
+        pub fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).map(String::as_str)
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+            self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+        }
+
+        pub(crate) fn parse(data: &str) -> Self {
+            Self(
+                data.lines()
+                    .filter_map(|line| line.split_once('='))
+                    .map(|(k, v)| (k.into(), v.into()))
+                    .collect(),
+            )
+        }
+    }
+
+    impl Display for Metadata {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            for (key, value) in &self.0 {
+                writeln!(f, "{}={}", key, value)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    impl Display for Termination {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            if self.message.is_empty() {
+                write!(f, "{}", self.reason)
+            } else {
+                write!(f, "{}: {}", self.reason, self.message)
+            }
+        }
+    }
 }
 #[cfg(test)]
 mod test {
@@ -388,7 +1023,213 @@ mod test {
         assert_eq!(id.port(), 0x3344);
     }
 
+    #[test]
+    fn termination() {
+        let termination = Termination::new(Reason::Shutdown, "bye");
+        let bytes = termination.to_bytes();
+        assert_eq!(Termination::from_bytes(Some(&bytes)), termination);
+        assert_eq!(
+            Termination::from_bytes(None),
+            Termination::new(Reason::Unknown, "")
+        );
+    }
+
+    #[test]
+    fn metadata() {
+        let metadata = Metadata::default().set("weight", "3").set("zone", "eu");
+        let parsed = Metadata::parse(&metadata.to_string());
+
+        assert_eq!(parsed, metadata);
+        assert_eq!(parsed.get("weight"), Some("3"));
+        assert_eq!(Metadata::parse(""), Metadata::default());
+    }
+
+    #[tokio::test]
+    async fn login_labels() {
+        let (mut con, mut server) = testing::duplex_pair();
+        let labels = Metadata::default()
+            .set("hostname", "node-1")
+            .set("version", "v1.0");
+
+        let expected = labels.clone();
+        let handler = tokio::spawn(async move {
+            match server.read().await? {
+                Message::Control(Control::Login { token, labels }) => {
+                    assert_eq!(token, "token");
+                    assert_eq!(labels, expected);
+                }
+                msg => panic!("expected login message got: {:?}", msg),
+            }
+
+            Ok::<_, Error>(())
+        });
+
+        con.control(Control::Login {
+            token: "token".into(),
+            labels,
+        })
+        .await
+        .unwrap();
+
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn pipeline() {
+        let (mut con, mut server) = testing::duplex_pair();
+        let handler = tokio::spawn(async move {
+            for round in 0..6 {
+                // the server switches to the pipeline in the middle of the session
+                if round == 3 {
+                    server.pipeline()?;
+                }
+
+                match server.read().await? {
+                    Message::Payload { id, mut data } => {
+                        assert_eq!(data.len(), MAX_PAYLOAD_SIZE - round);
+                        assert!(data.iter().all(|byte| *byte == round as u8));
+                        server.write(id, &mut data).await?;
+                    }
+                    msg => panic!("expected payload got: {:?}", msg),
+                }
+            }
+
+            Ok::<_, Error>(())
+        });
+
+        con.pipeline().unwrap();
+
+        let id = Stream::new(Registration::from(0), 1);
+        for round in 0..6 {
+            let mut data = vec![round as u8; MAX_PAYLOAD_SIZE - round];
+            con.write(id, &mut data).await.unwrap();
+            match con.read().await.unwrap() {
+                Message::Payload { data, .. } => {
+                    assert_eq!(data, vec![round as u8; MAX_PAYLOAD_SIZE - round]);
+                }
+                msg => panic!("expected payload got: {:?}", msg),
+            }
+        }
+
+        handler.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn redacted() {
+        let login = Control::Login {
+            token: "secret".into(),
+            labels: Metadata::default().set("hostname", "node-1"),
+        };
+        let debug = format!("{:?}", Message::Control(login));
+        assert!(debug.contains("node-1"));
+        assert!(!debug.contains("secret"));
+        assert!(!format!("{:?}", Control::Relogin("secret".into())).contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn pipes() {
+        // like the stdin and stdout of a command that carries the tunnel
+        let (client_read, server_write) = tokio::io::duplex(1024);
+        let (server_read, client_write) = tokio::io::duplex(1024);
+        let (client_read, _) = tokio::io::split(client_read);
+        let (_, server_write) = tokio::io::split(server_write);
+        let (server_read, _) = tokio::io::split(server_read);
+        let (_, client_write) = tokio::io::split(client_write);
+
+        let handler = tokio::spawn(async move {
+            let server = Pipes::new(server_read, server_write);
+            let con = super::Server::new(server, keypair()).accept().await?;
+            let (mut reader, mut writer) = con.split();
+            match reader.read().await? {
+                Message::Payload { id, mut data } => {
+                    writer.write(id, &mut data).await?;
+                }
+                msg => panic!("expected payload got: {:?}", msg),
+            }
+
+            Ok::<_, Error>(())
+        });
+
+        let client = Pipes::new(client_read, client_write);
+        let mut con = super::Client::new(client, keypair())
+            .negotiate()
+            .await
+            .unwrap();
+        con.write(
+            Stream::new(Registration::from(0), 1),
+            &mut b"hello".to_vec(),
+        )
+        .await
+        .unwrap();
+        match con.read().await.unwrap() {
+            Message::Payload { data, .. } => assert_eq!(data, b"hello"),
+            msg => panic!("expected payload got: {:?}", msg),
+        }
+
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn pinned_key() {
+        let server_key = keypair();
+        let pin = server_key.public_key();
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(super::Server::new(server, server_key).accept());
+        assert!(super::Client::new(client, keypair())
+            .with_pin(pin)
+            .negotiate()
+            .await
+            .is_ok());
+
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(super::Server::new(server, keypair()).accept());
+        let result = super::Client::new(client, keypair())
+            .with_pin(pin)
+            .negotiate()
+            .await;
+        assert!(matches!(result, Err(Error::UnexpectedKey(_))));
+    }
+
+    #[tokio::test]
+    async fn negotiate_version() {
+        // the lowest version of both sides is used
+        let (client, server) = tokio::io::duplex(1024);
+        let accept = tokio::spawn(super::Server::new(server, keypair()).accept());
+        let con = super::Client::new(client, keypair())
+            .with_version(3)
+            .negotiate()
+            .await
+            .unwrap();
+        assert_eq!(con.version(), 3);
+        assert_eq!(accept.await.unwrap().unwrap().version(), 3);
+
+        // a server can't answer with a higher version than the offered one
+        let (client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = [0; frame::HANDSHAKE_SIZE];
+            frame::read_handshake(&mut server, &mut buf).await?;
+            let key = keypair().public_key().serialize();
+            frame::write_handshake(&mut server, &mut buf, VERSION, key).await
+        });
+        let result = super::Client::new(client, keypair())
+            .with_version(1)
+            .negotiate()
+            .await;
+        assert!(matches!(result, Err(Error::InvalidVersion(VERSION))));
+
+        // only the supported versions can be offered
+        for version in [0, VERSION + 1] {
+            let (client, _server) = tokio::io::duplex(1024);
+            let result = super::Client::new(client, keypair())
+                .with_version(version)
+                .negotiate()
+                .await;
+            assert!(matches!(result, Err(Error::InvalidVersion(v)) if v == version));
+        }
+    }
+
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants)]
     async fn test_negotiate() {
         let server_key = keypair();
         let client_key = keypair();
@@ -402,6 +1243,7 @@ mod test {
             let (cl, _) = listener.accept().await.map_err(Error::IO)?;
             let server = super::Server::new(cl, server_key);
             let mut con = server.accept().await?;
+            assert_eq!(con.version(), VERSION);
 
             let msg = con.read().await.unwrap();
 
@@ -436,6 +1278,7 @@ mod test {
             .unwrap();
         let client = super::Client::new(client, client_key);
         let mut con = client.negotiate().await.unwrap();
+        assert_eq!(con.version(), VERSION);
 
         let mut msg = String::from("hello world");
         con.write(Stream::from(20), unsafe { msg.as_bytes_mut() })
@@ -0,0 +1,397 @@
+//! Recording of the frames of a session (after decryption) and their replay,
+//! so misbehaviours of the multiplexing seen in production can be reproduced
+//! deterministically in tests.
+//!
+//! A recording is written with [`Connection::record`] and loaded with
+//! [`Recording::open`]. [`Recording::replay`] then gives a connection to hand
+//! over to the code under test while the recorded remote side is played on the
+//! other end of it.
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use binary_layout::prelude::*;
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream},
+    task::JoinHandle,
+};
+
+use super::{
+    encrypt,
+    frame::{Frame, FrameReader, FrameStream, FrameWriter, Kind, FRAME_HEADER_SIZE},
+    Connection, MAX_PAYLOAD_SIZE,
+};
+use crate::{Error, Result};
+
+const MAGIC: u32 = 0x64677263;
+const HEADER_SIZE: usize = 5;
+const ENTRY_HEADER_SIZE: usize = 16;
+// frames waiting to be written to the recording file
+const RECORD_QUEUE: usize = 1024;
+
+define_layout!(header, BigEndian, {
+    magic: u32,
+    version: u8,
+});
+
+define_layout!(entry, BigEndian, {
+    direction: u8,
+    // time since the start of the recording in microseconds
+    elapsed: u64,
+    kind: u8,
+    id: u32,
+    size: u16,
+});
+
+/// Direction of a recorded frame, seen from the recorded side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// frame read from the remote side
+    Received,
+    /// frame written to the remote side
+    Sent,
+}
+
+// login tokens are credentials, only the frame is recorded not its payload
+fn redacted(kind: u8) -> bool {
+    kind == Kind::Login as u8 || kind == Kind::Relogin as u8
+}
+
+/// Recorder appends the frames of a connection to a file. The frames are
+/// written by a thread of the recorder so the connection never waits for the
+/// disk. Recording is best effort, it stops (without failing the session) on
+/// the first write error or if the file can't keep up with the frames
+pub struct Recorder {
+    started: Instant,
+    queue: Mutex<Option<SyncSender<Vec<u8>>>>,
+}
+
+impl Recorder {
+    /// create the recording file of a session with that wire version
+    pub fn create<P: AsRef<Path>>(path: P, version: u8) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let mut buf = [0; HEADER_SIZE];
+        let mut view = header::View::new(&mut buf[..]);
+        view.magic_mut().write(MAGIC);
+        view.version_mut().write(version);
+        file.write_all(&buf)?;
+        file.flush()?;
+
+        let (queue, entries) = mpsc::sync_channel(RECORD_QUEUE);
+        std::thread::Builder::new()
+            .name("diglett-record".into())
+            .spawn(move || write(file, entries))?;
+
+        Ok(Self {
+            started: Instant::now(),
+            queue: Mutex::new(Some(queue)),
+        })
+    }
+
+    pub(crate) fn append(&self, direction: Direction, kind: u8, id: u32, payload: Option<&[u8]>) {
+        let mut queue = self.queue.lock().unwrap();
+        let Some(sender) = queue.as_ref() else {
+            return;
+        };
+
+        let payload = match payload {
+            Some(_) if redacted(kind) => &[],
+            Some(data) => data,
+            None => &[],
+        };
+
+        let mut buf = vec![0; ENTRY_HEADER_SIZE + payload.len()];
+        let mut view = entry::View::new(&mut buf[..ENTRY_HEADER_SIZE]);
+        view.direction_mut().write(direction as u8);
+        view.elapsed_mut()
+            .write(self.started.elapsed().as_micros() as u64);
+        view.kind_mut().write(kind);
+        view.id_mut().write(id);
+        view.size_mut().write(payload.len() as u16);
+        buf[ENTRY_HEADER_SIZE..].copy_from_slice(payload);
+
+        match sender.try_send(buf) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                log::error!("recording can't keep up with the frames, recording stopped");
+                *queue = None;
+            }
+            // the writer failed, it logged why
+            Err(TrySendError::Disconnected(_)) => *queue = None,
+        }
+    }
+}
+
+// write the queued frames until the recorder is dropped
+fn write(mut file: BufWriter<File>, entries: mpsc::Receiver<Vec<u8>>) {
+    for entry in entries {
+        if let Err(err) = file.write_all(&entry) {
+            log::error!("failed to record frame, recording stopped: {}", err);
+            return;
+        }
+    }
+
+    if let Err(err) = file.flush() {
+        log::error!("failed to flush the recording: {}", err);
+    }
+}
+
+/// A recorded frame
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub direction: Direction,
+    /// time since the start of the recording
+    pub elapsed: Duration,
+    /// raw frame kind
+    pub kind: u8,
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "frame (kind: {}, id: {}, size: {})",
+            self.kind,
+            self.id,
+            self.payload.len()
+        )
+    }
+}
+
+/// A loaded recording
+#[derive(Debug, Clone)]
+pub struct Recording {
+    /// negotiated wire version of the recorded session
+    pub version: u8,
+    pub entries: Vec<Entry>,
+}
+
+impl Recording {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// read a recording. A truncated last frame (if the recording process
+    /// crashed) is ignored
+    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let mut buf = [0; HEADER_SIZE];
+        reader.read_exact(&mut buf)?;
+        let view = header::View::new(&buf[..]);
+        if view.magic().read() != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+
+        let version = view.version().read();
+        let mut entries = vec![];
+        let mut buf = [0; ENTRY_HEADER_SIZE];
+        loop {
+            match reader.read_exact(&mut buf) {
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            }
+
+            let view = entry::View::new(&buf[..]);
+            let direction = match view.direction().read() {
+                0 => Direction::Received,
+                1 => Direction::Sent,
+                _ => return Err(Error::InvalidHeader),
+            };
+
+            let mut payload = vec![0; view.size().read() as usize];
+            match reader.read_exact(&mut payload) {
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            }
+
+            entries.push(Entry {
+                direction,
+                elapsed: Duration::from_micros(view.elapsed().read()),
+                kind: view.kind().read(),
+                id: view.id().read(),
+                payload,
+            });
+        }
+
+        Ok(Self { version, entries })
+    }
+
+    /// replay the recording. The returned connection takes the place of the
+    /// recorded side and is handed to the code under test, while the recorded
+    /// remote side is played on the other end: frames the recorded side received
+    /// are sent to it, and frames it sent are expected back in the same order.
+    ///
+    /// The handle completes once all frames are replayed, or with
+    /// [`Error::Diverged`] on the first frame that differs from the recording.
+    /// Payloads of login frames are not recorded, so they are not compared either
+    pub fn replay(
+        self,
+    ) -> (
+        Connection<DuplexStream, FrameStream>,
+        JoinHandle<Result<()>>,
+    ) {
+        let (local, remote) = pair(self.version);
+        let handle = tokio::spawn(play(self, remote));
+
+        (local, handle)
+    }
+}
+
+// a pair of connections to each other
+pub(crate) fn pair(
+    version: u8,
+) -> (
+    Connection<DuplexStream, FrameStream>,
+    Connection<DuplexStream, FrameStream>,
+) {
+    let (local, remote) = tokio::io::duplex(FRAME_HEADER_SIZE + MAX_PAYLOAD_SIZE);
+    let (kp, peer) = (encrypt::keypair(), encrypt::keypair());
+    let key = encrypt::shared(&kp, peer.public_key());
+
+    (
+        Connection::new(local, &key, version, peer.public_key()),
+        Connection::new(remote, &key, version, kp.public_key()),
+    )
+}
+
+async fn play(
+    recording: Recording,
+    mut remote: Connection<DuplexStream, FrameStream>,
+) -> Result<()> {
+    for (index, expected) in recording.entries.into_iter().enumerate() {
+        match expected.direction {
+            Direction::Received => {
+                let kind = Kind::try_from(expected.kind).map_err(|_| Error::InvalidHeader)?;
+                let mut payload = expected.payload;
+                remote
+                    .frame
+                    .write(
+                        &mut remote.inner,
+                        Frame {
+                            kind,
+                            id: expected.id,
+                        },
+                        (!payload.is_empty()).then_some(&mut payload[..]),
+                    )
+                    .await?;
+                remote.inner.flush().await?;
+            }
+            Direction::Sent => {
+                let (frm, payload) = remote.frame.read(&mut remote.inner).await.map_err(|err| {
+                    Error::Diverged(index, format!("expected {}: {}", expected, err))
+                })?;
+
+                let got = Entry {
+                    direction: Direction::Sent,
+                    elapsed: Duration::ZERO,
+                    kind: frm.kind as u8,
+                    id: frm.id,
+                    payload: payload.map(Vec::from).unwrap_or_default(),
+                };
+
+                if got.kind != expected.kind
+                    || got.id != expected.id
+                    || (!redacted(got.kind) && got.payload != expected.payload)
+                {
+                    return Err(Error::Diverged(
+                        index,
+                        format!("expected {}, got {}", expected, got),
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::{Control, Message, Metadata, Stream, VERSION};
+
+    #[tokio::test]
+    async fn record_replay() {
+        let path = std::env::temp_dir().join(format!("diglett-record-{}.rec", std::process::id()));
+        let id = Stream::new(1.into(), 80);
+
+        let (mut local, mut remote) = pair(VERSION);
+        local.record(&path).unwrap();
+
+        remote
+            .control(Control::Login {
+                token: "secret".into(),
+                labels: Metadata::default(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            local.read().await.unwrap(),
+            Message::Control(Control::Login { .. })
+        ));
+        local.ok().await.unwrap();
+        remote.read().await.unwrap().ok_or_err().unwrap();
+        remote.write(id, &mut b"hello".to_vec()).await.unwrap();
+        local.read().await.unwrap();
+        local.control(Control::Close { id }).await.unwrap();
+        // the recording is flushed by its thread once it's dropped
+        drop(local);
+
+        let mut recording = Recording::open(&path).unwrap();
+        for _ in 0..100 {
+            if recording.entries.len() == 4 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            recording = Recording::open(&path).unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recording.version, VERSION);
+        let directions: Vec<_> = recording.entries.iter().map(|e| e.direction).collect();
+        assert_eq!(
+            directions,
+            vec![
+                Direction::Received,
+                Direction::Sent,
+                Direction::Received,
+                Direction::Sent
+            ]
+        );
+        // the token is not recorded
+        assert!(recording.entries[0].payload.is_empty());
+        assert_eq!(recording.entries[2].payload, b"hello");
+
+        // the same logic against the replay
+        let (mut con, replay) = recording.clone().replay();
+        con.read().await.unwrap();
+        con.ok().await.unwrap();
+        match con.read().await.unwrap() {
+            Message::Payload { id: got, data } => {
+                assert_eq!(got, id);
+                assert_eq!(data, b"hello");
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        con.control(Control::Close { id }).await.unwrap();
+        replay.await.unwrap().unwrap();
+
+        // a different answer diverges
+        let (mut con, replay) = recording.replay();
+        con.read().await.unwrap();
+        con.error("denied").await.unwrap();
+        assert!(matches!(replay.await.unwrap(), Err(Error::Diverged(1, _))));
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+use super::Stream;
+
+/// State of a stream multiplexed over a connection, a closed stream is
+/// simply not tracked anymore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    /// data flows in both directions
+    Open,
+    /// we sent a close and wait for the remote side to acknowledge it. Data
+    /// that was already in flight is dropped and the id can't be reused yet
+    HalfClosed,
+}
+
+enum State<T> {
+    Open(T),
+    HalfClosed,
+}
+
+/// StreamMap holds the streams of a connection with their state. Since
+/// version 4 of the wire a close is acknowledged, so both sides agree that
+/// a stream is gone before its id is reused. With older versions a closed
+/// stream is forgotten right away
+pub struct StreamMap<T> {
+    streams: HashMap<Stream, State<T>>,
+    acknowledged: bool,
+}
+
+impl<T> StreamMap<T> {
+    /// streams of a connection with that negotiated wire version
+    pub fn new(version: u8) -> Self {
+        Self {
+            streams: HashMap::default(),
+            acknowledged: version >= 4,
+        }
+    }
+
+    /// tell if closes are acknowledged, a `CloseAck` must then be sent back
+    /// for every received `Close`
+    pub fn acknowledged(&self) -> bool {
+        self.acknowledged
+    }
+
+    pub fn state(&self, id: &Stream) -> Option<StreamState> {
+        self.streams.get(id).map(|state| match state {
+            State::Open(_) => StreamState::Open,
+            State::HalfClosed => StreamState::HalfClosed,
+        })
+    }
+
+    /// the value of an open stream
+    pub fn get(&self, id: &Stream) -> Option<&T> {
+        match self.streams.get(id) {
+            Some(State::Open(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// the value of an open stream
+    pub fn get_mut(&mut self, id: &Stream) -> Option<&mut T> {
+        match self.streams.get_mut(id) {
+            Some(State::Open(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// open a new stream, fails (giving the value back) if the id is still
+    /// in use
+    pub fn open(&mut self, id: Stream, value: T) -> Result<(), T> {
+        if self.streams.contains_key(&id) {
+            return Err(value);
+        }
+
+        self.streams.insert(id, State::Open(value));
+        Ok(())
+    }
+
+    /// close an open stream from our side, the caller then sends a `Close`.
+    /// Returns the value of the stream or None if it's not open (so nothing
+    /// needs to be sent)
+    pub fn close(&mut self, id: Stream) -> Option<T> {
+        match self.streams.remove(&id) {
+            Some(State::Open(value)) => {
+                if self.acknowledged {
+                    self.streams.insert(id, State::HalfClosed);
+                }
+                Some(value)
+            }
+            Some(State::HalfClosed) => {
+                self.streams.insert(id, State::HalfClosed);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// close a stream that was never opened on our side (for example the
+    /// agent failed to connect to the backend), the caller then sends a `Close`
+    pub fn reject(&mut self, id: Stream) {
+        if self.acknowledged {
+            self.streams.insert(id, State::HalfClosed);
+        }
+    }
+
+    /// the remote side closed the stream, it's gone whatever its state is
+    pub fn remote_closed(&mut self, id: Stream) -> Option<T> {
+        match self.streams.remove(&id) {
+            Some(State::Open(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// the remote side acknowledged our close
+    pub fn acked(&mut self, id: Stream) {
+        if let Some(State::HalfClosed) = self.streams.get(&id) {
+            self.streams.remove(&id);
+        }
+    }
+
+    /// ids of the open streams
+    pub fn open_ids(&self) -> Vec<Stream> {
+        self.streams
+            .iter()
+            .filter(|(_, state)| matches!(state, State::Open(_)))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// number of streams waiting for their close to be acknowledged
+    pub fn half_closed(&self) -> usize {
+        self.streams
+            .values()
+            .filter(|state| matches!(state, State::HalfClosed))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.streams.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn acknowledged() {
+        let id = Stream::new(1.into(), 1000);
+        let mut streams = StreamMap::new(4);
+
+        streams.open(id, "first").unwrap();
+        assert_eq!(streams.get(&id), Some(&"first"));
+
+        // closed on our side, the id is not reusable until the ack
+        assert_eq!(streams.close(id), Some("first"));
+        assert_eq!(streams.state(&id), Some(StreamState::HalfClosed));
+        assert_eq!(streams.get(&id), None);
+        assert_eq!(streams.close(id), None);
+        assert_eq!(streams.open(id, "second"), Err("second"));
+        assert_eq!(streams.half_closed(), 1);
+
+        streams.acked(id);
+        assert_eq!(streams.state(&id), None);
+        streams.open(id, "second").unwrap();
+
+        // a late ack doesn't close a reused id
+        streams.acked(id);
+        assert_eq!(streams.state(&id), Some(StreamState::Open));
+
+        // closed by the remote side
+        assert_eq!(streams.remote_closed(id), Some("second"));
+        assert!(streams.is_empty());
+
+        streams.reject(id);
+        assert_eq!(streams.state(&id), Some(StreamState::HalfClosed));
+        // both sides closed at the same time
+        assert_eq!(streams.remote_closed(id), None);
+        assert!(streams.is_empty());
+    }
+
+    #[test]
+    fn unacknowledged() {
+        let id = Stream::new(1.into(), 1000);
+        let mut streams = StreamMap::new(3);
+        assert!(!streams.acknowledged());
+
+        streams.open(id, "first").unwrap();
+        assert_eq!(streams.close(id), Some("first"));
+        assert_eq!(streams.state(&id), None);
+
+        streams.reject(id);
+        assert!(streams.is_empty());
+    }
+}
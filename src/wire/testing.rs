@@ -0,0 +1,231 @@
+//! In-memory connections to test tunnel logic without binding tcp ports.
+//! Both ends of a pair are connected over a `tokio::io::duplex` and can be
+//! used like any other connection
+//!
+//! ```ignore
+//! use diglett::wire::{testing, Message, Registration, Stream};
+//!
+//! let ((mut agent, faults), (mut server, _)) = testing::faulty_pair();
+//! // the next frame the agent writes arrives corrupted
+//! faults.corrupt(1);
+//! agent.write(Stream::new(Registration::from(0), 1), &mut b"hello".to_vec()).await?;
+//! assert!(server.read().await.is_err());
+//! ```
+//!
+//! Available with the `testing` feature
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf, ReadHalf, WriteHalf},
+    time::Sleep,
+};
+
+use super::{
+    frame::FRAME_HEADER_SIZE, keypair, shared, Connection, FrameStream, Split, MAX_PAYLOAD_SIZE,
+    VERSION,
+};
+
+/// a negotiated in-memory connection
+pub type Duplex = Connection<Link, FrameStream>;
+
+#[derive(Debug, Default)]
+struct State {
+    delay: Option<Duration>,
+    corrupt: usize,
+}
+
+/// Faults injected in the writes of one end of a pair. The handle can be
+/// cloned and changed while the connection is in use
+#[derive(Clone, Default, Debug)]
+pub struct Faults {
+    state: Arc<Mutex<State>>,
+}
+
+impl Faults {
+    /// delay every write by the given duration, zero removes the delay
+    pub fn delay(&self, delay: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.delay = if delay.is_zero() { None } else { Some(delay) };
+    }
+
+    /// flip the first byte of the next `count` writes. A frame header and
+    /// its payload are written separately, so a corrupted header fails the
+    /// read of the other end with `Error::InvalidHeader`
+    pub fn corrupt(&self, count: usize) {
+        self.state.lock().unwrap().corrupt = count;
+    }
+
+    fn delayed(&self) -> Option<Duration> {
+        self.state.lock().unwrap().delay
+    }
+
+    fn corrupting(&self) -> bool {
+        self.state.lock().unwrap().corrupt > 0
+    }
+
+    fn corrupted(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.corrupt = state.corrupt.saturating_sub(1);
+    }
+}
+
+/// one end of an in-memory stream with the faults of its writes
+pub struct Link {
+    inner: DuplexStream,
+    faults: Faults,
+    sleep: Option<Pin<Box<Sleep>>>,
+    // the delay of the current write has elapsed
+    delayed: bool,
+}
+
+impl Link {
+    fn new(inner: DuplexStream, faults: Faults) -> Self {
+        Self {
+            inner,
+            faults,
+            sleep: None,
+            delayed: false,
+        }
+    }
+}
+
+impl AsyncRead for Link {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Link {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.delayed {
+            if let Some(delay) = this.faults.delayed() {
+                let sleep = this
+                    .sleep
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(delay)));
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            this.delayed = true;
+        }
+
+        let corrupt = !buf.is_empty() && this.faults.corrupting();
+        let result = if corrupt {
+            let mut data = buf.to_vec();
+            data[0] ^= 0xff;
+            ready!(Pin::new(&mut this.inner).poll_write(cx, &data))
+        } else {
+            ready!(Pin::new(&mut this.inner).poll_write(cx, buf))
+        };
+
+        this.delayed = false;
+        if corrupt && matches!(result, Ok(count) if count > 0) {
+            this.faults.corrupted();
+        }
+
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Split for Link {
+    type Read = ReadHalf<Self>;
+    type Write = WriteHalf<Self>;
+
+    fn split(self) -> (Self::Read, Self::Write) {
+        tokio::io::split(self)
+    }
+}
+
+/// a pair of connections to each other, the client (agent) end first. The
+/// ends share a key and the current protocol version as if they negotiated
+pub fn duplex_pair() -> (Duplex, Duplex) {
+    let ((client, _), (server, _)) = faulty_pair();
+    (client, server)
+}
+
+/// like [`duplex_pair`] with the faults handle of each end
+pub fn faulty_pair() -> ((Duplex, Faults), (Duplex, Faults)) {
+    let (client, server) = tokio::io::duplex(FRAME_HEADER_SIZE + MAX_PAYLOAD_SIZE);
+    let (client_faults, server_faults) = (Faults::default(), Faults::default());
+    let (client_kp, server_kp) = (keypair(), keypair());
+    let key = shared(&client_kp, server_kp.public_key());
+
+    let client = Connection::new(
+        Link::new(client, client_faults.clone()),
+        &key,
+        VERSION,
+        server_kp.public_key(),
+    );
+    let server = Connection::new(
+        Link::new(server, server_faults.clone()),
+        &key,
+        VERSION,
+        client_kp.public_key(),
+    );
+
+    ((client, client_faults), (server, server_faults))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        wire::{Message, Registration, Stream},
+        Error,
+    };
+
+    #[tokio::test]
+    async fn corrupt() {
+        let ((mut client, faults), (mut server, _)) = faulty_pair();
+        let id = Stream::new(Registration::from(0), 1);
+
+        faults.corrupt(1);
+        client.write(id, &mut b"hello".to_vec()).await.unwrap();
+        assert!(matches!(server.read().await, Err(Error::InvalidHeader)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay() {
+        let ((mut client, _), (mut server, faults)) = faulty_pair();
+        let id = Stream::new(Registration::from(0), 1);
+
+        faults.delay(Duration::from_secs(2));
+        let started = tokio::time::Instant::now();
+        server.write(id, &mut b"hello".to_vec()).await.unwrap();
+        match client.read().await.unwrap() {
+            Message::Payload { data, .. } => assert_eq!(data, b"hello"),
+            msg => panic!("expected payload got: {:?}", msg),
+        }
+        // the header and the payload are delayed
+        assert!(started.elapsed() >= Duration::from_secs(4));
+
+        faults.delay(Duration::ZERO);
+        let started = tokio::time::Instant::now();
+        server.write(id, &mut b"again".to_vec()).await.unwrap();
+        client.read().await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
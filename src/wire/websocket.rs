@@ -0,0 +1,117 @@
+//! websocket transport of the browser build (wasm32). Browsers can't open
+//! tcp connections, so the wire protocol is carried by the binary messages
+//! of a websocket to the websocket listener of the server:
+//!
+//! ```ignore
+//! let stream = websocket::connect("wss://gateway.com:20443").await?;
+//! let mut connection = Client::new(stream, keypair()).negotiate().await?;
+//! client::dial(&mut connection, "db", "secret").await?;
+//! ```
+use js_sys::{ArrayBuffer, Uint8Array};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    sync::mpsc,
+};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, ErrorEvent, MessageEvent, WebSocket};
+
+use crate::{Error, Result};
+
+/// size of the buffer between the websocket and the connection
+const BUFFER: usize = 64 * 1024;
+
+// events of the websocket, handled by the relay tasks
+enum Event {
+    Open,
+    Message(Vec<u8>),
+    Closed(String),
+}
+
+/// connect to the websocket listener of the server, the returned stream
+/// is the transport of a wire client. It must be called from a browser
+/// task (wasm_bindgen_futures) since the websocket is not Send
+pub async fn connect(url: &str) -> Result<DuplexStream> {
+    let socket = WebSocket::new(url).map_err(js_error)?;
+    socket.set_binary_type(BinaryType::Arraybuffer);
+
+    let (events, mut received) = mpsc::unbounded_channel();
+
+    let sender = events.clone();
+    let on_open = Closure::<dyn FnMut()>::new(move || {
+        let _ = sender.send(Event::Open);
+    });
+    let sender = events.clone();
+    let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+        // text messages are not part of the protocol
+        if let Ok(data) = event.data().dyn_into::<ArrayBuffer>() {
+            let _ = sender.send(Event::Message(Uint8Array::new(&data).to_vec()));
+        }
+    });
+    let sender = events.clone();
+    let on_error = Closure::<dyn FnMut(ErrorEvent)>::new(move |event: ErrorEvent| {
+        let _ = sender.send(Event::Closed(event.message()));
+    });
+    let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |event: CloseEvent| {
+        let _ = events.send(Event::Closed(format!("closed with code {}", event.code())));
+    });
+
+    socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+    socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+    socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+    match received.recv().await {
+        Some(Event::Open) => {}
+        Some(Event::Closed(reason)) => return Err(websocket_error(reason)),
+        _ => return Err(websocket_error("failed to open websocket")),
+    }
+
+    let (stream, relayed) = tokio::io::duplex(BUFFER);
+    let (mut reader, mut writer) = tokio::io::split(relayed);
+
+    // the callbacks live as long as the messages are received
+    wasm_bindgen_futures::spawn_local(async move {
+        let _callbacks = (on_open, on_message, on_error, on_close);
+        while let Some(event) = received.recv().await {
+            match event {
+                Event::Message(data) => {
+                    if writer.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                Event::Closed(reason) => {
+                    log::debug!("websocket closed: {}", reason);
+                    break;
+                }
+                Event::Open => {}
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let mut buf = vec![0; BUFFER];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(count) => {
+                    if let Err(err) = socket.send_with_u8_array(&buf[..count]) {
+                        log::debug!("failed to send to websocket: {:?}", err);
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = socket.close();
+    });
+
+    Ok(stream)
+}
+
+fn websocket_error<S: Into<String>>(reason: S) -> Error {
+    Error::IO(std::io::Error::other(reason.into()))
+}
+
+fn js_error(value: JsValue) -> Error {
+    websocket_error(format!("{:?}", value))
+}